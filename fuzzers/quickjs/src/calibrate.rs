@@ -0,0 +1,203 @@
+use crate::runner::{Runner, Verdict};
+use crate::targets::Target;
+use crate::triage::list_inputs;
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Number of runs of each seed
+const CALIBRATION_RUNS: u32 = 4;
+/// Factor applied to the slowest seed to get the fuzzing timeout
+const TIMEOUT_FACTOR: u32 = 5;
+/// Lower bound of a calibrated timeout
+const MIN_TIMEOUT: Duration = Duration::from_millis(20);
+/// Number of seeds whose coverage stability is measured
+const STABILITY_SAMPLE: usize = 16;
+
+/// Measurements of a seed
+struct SeedCalibration {
+    /// Average execution time
+    average: Duration,
+    /// Slowest execution time
+    slowest: Duration,
+    /// Verdict of the first run
+    verdict: &'static str,
+    /// Whether every run had the same verdict
+    stable: bool,
+}
+
+/// Short name of a verdict
+fn verdict_name(verdict: &Verdict) -> &'static str {
+    match verdict {
+        Verdict::Ok => "ok",
+        Verdict::Crash(..) => "crash",
+        Verdict::Timeout => "timeout",
+        Verdict::Denied(_) => "denied",
+    }
+}
+
+/// Runs a seed `CALIBRATION_RUNS` times
+fn calibrate_seed(runner: &mut Runner, input: &[u8]) -> SeedCalibration {
+    let mut total = Duration::ZERO;
+    let mut slowest = Duration::ZERO;
+    let mut verdicts = Vec::new();
+
+    for _ in 0..CALIBRATION_RUNS {
+        let start = Instant::now();
+        let (verdict, _) = runner.run(input);
+        let elapsed = start.elapsed();
+
+        total += elapsed;
+        slowest = slowest.max(elapsed);
+        verdicts.push(verdict_name(&verdict));
+    }
+
+    SeedCalibration {
+        average: total / CALIBRATION_RUNS,
+        slowest,
+        verdict: verdicts[0],
+        stable: verdicts.iter().all(|v| *v == verdicts[0]),
+    }
+}
+
+/// Runs each seed of `corpus_dir` a few times before fuzzing, writing the
+/// measurements to `<output_dir>/calibration.txt`. The coverage breakpoints
+/// being one-shot, the stability of a seed is the stability of its verdict.
+///
+/// Returns a timeout fitting the seeds that exited normally, if any.
+pub fn calibrate(
+    target: &Target,
+    dictionary: Option<&str>,
+    timeout: Duration,
+    corpus_dir: &Path,
+    output_dir: &Path,
+) -> Option<Duration> {
+    let mut runner = Runner::new(target, dictionary, timeout, false);
+    let mut report = String::new();
+    let mut slowest = None;
+
+    for path in list_inputs(corpus_dir) {
+        let input = fs::read(&path).expect("Could not read seed");
+        let seed = calibrate_seed(&mut runner, &input);
+
+        report.push_str(&format!(
+            "{} average {}us slowest {}us verdict {} {}\n",
+            path.file_name().unwrap().to_string_lossy(),
+            seed.average.as_micros(),
+            seed.slowest.as_micros(),
+            seed.verdict,
+            match seed.stable {
+                true => "stable",
+                false => "unstable",
+            }
+        ));
+
+        if seed.stable && seed.verdict == "ok" {
+            slowest = slowest.max(Some(seed.slowest));
+        }
+    }
+
+    fs::create_dir_all(output_dir).expect("Could not create output directory");
+    fs::write(output_dir.join("calibration.txt"), report)
+        .expect("Could not write calibration file");
+
+    slowest.map(|slowest| (slowest * TIMEOUT_FACTOR).max(MIN_TIMEOUT))
+}
+
+/// Formats a coverage address relative to its module
+fn describe(runner: &Runner, address: u64) -> String {
+    match runner
+        .modules()
+        .values()
+        .find(|m| m.start <= address && address < m.end)
+    {
+        Some(module) => format!("{}+0x{:x}", module.name, address - module.start),
+        None => format!("0x{:x}", address),
+    }
+}
+
+/// Runs a sample of the seeds of `corpus_dir` `CALIBRATION_RUNS` times each
+/// with persistent coverage, writing the coverage points which were not hit
+/// by every run of a seed to `<output_dir>/stability.txt`.
+///
+/// Returns the stability, the percentage of the coverage points hit by the
+/// sample which were hit by every run of the seeds hitting them, like AFL.
+/// Nondeterminism (uninitialized memory, timing...) lowers it.
+pub fn measure_stability(
+    target: &Target,
+    dictionary: Option<&str>,
+    timeout: Duration,
+    corpus_dir: &Path,
+    output_dir: &Path,
+) -> Option<f64> {
+    let mut runner = Runner::new(target, dictionary, timeout, true);
+    runner.set_persistent_coverage();
+
+    let seeds = list_inputs(corpus_dir);
+    let step = (seeds.len() / STABILITY_SAMPLE).max(1);
+
+    let mut stable = BTreeSet::new();
+    let mut variable = BTreeSet::new();
+    let mut report = String::new();
+
+    for path in seeds.iter().step_by(step).take(STABILITY_SAMPLE) {
+        let input = fs::read(path).expect("Could not read seed");
+
+        // Points hit by every run, and by any run
+        let mut always: Option<BTreeSet<u64>> = None;
+        let mut any = BTreeSet::new();
+        let mut counts = Vec::new();
+        for _ in 0..CALIBRATION_RUNS {
+            runner.run(&input);
+            let hits = runner.hits();
+
+            counts.push(hits.len());
+            any.extend(hits.iter().copied());
+            always = Some(match always {
+                Some(always) => always.intersection(hits).copied().collect(),
+                None => hits.clone(),
+            });
+        }
+
+        let always = always.unwrap_or_default();
+        let seed_variable: Vec<u64> = any.difference(&always).copied().collect();
+        stable.extend(always);
+        variable.extend(seed_variable.iter().copied());
+
+        report.push_str(&format!(
+            "{} hits min {} max {} variable {}",
+            path.file_name().unwrap().to_string_lossy(),
+            counts.iter().min().unwrap(),
+            counts.iter().max().unwrap(),
+            seed_variable.len()
+        ));
+        for address in seed_variable {
+            report.push_str(&format!(" {}", describe(&runner, address)));
+        }
+        report.push('\n');
+    }
+
+    // A point is stable if no seed hit it nondeterministically
+    let stable = stable.difference(&variable).count();
+    let total = stable + variable.len();
+    let stability = match total {
+        0 => None,
+        _ => Some(stable as f64 * 100.0 / total as f64),
+    };
+
+    report.insert_str(
+        0,
+        &format!(
+            "stability {:.2}% ({} stable, {} variable coverage points)\n",
+            stability.unwrap_or(100.0),
+            stable,
+            variable.len()
+        ),
+    );
+    fs::create_dir_all(output_dir).expect("Could not create output directory");
+    fs::write(output_dir.join("stability.txt"), report).expect("Could not write stability file");
+
+    stability
+}
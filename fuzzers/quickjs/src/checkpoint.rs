@@ -0,0 +1,216 @@
+//! Periodic checkpoints of the campaign state of the clients
+
+use crate::scheduler::{EntryEnergy, WeightedCorpusScheduler};
+use crate::shared_map::SharedCoverageMap;
+
+use libafl::{
+    bolts::HasLen,
+    corpus::{Corpus, Testcase},
+    inputs::Input,
+    state::{HasCorpus, HasExecutions, HasMetadata, HasRand, HasSolutions},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default interval between two checkpoints
+pub(crate) const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// State of a client at the time of a checkpoint
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Time of the checkpoint, in seconds since the epoch
+    pub time: u64,
+    /// Executions of the client
+    pub executions: usize,
+    /// Entries in the corpus of the client
+    pub corpus: usize,
+    /// Entries in the solutions of the client
+    pub objectives: usize,
+    /// Energy of the corpus entries by queue file name
+    pub energies: BTreeMap<String, SavedEnergy>,
+    /// Hit count buckets of the coverage map shared by the workers of the
+    /// target, as hex
+    pub coverage: String,
+}
+
+/// Energy of a corpus entry saved in a checkpoint, its path and coverage
+/// points being recomputed when the entry is loaded again
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedEnergy {
+    /// Weight of the entry from its speed and size
+    pub speed: u64,
+    /// Number of times the entry was picked
+    pub scheduled: u64,
+}
+
+/// Path of the checkpoint of the client on `core_id` in an output directory
+pub fn checkpoint_path<P: AsRef<Path>>(output_dir: P, core_id: usize) -> PathBuf {
+    output_dir
+        .as_ref()
+        .join(format!("checkpoint.{}.json", core_id))
+}
+
+/// Name of the queue file of a corpus entry
+fn queue_name<I: Input>(testcase: &Testcase<I>) -> Option<String> {
+    let path = testcase.filename().as_ref()?;
+    let name = Path::new(path).file_name()?;
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Encodes bytes as hex
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes hex, `None` if it is malformed
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl Checkpoint {
+    /// Captures the state of a client fuzzing a target covered by `coverage`
+    pub fn capture<I, S>(state: &S, coverage: &SharedCoverageMap) -> Result<Self, Error>
+    where
+        I: Input,
+        S: HasCorpus<I> + HasSolutions<I> + HasExecutions + HasMetadata,
+    {
+        let mut energies = BTreeMap::new();
+        for idx in 0..state.corpus().count() {
+            let testcase = state.corpus().get(idx)?.borrow();
+            let energy = testcase.metadata().get::<EntryEnergy>();
+            if let (Some(name), Some(energy)) = (queue_name(&testcase), energy) {
+                energies.insert(
+                    name,
+                    SavedEnergy {
+                        speed: energy.speed,
+                        scheduled: energy.scheduled,
+                    },
+                );
+            }
+        }
+
+        Ok(Checkpoint {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            executions: *state.executions(),
+            corpus: state.corpus().count(),
+            objectives: state.solutions().count(),
+            energies,
+            coverage: to_hex(&coverage.snapshot()),
+        })
+    }
+
+    /// Reads a checkpoint, `None` if there is none or it cannot be parsed
+    pub fn read<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let content = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Writes the checkpoint atomically: it is written to a temporary file
+    /// renamed over the previous checkpoint once synced
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("json.tmp");
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Returns the coverage map of the checkpoint, `None` if it is malformed
+    pub fn coverage(&self) -> Option<Vec<u8>> {
+        from_hex(&self.coverage)
+    }
+
+    /// Restores the energies of the corpus entries found in the checkpoint,
+    /// matched by queue file name, and recomputes their weights
+    pub fn restore_energies<I, S>(
+        &self,
+        state: &mut S,
+        scheduler: &WeightedCorpusScheduler<I, S>,
+    ) -> Result<(), Error>
+    where
+        I: Input + HasLen,
+        S: HasCorpus<I> + HasMetadata + HasRand,
+    {
+        for idx in 0..state.corpus().count() {
+            {
+                let mut testcase = state.corpus().get(idx)?.borrow_mut();
+                let saved = match queue_name(&testcase).and_then(|n| self.energies.get(&n)) {
+                    Some(&saved) => saved,
+                    None => continue,
+                };
+                let entry = match testcase.metadata_mut().get_mut::<EntryEnergy>() {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                entry.speed = saved.speed.max(1);
+                entry.scheduled = saved.scheduled;
+            }
+
+            scheduler.update_weight(state, idx, false)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes a checkpoint of the state of a client to `path`, logging the
+/// failures instead of stopping the client
+pub fn save_checkpoint<I, S>(state: &S, coverage: &SharedCoverageMap, path: &Path)
+where
+    I: Input,
+    S: HasCorpus<I> + HasSolutions<I> + HasExecutions + HasMetadata,
+{
+    let result = Checkpoint::capture(state, coverage)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{:?}", err)))
+        .and_then(|checkpoint| checkpoint.write(path));
+
+    match result {
+        Ok(()) => log::debug!("Checkpoint written to {}", path.display()),
+        Err(err) => log::warn!("Could not write checkpoint {}: {}", path.display(), err),
+    }
+}
+
+/// Merges the coverage maps of the checkpoints in an output directory,
+/// `None` if there are none
+pub fn checkpoint_coverage<P: AsRef<Path>>(output_dir: P) -> Option<Vec<u8>> {
+    let mut merged: Option<Vec<u8>> = None;
+
+    for entry in fs::read_dir(output_dir).ok()?.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("checkpoint.") || !name.ends_with(".json") {
+            continue;
+        }
+
+        let coverage = match Checkpoint::read(entry.path()).and_then(|c| c.coverage()) {
+            Some(coverage) => coverage,
+            None => continue,
+        };
+        match &mut merged {
+            Some(merged) => {
+                for (entry, buckets) in merged.iter_mut().zip(coverage) {
+                    *entry |= buckets;
+                }
+            }
+            None => merged = Some(coverage),
+        }
+    }
+
+    merged
+}
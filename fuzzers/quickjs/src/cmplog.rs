@@ -0,0 +1,257 @@
+//! Input-to-state replacement of the operands of the comparisons, in the
+//! style of RedQueen
+
+use crate::runner::{LoggedComparison, Runner, Verdict};
+use crate::shutdown::terminating;
+use crate::targets::Target;
+
+use libafl::{
+    bolts::rands::Rand,
+    corpus::Corpus,
+    fuzzer::Evaluator,
+    inputs::{HasBytesVec, Input},
+    stages::Stage,
+    state::{HasCorpus, HasMetadata, HasRand},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+use std::marker::PhantomData;
+use std::ops::Range;
+
+/// Maximum number of runs spent colorizing an entry
+const MAX_COLORIZATION_RUNS: usize = 256;
+
+/// Maximum number of replacements evaluated for an entry
+const MAX_REPLACEMENTS: usize = 1024;
+
+/// Marks the corpus entries whose comparisons were already solved, with the
+/// number of replacements evaluated
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InputToStateDone {
+    /// Number of replaced inputs evaluated
+    pub replacements: usize,
+}
+
+libafl::impl_serdeany!(InputToStateDone);
+
+/// Stage solving the comparisons of the corpus entries scheduled for the
+/// first time against magic values. The entry is first colorized: chunks of
+/// tokens are replaced by random bytes as long as it hits the same coverage
+/// points, so that the bytes reaching a comparison are easy to tell apart.
+/// The operands of the comparisons run by the colorized entry are then
+/// searched in it, and each match is replaced in the entry by the other
+/// operand. The replaced entries are evaluated like any fuzz case. The
+/// comparisons are logged by a runner with breakpoints on the `cmp`
+/// instructions and the memory comparison functions. Disabled without a
+/// runner.
+pub struct InputToStateStage<I> {
+    /// Runner logging the comparisons, if enabled
+    runner: Option<Runner>,
+    phantom: PhantomData<I>,
+}
+
+impl<I> InputToStateStage<I> {
+    /// Creates a stage logging the comparisons of `target` with a runner
+    /// instrumented with the coverage breakpoints, disabled if not set
+    pub fn new(mut runner: Option<Runner>, target: &Target) -> Self {
+        if let Some(runner) = &mut runner {
+            let sites = runner.set_comparison_log(target);
+            log::info!("Logging {} comparison sites for {}", sites, target.name);
+        }
+
+        InputToStateStage {
+            runner,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the stable coverage points hit by a normal run of an input,
+    /// none if it did not exit normally
+    fn signature(
+        runner: &mut Runner,
+        input: &[u8],
+        unstable: &BTreeSet<u64>,
+    ) -> Option<BTreeSet<u64>> {
+        match runner.run(input).0 {
+            Verdict::Ok => Some(runner.hits().difference(unstable).copied().collect()),
+            _ => None,
+        }
+    }
+
+    /// Replaces chunks of tokens of `input` by random bytes as long as its
+    /// coverage signature stays `signature`, within `MAX_COLORIZATION_RUNS`
+    /// runs
+    fn colorize<R: Rand>(
+        runner: &mut Runner,
+        rand: &mut R,
+        input: &mut [u8],
+        signature: &BTreeSet<u64>,
+        unstable: &BTreeSet<u64>,
+    ) {
+        let mut runs = 0;
+
+        // Chunks are tried from the largest, skipping the parts of the
+        // ranges already colorized
+        let mut pending = vec![Range {
+            start: 0,
+            end: input.len() & !1,
+        }];
+        while let Some(range) = pending.pop() {
+            if range.is_empty() || runs == MAX_COLORIZATION_RUNS || terminating() {
+                continue;
+            }
+            runs += 1;
+
+            let mut candidate = input.to_vec();
+            for byte in &mut candidate[range.clone()] {
+                *byte = rand.below(256) as u8;
+            }
+
+            if Self::signature(runner, &candidate, unstable).as_ref() == Some(signature) {
+                input.copy_from_slice(&candidate);
+            } else if range.len() > 2 {
+                let middle = range.start + ((range.len() / 2) & !1);
+                pending.push(middle..range.end);
+                pending.push(range.start..middle);
+            }
+        }
+    }
+
+    /// Returns the values to search in the input for each comparison, with
+    /// the values replacing them: each operand for the other one, in both
+    /// byte orders for the integers
+    fn patterns(comparisons: &[LoggedComparison]) -> BTreeSet<(Vec<u8>, Vec<u8>)> {
+        let mut patterns = BTreeSet::new();
+
+        for comparison in comparisons {
+            match comparison {
+                LoggedComparison::Integer(operands) if operands.left != operands.right => {
+                    // Single bytes match anywhere in a colorized input
+                    let size = operands.size as usize;
+                    if size < 2 {
+                        continue;
+                    }
+                    for (from, to) in [
+                        (operands.left, operands.right),
+                        (operands.right, operands.left),
+                    ] {
+                        let from = from.to_le_bytes()[..size].to_vec();
+                        let to = to.to_le_bytes()[..size].to_vec();
+                        let reversed = |bytes: &[u8]| bytes.iter().rev().copied().collect();
+                        patterns.insert((reversed(&from), reversed(&to)));
+                        patterns.insert((from, to));
+                    }
+                }
+                LoggedComparison::Bytes(left, right) if left != right => {
+                    let len = left.len().min(right.len());
+                    if len < 2 {
+                        continue;
+                    }
+                    patterns.insert((left[..len].to_vec(), right[..len].to_vec()));
+                    patterns.insert((right[..len].to_vec(), left[..len].to_vec()));
+                }
+                _ => {}
+            }
+        }
+
+        patterns
+    }
+}
+
+impl<E, EM, I, S, Z> Stage<E, EM, S, Z> for InputToStateStage<I>
+where
+    I: Input + HasBytesVec,
+    S: HasCorpus<I> + HasRand,
+    Z: Evaluator<E, EM, I, S>,
+{
+    fn perform(
+        &mut self,
+        fuzzer: &mut Z,
+        executor: &mut E,
+        state: &mut S,
+        manager: &mut EM,
+        corpus_idx: usize,
+    ) -> Result<(), Error> {
+        let runner = match &mut self.runner {
+            Some(runner) => runner,
+            None => return Ok(()),
+        };
+
+        let original = {
+            let mut testcase = state.corpus().get(corpus_idx)?.borrow_mut();
+            if testcase.has_metadata::<InputToStateDone>() {
+                return Ok(());
+            }
+            testcase.load_input()?.clone()
+        };
+
+        // Step 1: Coverage of the entry, without its unstable points
+        let none = BTreeSet::new();
+        let baseline = match (
+            Self::signature(runner, original.bytes(), &none),
+            Self::signature(runner, original.bytes(), &none),
+        ) {
+            (Some(first), Some(second)) => {
+                let unstable: BTreeSet<u64> =
+                    first.symmetric_difference(&second).copied().collect();
+                let signature = first.difference(&unstable).copied().collect();
+                Some((signature, unstable))
+            }
+            _ => None,
+        };
+
+        // Step 2: Colorization, then comparisons of the colorized entry
+        let mut replacements = Vec::new();
+        if let Some((signature, unstable)) = baseline {
+            let mut colorized = original.bytes().to_vec();
+            Self::colorize(
+                runner,
+                state.rand_mut(),
+                &mut colorized,
+                &signature,
+                &unstable,
+            );
+            runner.run(&colorized);
+
+            // Step 3: Replacement of the operands found in the colorized
+            // entry, at the same offsets in the original one
+            for (from, to) in Self::patterns(runner.comparisons()) {
+                for offset in 0..colorized.len().saturating_sub(from.len() - 1) {
+                    if colorized[offset..offset + from.len()] == from[..] {
+                        let mut replaced = original.clone();
+                        replaced.bytes_mut()[offset..offset + to.len()].copy_from_slice(&to);
+                        replacements.push(replaced);
+                    }
+                }
+            }
+        }
+        replacements.truncate(MAX_REPLACEMENTS);
+
+        let evaluated = replacements.len();
+        for replaced in replacements {
+            fuzzer.evaluate_input(state, executor, manager, replaced)?;
+
+            // The replacements of a large entry are cut short on shutdown
+            if terminating() {
+                break;
+            }
+        }
+
+        log::debug!(
+            "Entry {}: {} input-to-state replacements",
+            corpus_idx,
+            evaluated
+        );
+        state
+            .corpus()
+            .get(corpus_idx)?
+            .borrow_mut()
+            .add_metadata(InputToStateDone {
+                replacements: evaluated,
+            });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+use crate::checkpoint;
+use crate::corpus;
+use crate::formats::InputFormat;
+use crate::fuzz::{self, FeedbackMethod, FuzzerConfig};
+use crate::mutator;
+use crate::plateau::PlateauActions;
+use crate::report::ReportFormat;
+use crate::scheduler::PowerSchedule;
+use crate::targets;
+
+use clap::ArgMatches;
+use libafl::bolts::core_affinity::Cores;
+use tartiflette_vm::BranchRecording;
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Error in the command line configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A flag has a value which could not be parsed
+    InvalidValue {
+        /// Name of the flag
+        flag: &'static str,
+        /// Value given on the command line
+        value: String,
+        /// Description of the expected values
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidValue {
+                flag,
+                value,
+                expected,
+            } => write!(
+                f,
+                "invalid value '{}' for --{}: expected {}",
+                value, flag, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Result type of the configuration parsing
+type Result<T> = std::result::Result<T, ConfigError>;
+
+/// Parses the value of an optional flag
+fn parse<T: FromStr>(
+    matches: &ArgMatches,
+    id: &str,
+    flag: &'static str,
+    expected: &'static str,
+) -> Result<Option<T>> {
+    matches
+        .value_of(id)
+        .map(|value| {
+            value.parse().map_err(|_| ConfigError::InvalidValue {
+                flag,
+                value: value.to_string(),
+                expected,
+            })
+        })
+        .transpose()
+}
+
+/// Returns the feedback method selected by `--feedback`, `hw` recording the
+/// branches with the mechanism given by `--branches` (LBR by default).
+/// `--branches` alone selects `hw`, and `--edges` only applies to `soft`.
+fn compute_feedback(matches: &ArgMatches) -> Result<FeedbackMethod> {
+    let recording = parse::<BranchRecording>(matches, "branches", "branches", "lbr")?;
+    let method = match matches.value_of("feedback") {
+        Some(method) => method,
+        None if recording.is_some() => "hw",
+        None => "soft",
+    };
+
+    let feedback = match method {
+        "soft" => FeedbackMethod::Soft,
+        "hw" => FeedbackMethod::Hw(recording.unwrap_or(BranchRecording::Lbr)),
+        "none" => FeedbackMethod::None,
+        _ => {
+            return Err(ConfigError::InvalidValue {
+                flag: "feedback",
+                value: method.to_string(),
+                expected: "one of soft, hw or none",
+            })
+        }
+    };
+
+    if let Some(branches) = matches.value_of("branches").filter(|_| method != "hw") {
+        return Err(ConfigError::InvalidValue {
+            flag: "branches",
+            value: branches.to_string(),
+            expected: "no mechanism without the hw feedback",
+        });
+    }
+    if matches.is_present("edges") && feedback != FeedbackMethod::Soft {
+        return Err(ConfigError::InvalidValue {
+            flag: "feedback",
+            value: method.to_string(),
+            expected: "soft with --edges",
+        });
+    }
+
+    Ok(feedback)
+}
+
+/// Builds the fuzzer configuration from the command line, validating the
+/// values used later on by the fuzzing clients
+pub fn parse_config(matches: &ArgMatches) -> Result<FuzzerConfig> {
+    let cores = matches.value_of("cores").unwrap();
+    if Cores::from_cmdline(cores).is_err() {
+        return Err(ConfigError::InvalidValue {
+            flag: "core",
+            value: cores.to_string(),
+            expected: "a list of cores (e.g. 1,3-5) or 'all'",
+        });
+    }
+    parse::<u16>(matches, "broker_port", "port", "a port number")?;
+    parse::<SocketAddr>(matches, "broker_address", "address", "an ip:port address")?;
+    parse::<SocketAddr>(matches, "status", "status", "an ip:port address")?;
+
+    let timeout: u64 = parse(matches, "timeout", "timeout", "a number of milliseconds")?.unwrap();
+    if timeout == 0 {
+        return Err(ConfigError::InvalidValue {
+            flag: "timeout",
+            value: timeout.to_string(),
+            expected: "at least one millisecond",
+        });
+    }
+
+    let max_stack_pow = parse(matches, "stack_pow", "stack-pow", "a number from 1 to 63")?
+        .unwrap_or(mutator::DEFAULT_MAX_STACK_POW);
+    if !(1..64).contains(&max_stack_pow) {
+        return Err(ConfigError::InvalidValue {
+            flag: "stack-pow",
+            value: max_stack_pow.to_string(),
+            expected: "a number from 1 to 63",
+        });
+    }
+
+    let corpus_cache = parse(
+        matches,
+        "corpus_cache",
+        "corpus-cache",
+        "a number of inputs",
+    )?
+    .unwrap_or(corpus::DEFAULT_CACHE_SIZE);
+    if corpus_cache == 0 {
+        return Err(ConfigError::InvalidValue {
+            flag: "corpus-cache",
+            value: corpus_cache.to_string(),
+            expected: "at least one input",
+        });
+    }
+
+    let speed_pow = parse(matches, "speed_pow", "speed-pow", "a number from 0 to 62")?
+        .unwrap_or(mutator::DEFAULT_SPEED_POW);
+    if speed_pow > 62 {
+        return Err(ConfigError::InvalidValue {
+            flag: "speed-pow",
+            value: speed_pow.to_string(),
+            expected: "a number from 0 to 62",
+        });
+    }
+
+    let feedback = compute_feedback(matches)?;
+
+    // The rare and fast schedules compare the coverage of different runs,
+    // empty past the first hit of one-shot coverage breakpoints
+    let schedule: PowerSchedule = parse(
+        matches,
+        "schedule",
+        "schedule",
+        "one of speed, round-robin, rare or fast",
+    )?
+    .unwrap();
+    let repeatable = match feedback {
+        FeedbackMethod::Soft => matches.is_present("edges"),
+        FeedbackMethod::Hw(_) => true,
+        FeedbackMethod::None => false,
+    };
+    if schedule.tracks_paths() && !repeatable {
+        return Err(ConfigError::InvalidValue {
+            flag: "schedule",
+            value: schedule.to_string(),
+            expected: "speed or round-robin without --edges or the hw feedback",
+        });
+    }
+
+    if let Some(path) = matches.value_of("targets") {
+        let names = targets::read_targets(path)
+            .map_err(|_| ConfigError::InvalidValue {
+                flag: "targets",
+                value: path.to_string(),
+                expected: "a JSON list of targets with unique names",
+            })?
+            .into_iter()
+            .map(|target| target.name)
+            .collect::<Vec<_>>();
+
+        if let Some(name) = matches.value_of("target") {
+            if !names.iter().any(|n| n == name) {
+                return Err(ConfigError::InvalidValue {
+                    flag: "target",
+                    value: name.to_string(),
+                    expected: "the name of a target of the targets file",
+                });
+            }
+        }
+    }
+
+    Ok(FuzzerConfig {
+        cores,
+        broker_address: matches.value_of("broker_address"),
+        broker_port: matches.value_of("broker_port").unwrap(),
+        crash_dir: matches.value_of("crash_dir"),
+        timeout_dir: matches.value_of("timeout_dir"),
+        cov_dir: matches.value_of("cov_dir"),
+        legacy_layout: matches.is_present("legacy_layout"),
+        timeout: Duration::from_millis(timeout),
+        auto_timeout: matches.occurrences_of("timeout") == 0,
+        dictionary: matches.value_of("dictionary"),
+        tui: matches.is_present("tui"),
+        report_format: parse(matches, "report", "report", "one of text or json")?
+            .unwrap_or(ReportFormat::Text),
+        quiet: matches.is_present("quiet"),
+        output_dir: matches.value_of("output_dir").unwrap(),
+        edge_coverage: matches.is_present("edges"),
+        feedback,
+        drcov: matches.value_of("drcov"),
+        access_trace: matches.value_of("access_trace"),
+        max_input_size: parse(
+            matches,
+            "max_input_size",
+            "max-input-size",
+            "a size in bytes",
+        )?
+        .unwrap_or(fuzz::DEFAULT_MAX_INPUT_SIZE),
+        fixed_input_size: matches.is_present("fixed_size"),
+        corpus_cache,
+        seed: parse(matches, "seed", "seed", "an unsigned 64 bits integer")?,
+        max_stack_pow,
+        speed_pow,
+        mutation_weights: matches.value_of("weights"),
+        adaptive_weights: matches.is_present("mopt"),
+        taint: matches.is_present("taint"),
+        trim: matches.is_present("trim"),
+        cmplog: matches.is_present("cmplog"),
+        schedule,
+        input_format: parse(
+            matches,
+            "format",
+            "format",
+            "one of raw, chunks, tlv or json",
+        )?
+        .unwrap_or(InputFormat::Raw),
+        input_delivery: parse(
+            matches,
+            "input_delivery",
+            "input-delivery",
+            "one of registers, shared, hypercall or hypercall:<number>",
+        )?,
+        checkpoint_interval: match parse(
+            matches,
+            "checkpoint",
+            "checkpoint",
+            "a number of seconds",
+        )? {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => Some(checkpoint::DEFAULT_CHECKPOINT_INTERVAL),
+        },
+        plateau: parse(matches, "plateau", "plateau", "a number of seconds")?
+            .map(Duration::from_secs),
+        plateau_actions: parse(
+            matches,
+            "plateau_actions",
+            "plateau-actions",
+            "a list of log, mutations, deterministic or exit",
+        )?
+        .unwrap_or(PlateauActions {
+            log: true,
+            ..PlateauActions::default()
+        }),
+        run_time: parse(matches, "run_time", "run-time", "a number of seconds")?
+            .map(Duration::from_secs),
+        max_crashes: parse(
+            matches,
+            "max_crashes",
+            "exit-on-crash-count",
+            "a number of crashes",
+        )?,
+        status_address: matches.value_of("status"),
+        targets: matches.value_of("targets"),
+        target: matches.value_of("target"),
+        target_schedule: parse(
+            matches,
+            "target_schedule",
+            "target-schedule",
+            "static or dynamic",
+        )?
+        .unwrap(),
+        log_level: parse(matches, "log_level", "log-level", "a log level (e.g. info)")?.unwrap(),
+    })
+}
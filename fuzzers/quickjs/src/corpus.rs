@@ -0,0 +1,168 @@
+//! On disk corpus keeping only the most recently used inputs in memory
+
+use libafl::{
+    corpus::{ondisk::OnDiskMetadataFormat, Corpus, OnDiskCorpus, Testcase},
+    inputs::Input,
+    Error,
+};
+use serde::{Deserialize, Serialize};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// Default number of inputs kept in memory
+pub(crate) const DEFAULT_CACHE_SIZE: usize = 4096;
+
+/// Least recently used order of the entries whose input is in memory
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+struct LruIndex {
+    /// Last use of each cached entry
+    stamps: HashMap<usize, u64>,
+    /// Cached entries by last use
+    order: BTreeMap<u64, usize>,
+    /// Counter of the uses
+    clock: u64,
+}
+
+impl LruIndex {
+    /// Marks the entry at `idx` as the most recently used one
+    fn touch(&mut self, idx: usize) {
+        self.clock += 1;
+        if let Some(stamp) = self.stamps.insert(idx, self.clock) {
+            self.order.remove(&stamp);
+        }
+        self.order.insert(self.clock, idx);
+    }
+
+    /// Removes the entry at `idx` from the cached entries
+    fn forget(&mut self, idx: usize) {
+        if let Some(stamp) = self.stamps.remove(&idx) {
+            self.order.remove(&stamp);
+        }
+    }
+
+    /// Returns the least recently used entry
+    fn oldest(&self) -> Option<usize> {
+        self.order.values().next().copied()
+    }
+
+    /// Shifts the entries following a removed one
+    fn shift_after(&mut self, removed: usize) {
+        for idx in self.order.values_mut().filter(|idx| **idx > removed) {
+            *idx -= 1;
+        }
+        self.stamps = self.order.iter().map(|(&s, &idx)| (idx, s)).collect();
+    }
+}
+
+/// Corpus saving its entries to disk and keeping in memory the inputs of the
+/// `cache_size` most recently used ones. The other entries only keep their
+/// metadata (execution time, mutation logs...) and their input is loaded
+/// again on demand.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(bound = "I: serde::de::DeserializeOwned")]
+pub struct IndexedCorpus<I>
+where
+    I: Input,
+{
+    /// Entries, saved to disk
+    inner: OnDiskCorpus<I>,
+    /// Entries whose input is in memory
+    cache: RefCell<LruIndex>,
+    /// Maximum number of inputs in memory
+    cache_size: usize,
+}
+
+impl<I> IndexedCorpus<I>
+where
+    I: Input,
+{
+    /// Creates a corpus in `dir_path`, saving the metadata of the entries in
+    /// `meta_format`
+    pub fn new_save_meta(
+        dir_path: PathBuf,
+        meta_format: Option<OnDiskMetadataFormat>,
+        cache_size: usize,
+    ) -> Result<Self, Error> {
+        if cache_size == 0 {
+            return Err(Error::illegal_argument("The corpus cache size cannot be 0"));
+        }
+
+        Ok(IndexedCorpus {
+            inner: OnDiskCorpus::new_save_meta(dir_path, meta_format)?,
+            cache: RefCell::new(LruIndex::default()),
+            cache_size,
+        })
+    }
+
+    /// Marks the entry at `idx` as used, dropping the least recently used
+    /// inputs past the cache size. Inputs in use are kept.
+    fn touch(&self, idx: usize) -> Result<(), Error> {
+        let mut cache = self.cache.borrow_mut();
+        cache.touch(idx);
+
+        while cache.stamps.len() > self.cache_size {
+            let oldest = cache.oldest().unwrap();
+            match self.inner.get(oldest)?.try_borrow_mut() {
+                Ok(mut testcase) => *testcase.input_mut() = None,
+                Err(_) => break,
+            }
+            cache.forget(oldest);
+        }
+
+        Ok(())
+    }
+}
+
+impl<I> Corpus<I> for IndexedCorpus<I>
+where
+    I: Input,
+{
+    #[inline]
+    fn count(&self) -> usize {
+        self.inner.count()
+    }
+
+    fn add(&mut self, testcase: Testcase<I>) -> Result<usize, Error> {
+        let idx = self.inner.add(testcase)?;
+        self.touch(idx)?;
+        Ok(idx)
+    }
+
+    fn replace(&mut self, idx: usize, testcase: Testcase<I>) -> Result<Testcase<I>, Error> {
+        let previous = self.inner.replace(idx, testcase)?;
+        self.touch(idx)?;
+        Ok(previous)
+    }
+
+    fn remove(&mut self, idx: usize) -> Result<Option<Testcase<I>>, Error> {
+        let testcase = self.inner.remove(idx)?;
+        if testcase.is_some() {
+            let mut cache = self.cache.borrow_mut();
+            cache.forget(idx);
+            cache.shift_after(idx);
+        }
+        Ok(testcase)
+    }
+
+    fn get(&self, idx: usize) -> Result<&RefCell<Testcase<I>>, Error> {
+        let testcase = self.inner.get(idx)?;
+        if testcase.borrow().input().is_none() {
+            testcase.borrow_mut().load_input()?;
+        }
+
+        self.touch(idx)?;
+        Ok(testcase)
+    }
+
+    #[inline]
+    fn current(&self) -> &Option<usize> {
+        self.inner.current()
+    }
+
+    #[inline]
+    fn current_mut(&mut self) -> &mut Option<usize> {
+        self.inner.current_mut()
+    }
+}
@@ -0,0 +1,54 @@
+use crate::executor::install_alarm_handler;
+use crate::fuzz::FuzzerConfig;
+use crate::runner::Runner;
+use crate::targets::selected_target;
+use crate::triage::list_inputs;
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use tartiflette_vm::{write_drcov, Symbolizer};
+
+/// Measures the coverage reached by a corpus, exported as a drcov file if
+/// one is configured
+pub fn coverage<P: AsRef<Path>>(config: FuzzerConfig, corpus_dir: P) {
+    install_alarm_handler();
+    let mut runner = Runner::new(
+        &selected_target(&config),
+        config.dictionary,
+        config.timeout,
+        true,
+    );
+    let mut symbolizer = Symbolizer::new(runner.modules());
+
+    for path in list_inputs(corpus_dir) {
+        let input = fs::read(&path).expect("Could not read input file");
+        let new_coverage = runner.run(&input).1;
+        println!(
+            "{}: {} new coverage points",
+            path.file_name().unwrap().to_string_lossy(),
+            new_coverage
+        );
+
+        // The new points are the last ones covered
+        if log::log_enabled!(log::Level::Debug) {
+            let covered = runner.covered();
+            for &address in &covered[covered.len() - new_coverage..] {
+                log::debug!("New coverage: {}", symbolizer.symbolize(address));
+            }
+        }
+    }
+
+    println!("Total: {} coverage points", runner.covered().len());
+
+    if let Some(path) = config.drcov {
+        let file = File::create(path).expect("Could not create drcov file");
+        write_drcov(
+            BufWriter::new(file),
+            runner.modules(),
+            runner.covered().iter().copied(),
+        )
+        .expect("Could not write drcov file");
+    }
+}
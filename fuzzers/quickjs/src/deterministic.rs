@@ -0,0 +1,99 @@
+//! Deterministic walk over the tokens of the corpus entries
+
+use crate::shutdown::terminating;
+
+use libafl::{
+    corpus::Corpus,
+    fuzzer::Evaluator,
+    inputs::{HasBytesVec, Input},
+    stages::Stage,
+    state::{HasCorpus, HasMetadata},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// Number of leading tokens of an entry walked by the stage
+const MAX_WALKED_TOKENS: usize = 512;
+
+/// Marks the corpus entries the deterministic stage already walked
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DeterministicDone;
+
+libafl::impl_serdeany!(DeterministicDone);
+
+/// Stage replacing, one at a time, each token of the corpus entries with
+/// its two neighbouring tokens. Disabled until its flag is set, e.g. on a
+/// coverage plateau, it then walks each entry once when it is scheduled.
+pub struct DeterministicStage<I> {
+    /// Whether the entries are walked, shared with whoever enables the stage
+    enabled: Rc<Cell<bool>>,
+    phantom: PhantomData<I>,
+}
+
+impl<I> DeterministicStage<I> {
+    /// Creates a stage walking the entries while `enabled` is set
+    pub fn new(enabled: Rc<Cell<bool>>) -> Self {
+        DeterministicStage {
+            enabled,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<I> Default for DeterministicStage<I> {
+    fn default() -> Self {
+        Self::new(Rc::default())
+    }
+}
+
+impl<E, EM, I, S, Z> Stage<E, EM, S, Z> for DeterministicStage<I>
+where
+    I: Input + HasBytesVec,
+    S: HasCorpus<I>,
+    Z: Evaluator<E, EM, I, S>,
+{
+    fn perform(
+        &mut self,
+        fuzzer: &mut Z,
+        executor: &mut E,
+        state: &mut S,
+        manager: &mut EM,
+        corpus_idx: usize,
+    ) -> Result<(), Error> {
+        if !self.enabled.get() {
+            return Ok(());
+        }
+
+        let input = {
+            let mut testcase = state.corpus().get(corpus_idx)?.borrow_mut();
+            if testcase.has_metadata::<DeterministicDone>() {
+                return Ok(());
+            }
+            testcase.add_metadata(DeterministicDone);
+            testcase.load_input()?.clone()
+        };
+
+        let len = input.bytes().len() & !1;
+        for offset in (0..len).step_by(2).take(MAX_WALKED_TOKENS) {
+            let bytes = input.bytes();
+            let token = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+
+            for neighbour in [token.wrapping_add(1), token.wrapping_sub(1)] {
+                let mut mutated = input.clone();
+                mutated.bytes_mut()[offset..offset + 2].copy_from_slice(&neighbour.to_le_bytes());
+                fuzzer.evaluate_input(state, executor, manager, mutated)?;
+            }
+
+            // The walk of a large entry is cut short on shutdown
+            if terminating() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -2,25 +2,78 @@ use core::fmt::{self, Debug, Formatter};
 use core::marker::PhantomData;
 use libafl::bolts::AsMutSlice;
 use libafl::{
+    events::{Event, EventFirer},
     executors::{Executor, ExitKind, HasObservers},
     inputs::Input,
+    monitors::UserStats,
     observers::{ObserversTuple, StdMapObserver},
     Error,
 };
+use nix::libc;
 use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
-use nix::unistd::alarm;
 use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Not;
+use std::path::PathBuf;
+use std::ptr;
 use std::time::{Duration, Instant};
 
-use tartiflette_vm::{Register, Vm, VmExit};
+use crate::shared_map::mark_local_run;
+use crate::shutdown::terminating;
+
+use tartiflette_vm::{
+    kick_current_thread, BranchRecording, Crash, CrashStore, GuestFailure, Register,
+    SnapshotModule, Symbolizer, Vm, VmError, VmExit, VmStats,
+};
 
 const INT3: u8 = 0xCC;
 
-// XXX: Big hack to handle timeouts. We simply catch SIGALARM and do nothing,
-//      which will make kvm_run(...) fail with EINTR so we can return a timeout.
+/// Interval between two reports of the vm statistics
+const STATS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Number of coverage points kept for crash reports
+pub const COVERAGE_TAIL: usize = 16;
+
+/// Number of the last execution times an adaptive timeout is derived from
+const TIMEOUT_WINDOW: usize = 1000;
+
+/// Number of execution times recorded between two updates of an adaptive
+/// timeout
+const TIMEOUT_UPDATE: usize = 100;
+
+/// Percentile of the execution times an adaptive timeout is derived from
+const TIMEOUT_PERCENTILE: usize = 95;
+
+/// Factor applied to the percentile of the execution times to get an
+/// adaptive timeout
+const TIMEOUT_FACTOR: u32 = 10;
+
+/// Lower bound of an adaptive timeout
+const MIN_TIMEOUT: Duration = Duration::from_millis(1);
+
+// Timeouts are handled by catching SIGALARM, which will make kvm_run(...) fail
+// with EINTR so we can return a timeout. Kicking the vcpu also covers an alarm
+// landing right before kvm_run(...).
 extern "C" fn alarm_handler(_: i32) {
-    // Do nothing
+    kick_current_thread();
+}
+
+/// Arms the real time timer to deliver SIGALRM after `duration`. A zero
+/// duration disarms it.
+pub fn set_alarm(duration: Duration) {
+    let timer = libc::itimerval {
+        it_interval: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        it_value: libc::timeval {
+            tv_sec: duration.as_secs() as libc::time_t,
+            tv_usec: duration.subsec_micros() as libc::suseconds_t,
+        },
+    };
+
+    unsafe {
+        libc::setitimer(libc::ITIMER_REAL, &timer, ptr::null_mut());
+    }
 }
 
 pub fn install_alarm_handler() {
@@ -35,6 +88,82 @@ pub fn install_alarm_handler() {
     }
 }
 
+/// Records a coverage point in the tail of the last ones reached
+pub fn record_recent(recent: &mut Vec<u64>, address: u64) {
+    if recent.len() == COVERAGE_TAIL {
+        recent.remove(0);
+    }
+    recent.push(address);
+}
+
+/// Buckets the hit counts of a coverage map the way AFL does, so that only
+/// significant changes in loop iterations make an input interesting
+fn classify_counts(map: &mut [u8]) {
+    for count in map.iter_mut() {
+        *count = match *count {
+            0..=3 => *count,
+            4..=7 => 8,
+            8..=15 => 16,
+            16..=31 => 32,
+            32..=127 => 64,
+            128..=255 => 128,
+        };
+    }
+}
+
+/// Classifies a crash if a crash store is installed. Crashes without a vm
+/// exit were reported by a hook.
+fn classify_crash(
+    crash_store: &Option<(CrashStore, &BTreeMap<String, SnapshotModule>)>,
+    vm: &Vm,
+    vmexit: Option<&VmExit>,
+) -> Option<Crash> {
+    let (_, modules) = crash_store.as_ref()?;
+
+    match vmexit {
+        Some(vmexit) => Crash::from_exit(vm, vmexit, modules),
+        None => Some(Crash::from_hook(vm, modules)),
+    }
+}
+
+/// Timeout following the execution times of the inputs which exited
+/// normally, `TIMEOUT_FACTOR` times their `TIMEOUT_PERCENTILE`th percentile
+/// over the last `TIMEOUT_WINDOW` ones, bounded by a ceiling
+struct AdaptiveTimeout {
+    /// Upper bound of the timeout
+    ceiling: Duration,
+    /// Last execution times, as a ring buffer
+    exec_times: Vec<Duration>,
+    /// Position of the next execution time in the ring buffer
+    next: usize,
+    /// Number of execution times recorded since the last update
+    pending: usize,
+}
+
+impl AdaptiveTimeout {
+    /// Records an execution time. Returns the new timeout if it was updated.
+    fn record(&mut self, elapsed: Duration) -> Option<Duration> {
+        if self.exec_times.len() < TIMEOUT_WINDOW {
+            self.exec_times.push(elapsed);
+        } else {
+            self.exec_times[self.next] = elapsed;
+        }
+        self.next = (self.next + 1) % TIMEOUT_WINDOW;
+
+        self.pending += 1;
+        if self.pending < TIMEOUT_UPDATE {
+            return None;
+        }
+        self.pending = 0;
+
+        let mut sorted = self.exec_times.clone();
+        sorted.sort_unstable();
+        let percentile = sorted[(sorted.len() - 1) * TIMEOUT_PERCENTILE / 100];
+
+        Some((percentile * TIMEOUT_FACTOR).clamp(MIN_TIMEOUT, self.ceiling))
+    }
+}
+
 /// Error during executor actions
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ExecutorError {
@@ -52,10 +181,14 @@ pub enum HookResult {
     Crash,
     /// Hook induced stop
     Exit,
+    /// Hook found the fuzz case cannot go on, it is stopped and counted as a
+    /// harness error rather than a crash
+    Abort,
 }
 
 pub type TartifletteHook = dyn FnMut(&mut Vm) -> HookResult;
 pub type CoverageHook = dyn FnMut(u64);
+pub type ReportHook = dyn FnMut() -> String;
 
 pub struct TartifletteExecutor<'a, H, I, OT: Debug, S>
 where
@@ -75,6 +208,17 @@ where
     coverage: BTreeSet<u64>,
     /// Coverage hook
     coverage_hook: Option<&'a mut CoverageHook>,
+    /// Hook providing additional crash report content
+    report_hook: Option<&'a mut ReportHook>,
+    /// Whether coverage points are persistent vm coverage points, staying
+    /// armed to record edges
+    edge_coverage: bool,
+    /// Whether edges are read from the branches recorded by the vm
+    branch_coverage: bool,
+    /// Coverage addresses already reached, for edge coverage
+    covered: BTreeSet<u64>,
+    /// Last coverage points reached during the current run
+    recent_coverage: Vec<u64>,
     /// Original bytes before hooks or coverage
     orig_bytes: BTreeMap<u64, u8>,
     /// Vm used for the execution
@@ -83,12 +227,34 @@ where
     reset_vm: Vm,
     /// Timeout duration
     timeout_duration: Duration,
+    /// Adaptation of the timeout to the execution times, if enabled
+    adaptive_timeout: Option<AdaptiveTimeout>,
+    /// Number of inputs exceeding the adaptive timeout but not its ceiling
+    slow_inputs: usize,
+    /// Deduplicated crash store and the modules used to classify crashes
+    crash_store: Option<(CrashStore, &'a BTreeMap<String, SnapshotModule>)>,
+    /// Symbolizer of the crash addresses, over the crash store modules
+    symbolizer: Option<Symbolizer>,
+    /// State of the guest kvm could not run anymore in the last execution,
+    /// added to its crash report
+    guest_failure: Option<Box<GuestFailure>>,
+    /// Directory where inputs triggering a timeout are saved
+    timeout_dir: Option<PathBuf>,
+    /// Number of inputs which triggered a timeout
+    timeouts: usize,
+    /// Number of fuzz cases aborted by a hook
+    harness_errors: usize,
+    /// Vm statistics at the time of the last report
+    reported_stats: VmStats,
+    /// Time of the last report of the vm statistics
+    reported_time: Instant,
     /// Execution hooks
     phantom: PhantomData<(I, S)>,
 }
 
 impl<'a, EM, H, I, OT: Debug, S, Z> Executor<EM, I, S, Z> for TartifletteExecutor<'a, H, I, OT, S>
 where
+    EM: EventFirer<I>,
     H: FnMut(&mut Vm, &I) -> ExitKind,
     I: Input,
     OT: ObserversTuple<I, S>,
@@ -96,10 +262,151 @@ where
     fn run_target(
         &mut self,
         _fuzzer: &mut Z,
-        _state: &mut S,
-        _mgr: &mut EM,
+        state: &mut S,
+        mgr: &mut EM,
         input: &I,
     ) -> std::result::Result<ExitKind, Error> {
+        // Place the input in memory and run it
+        let start = Instant::now();
+        let (mut exit_kind, mut crash) = self.execute(input, self.timeout_duration);
+
+        // Inputs exceeding an adaptive timeout are run again with its
+        // ceiling, and only reported as hangs if they exceed it as well
+        let ceiling = self
+            .adaptive_timeout
+            .as_ref()
+            .map(|adaptive| adaptive.ceiling)
+            .filter(|&ceiling| ceiling > self.timeout_duration);
+        if let (ExitKind::Timeout, Some(ceiling)) = (exit_kind, ceiling) {
+            if self.edge_coverage || self.branch_coverage {
+                self.coverage_map().fill(0);
+            }
+            self.exec_vm.reset(&self.reset_vm);
+
+            let start = Instant::now();
+            (exit_kind, crash) = self.execute(input, ceiling);
+            if exit_kind != ExitKind::Timeout {
+                log::debug!("Slow input: {:?}", start.elapsed());
+                self.slow_inputs += 1;
+                self.record_exec_time(start.elapsed());
+            }
+        } else if exit_kind == ExitKind::Ok {
+            self.record_exec_time(start.elapsed());
+        }
+
+        // Bucket the edge hit counts before the map is compared against the
+        // global one by the feedback
+        if self.edge_coverage || self.branch_coverage {
+            classify_counts(self.coverage_map());
+        }
+
+        // Save the inputs triggering timeouts apart from crashes
+        if exit_kind == ExitKind::Timeout {
+            if let Some(dir) = &self.timeout_dir {
+                input.to_file(dir.join(input.generate_name(self.timeouts)))?;
+                log::debug!("Timeout input saved in {}", dir.display());
+            }
+            self.timeouts += 1;
+        }
+
+        // Save the input of crashes not seen before
+        if let (Some(crash), Some((store, modules))) = (crash, &mut self.crash_store) {
+            if let Some(path) = store.register(&crash)? {
+                match &mut self.symbolizer {
+                    Some(symbolizer) => {
+                        log::info!("New crash: {} in {}", crash, symbolizer.symbolize(crash.pc));
+                        if log::log_enabled!(log::Level::Debug) {
+                            for frame in &crash.frames {
+                                log::debug!("    called from {}", symbolizer.symbolize(*frame));
+                            }
+                        }
+                    }
+                    None => log::info!("New crash: {}", crash),
+                }
+                input.to_file(path)?;
+                let mut report = crash.report(
+                    &self.exec_vm,
+                    &self.reset_vm,
+                    modules,
+                    &self.recent_coverage,
+                );
+                if let Some(hook) = &mut self.report_hook {
+                    report.push_str(&hook());
+                }
+                if let Some(failure) = &self.guest_failure {
+                    report += &format!("guest failure:\n{}\n", failure);
+                }
+                store.save_report(&crash, &report)?;
+            }
+        }
+
+        // Reset the vm to its original state
+        self.exec_vm.reset(&self.reset_vm);
+
+        if self.reported_time.elapsed() >= STATS_INTERVAL {
+            self.report_stats(state, mgr)?;
+        }
+
+        // Crashes are solutions, the coverage feedback does not rate them
+        if exit_kind != ExitKind::Crash {
+            mark_local_run();
+        }
+
+        Ok(exit_kind)
+    }
+}
+
+impl<'a, H, I, OT: Debug, S> TartifletteExecutor<'a, H, I, OT, S>
+where
+    H: FnMut(&mut Vm, &I) -> ExitKind,
+    I: Input,
+    OT: ObserversTuple<I, S>,
+{
+    pub fn new(
+        vm: &Vm,
+        timeout: Duration,
+        observers: OT,
+        harness: &'a mut H,
+    ) -> Result<Self, ExecutorError> {
+        assert!(
+            timeout >= Duration::from_millis(1),
+            "Timeout must at least be 1 millisecond"
+        );
+
+        Ok(TartifletteExecutor {
+            harness_fn: harness,
+            observers,
+            exec_vm: vm.clone(),
+            reset_vm: vm.clone(),
+            hooks: Default::default(),
+            syscall_hook: None,
+            coverage: Default::default(),
+            coverage_hook: None,
+            report_hook: None,
+            guest_failure: None,
+            edge_coverage: false,
+            branch_coverage: false,
+            covered: Default::default(),
+            recent_coverage: Vec::with_capacity(COVERAGE_TAIL),
+            orig_bytes: Default::default(),
+            timeout_duration: timeout,
+            adaptive_timeout: None,
+            slow_inputs: 0,
+            crash_store: None,
+            symbolizer: None,
+            timeout_dir: None,
+            timeouts: 0,
+            harness_errors: 0,
+            reported_stats: VmStats::default(),
+            reported_time: Instant::now(),
+            phantom: PhantomData::<(I, S)>,
+        })
+    }
+
+    /// Places an input in the vm and runs it until it exits, crashes or
+    /// exceeds `timeout`. Returns how it ended, with the classification of
+    /// the crash if a crash store is installed.
+    fn execute(&mut self, input: &I, timeout: Duration) -> (ExitKind, Option<Crash>) {
         // Load the map we will modify with coverage
         let map_observer = self
             .observers
@@ -113,30 +420,65 @@ where
         // contain the address where we removed the breakpoint.
         let mut singlestep: Option<u64> = None;
 
-        // Install the alarm
-        alarm::set(self.timeout_duration.as_secs() as u32);
+        // Install the alarm, kicking the vcpu out of kvm_run on expiration
+        set_alarm(timeout);
 
         // Usually the SIGALRM should land when we are in the kvm_run ioctl.
         // In the rare case where it would land outside the kvm_run, we have
         // to manually track the time to exit early on the next kvm_run.
         let starting_time = Instant::now();
 
+        // Crash classification, if a crash store is installed
+        let mut crash: Option<Crash> = None;
+        self.guest_failure = None;
+
+        // Previous block, for edge coverage
+        let mut prev_location: u64 = 0;
+        self.recent_coverage.clear();
+        self.exec_vm.clear_coverage_hits();
+
+        // Drop the branches of the previous run
+        self.exec_vm.take_branches();
+
         // Execution loop
         let exit_kind = loop {
-            if starting_time.elapsed() > self.timeout_duration {
+            if starting_time.elapsed() > timeout {
                 break ExitKind::Timeout;
             }
 
-            let vmexit = self.exec_vm.run().expect("Unexpected vm error");
+            let vmexit = match self.exec_vm.run() {
+                Ok(vmexit) => vmexit,
+                // The guest can not run anymore, e.g. after a triple fault
+                Err(VmError::GuestFailure(failure)) => {
+                    log::debug!("Guest failure: {}", failure);
+                    let exec_vm = &self.exec_vm;
+                    crash = self
+                        .crash_store
+                        .as_ref()
+                        .map(|(_, modules)| Crash::from_failure(exec_vm, &failure, modules));
+                    self.guest_failure = Some(failure);
+                    break ExitKind::Crash;
+                }
+                Err(err) => panic!("Unexpected vm error: {:?}", err),
+            };
             let rip = self.exec_vm.get_reg(Register::Rip);
 
             match vmexit {
+                // A shutdown request lets the fuzz case finish
+                VmExit::Interrupted if terminating() && starting_time.elapsed() < timeout => {}
                 VmExit::Interrupted => break ExitKind::Timeout,
                 VmExit::Syscall => {
                     if let Some(hook) = &mut self.syscall_hook {
                         match hook(&mut self.exec_vm) {
-                            HookResult::Crash => break ExitKind::Crash,
+                            HookResult::Crash => {
+                                crash = classify_crash(&self.crash_store, &self.exec_vm, None);
+                                break ExitKind::Crash;
+                            }
                             HookResult::Exit => break ExitKind::Ok,
+                            HookResult::Abort => {
+                                self.harness_errors += 1;
+                                break ExitKind::Ok;
+                            }
                             _ => {}
                         }
                     } else {
@@ -163,7 +505,10 @@ where
                             rflags &= !(1 << 8);
                             self.exec_vm.set_reg(Register::Rflags, rflags);
                         }
-                        _ => break ExitKind::Crash,
+                        _ => {
+                            crash = classify_crash(&self.crash_store, &self.exec_vm, Some(&vmexit));
+                            break ExitKind::Crash;
+                        }
                     }
                 }
                 VmExit::Breakpoint => {
@@ -181,8 +526,20 @@ where
                         self.exec_vm.set_reg(Register::Rflags, rflags);
                     }
 
-                    // Handle coverage
-                    if self.coverage.contains(&rip) {
+                    // Handle coverage, the vm steps over the persistent
+                    // coverage points and re-arms them
+                    if self.edge_coverage && self.coverage.contains(&rip) {
+                        self.exec_vm
+                            .hit_coverage_point()
+                            .expect("Error while stepping over coverage");
+
+                        // Record the edge from the previous block
+                        let map = map_observer.as_mut_slice();
+                        let edge_index = ((prev_location ^ rip) as usize) % map.len();
+                        map[edge_index] = map[edge_index].saturating_add(1);
+                        prev_location = rip >> 1;
+                        record_recent(&mut self.recent_coverage, rip);
+                    } else if self.coverage.contains(&rip) {
                         // Restore the original instruction byte
                         // The unwrap should be safe as these two structures should
                         // be in sync.
@@ -205,6 +562,7 @@ where
                         let map = map_observer.as_mut_slice();
                         let bb_index = (rip as usize) % map.len();
                         map[bb_index] += 1;
+                        record_recent(&mut self.recent_coverage, rip);
 
                         // Call coverage hook if any
                         if let Some(hook) = &mut self.coverage_hook {
@@ -216,9 +574,16 @@ where
                     if let Some(hook) = self.hooks.get_mut(&rip) {
                         match hook(&mut self.exec_vm) {
                             HookResult::Exit => break ExitKind::Ok,
-                            HookResult::Crash => break ExitKind::Crash,
+                            HookResult::Abort => {
+                                self.harness_errors += 1;
+                                break ExitKind::Ok;
+                            }
+                            HookResult::Crash => {
+                                crash = classify_crash(&self.crash_store, &self.exec_vm, None);
+                                break ExitKind::Crash;
+                            }
                             HookResult::Continue => {
-                                println!("Continue Hook: {:x}", rip);
+                                log::debug!("Continue hook: {:x}", rip);
                                 // The user wants to continue execution right
                                 // after its hook. First restore the original
                                 // code byte.
@@ -244,50 +609,56 @@ where
                 VmExit::Hlt => {
                     panic!("guest abort (hlt)");
                 }
-                _ => break ExitKind::Crash,
+                _ => {
+                    crash = classify_crash(&self.crash_store, &self.exec_vm, Some(&vmexit));
+                    break ExitKind::Crash;
+                }
             }
         };
 
         // Remove the alarm
-        alarm::cancel();
+        set_alarm(Duration::ZERO);
+
+        // Call coverage hook the first time a block is reached
+        if self.edge_coverage {
+            for &address in self.exec_vm.coverage_hits().keys() {
+                if self.covered.insert(address) {
+                    if let Some(hook) = &mut self.coverage_hook {
+                        hook(address)
+                    }
+                }
+            }
+        }
 
-        // Reset the vm to its original state
-        self.exec_vm.reset(&self.reset_vm);
+        // Record the edges of the branches taken during the run
+        if self.branch_coverage {
+            let map = map_observer.as_mut_slice();
+            for branch in self.exec_vm.take_branches() {
+                let edge_index = (branch.edge_hash() as usize) % map.len();
+                map[edge_index] = map[edge_index].saturating_add(1);
+                record_recent(&mut self.recent_coverage, branch.to);
+            }
+        }
 
-        Ok(exit_kind)
+        (exit_kind, crash)
     }
-}
 
-impl<'a, H, I, OT: Debug, S> TartifletteExecutor<'a, H, I, OT, S>
-where
-    H: FnMut(&mut Vm, &I) -> ExitKind,
-    I: Input,
-    OT: ObserversTuple<I, S>,
-{
-    pub fn new(
-        vm: &Vm,
-        timeout: Duration,
-        observers: OT,
-        harness: &'a mut H,
-    ) -> Result<Self, ExecutorError> {
-        assert!(
-            timeout >= Duration::from_secs(1),
-            "Timeout must at least be 1 second"
-        );
+    /// Returns the coverage map
+    fn coverage_map(&mut self) -> &mut [u8] {
+        self.observers
+            .match_name_mut::<StdMapObserver<u8>>("coverage")
+            .expect("TartifletteExecutor expects a StdMapObserver<u8> named 'coverage'")
+            .as_mut_slice()
+    }
 
-        Ok(TartifletteExecutor {
-            harness_fn: harness,
-            observers,
-            exec_vm: vm.clone(),
-            reset_vm: vm.clone(),
-            hooks: Default::default(),
-            syscall_hook: None,
-            coverage: Default::default(),
-            coverage_hook: None,
-            orig_bytes: Default::default(),
-            timeout_duration: timeout,
-            phantom: PhantomData::<(I, S)>,
-        })
+    /// Records the execution time of an input which exited normally, and
+    /// updates the adaptive timeout every `TIMEOUT_UPDATE` records
+    fn record_exec_time(&mut self, elapsed: Duration) {
+        if let Some(adaptive) = &mut self.adaptive_timeout {
+            if let Some(timeout) = adaptive.record(elapsed) {
+                self.timeout_duration = timeout;
+            }
+        }
     }
 
     /// Adds a coverage point to the executor
@@ -299,7 +670,15 @@ where
             ));
         }
 
-        if self.coverage.contains(&address).not() {
+        // Edge coverage points are persistent points of the vms
+        if self.edge_coverage {
+            for vm in [&mut self.reset_vm, &mut self.exec_vm] {
+                vm.add_coverage_point(address).map_err(|_| {
+                    ExecutorError::VmError("Could not add coverage point (invalid address ?)")
+                })?;
+            }
+            self.coverage.insert(address);
+        } else if self.coverage.contains(&address).not() {
             // Read original byte from memory
             let mut orig_byte: [u8; 1] = [0; 1];
             self.exec_vm.read(address, &mut orig_byte).map_err(|_| {
@@ -354,11 +733,143 @@ where
         self.syscall_hook = Some(hook);
     }
 
+    /// Adds a crash store to the executor. Crashes are classified using
+    /// `modules` and only the first input of each unique crash is saved.
+    #[inline]
+    pub fn add_crash_store(
+        &mut self,
+        store: CrashStore,
+        modules: &'a BTreeMap<String, SnapshotModule>,
+    ) {
+        self.crash_store = Some((store, modules));
+        self.symbolizer = Some(Symbolizer::new(modules));
+    }
+
+    /// Saves the inputs triggering a timeout in a directory
+    pub fn add_timeout_dir<P: Into<PathBuf>>(&mut self, dir: P) -> Result<(), ExecutorError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .map_err(|_| ExecutorError::VmError("Could not create timeout directory"))?;
+
+        self.timeout_dir = Some(dir);
+        Ok(())
+    }
+
+    /// Adapts the timeout to the execution times of the inputs, up to
+    /// `ceiling`. Inputs exceeding the timeout are run again with the
+    /// ceiling: the ones finishing in time are slow rather than hanging.
+    pub fn set_adaptive_timeout(&mut self, ceiling: Duration) {
+        self.timeout_duration = self.timeout_duration.min(ceiling);
+        self.adaptive_timeout = Some(AdaptiveTimeout {
+            ceiling,
+            exec_times: Vec::with_capacity(TIMEOUT_WINDOW),
+            next: 0,
+            pending: 0,
+        });
+    }
+
+    /// Returns the number of inputs which triggered a timeout
+    #[inline]
+    pub fn timeouts(&self) -> usize {
+        self.timeouts
+    }
+
+    /// Returns the statistics of the execution vm
+    #[inline]
+    pub fn vm_stats(&self) -> VmStats {
+        self.exec_vm.stats()
+    }
+
+    /// Reports the per execution averages of the vm statistics since the
+    /// last report to the monitor
+    fn report_stats<EM: EventFirer<I>>(
+        &mut self,
+        state: &mut S,
+        mgr: &mut EM,
+    ) -> Result<(), Error> {
+        let stats = self.exec_vm.stats();
+        let last = self.reported_stats;
+        let execs = stats.resets.saturating_sub(last.resets).max(1);
+
+        let per_exec = |total: Duration, last: Duration| {
+            UserStats::Number((total.saturating_sub(last).as_micros() as u64) / execs)
+        };
+        let values = [
+            (
+                "dirty pages",
+                UserStats::Number((stats.reset_pages - last.reset_pages) / execs),
+            ),
+            ("reset us", per_exec(stats.reset_time, last.reset_time)),
+            (
+                "write us",
+                per_exec(stats.memory_write_time, last.memory_write_time),
+            ),
+            ("run us", per_exec(stats.run_time, last.run_time)),
+            (
+                "timeout ms",
+                UserStats::Number(self.timeout_duration.as_millis() as u64),
+            ),
+            ("slow inputs", UserStats::Number(self.slow_inputs as u64)),
+            ("timeouts", UserStats::Number(self.timeouts as u64)),
+            (
+                "harness errors",
+                UserStats::Number(self.harness_errors as u64),
+            ),
+        ];
+
+        for (name, value) in values {
+            mgr.fire(
+                state,
+                Event::UpdateUserStats {
+                    name: name.to_string(),
+                    value,
+                    phantom: PhantomData,
+                },
+            )?;
+        }
+
+        self.reported_stats = stats;
+        self.reported_time = Instant::now();
+        Ok(())
+    }
+
+    /// Enables edge coverage. Coverage points are persistent vm coverage
+    /// points and each hit records the edge from the previous coverage point
+    /// (AFL-style `prev >> 1 ^ cur` index) instead of the block alone. This
+    /// costs a singlestep per hit. Must be enabled before adding the coverage
+    /// points.
+    #[inline]
+    pub fn set_edge_coverage(&mut self, enabled: bool) {
+        self.edge_coverage = enabled;
+        self.reset_vm.set_persistent_coverage(enabled);
+        self.exec_vm.set_persistent_coverage(enabled);
+    }
+
+    /// Enables edge coverage from the branches the vm records in the LBR
+    /// stack, instead of coverage breakpoints. The coverage is partial, only
+    /// the last branches before each vm exit being recorded, but costs next
+    /// to nothing at run time.
+    pub fn set_branch_coverage(&mut self, recording: BranchRecording) -> Result<(), ExecutorError> {
+        for vm in [&mut self.reset_vm, &mut self.exec_vm] {
+            vm.enable_branch_recording(recording)
+                .map_err(|_| ExecutorError::VmError("Could not enable branch recording"))?;
+        }
+        self.branch_coverage = true;
+
+        Ok(())
+    }
+
     /// Adds a hook to the executor that is called each time there is new coverage
     #[inline]
     pub fn add_coverage_hook(&mut self, hook: &'a mut CoverageHook) {
         self.coverage_hook = Some(hook);
     }
+
+    /// Adds a hook whose output is appended to the crash reports
+    #[inline]
+    pub fn add_report_hook(&mut self, hook: &'a mut ReportHook) {
+        self.report_hook = Some(hook);
+    }
 }
 
 impl<'a, H, I, OT: Debug, S> HasObservers<I, OT, S> for TartifletteExecutor<'a, H, I, OT, S>
@@ -0,0 +1,242 @@
+//! Structure-aware mutations of common input formats, layered on the generic
+//! byte mutations
+
+use libafl::{
+    bolts::{rands::Rand, tuples::Named},
+    inputs::{HasBytesVec, Input},
+    mutators::{MutationResult, Mutator},
+    state::{HasMaxSize, HasRand},
+    Error,
+};
+
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Values replacing JSON tokens
+const JSON_VALUES: &[&[u8]] = &[
+    b"0",
+    b"-1",
+    b"1e308",
+    b"-2147483649",
+    b"4294967296",
+    b"0.0000001",
+    b"\"\"",
+    b"\"\\u0000\"",
+    b"null",
+    b"true",
+    b"false",
+    b"[]",
+    b"{}",
+];
+
+/// Structure of the inputs of a target
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InputFormat {
+    /// No known structure, only the generic mutations are applied
+    Raw,
+    /// Chunks prefixed by their big endian u32 length
+    Chunks,
+    /// Type-length-value records with u8 type and length fields
+    Tlv,
+    /// JSON-like text
+    Json,
+}
+
+impl FromStr for InputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(InputFormat::Raw),
+            "chunks" => Ok(InputFormat::Chunks),
+            "tlv" => Ok(InputFormat::Tlv),
+            "json" => Ok(InputFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Splits `data` in records made of a `header` bytes header followed by a
+/// payload whose length is read from the header. Trailing bytes which do not
+/// form a whole record are left out.
+fn records(data: &[u8], header: usize, length: fn(&[u8]) -> usize) -> Vec<Range<usize>> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+
+    while offset + header <= data.len() {
+        let end = match (offset + header).checked_add(length(&data[offset..offset + header])) {
+            Some(end) if end <= data.len() => end,
+            _ => break,
+        };
+
+        result.push(offset..end);
+        offset = end;
+    }
+
+    result
+}
+
+/// Splits JSON-like text in tokens: strings, numbers, words and single
+/// punctuation bytes. Whitespace is left out.
+fn json_tokens(data: &[u8]) -> Vec<Range<usize>> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let start = offset;
+        let byte = data[offset];
+        offset += 1;
+
+        match byte {
+            b if b.is_ascii_whitespace() => continue,
+            b'"' => {
+                // Up to the closing quote, skipping the escaped bytes
+                while offset < data.len() && data[offset] != b'"' {
+                    offset += if data[offset] == b'\\' { 2 } else { 1 };
+                }
+                offset = (offset + 1).min(data.len());
+            }
+            b'-' | b'0'..=b'9' => {
+                while offset < data.len()
+                    && matches!(data[offset], b'0'..=b'9' | b'.' | b'e' | b'E' | b'+' | b'-')
+                {
+                    offset += 1;
+                }
+            }
+            b if b.is_ascii_alphabetic() => {
+                while offset < data.len() && data[offset].is_ascii_alphanumeric() {
+                    offset += 1;
+                }
+            }
+            _ => {}
+        }
+
+        result.push(start..offset);
+    }
+
+    result
+}
+
+/// Mutator duplicating, removing and swapping the records or tokens of an
+/// input, or corrupting their fields, according to its format
+pub struct FormatMutator {
+    /// Format of the inputs
+    format: InputFormat,
+}
+
+impl FormatMutator {
+    /// Creates a mutator for inputs of the given format. Raw inputs are left
+    /// untouched.
+    pub fn new(format: InputFormat) -> Self {
+        FormatMutator { format }
+    }
+
+    /// Returns the records or tokens of `data`
+    fn units(&self, data: &[u8]) -> Vec<Range<usize>> {
+        match self.format {
+            InputFormat::Raw => Vec::new(),
+            InputFormat::Chunks => records(data, 4, |h| {
+                u32::from_be_bytes([h[0], h[1], h[2], h[3]]) as usize
+            }),
+            InputFormat::Tlv => records(data, 2, |h| h[1] as usize),
+            InputFormat::Json => json_tokens(data),
+        }
+    }
+
+    /// Corrupts the header of a record, or replaces a JSON token
+    fn mutate_field<R: Rand>(&self, rand: &mut R, data: &mut Vec<u8>, unit: Range<usize>) {
+        match self.format {
+            InputFormat::Raw => {}
+            InputFormat::Chunks => {
+                let length = (unit.len() - 4) as u32;
+                let values = [
+                    0,
+                    length.wrapping_sub(1),
+                    length.wrapping_add(1),
+                    0x7fffffff,
+                    0xffffffff,
+                ];
+                let value = *rand.choose(&values);
+                data[unit.start..unit.start + 4].copy_from_slice(&value.to_be_bytes());
+            }
+            InputFormat::Tlv => match rand.below(2) {
+                0 => data[unit.start] = rand.below(256) as u8,
+                _ => {
+                    let length = (unit.len() - 2) as u8;
+                    let values = [0, length.wrapping_sub(1), length.wrapping_add(1), 0xff];
+                    data[unit.start + 1] = *rand.choose(&values);
+                }
+            },
+            InputFormat::Json => {
+                let value = rand.choose(JSON_VALUES);
+                data.splice(unit, value.iter().copied());
+            }
+        }
+    }
+}
+
+impl<I, S> Mutator<I, S> for FormatMutator
+where
+    I: Input + HasBytesVec,
+    S: HasRand + HasMaxSize,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut I,
+        _stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        let units = self.units(input.bytes());
+        if units.is_empty() {
+            return Ok(MutationResult::Skipped);
+        }
+
+        let mut data = input.bytes().to_vec();
+        let rand = state.rand_mut();
+        let unit = rand.choose(&units).clone();
+
+        match rand.below(4) {
+            // Duplicate
+            0 => {
+                let copy = data[unit.clone()].to_vec();
+                data.splice(unit.end..unit.end, copy);
+            }
+            // Remove
+            1 => {
+                data.drain(unit);
+            }
+            // Swap with another unit
+            2 => {
+                let other = rand.choose(&units).clone();
+                let (first, second) = match unit.start < other.start {
+                    true => (unit, other),
+                    false => (other, unit),
+                };
+                if first == second {
+                    return Ok(MutationResult::Skipped);
+                }
+
+                let mut swapped = data[..first.start].to_vec();
+                swapped.extend_from_slice(&data[second.clone()]);
+                swapped.extend_from_slice(&data[first.end..second.start]);
+                swapped.extend_from_slice(&data[first]);
+                swapped.extend_from_slice(&data[second.end..]);
+                data = swapped;
+            }
+            _ => self.mutate_field(rand, &mut data, unit),
+        }
+
+        if data.len() > state.max_size() {
+            return Ok(MutationResult::Skipped);
+        }
+
+        *input.bytes_mut() = data;
+        Ok(MutationResult::Mutated)
+    }
+}
+
+impl Named for FormatMutator {
+    fn name(&self) -> &str {
+        "FormatMutator"
+    }
+}
@@ -1,44 +1,92 @@
+use crate::calibrate::{calibrate, measure_stability};
+use crate::checkpoint::{checkpoint_coverage, checkpoint_path, save_checkpoint, Checkpoint};
+use crate::cmplog::InputToStateStage;
+use crate::corpus::IndexedCorpus;
+use crate::deterministic::DeterministicStage;
 use crate::executor::{install_alarm_handler, HookResult, TartifletteExecutor};
-use crate::sysemu::SysEmu;
+use crate::formats::{FormatMutator, InputFormat};
+use crate::logger;
+use crate::mutator::{SizeAdaptiveMutator, WeightedScheduledMutator};
+use crate::plateau::{
+    plateau_reached, PlateauActions, PlateauDetector, PlateauEvent, PlateauMonitor,
+    PLATEAU_EXIT_CODE,
+};
+use crate::report::{ReportFormat, ReportMonitor};
+use crate::runner::Runner;
+use crate::scheduler::{PathFeedback, PowerSchedule, WeightedCorpusScheduler};
+use crate::shared_map::{SharedCoverageMap, SharedMapFeedback};
+use crate::shutdown::{
+    install_shutdown_handler, terminate_with_parent, terminating, INTERRUPTED_EXIT_CODE,
+};
+use crate::slice::{SliceMutator, SliceStage};
+use crate::status::StatusMonitor;
+use crate::supervisor::supervise;
+use crate::sysemu::{describe_syscall, output_section, SysEmu, SyscallOutcome};
+use crate::taint::{TaintMutator, TaintStage};
+use crate::targets::{
+    load_targets, DeliveryMode, Target, TargetBoard, TargetSchedule, REBALANCE_INTERVAL,
+};
+use crate::trim::TrimStage;
 
 use libafl::{
     bolts::{
         core_affinity::Cores,
-        current_nanos,
+        current_nanos, current_time,
         launcher::Launcher,
         rands::StdRand,
         shmem::{ShMemProvider, StdShMemProvider},
         tuples::{tuple_list, tuple_list_type},
     },
-    corpus::InMemoryCorpus,
+    corpus::{ondisk::OnDiskMetadataFormat, Corpus, InMemoryCorpus},
+    events::{Event, EventConfig, EventFirer, ProgressReporter},
     executors::ExitKind,
     feedback_or,
-    feedbacks::{CrashFeedback, MaxMapFeedback, TimeFeedback},
-    fuzzer::{Fuzzer, StdFuzzer},
+    feedbacks::{CrashFeedback, TimeFeedback},
+    fuzzer::{Fuzzer, HasScheduler, StdFuzzer},
     inputs::{BytesInput, HasBytesVec},
-    monitors::MultiMonitor,
+    monitors::{tui::TuiMonitor, Monitor, UserStats},
     mutators::mutations::{
-        ByteRandMutator, BytesExpandMutator, BytesInsertMutator, BytesSwapMutator,
-        CrossoverInsertMutator, CrossoverReplaceMutator,
+        ByteAddMutator, ByteInterestingMutator, ByteRandMutator, BytesExpandMutator,
+        BytesInsertMutator, BytesSwapMutator, CrossoverInsertMutator, CrossoverReplaceMutator,
+        DwordAddMutator, DwordInterestingMutator, QwordAddMutator, WordAddMutator,
+        WordInterestingMutator,
     },
-    mutators::scheduled::StdScheduledMutator,
+    mutators::token_mutations::Tokens,
     observers::{StdMapObserver, TimeObserver},
-    schedulers::QueueScheduler,
     stages::mutational::StdMutationalStage,
-    state::StdState,
+    state::{HasCorpus, HasExecutions, HasMaxSize, StdState},
+    Error,
 };
+use log::LevelFilter;
 use serde::Deserialize;
 
-use std::cell::RefCell;
-use std::fs::File;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
 use std::io::BufWriter;
 use std::io::{prelude::*, BufReader, LineWriter};
+use std::marker::PhantomData;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::process;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tartiflette_vm::{PagePermissions, Register, SnapshotInfo, Vm};
+use tartiflette_vm::{
+    write_drcov, BranchRecording, CrashStore, InputDelivery, PagePermissions, Register,
+    SnapshotInfo, SnapshotModule, Symbolizer, Vm,
+};
+
+/// Source of the coverage guiding the fuzzer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FeedbackMethod {
+    /// Coverage breakpoints placed in the target
+    Soft,
+    /// Edges from the branches recorded by the cpu
+    Hw(BranchRecording),
+    /// No coverage, the seeds are mutated blindly
+    None,
+}
 
 /// Configuration of the fuzzer
 #[derive(Copy, Clone)]
@@ -49,21 +97,176 @@ pub struct FuzzerConfig<'a> {
     pub broker_address: Option<&'a str>,
     /// Broker port
     pub broker_port: &'a str,
+    /// Directory where unique crashing inputs are saved, under the output
+    /// directory if not set
+    pub crash_dir: Option<&'a str>,
+    /// Directory where inputs triggering a timeout are saved, under the
+    /// output directory if not set
+    pub timeout_dir: Option<&'a str>,
+    /// Directory receiving the coverage file, under the output directory if
+    /// not set
+    pub cov_dir: Option<&'a str>,
+    /// Whether the crashes, timeouts and coverage default to the directories
+    /// of the earlier versions, in the working directory
+    pub legacy_layout: bool,
+    /// Execution timeout of a fuzz case
+    pub timeout: Duration,
+    /// Whether to replace the timeout by the one found by calibrating the seeds
+    pub auto_timeout: bool,
+    /// AFL-format dictionary whose tokens are added to the token table
+    pub dictionary: Option<&'a str>,
+    /// Whether to display the terminal user interface
+    pub tui: bool,
+    /// Format of the report lines printed without the user interface
+    pub report_format: ReportFormat,
+    /// Whether only new crashes and a periodic summary are reported
+    pub quiet: bool,
+    /// Directory holding the corpus of the session
+    pub output_dir: &'a str,
+    /// Whether to record edges instead of first block hits
+    pub edge_coverage: bool,
+    /// Source of the coverage guiding the fuzzer
+    pub feedback: FeedbackMethod,
+    /// Path of the drcov files receiving the coverage, suffixed with the
+    /// target if there are several, and with the core of each client
+    pub drcov: Option<&'a str>,
+    /// Path of the trace receiving the accesses to the input area of a
+    /// reproduced input
+    pub access_trace: Option<&'a str>,
+    /// Maximum size of an encoded input
+    pub max_input_size: usize,
+    /// Number of corpus inputs kept in memory
+    pub corpus_cache: usize,
+    /// Whether the maximum input size stays fixed instead of adapting to the
+    /// sizes producing new coverage
+    pub fixed_input_size: bool,
+    /// Campaign seed of the random generators, random if not set
+    pub seed: Option<u64>,
+    /// Maximum power of two of the number of stacked mutations
+    pub max_stack_pow: u64,
+    /// Maximum adjustment of the stack power to the speed of the entries
+    pub speed_pow: u64,
+    /// Mutation weights as a `name=weight,...` list
+    pub mutation_weights: Option<&'a str>,
+    /// Whether the mutation weights adapt to the success of the operators
+    pub adaptive_weights: bool,
+    /// Whether the tokens influencing the coverage of the entries are
+    /// inferred and targeted by the mutations
+    pub taint: bool,
+    /// Whether the entries are trimmed, keeping their coverage
+    pub trim: bool,
+    /// Whether the operands of the comparisons are replaced in the entries
+    pub cmplog: bool,
+    /// Power schedule giving the weights of the corpus entries
+    pub schedule: PowerSchedule,
+    /// Format of the inputs, enabling the matching structure-aware mutations
+    pub input_format: InputFormat,
+    /// Way the harness receives the input, for the targets not setting it
+    pub input_delivery: Option<DeliveryMode>,
+    /// Interval between two checkpoints of the clients, disabled if not set
+    pub checkpoint_interval: Option<Duration>,
+    /// Duration without new coverage making a plateau, disabled if not set
+    pub plateau: Option<Duration>,
+    /// Actions taken on a coverage plateau
+    pub plateau_actions: PlateauActions,
+    /// Duration after which the session is stopped
+    pub run_time: Option<Duration>,
+    /// Number of new unique crashes after which the session is stopped
+    pub max_crashes: Option<usize>,
+    /// Address of the HTTP status endpoint, disabled if not set
+    pub status_address: Option<&'a str>,
+    /// JSON file listing the targets, the snapshot of `./data` if not set
+    pub targets: Option<&'a str>,
+    /// Target run by the subcommands, the first one if not set
+    pub target: Option<&'a str>,
+    /// Scheduling of the workers across the targets
+    pub target_schedule: TargetSchedule,
+    /// Most verbose level of the logs
+    pub log_level: LevelFilter,
+}
+
+impl FuzzerConfig<'_> {
+    /// Directory of the unique crashes of a target
+    pub fn target_crash_dir(&self, target: &Target) -> PathBuf {
+        self.artifact_dir(target, self.crash_dir, "crashes", LEGACY_CRASH_DIR)
+    }
+
+    /// Directory of the inputs of a target triggering a timeout
+    pub fn target_timeout_dir(&self, target: &Target) -> PathBuf {
+        self.artifact_dir(target, self.timeout_dir, "timeouts", LEGACY_TIMEOUT_DIR)
+    }
+
+    /// Directory of the coverage file of a target
+    pub fn target_cov_dir(&self, target: &Target) -> PathBuf {
+        self.artifact_dir(target, self.cov_dir, "cov", LEGACY_COV_DIR)
+    }
+
+    /// Directory of an artifact of a target: the given one, else the `name`
+    /// directory next to the queue of the target, or the `legacy` directory
+    /// with the layout of the earlier versions
+    fn artifact_dir(
+        &self,
+        target: &Target,
+        dir: Option<&str>,
+        name: &str,
+        legacy: &str,
+    ) -> PathBuf {
+        match (dir, self.legacy_layout) {
+            (Some(dir), _) => target.session_dir(dir),
+            (None, true) => target.session_dir(legacy),
+            (None, false) => target.session_dir(self.output_dir).join(name),
+        }
+    }
 }
 
 /// Encoded javascript tokens
 #[derive(Deserialize)]
-struct TokenCache {
+pub(crate) struct TokenCache {
     tokens: Vec<String>,
 }
 
+/// Crash directory of the earlier versions
+const LEGACY_CRASH_DIR: &str = "./crashes";
+/// Timeout directory of the earlier versions
+const LEGACY_TIMEOUT_DIR: &str = "./timeouts";
+/// Coverage directory of the earlier versions
+const LEGACY_COV_DIR: &str = ".";
+
+/// Vm memory size, 32Mb should be enough
+const MEMORY_SIZE: usize = 32 * 1024 * 1024;
+/// Start of the area reserved for the syscall emulation layer
+pub(crate) const MMAP_START: u64 = 0x1337000;
+/// Size of the area reserved for the syscall emulation layer
+const MMAP_SIZE: u64 = 0x100000;
+/// End of the area reserved for the syscall emulation layer
+pub(crate) const MMAP_END: u64 = MMAP_START + MMAP_SIZE;
+/// Start of the harness input area
+pub(crate) const INPUT_START: u64 = 0x22000;
+/// Size of the harness input area
+pub(crate) const INPUT_SIZE: u64 = 0x2000;
+/// Default maximum size of an encoded input. Each u16 token index decodes to
+/// at least one byte, larger inputs cannot fit in the input area.
+pub(crate) const DEFAULT_MAX_INPUT_SIZE: usize = 2 * (INPUT_SIZE as usize - 1);
+/// Offset of the exit call in the program module
+pub(crate) const EXIT_OFFSET: u64 = 0x1768e;
+
+/// Factor applied to the calibrated timeout to get the ceiling of the
+/// adaptive timeout
+const TIMEOUT_CEILING_FACTOR: u32 = 4;
+
+/// Interval between two reports of the client statistics to the monitor
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Interval between two writes of the drcov file of a client
+const DRCOV_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Coverage byte size
 const COVERAGE_SIZE: usize = 1 << 15;
 // TODO: Find how to have a coverage map without unsafe and static
 static mut COVERAGE: [u8; COVERAGE_SIZE] = [0u8; COVERAGE_SIZE];
 
 /// Loads breakpoints from a file
-fn load_breakpoints<T: AsRef<Path>>(s: T) -> Vec<u64> {
+pub(crate) fn load_breakpoints<T: AsRef<Path>>(s: T) -> Vec<u64> {
     let bpkt_file = File::open(s).expect("Could not open breakpoint file");
     let reader = BufReader::new(bpkt_file);
     let mut result = Vec::new();
@@ -79,14 +282,32 @@ fn load_breakpoints<T: AsRef<Path>>(s: T) -> Vec<u64> {
     result
 }
 
-/// Construct the list of mutator to be used for token fuzzing
-fn token_mutations() -> tuple_list_type!(
+/// Construct the list of mutator to be used for token fuzzing.
+/// The arithmetic mutations (little and big endian) move token indices to
+/// neighbouring tokens, the interesting values land on boundary indices.
+/// The format mutations are applied on top when the inputs have a known
+/// structure, the slice mutations focus on the tokens read before the
+/// coverage reached first by the fuzzed entry and the taint mutations on the
+/// tokens influencing its coverage.
+fn token_mutations(
+    format: InputFormat,
+) -> tuple_list_type!(
     ByteRandMutator,
     BytesInsertMutator,
     BytesSwapMutator,
     BytesExpandMutator,
     CrossoverReplaceMutator,
-    CrossoverInsertMutator
+    CrossoverInsertMutator,
+    ByteAddMutator,
+    WordAddMutator,
+    DwordAddMutator,
+    QwordAddMutator,
+    ByteInterestingMutator,
+    WordInterestingMutator,
+    DwordInterestingMutator,
+    FormatMutator,
+    SliceMutator,
+    TaintMutator
 ) {
     tuple_list!(
         ByteRandMutator::new(),
@@ -94,229 +315,865 @@ fn token_mutations() -> tuple_list_type!(
         BytesSwapMutator::new(),
         BytesExpandMutator::new(),
         CrossoverReplaceMutator::new(),
-        CrossoverInsertMutator::new()
+        CrossoverInsertMutator::new(),
+        ByteAddMutator::new(),
+        WordAddMutator::new(),
+        DwordAddMutator::new(),
+        QwordAddMutator::new(),
+        ByteInterestingMutator::new(),
+        WordInterestingMutator::new(),
+        DwordInterestingMutator::new(),
+        FormatMutator::new(format),
+        SliceMutator::new(),
+        TaintMutator::new()
     )
 }
 
-/// Starts a fuzzing session given a `FuzzerConfig`
-pub fn fuzz(config: FuzzerConfig) {
-    let mut run_client = |state: Option<_>, mut mgr, _core_id| {
-        // Install the SIGALRM handler
-        install_alarm_handler();
+/// Returns the size of the largest input of the corpus
+fn largest_input<S: HasCorpus<BytesInput>>(state: &mut S) -> Result<usize, Error> {
+    let mut largest = 0;
+    for idx in 0..state.corpus().count() {
+        let mut testcase = state.corpus().get(idx)?.borrow_mut();
+        largest = largest.max(testcase.load_input()?.bytes().len());
+    }
+
+    Ok(largest)
+}
 
-        // Vm memory size, 32Mb should be enough
-        const MEMORY_SIZE: usize = 32 * 1024 * 1024;
-
-        // Load the snapshot info (contains mappings and symbols)
-        let snapshot_info = SnapshotInfo::from_file("./data/snapshot_info.json")
-            .expect("Crash while parsing snapshot information");
-        // Get the program module info. Userful for setting breakpoint when PIE
-        // is enabled
-        let program_module = snapshot_info
-            .modules
-            .get("qjs")
-            .expect("Could not find program module");
-
-        // Load the VM state from the snapshot info + memory dump
-        let mut orig_vm = Vm::from_snapshot(
-            "./data/snapshot_info.json",
-            "./data/snapshot_data.bin",
-            MEMORY_SIZE,
-        )
+/// Loads the vm from the snapshot of a target and reserves the areas used by
+/// the harness and the syscall emulation layer
+pub(crate) fn load_vm(target: &Target) -> Vm {
+    // Load the VM state from the snapshot info + memory dump
+    let mut vm = Vm::from_snapshot(target.snapshot_info(), target.snapshot_data(), MEMORY_SIZE)
         .expect("Could not create vm from snapshot");
 
-        // Reserve area for the syscall emulation layer
-        const MMAP_START: u64 = 0x1337000;
-        const MMAP_SIZE: u64 = 0x100000;
-        const MMAP_END: u64 = MMAP_START + MMAP_SIZE;
-        orig_vm
-            .mmap(
-                MMAP_START,
-                MMAP_SIZE as usize,
-                PagePermissions::READ | PagePermissions::WRITE,
-            )
-            .expect("Could not allocate mmap memory");
-
-        let sysemu = Rc::new(RefCell::new(SysEmu::new(MMAP_START, MMAP_END)));
-
-        // Reserve area for the harness input place
-        const INPUT_START: u64 = 0x22000;
-        const INPUT_SIZE: u64 = 0x2000;
-        orig_vm
-            .mmap(INPUT_START, INPUT_SIZE as usize, PagePermissions::READ)
-            .expect("Could not allocate input memory");
-
-        // Create the fuzzing harness
-        let hemu = Rc::clone(&sysemu);
-
-        // Setup the decoding objects
-        let tokens_str = std::fs::read_to_string("./data/tokens.json").unwrap();
-        let token_cache: TokenCache = serde_json::from_str(&tokens_str).unwrap();
-
-        let mut harness = move |vm: &mut Vm, input: &BytesInput| {
-            // Reset the emulaton layer state
-            let mut emu = hemu.borrow_mut();
-            emu.reset();
-
-            // Decode the encoded input to text javascript
-            let mut input_buffer = [0u8; (INPUT_SIZE - 1) as usize];
-            let mut token_writer =
-                BufWriter::with_capacity(INPUT_SIZE as usize - 1, input_buffer.as_mut());
-
-            // TODO: Use a BytesInput of u16 instead of u8
-            // Loop through chunk of u16 inside the libafl input
-            for chunk in input.bytes().chunks_exact(2) {
-                // Compute token index
-                let token_index: u16 = chunk[0] as u16 | ((chunk[1] as u16) << 8);
-
-                // Get the token str representation
-                let token_str =
-                    &token_cache.tokens[token_index as usize % token_cache.tokens.len()];
-
-                // Make sure to not overfeed the input buffer
-                if token_writer.buffer().len() + token_str.len() + 1 > token_writer.capacity() {
-                    break;
-                }
+    // Reserve area for the syscall emulation layer
+    vm.mmap(
+        MMAP_START,
+        MMAP_SIZE as usize,
+        PagePermissions::READ | PagePermissions::WRITE,
+    )
+    .expect("Could not allocate mmap memory");
 
-                // Write token to memory
-                token_writer.write(token_str.as_bytes()).unwrap();
-            }
+    // Reserve area for the harness input place
+    vm.mmap(INPUT_START, INPUT_SIZE as usize, PagePermissions::READ)
+        .expect("Could not allocate input memory");
+
+    // Steer the guest down the paths of the campaign without rebuilding the
+    // snapshot
+    for cpuid in &target.cpuid {
+        let (leaf, subleaf) = (cpuid.leaf as u32, cpuid.subleaf as u32);
+        vm.set_cpuid_override(leaf, subleaf, cpuid.apply(vm.cpuid(leaf, subleaf)))
+            .expect("Could not override cpuid");
+    }
+
+    vm
+}
+
+/// Loads the token mappings of a target, extended with the tokens of an
+/// AFL-format dictionary
+pub(crate) fn load_tokens(target: &Target, dictionary: Option<&str>) -> TokenCache {
+    let tokens_str = std::fs::read_to_string(target.tokens()).unwrap();
+    let mut token_cache: TokenCache = serde_json::from_str(&tokens_str).unwrap();
+
+    // Extend the token table with the user dictionary. Encoded inputs
+    // reference tokens by index, so mutations will pick them as well.
+    if let Some(dictionary) = dictionary {
+        let tokens = Tokens::from_file(dictionary).expect("Could not load dictionary");
+        token_cache.tokens.extend(
+            tokens
+                .tokens()
+                .iter()
+                .map(|t| String::from_utf8_lossy(t).into_owned()),
+        );
+    }
+
+    token_cache
+}
+
+/// Returns the tokens an encoded input decodes to, up to what fits the input
+/// area along with the null terminator
+fn decoded_tokens<'a>(
+    token_cache: &'a TokenCache,
+    input: &'a [u8],
+) -> impl Iterator<Item = &'a str> + 'a {
+    let mut len = 0;
+
+    // TODO: Use a BytesInput of u16 instead of u8
+    // Loop through chunk of u16 inside the libafl input
+    input
+        .chunks_exact(2)
+        .map(move |chunk| {
+            // Compute token index
+            let token_index: u16 = chunk[0] as u16 | ((chunk[1] as u16) << 8);
+
+            // Get the token str representation
+            token_cache.tokens[token_index as usize % token_cache.tokens.len()].as_str()
+        })
+        .take_while(move |token_str| {
+            // Make sure to not overfeed the input buffer
+            len += token_str.len();
+            len < INPUT_SIZE as usize - 1
+        })
+}
+
+/// Returns the offset in the decoded javascript of each token of an encoded
+/// input, as placed by `write_input`
+pub(crate) fn token_starts(token_cache: &TokenCache, input: &[u8]) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+
+    for token_str in decoded_tokens(token_cache, input) {
+        starts.push(offset);
+        offset += token_str.len();
+    }
+
+    starts
+}
 
-            // Null terminate the fuzz case
-            token_writer.write(&[0u8; 1]).unwrap();
+/// Decodes an encoded input to javascript into `js_input` and delivers it
+/// to the vm. With the hypercall delivery, the guest requests it later on.
+pub(crate) fn write_input(
+    vm: &mut Vm,
+    delivery: &InputDelivery,
+    token_cache: &TokenCache,
+    input: &[u8],
+    js_input: &mut Vec<u8>,
+) {
+    // Decode the encoded input to text javascript
+    js_input.clear();
+    for token_str in decoded_tokens(token_cache, input) {
+        js_input.extend_from_slice(token_str.as_bytes());
+    }
 
-            // Set vm registers
-            let js_input = token_writer.buffer();
-            vm.set_reg(Register::Rsi, INPUT_START);
-            vm.set_reg(Register::Rdx, js_input.len() as u64 - 1);
+    // Write the fuzz case to the vm memory
+    delivery
+        .deliver(vm, js_input)
+        .expect("Could not write fuzz case to vm memory");
 
-            // Write the fuzz case to the vm memory
-            vm.write(INPUT_START, &js_input)
+    // Null terminate the fuzz case
+    if let Some((address, capacity)) = delivery.buffer() {
+        if js_input.len() < capacity {
+            vm.write_value::<u8>(address + js_input.len() as u64, 0)
                 .expect("Could not write fuzz case to vm memory");
+        }
+    }
+}
+
+/// Writes the blocks reached by a client as a drcov file, replacing the
+/// previous one
+fn save_drcov(path: &Path, modules: &BTreeMap<String, SnapshotModule>, blocks: &[u64]) {
+    let result = File::create(path)
+        .and_then(|file| write_drcov(BufWriter::new(file), modules, blocks.iter().copied()));
+
+    match result {
+        Ok(()) => log::debug!("Coverage written to {}", path.display()),
+        Err(err) => log::warn!("Could not write drcov file {}: {}", path.display(), err),
+    }
+}
+
+/// Prepares the resumption of a previous session. The queue it left is moved
+/// aside to be loaded as the initial corpus, the new queue being written from
+/// scratch. Returns whether there is a queue to resume from.
+fn prepare_resume(queue_dir: &Path, resume_dir: &Path) -> bool {
+    let has_entries = |dir: &Path| {
+        fs::read_dir(dir)
+            .map(|mut d| d.next().is_some())
+            .unwrap_or(false)
+    };
+
+    // A previous session interrupted while resuming only left `resume_dir`
+    if has_entries(queue_dir) {
+        if resume_dir.exists() {
+            fs::remove_dir_all(resume_dir).expect("Could not remove previous queue");
+        }
+        fs::rename(queue_dir, resume_dir).expect("Could not move previous queue");
+
+        // Keep the hidden metadata files (mutation logs) out of the inputs
+        let metadata_dir = resume_dir.with_file_name("metadata");
+        fs::create_dir_all(&metadata_dir).expect("Could not create metadata directory");
+        for entry in fs::read_dir(resume_dir).expect("Could not list previous queue") {
+            let path = entry.expect("Could not list previous queue").path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            if name.ends_with(".metadata") {
+                fs::rename(&path, metadata_dir.join(name.trim_start_matches('.')))
+                    .expect("Could not move metadata file");
+            }
+        }
+    }
 
-            ExitKind::Ok
+    has_entries(resume_dir)
+}
+
+/// Directories, timeout and coverage of a target during a session
+struct TargetSession {
+    /// Snapshot and harness settings
+    target: Target,
+    /// Directory holding the outputs of the target
+    output_dir: PathBuf,
+    /// Queue of the target
+    queue_dir: PathBuf,
+    /// Inputs the workers of the target start from
+    corpus_dir: PathBuf,
+    /// Execution timeout of a fuzz case
+    timeout: Duration,
+    /// Whether the timeout adapts to the execution times, `timeout` being
+    /// its starting point
+    adaptive_timeout: bool,
+    /// Coverage of all the workers of the target
+    coverage: SharedCoverageMap,
+    /// Whether the session resumes a previous one
+    resumed: bool,
+    /// Coverage of the target in the checkpoints of the resumed session
+    checkpoint_coverage: Option<Vec<u8>>,
+}
+
+impl TargetSession {
+    /// Prepares the session of a target, resuming from the queue of a
+    /// previous session if there is one, and measures its seeds
+    fn new(config: &FuzzerConfig, target: Target) -> Self {
+        let output_dir = target.session_dir(config.output_dir);
+        let queue_dir = output_dir.join("queue");
+        let resume_dir = output_dir.join("queue.resume");
+        let resumed = prepare_resume(&queue_dir, &resume_dir);
+        let corpus_dir = match resumed {
+            true => {
+                log::info!("Resuming from {}", resume_dir.display());
+                resume_dir
+            }
+            false => target.seeds(),
         };
 
-        // Setup LibAFL
-        // Create an observation channel using the coverage map
-        let cov = unsafe { &mut COVERAGE };
-        let cov_observer = StdMapObserver::new("coverage", cov);
-        // Create an observation channel to keep track of the execution time
-        let time_observer = TimeObserver::new("time");
+        // Measure the seeds, deriving the timeout from them unless one was
+        // given
+        let mut timeout = config.timeout;
+        let calibrated = calibrate(
+            &target,
+            config.dictionary,
+            config.timeout,
+            &corpus_dir,
+            &output_dir,
+        );
+        if let (true, Some(calibrated)) = (config.auto_timeout, calibrated) {
+            log::info!("Calibrated timeout of {}: {:?}", target.name, calibrated);
+            timeout = calibrated;
+        }
+
+        // Nondeterministic coverage silently ruins the feedback
+        let stability = measure_stability(
+            &target,
+            config.dictionary,
+            timeout,
+            &corpus_dir,
+            &output_dir,
+        );
+        if let Some(stability) = stability {
+            log::info!("Stability of {}: {:.2}%", target.name, stability);
+            if stability < 90.0 {
+                log::warn!(
+                    "Unstable coverage, see {}",
+                    output_dir.join("stability.txt").display()
+                );
+            }
+        }
+
+        // The coverage of the resumed session is read before the workers
+        // start checkpointing their own
+        let checkpoint_coverage = match resumed {
+            true => checkpoint_coverage(&output_dir),
+            false => None,
+        };
 
-        // The state of the coverage feedback
-        //let feedback_state = MapFeedbackState::with_observer(&cov_observer);
+        TargetSession {
+            target,
+            output_dir,
+            queue_dir,
+            corpus_dir,
+            timeout,
+            adaptive_timeout: config.auto_timeout,
+            coverage: SharedCoverageMap::new(COVERAGE_SIZE),
+            resumed,
+            checkpoint_coverage,
+        }
+    }
+}
 
-        // Feedback to rate the interestingness of an input
-        let mut feedback = feedback_or!(
-            MaxMapFeedback::new(&cov_observer),
-            TimeFeedback::new_with_observer(&time_observer)
+/// Starts a fuzzing session given a `FuzzerConfig`, supervised if it has
+/// stop conditions
+pub fn fuzz(config: FuzzerConfig) {
+    // The crashes of the sessions of the earlier versions are left where
+    // they are, the new ones go to the output directory
+    if !config.legacy_layout && config.crash_dir.is_none() && Path::new(LEGACY_CRASH_DIR).is_dir() {
+        log::warn!(
+            "Crashes are now saved under {}, use --legacy-layout to keep saving them to {}",
+            config.output_dir,
+            LEGACY_CRASH_DIR
         );
+    }
+
+    // Prepare the targets before any client starts writing to their queues
+    install_alarm_handler();
+    let sessions: Vec<_> = load_targets(&config)
+        .into_iter()
+        .map(|target| TargetSession::new(&config, target))
+        .collect();
+    let crash_dirs: Vec<_> = sessions
+        .iter()
+        .map(|session| config.target_crash_dir(&session.target))
+        .collect();
 
-        // Feedback to choose if an input is a solution or not
-        let mut objective = CrashFeedback::new();
-
-        // The fuzzer's state, create a State from scratch if restarting
-        let mut state = state.unwrap_or_else(|| {
-            StdState::new(
-                // First argument is the randomness sources
-                StdRand::with_seed(current_nanos()),
-                // Second argument is the corpus
-                InMemoryCorpus::new(),
-                // Third argument is the solutions corpus (here crashes)
-                InMemoryCorpus::new(),
-                // Fourth argument is the feedback states, used to evaluate the input
-                &mut feedback,
-                &mut objective,
+    // Monitors are wrapped to serve the status endpoint, if enabled, and to
+    // watch the coverage plateaus of the campaign
+    let address = config.status_address;
+    let status_dirs = crash_dirs.clone();
+    let (plateau, actions) = (config.plateau, config.plateau_actions);
+    let session = move || match config.tui {
+        true => {
+            let monitor = TuiMonitor::new("quickjs-fuzzer".to_string(), true);
+            let monitor = StatusMonitor::new(monitor, address, status_dirs);
+            launch(
+                config,
+                sessions,
+                PlateauMonitor::new(monitor, plateau, actions),
             )
-            .unwrap()
-        });
-
-        // Setting up the fuzzer
-        // The corpus fuzz case scheduling policy
-        let corpus_scheduler = QueueScheduler::new();
-        // The fuzzer itself
-        let mut fuzzer = StdFuzzer::new(corpus_scheduler, feedback, objective);
-
-        // Setup the executor and related hooks
-        let mut executor = TartifletteExecutor::new(
-            &orig_vm,
-            Duration::from_millis(1000),
-            tuple_list!(cov_observer, time_observer),
-            &mut harness,
-        )
-        .expect("Could not create executor");
-
-        // Exit hook to end the fuzz case when the guest calls exit(...)
-        let mut exit_hook = |_: &mut Vm| HookResult::Exit;
-        executor
-            .add_hook(program_module.start + 0x1768e, &mut exit_hook)
-            .expect("Could not install exit hook");
-
-        // Install syscall hook
-        let semu = Rc::clone(&sysemu);
-        let mut syscall_hook = move |vm: &mut Vm| {
-            // Get the syscall emulation layer
-            let mut emu = semu.borrow_mut();
-
-            // Emulate the syscall
-            match emu.syscall(vm) {
-                true => HookResult::Continue,
-                false => HookResult::Exit,
+        }
+        // Implementation of stats when in a multithreading context
+        false => {
+            let monitor = ReportMonitor::new(config.report_format, config.quiet);
+            let monitor = StatusMonitor::new(monitor, address, status_dirs);
+            launch(
+                config,
+                sessions,
+                PlateauMonitor::new(monitor, plateau, actions),
+            )
+        }
+    };
+
+    // Unless supervised, the broker only returns once stopped by a signal or
+    // on a plateau
+    match config.run_time.is_some() || config.max_crashes.is_some() {
+        true => supervise(config, &crash_dirs, session),
+        false => {
+            session();
+            log::info!("Session stopped");
+            match plateau_reached() {
+                true => process::exit(PLATEAU_EXIT_CODE),
+                false => process::exit(INTERRUPTED_EXIT_CODE),
             }
-        };
-        executor.add_syscall_hook(&mut syscall_hook);
+        }
+    }
+}
+
+/// Launches the fuzzing clients of the target `sessions`, reporting their
+/// statistics to `monitor`
+fn launch<MT: Monitor + Clone>(config: FuzzerConfig, sessions: Vec<TargetSession>, monitor: MT) {
+    // List of cores on which to run the fuzzer, use "all" to run on all cores
+    let cores = Cores::from_cmdline(config.cores).unwrap();
+
+    // Workers of each target, shared with the clients when they are forked
+    let board = TargetBoard::new(cores.ids.len(), sessions.len());
+    let dynamic = config.target_schedule == TargetSchedule::Dynamic && sessions.len() > 1;
+    if sessions.len() > 1 {
+        log::info!(
+            "Fuzzing {} targets on {} cores ({} schedule)",
+            sessions.len(),
+            cores.ids.len(),
+            config.target_schedule
+        );
+    }
+
+    let mut run_client = |state: Option<_>, mut mgr, core_id: usize| {
+        logger::set_worker(core_id);
+
+        // Install the SIGALRM handler
+        install_alarm_handler();
+
+        // Stop gracefully on SIGINT and SIGTERM, and along with the process
+        // restarting the client
+        install_shutdown_handler();
+        terminate_with_parent();
+
+        // The worker keeps its target when it is restarted, along with the
+        // state of the restarted client
+        let worker = cores.ids.iter().position(|c| c.id == core_id).unwrap();
+        let mut current = board.target_of(worker);
+        let mut restored = state;
+        let mut joined = false;
+
+        loop {
+            board.assign(worker, current);
+            let session = &sessions[current];
+            let target = &session.target;
+
+            // Load the snapshot info (contains mappings and symbols)
+            let snapshot_info = SnapshotInfo::from_file(target.snapshot_info())
+                .expect("Crash while parsing snapshot information");
+            // Get the program module info. Userful for setting breakpoint when PIE
+            // is enabled
+            let program_module = snapshot_info
+                .modules
+                .get(&target.module)
+                .expect("Could not find program module");
+
+            // Load the VM state
+            let orig_vm = load_vm(target);
+            let sysemu = Rc::new(RefCell::new(SysEmu::new(
+                MMAP_START,
+                MMAP_END,
+                target.syscalls.clone(),
+            )));
+
+            // Create the fuzzing harness. The javascript of the fuzz case
+            // is kept for the guest requests of the hypercall delivery.
+            let hemu = Rc::clone(&sysemu);
+            let token_cache = load_tokens(target, config.dictionary);
+            let delivery = target.delivery();
+            let js_input = Rc::new(RefCell::new(Vec::with_capacity(INPUT_SIZE as usize)));
+            let harness_input = Rc::clone(&js_input);
 
-        // Load coverage breakponts
-        let breakpoints = load_breakpoints("./data/breakpoints.txt");
-        for bkpt in &breakpoints {
+            let mut harness = move |vm: &mut Vm, input: &BytesInput| {
+                // Reset the emulaton layer state
+                let mut emu = hemu.borrow_mut();
+                emu.reset();
+
+                let mut js_input = harness_input.borrow_mut();
+                write_input(vm, &delivery, &token_cache, input.bytes(), &mut js_input);
+
+                ExitKind::Ok
+            };
+
+            // Setup LibAFL
+            // Create an observation channel using the coverage map
+            let cov = unsafe { &mut COVERAGE };
+            let cov_observer = StdMapObserver::new("coverage", cov);
+            // Create an observation channel to keep track of the execution time
+            let time_observer = TimeObserver::new("time");
+
+            // Feedback to rate the interestingness of an input, against the
+            // coverage of all the clients fuzzing the target, recording the
+            // paths for the power schedule
+            let mut feedback = feedback_or!(
+                SharedMapFeedback::new(&session.coverage, &cov_observer),
+                TimeFeedback::new_with_observer(&time_observer),
+                PathFeedback::new(&cov_observer, config.schedule)
+            );
+
+            // Feedback to choose if an input is a solution or not
+            let mut objective = CrashFeedback::new();
+
+            // The fuzzer's state, create a State from scratch if restarting
+            let fresh = restored.is_none();
+            let mut state = restored.take().unwrap_or_else(|| {
+                StdState::new(
+                    // First argument is the randomness sources
+                    // (derived per core from the campaign seed, if any)
+                    StdRand::with_seed(match config.seed {
+                        Some(seed) => seed ^ (core_id as u64).wrapping_mul(0x9e3779b97f4a7c15),
+                        None => current_nanos(),
+                    }),
+                    // Second argument is the corpus, kept on disk to resume sessions.
+                    // The metadata (mutation logs) is saved next to each entry and
+                    // only the recently used inputs stay in memory.
+                    IndexedCorpus::new_save_meta(
+                        session.queue_dir.clone(),
+                        Some(OnDiskMetadataFormat::JsonPretty),
+                        config.corpus_cache,
+                    )
+                    .expect("Could not create queue directory"),
+                    // Third argument is the solutions corpus (here crashes)
+                    InMemoryCorpus::new(),
+                    // Fourth argument is the feedback states, used to evaluate the input
+                    &mut feedback,
+                    &mut objective,
+                )
+                .unwrap()
+            });
+
+            // Do not let mutations grow inputs past what the harness can decode
+            state.set_max_size(config.max_input_size);
+
+            // Setting up the fuzzer
+            // The corpus fuzz case scheduling policy, weighted random picks
+            // following the power schedule
+            let corpus_scheduler = WeightedCorpusScheduler::new(config.schedule);
+            // The fuzzer itself
+            let mut fuzzer = StdFuzzer::new(corpus_scheduler, feedback, objective);
+
+            // Setup the executor and related hooks
+            let mut executor = TartifletteExecutor::new(
+                &orig_vm,
+                session.timeout,
+                tuple_list!(cov_observer, time_observer),
+                &mut harness,
+            )
+            .expect("Could not create executor");
+
+            // Exit hook to end the fuzz case when the guest calls exit(...)
+            let mut exit_hook = |_: &mut Vm| HookResult::Exit;
             executor
-                .add_coverage(program_module.start + bkpt)
-                .expect("Error while adding breakpoint");
-        }
+                .add_hook(program_module.start + target.exit_offset, &mut exit_hook)
+                .expect("Could not install exit hook");
 
-        println!("Added {} coverage breakpoints", breakpoints.len());
+            // Install syscall hook. Syscalls denied by the policy of the
+            // target abort the fuzz case, logged once per syscall.
+            let semu = Rc::clone(&sysemu);
+            let mut denied = BTreeSet::new();
+            let mut syscall_hook = move |vm: &mut Vm| {
+                // Serve the input requests of the guest
+                let served = delivery
+                    .handle_syscall(vm, &js_input.borrow())
+                    .expect("Could not write fuzz case to vm memory");
+                if served {
+                    return HookResult::Continue;
+                }
 
-        // Setup a coverage hook to output coverage for lightouse
-        let cov_file = File::create("cov.txt").expect("Could not create coverage file");
-        let mut cov_file = LineWriter::new(cov_file);
-        let mod_base = program_module.start;
+                // Get the syscall emulation layer
+                let mut emu = semu.borrow_mut();
 
-        let mut coverage_hook = move |addr| {
-            let offset = addr - mod_base;
-            write!(cov_file, "qjs+0x{:x}\n", offset).expect("Could not write to file");
-        };
-        executor.add_coverage_hook(&mut coverage_hook);
+                // Emulate the syscall
+                match emu.syscall(vm) {
+                    SyscallOutcome::Continue => HookResult::Continue,
+                    SyscallOutcome::Exit => HookResult::Exit,
+                    SyscallOutcome::Denied(syscall) => {
+                        if denied.insert(syscall) {
+                            log::warn!(
+                                "Denied syscall {} at {:#x}, aborting the fuzz cases using it",
+                                describe_syscall(syscall),
+                                vm.get_reg(Register::Rip)
+                            );
+                        }
+                        HookResult::Abort
+                    }
+                }
+            };
+            executor.add_syscall_hook(&mut syscall_hook);
+
+            // Attach what the guest printed to the crash reports
+            let oemu = Rc::clone(&sysemu);
+            let mut report_hook = move || output_section(oemu.borrow().output());
+            executor.add_report_hook(&mut report_hook);
 
-        // Load initial inputs
-        let corpus_folders = &[PathBuf::from("./data/corpus")];
-        state
-            .load_initial_inputs(&mut fuzzer, &mut executor, &mut mgr, corpus_folders)
+            // Save deduplicated crashes
+            let crash_store = CrashStore::new(config.target_crash_dir(target))
+                .expect("Could not open crash directory");
+            executor.add_crash_store(crash_store, &snapshot_info.modules);
+            executor
+                .add_timeout_dir(config.target_timeout_dir(target))
+                .expect("Could not open timeout directory");
+            if session.adaptive_timeout {
+                executor.set_adaptive_timeout(session.timeout * TIMEOUT_CEILING_FACTOR);
+            }
+
+            // Record the branches of the guest, or load coverage breakponts
+            match config.feedback {
+                FeedbackMethod::Hw(recording) => {
+                    executor
+                        .set_branch_coverage(recording)
+                        .expect("Could not enable branch recording");
+                    log::info!(
+                        "Recording {:?} branch coverage for {}",
+                        recording,
+                        target.name
+                    );
+                }
+                FeedbackMethod::Soft => {
+                    executor.set_edge_coverage(config.edge_coverage);
+                    let breakpoints = load_breakpoints(target.breakpoints());
+                    for bkpt in &breakpoints {
+                        executor
+                            .add_coverage(program_module.start + bkpt)
+                            .expect("Error while adding breakpoint");
+                    }
+
+                    log::info!(
+                        "Added {} coverage breakpoints for {}",
+                        breakpoints.len(),
+                        target.name
+                    );
+                }
+                FeedbackMethod::None => log::info!("No coverage feedback for {}", target.name),
+            }
+
+            // Setup a coverage hook to output coverage for lightouse
+            let cov_dir = config.target_cov_dir(target);
+            fs::create_dir_all(&cov_dir).expect("Could not create coverage directory");
+            let cov_file =
+                File::create(cov_dir.join("cov.txt")).expect("Could not create coverage file");
+            let mut cov_file = LineWriter::new(cov_file);
+            let mod_base = program_module.start;
+            let mod_name = target.module.clone();
+
+            // Blocks reached by the worker, written to a drcov file of its
+            // own on a timer, when it leaves the target and on shutdown
+            let blocks = Rc::new(RefCell::new(Vec::new()));
+            let hook_blocks = Rc::clone(&blocks);
+            let modules = snapshot_info.modules.clone();
+            let drcov = config.drcov.map(|path| match sessions.len() {
+                1 => format!("{}.{}", path, core_id),
+                _ => format!("{}.{}.{}", path, target.name, core_id),
+            });
+            let record_blocks = drcov.is_some();
+
+            // New coverage is only symbolized when it is logged
+            let mut symbolizer = Symbolizer::new(&modules);
+
+            let mut coverage_hook = move |addr| {
+                let offset = addr - mod_base;
+                write!(cov_file, "{}+0x{:x}\n", mod_name, offset).expect("Could not write to file");
+
+                if log::log_enabled!(log::Level::Debug) {
+                    log::debug!("New coverage: {}", symbolizer.symbolize(addr));
+                }
+
+                if record_blocks {
+                    hook_blocks.borrow_mut().push(addr);
+                }
+            };
+            executor.add_coverage_hook(&mut coverage_hook);
+
+            // Show the target of the worker next to its statistics
+            if sessions.len() > 1 {
+                EventFirer::fire(
+                    &mut mgr,
+                    &mut state,
+                    Event::UpdateUserStats {
+                        name: "target".to_string(),
+                        value: UserStats::String(target.name.clone()),
+                        phantom: PhantomData,
+                    },
+                )
+                .expect("Could not report the target");
+            }
+
+            // Load initial inputs. The coverage of the target is already known
+            // when joining it, its inputs would not be interesting anymore.
+            // Without feedback, no input is ever interesting.
+            let corpus_folders = &[session.corpus_dir.clone()];
+            match joined || config.feedback == FeedbackMethod::None {
+                true => state.load_initial_inputs_forced(
+                    &mut fuzzer,
+                    &mut executor,
+                    &mut mgr,
+                    corpus_folders,
+                ),
+                false => {
+                    state.load_initial_inputs(&mut fuzzer, &mut executor, &mut mgr, corpus_folders)
+                }
+            }
             .expect("Could not load corpus files");
 
-        // Setup a mutator with a mutational stage
-        let mutator = StdScheduledMutator::new(token_mutations());
-        let mut stages = tuple_list!(StdMutationalStage::new(mutator));
+            // Resume the campaign state of the previous session from its
+            // checkpoints: the coverage of the target, and the executions and
+            // entry weights of the worker
+            let checkpoint = checkpoint_path(&session.output_dir, core_id);
+            if session.resumed && fresh && !joined {
+                if let Some(coverage) = &session.checkpoint_coverage {
+                    session.coverage.restore(coverage);
+                }
+                if let Some(previous) = Checkpoint::read(&checkpoint) {
+                    *state.executions_mut() += previous.executions;
+                    previous
+                        .restore_energies(&mut state, fuzzer.scheduler())
+                        .expect("Could not restore the entry weights");
+                    log::info!("Resumed from {}", checkpoint.display());
+                }
+            }
+
+            // Setup a mutator with a mutational stage
+            // The mutations which produced each corpus entry are logged in its metadata
+            let mut mutator = WeightedScheduledMutator::new(
+                token_mutations(config.input_format),
+                config.max_stack_pow,
+            );
+            if config.input_format == InputFormat::Raw {
+                mutator
+                    .set_weights("FormatMutator=0")
+                    .expect("Invalid mutation weights");
+            }
+            if !config.taint {
+                mutator
+                    .set_weights("TaintMutator=0")
+                    .expect("Invalid mutation weights");
+            }
+            if let Some(weights) = config.mutation_weights {
+                mutator
+                    .set_weights(weights)
+                    .expect("Invalid mutation weights");
+            }
+            if config.adaptive_weights {
+                mutator.set_adaptive();
+            }
+
+            // Set while the target is on a coverage plateau, for the stages
+            // taking the plateau actions
+            let on_plateau = Rc::new(Cell::new(false));
+            if config.plateau_actions.mutations {
+                mutator.set_plateau(on_plateau.clone());
+            }
+            mutator.set_speed_pow(config.speed_pow);
+            mutator.set_stats_path(
+                session
+                    .output_dir
+                    .join(format!("mutation_stats.{}", core_id)),
+            );
+
+            // The effective maximum input size starts from the largest seed
+            // unless it is fixed
+            let initial_size = match config.fixed_input_size {
+                true => config.max_input_size,
+                false => largest_input(&mut state).expect("Could not read the corpus"),
+            };
+            let mutator = SizeAdaptiveMutator::new(mutator, initial_size, config.max_input_size);
+
+            // Entries are trimmed if enabled, sliced, probed for their
+            // influential tokens and their comparisons solved if enabled, the
+            // first time they are scheduled, by runners of their own
+            let trimmer = config
+                .trim
+                .then(|| Runner::new(target, config.dictionary, session.timeout, true));
+            let slicer = Runner::new(target, config.dictionary, session.timeout, true);
+            let prober = config
+                .taint
+                .then(|| Runner::new(target, config.dictionary, session.timeout, true));
+            let solver = config
+                .cmplog
+                .then(|| Runner::new(target, config.dictionary, session.timeout, true));
+            let mut stages = tuple_list!(
+                TrimStage::new(trimmer),
+                SliceStage::new(slicer),
+                TaintStage::new(prober),
+                InputToStateStage::new(solver, target),
+                DeterministicStage::new(match config.plateau_actions.deterministic {
+                    true => on_plateau.clone(),
+                    false => Rc::default(),
+                }),
+                StdMutationalStage::new(mutator)
+            );
+
+            // Fuzz. With the dynamic schedule, the worker moves to another
+            // target when its coverage grows much faster than the current one.
+            let mut last_report = current_time();
+            let mut last_check = Instant::now();
+            let mut last_checkpoint = Instant::now();
+            let mut last_drcov = Instant::now();
+            let mut written_blocks = 0;
+            let mut flush_drcov = |force: bool| {
+                let blocks = blocks.borrow();
+                if let Some(path) = &drcov {
+                    if blocks.len() > written_blocks
+                        && (force || last_drcov.elapsed() >= DRCOV_INTERVAL)
+                    {
+                        save_drcov(Path::new(path), &modules, &blocks);
+                        written_blocks = blocks.len();
+                        last_drcov = Instant::now();
+                    }
+                }
+            };
+            let mut plateau = config.plateau.map(PlateauDetector::new);
+            let next = loop {
+                fuzzer.fuzz_one(&mut stages, &mut executor, &mut state, &mut mgr)?;
+
+                // On shutdown, the corpus and crashes are already written,
+                // only the final checkpoint and statistics are left
+                if terminating() {
+                    if config.checkpoint_interval.is_some() {
+                        save_checkpoint(&state, &session.coverage, &checkpoint);
+                    }
+                    flush_drcov(true);
+                    ProgressReporter::maybe_report_progress(
+                        &mut mgr,
+                        &mut state,
+                        last_report,
+                        Duration::ZERO,
+                    )?;
+                    log::info!("Worker stopped");
+                    process::exit(0);
+                }
+
+                let reported = ProgressReporter::maybe_report_progress(
+                    &mut mgr,
+                    &mut state,
+                    last_report,
+                    PROGRESS_INTERVAL,
+                )?;
+
+                // The coverage of the target goes along with the statistics
+                if reported != last_report {
+                    last_report = reported;
+                    EventFirer::fire(
+                        &mut mgr,
+                        &mut state,
+                        Event::UpdateUserStats {
+                            name: "coverage".to_string(),
+                            value: UserStats::Number(session.coverage.covered() as u64),
+                            phantom: PhantomData,
+                        },
+                    )?;
+                }
+
+                flush_drcov(false);
+
+                if let Some(interval) = config.checkpoint_interval {
+                    if last_checkpoint.elapsed() >= interval {
+                        save_checkpoint(&state, &session.coverage, &checkpoint);
+                        last_checkpoint = Instant::now();
+                    }
+                }
+
+                // On a plateau of the target, the deterministic stage and the
+                // other mutation weights are given a chance until it grows again
+                let covered = session.coverage.covered() as u64;
+                if let Some(event) = plateau.as_mut().and_then(|p| p.update(covered)) {
+                    let actions = config.plateau_actions;
+                    let reached = event == PlateauEvent::Reached;
+                    on_plateau.set(reached);
+                    if actions.mutations || actions.deterministic {
+                        log::info!(
+                            "{} a plateau on {}",
+                            if reached { "Reached" } else { "Left" },
+                            target.name
+                        );
+                    }
+                }
 
-        // Fuzz
-        fuzzer
-            .fuzz_loop(&mut stages, &mut executor, &mut state, &mut mgr)
-            .expect("Error in the fuzzing loop");
+                if dynamic && last_check.elapsed() >= REBALANCE_INTERVAL {
+                    last_check = Instant::now();
+                    board.record(current, session.coverage.covered());
+                    if let Some(next) = board.rebalance(current) {
+                        break next;
+                    }
+                }
+            };
+            flush_drcov(true);
 
-        Ok(())
+            log::info!(
+                "Moving from {} to {}",
+                target.name,
+                sessions[next].target.name
+            );
+            current = next;
+            joined = true;
+        }
     };
 
     // Launcher setup
-    // List of cores on which to run the fuzzer, use "all" to run on all cores
-    let cores = Cores::from_cmdline(config.cores).unwrap();
     // Port on which the broker will listen
     let port = config.broker_port.parse::<u16>().unwrap();
     // Address on which the broker is, None if it is local
     let address = config
         .broker_address
         .map_or(None, |a| Some(a.parse::<SocketAddr>().unwrap()));
-    // Implementation of stats when in a multithreading context
-    let monitor = MultiMonitor::new(|s| println!("{}", s));
+    // Output of the clients, silenced under the user interface
+    let stdout_file = match config.tui {
+        true => Some("/dev/null"),
+        false => None,
+    };
+    // Inputs found on another target are run again, and only kept if they
+    // reach new coverage of the target of the receiving client
+    let configuration = match sessions.len() {
+        1 => "default".into(),
+        _ => EventConfig::AlwaysUnique,
+    };
     // Provider for shared memory. Used by llmp for ipc
     let shmem_provider = StdShMemProvider::new().unwrap();
 
@@ -324,10 +1181,11 @@ pub fn fuzz(config: FuzzerConfig) {
         .shmem_provider(shmem_provider)
         .monitor(monitor)
         .run_client(&mut run_client)
+        .stdout_file(stdout_file)
         .cores(&cores)
         .broker_port(port)
         .remote_broker_addr(address)
-        .configuration("default".into())
+        .configuration(configuration)
         .build()
         .launch()
     {
@@ -0,0 +1,49 @@
+use crate::executor::install_alarm_handler;
+use crate::fuzz::FuzzerConfig;
+use crate::minimize::distill;
+use crate::targets::selected_target;
+use crate::triage::list_inputs;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Finds the inputs of an AFL output directory (`queue`), of an AFL++ one
+/// (`<instance>/queue`) or of a flat libFuzzer corpus
+fn find_inputs(dir: &Path) -> Vec<PathBuf> {
+    let queue = dir.join("queue");
+    if queue.is_dir() {
+        return list_inputs(queue);
+    }
+
+    let instances: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("Could not list directory")
+        .map(|entry| {
+            entry
+                .expect("Could not list directory")
+                .path()
+                .join("queue")
+        })
+        .filter(|queue| queue.is_dir())
+        .collect();
+    if !instances.is_empty() {
+        return instances.into_iter().flat_map(list_inputs).collect();
+    }
+
+    // Metadata files are not inputs
+    list_inputs(dir)
+        .into_iter()
+        .filter(|path| path.extension().map_or(true, |ext| ext != "metadata"))
+        .collect()
+}
+
+/// Seeds the session queue with the inputs of another campaign reaching new
+/// coverage. The next fuzzing session resumes from them.
+pub fn import<P: AsRef<Path>>(config: FuzzerConfig, dir: P) {
+    install_alarm_handler();
+
+    let inputs = find_inputs(dir.as_ref());
+    let queue_dir = selected_target(&config)
+        .session_dir(config.output_dir)
+        .join("queue");
+    distill(config, inputs, &queue_dir);
+}
@@ -0,0 +1,42 @@
+//! Minimal backend of the `log` facade, prefixing each record with its level
+//! and the fuzzing client it comes from
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Core of the fuzzing client of this process, `usize::MAX` outside of them
+static WORKER: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Logger writing the records to stdout
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match WORKER.load(Ordering::Relaxed) {
+            usize::MAX => println!("[{:<5}] {}", record.level(), record.args()),
+            worker => println!("[{:<5} #{}] {}", record.level(), worker, record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, showing the records up to `level`
+pub fn init(level: LevelFilter) {
+    log::set_logger(&Logger).expect("Could not install logger");
+    log::set_max_level(level);
+}
+
+/// Tags the records of this process with the core of its fuzzing client
+pub fn set_worker(core_id: usize) {
+    WORKER.store(core_id, Ordering::Relaxed);
+}
@@ -1,11 +1,37 @@
 //! Token based fuzzer for quickjs
 
+mod calibrate;
+mod checkpoint;
+mod cmplog;
+mod config;
+mod corpus;
+mod coverage;
+mod deterministic;
 mod executor;
+mod formats;
 mod fuzz;
+mod import;
+mod logger;
+mod minimize;
+mod mutator;
+mod plateau;
+mod report;
+mod reproduce;
+mod runner;
+mod scheduler;
+mod server;
+mod shared_map;
+mod shutdown;
+mod slice;
+mod status;
+mod supervisor;
 mod sysemu;
+mod taint;
+mod targets;
+mod triage;
+mod trim;
 
 use clap::{Arg, Command};
-use fuzz::FuzzerConfig;
 
 fn main() {
     // Get the program args as Vec<&str>
@@ -41,17 +67,341 @@ fn main() {
                 .help("port of the broker")
                 .default_value("1337")
                 .takes_value(true),
+        )
+        .arg(
+            Arg::new("crash_dir")
+                .short('o')
+                .long("crashes")
+                .value_name("CRASH_DIR")
+                .help("directory where unique crashes are saved (defaults to OUTPUT_DIR/crashes)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("timeout_dir")
+                .long("timeouts")
+                .value_name("TIMEOUT_DIR")
+                .help("directory where inputs triggering a timeout are saved (defaults to OUTPUT_DIR/timeouts)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("cov_dir")
+                .long("cov-dir")
+                .value_name("COV_DIR")
+                .help("directory where the coverage file is written (defaults to OUTPUT_DIR/cov)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("legacy_layout")
+                .long("legacy-layout")
+                .help("defaults to ./crashes, ./timeouts and ./cov.txt like the earlier versions"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .short('t')
+                .long("timeout")
+                .value_name("TIMEOUT_MS")
+                .help("execution timeout of a fuzz case in milliseconds (derived from the seeds if not set)")
+                .default_value("1000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("dictionary")
+                .short('x')
+                .long("dict")
+                .value_name("DICT")
+                .help("AFL-format dictionary of additional javascript tokens")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("reproduce")
+                .short('r')
+                .long("reproduce")
+                .value_name("FILE")
+                .help("replays a single input and prints its crash report")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("access_trace")
+                .long("access-trace")
+                .value_name("FILE")
+                .help("with -r, logs the accesses to the input area to a trace and lists the code making them")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("output_dir")
+                .long("output")
+                .value_name("OUTPUT_DIR")
+                .help("session directory, resumed if it holds a previous queue")
+                .default_value("./output")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("socket")
+                .short('s')
+                .long("socket")
+                .value_name("ADDRESS")
+                .help("runs inputs received on a unix socket path or ip:port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("edges")
+                .long("edges")
+                .help("records edge coverage, keeping coverage breakpoints armed"),
+        )
+        .arg(
+            Arg::new("branches")
+                .long("branches")
+                .value_name("MECHANISM")
+                .help("mechanism recording the branches of the hw feedback (lbr, the default), selecting the hw feedback if no other is")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("feedback")
+                .long("feedback")
+                .value_name("METHOD")
+                .help("coverage guiding the fuzzer: coverage breakpoints (soft, the default), partial edges from the branches recorded by the cpu (hw) or none")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("drcov")
+                .long("drcov")
+                .value_name("FILE")
+                .help("dumps the coverage as a drcov file for lighthouse, one per client suffixed with its core when fuzzing")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max_input_size")
+                .short('m')
+                .long("max-input-size")
+                .value_name("BYTES")
+                .help("maximum size of an encoded input (defaults to what fits the input area)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("corpus_cache")
+                .long("corpus-cache")
+                .value_name("COUNT")
+                .help("number of corpus inputs kept in memory, the others are loaded from disk on demand (defaults to 4096)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("fixed_size")
+                .long("fixed-size")
+                .help("keeps the maximum input size fixed instead of adapting it to the sizes finding new coverage"),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("seeds the random generators to make the campaign reproducible")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("stack_pow")
+                .long("stack-pow")
+                .value_name("POW")
+                .help("stacks up to 2^POW mutations per fuzz case (defaults to 6)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("speed_pow")
+                .long("speed-pow")
+                .value_name("POW")
+                .help("adjusts the stack power by up to POW to the entry speed, more mutations on fast entries (defaults to 2, 0 disables)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("weights")
+                .long("weights")
+                .value_name("NAME=WEIGHT,...")
+                .help("relative weights of the mutations, by mutator name")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("mopt")
+                .long("mopt")
+                .help("adapts the weights of the mutations to their success rates, with periodic exploration"),
+        )
+        .arg(
+            Arg::new("taint")
+                .long("taint")
+                .help("infers the tokens of each entry influencing its coverage and focuses mutations on them"),
+        )
+        .arg(
+            Arg::new("schedule")
+                .long("schedule")
+                .value_name("SCHEDULE")
+                .help("power schedule weighting the corpus entries: speed, round-robin, rare or fast (rare and fast need --edges or the hw feedback)")
+                .default_value("speed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("cmplog")
+                .long("cmplog")
+                .help("replaces the input bytes reaching the comparisons by the values they are compared to, the first time an entry is fuzzed"),
+        )
+        .arg(
+            Arg::new("trim")
+                .long("trim")
+                .help("trims the entries as long as they reach the same coverage, the first time they are fuzzed"),
+        )
+        .arg(
+            Arg::new("input_delivery")
+                .long("input-delivery")
+                .value_name("MODE")
+                .help("way the harness receives the input: registers, shared or hypercall[:<number>], for the targets not setting input_delivery")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("structure of the inputs (raw, chunks, tlv or json), enabling the matching mutations")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("checkpoint")
+                .long("checkpoint")
+                .value_name("SECS")
+                .help("checkpoints the state of the clients every SECS seconds (defaults to 300, 0 disables)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("plateau")
+                .long("plateau")
+                .value_name("SECS")
+                .help("reports a coverage plateau once no new coverage was found for SECS seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("plateau_actions")
+                .long("plateau-actions")
+                .value_name("ACTIONS")
+                .help("actions taken on a plateau, among log, mutations, deterministic and exit (defaults to log)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("run_time")
+                .long("run-time")
+                .value_name("SECS")
+                .help("stops the session after SECS seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("max_crashes")
+                .long("exit-on-crash-count")
+                .value_name("N")
+                .help("stops the session once N new unique crashes were found")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("status")
+                .long("status")
+                .value_name("ADDRESS")
+                .help("serves the campaign status as JSON over HTTP on ip:port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("targets")
+                .long("targets")
+                .value_name("FILE")
+                .help("JSON list of the targets fuzzed by the session, each with its own corpus and crashes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("target_schedule")
+                .long("target-schedule")
+                .value_name("SCHEDULE")
+                .help("splits the cores evenly across the targets (static) or moves them to the targets whose coverage grows the fastest (dynamic)")
+                .default_value("static")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("target")
+                .long("target")
+                .value_name("NAME")
+                .help("target of the targets file run by the other subcommands (defaults to the first one)")
+                .requires("targets")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("log_level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("most verbose logs shown (off, error, warn, info, debug, trace)")
+                .default_value("info")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("tui")
+                .long("tui")
+                .help("displays a terminal user interface instead of the logs"),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("FORMAT")
+                .help("format of the campaign report lines, text or json (defaults to text)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .help("only reports new crashes, and the campaign once a minute"),
+        )
+        .subcommand(Command::new("fuzz").about("runs a fuzzing session (default)"))
+        .subcommand(
+            Command::new("triage")
+                .about("replays the inputs of a crash directory and groups them by crash")
+                .arg(Arg::new("crash_dir").value_name("CRASH_DIR").required(true)),
+        )
+        .subcommand(
+            Command::new("minimize")
+                .about("minimizes a corpus directory, or a single crashing input")
+                .arg(Arg::new("input").value_name("CORPUS|FILE").required(true))
+                .arg(Arg::new("output").value_name("OUTPUT").required(true)),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("seeds the queue with an AFL output directory or a libFuzzer corpus")
+                .arg(Arg::new("dir").value_name("DIR").required(true)),
+        )
+        .subcommand(
+            Command::new("cov")
+                .about("measures the coverage of a corpus (exported with --drcov)")
+                .arg(Arg::new("corpus").value_name("CORPUS").required(true)),
         );
 
     // Get the program args matches
     let matches = command.get_matches_from(args);
 
     // Compute the fuzzer configuration
-    let config = FuzzerConfig {
-        cores: matches.value_of("cores").unwrap(),
-        broker_address: matches.value_of("broker_address"),
-        broker_port: matches.value_of("broker_port").unwrap(),
+    let config = match config::parse_config(&matches) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(2);
+        }
     };
+    logger::init(config.log_level);
 
-    fuzz::fuzz(config);
+    match matches.subcommand() {
+        Some(("triage", sub)) => triage::triage(config, sub.value_of("crash_dir").unwrap()),
+        Some(("minimize", sub)) => minimize::minimize(
+            config,
+            sub.value_of("input").unwrap(),
+            sub.value_of("output").unwrap(),
+        ),
+        Some(("import", sub)) => import::import(config, sub.value_of("dir").unwrap()),
+        Some(("cov", sub)) => coverage::coverage(config, sub.value_of("corpus").unwrap()),
+        _ => {
+            if let Some(path) = matches.value_of("reproduce") {
+                reproduce::reproduce(config, path);
+            } else if let Some(address) = matches.value_of("socket") {
+                server::serve_socket(config, address);
+            } else {
+                fuzz::fuzz(config);
+            }
+        }
+    }
 }
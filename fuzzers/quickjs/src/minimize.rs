@@ -0,0 +1,163 @@
+use crate::executor::install_alarm_handler;
+use crate::fuzz::FuzzerConfig;
+use crate::runner::{Runner, Verdict};
+use crate::targets::selected_target;
+use crate::triage::list_inputs;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Writes to `output_dir` the smallest inputs reaching all the coverage of
+/// `paths`. Coverage breakpoints being one-shot, an input is kept if it
+/// reaches coverage that the smaller inputs did not.
+pub(crate) fn distill(config: FuzzerConfig, paths: Vec<PathBuf>, output_dir: &Path) {
+    let mut runner = Runner::new(
+        &selected_target(&config),
+        config.dictionary,
+        config.timeout,
+        true,
+    );
+
+    let mut inputs: Vec<_> = paths
+        .into_iter()
+        .map(|path| {
+            let input = fs::read(&path).expect("Could not read input file");
+            (path, input)
+        })
+        .collect();
+    inputs.sort_by_key(|(_, input)| input.len());
+
+    fs::create_dir_all(output_dir).expect("Could not create output directory");
+    let mut kept = 0;
+    for (path, input) in &inputs {
+        if runner.run(input).1 > 0 {
+            fs::write(output_dir.join(path.file_name().unwrap()), input)
+                .expect("Could not write input file");
+            kept += 1;
+        }
+    }
+
+    println!(
+        "Kept {} of {} inputs, reaching {} coverage points",
+        kept,
+        inputs.len(),
+        runner.covered().len()
+    );
+}
+
+/// Hash of the crash triggered by an input, if any
+fn crash_hash(runner: &mut Runner, input: &[u8]) -> Option<u64> {
+    match runner.run(input).0 {
+        Verdict::Crash(crash, _) => Some(crash.hash()),
+        _ => None,
+    }
+}
+
+/// Keeps the smallest inputs of a corpus reaching all of its coverage
+fn minimize_corpus(config: FuzzerConfig, corpus_dir: &Path, output_dir: &Path) {
+    distill(config, list_inputs(corpus_dir), output_dir);
+}
+
+/// Removes chunks of tokens (u16 indices) from `input` as long as it
+/// triggers the crash `hash`, halving their size down to one token. Returns
+/// whether the input changed.
+fn remove_chunks(runner: &mut Runner, input: &mut Vec<u8>, hash: u64) -> bool {
+    let size = input.len();
+
+    let mut chunk = (input.len() / 2) & !1;
+    while chunk >= 2 {
+        let mut offset = 0;
+        while offset + chunk <= input.len() {
+            let mut candidate = input[..offset].to_vec();
+            candidate.extend_from_slice(&input[offset + chunk..]);
+
+            match crash_hash(runner, &candidate) == Some(hash) {
+                true => *input = candidate,
+                false => offset += chunk,
+            }
+        }
+        chunk = (chunk / 2) & !1;
+    }
+
+    input.len() != size
+}
+
+/// Zeroes chunks of tokens of `input` as long as it triggers the crash
+/// `hash`, halving their size down to one token, so that the remaining
+/// tokens stand out. Returns whether the input changed.
+fn zero_chunks(runner: &mut Runner, input: &mut Vec<u8>, hash: u64) -> bool {
+    let mut changed = false;
+
+    let mut chunk = (input.len() / 2) & !1;
+    while chunk >= 2 {
+        for offset in (0..input.len() - input.len() % 2).step_by(chunk) {
+            let end = (offset + chunk).min(input.len());
+            if input[offset..end].iter().all(|&b| b == 0) {
+                continue;
+            }
+
+            let mut candidate = input.clone();
+            candidate[offset..end].fill(0);
+            if crash_hash(runner, &candidate) == Some(hash) {
+                *input = candidate;
+                changed = true;
+            }
+        }
+        chunk = (chunk / 2) & !1;
+    }
+
+    changed
+}
+
+/// Removes then zeroes tokens of a crashing input as long as it triggers the
+/// same crash, until neither changes it
+fn minimize_crash(config: FuzzerConfig, path: &Path, output: &Path) {
+    let mut runner = Runner::new(
+        &selected_target(&config),
+        config.dictionary,
+        config.timeout,
+        false,
+    );
+    let mut input = fs::read(path).expect("Could not read input file");
+    let size = input.len();
+
+    let hash = match crash_hash(&mut runner, &input) {
+        Some(hash) => hash,
+        None => {
+            println!("The input does not crash, nothing to minimize");
+            return;
+        }
+    };
+
+    // Zeroing can make more tokens removable, and the other way around
+    let mut round = 0;
+    loop {
+        round += 1;
+        let removed = remove_chunks(&mut runner, &mut input, hash);
+        let zeroed = zero_chunks(&mut runner, &mut input, hash);
+        println!("Round {}: {} bytes", round, input.len());
+
+        if !removed && !zeroed {
+            break;
+        }
+    }
+
+    fs::write(output, &input).expect("Could not write input file");
+    println!(
+        "Minimized from {} to {} bytes in {}",
+        size,
+        input.len(),
+        output.display()
+    );
+}
+
+/// Minimizes a corpus directory into `output`, or a single crashing input
+/// into the `output` file
+pub fn minimize<P: AsRef<Path>>(config: FuzzerConfig, path: P, output: P) {
+    install_alarm_handler();
+
+    match path.as_ref().is_dir() {
+        true => minimize_corpus(config, path.as_ref(), output.as_ref()),
+        false => minimize_crash(config, path.as_ref(), output.as_ref()),
+    }
+}
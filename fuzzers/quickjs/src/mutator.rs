@@ -0,0 +1,513 @@
+//! Scheduled mutator with tunable stacking and operator weights
+
+use libafl::{
+    bolts::{rands::Rand, tuples::NamedTuple, HasLen},
+    corpus::Corpus,
+    inputs::Input,
+    mutators::{scheduled::LogMutationMetadata, MutationResult, Mutator, MutatorsTuple},
+    state::{HasCorpus, HasMaxSize, HasMetadata, HasRand},
+    Error,
+};
+
+use std::cell::Cell;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Default maximum stack power, up to 2^6 stacked mutations per fuzz case
+pub(crate) const DEFAULT_MAX_STACK_POW: u64 = 6;
+
+/// Default range of the speed adjustment of the stack power
+pub(crate) const DEFAULT_SPEED_POW: u64 = 2;
+
+/// Smallest effective maximum input size
+const MIN_SIZE_LIMIT: usize = 64;
+
+/// Number of fuzz cases after which the effective maximum input size is
+/// adapted to the sizes of the new corpus entries
+const SIZE_WINDOW: u64 = 50_000;
+
+/// Number of fuzz cases of an exploration phase of the adaptive weights,
+/// where the operators are picked according to the configured weights
+const EXPLORE_CASES: u64 = 5_000;
+
+/// Number of fuzz cases of an exploitation phase of the adaptive weights,
+/// where the operators are picked according to their success rates
+const EXPLOIT_CASES: u64 = 50_000;
+
+/// Scale of the adaptive weights, given to the most successful operator
+const ADAPTIVE_SCALE: f64 = 1000.0;
+
+/// Smallest adaptive weight of an enabled operator, so that none of them is
+/// starved between two exploration phases
+const MIN_ADAPTIVE_WEIGHT: u64 = 10;
+
+/// Usage counters of a mutation operator
+#[derive(Default, Copy, Clone)]
+struct OperatorStats {
+    /// Number of times the operator was applied
+    uses: u64,
+    /// Number of new corpus entries the operator took part in
+    finds: u64,
+    /// Number of times the operator was applied in the current phase
+    phase_uses: u64,
+    /// Number of new corpus entries the operator took part in during the
+    /// current phase
+    phase_finds: u64,
+    /// Moving average of the finds per use over the phases
+    rate: f64,
+}
+
+/// Mutator stacking `2^(1..=max_stack_pow)` mutations per fuzz case, each
+/// picked according to its weight.
+///
+/// Like libafl's `LoggerScheduledMutator`, the mutations which produced a
+/// corpus entry are logged in its metadata. The success rate of each operator
+/// is written to a stats file whenever an entry is added.
+///
+/// The stack power is adjusted to the speed of the fuzzed entry: inputs
+/// faster than the average are mutated more aggressively, slower ones more
+/// lightly, by up to `speed_pow` powers of two.
+///
+/// Like MOpt, the weights can adapt to the success of the operators: short
+/// exploration phases using the configured weights alternate with longer
+/// exploitation phases where each operator is weighted by its finds per use,
+/// averaged over the phases.
+pub struct WeightedScheduledMutator<I, MT, S> {
+    /// Mutation operators
+    mutations: MT,
+    /// Weight of each operator, 0 disables it
+    weights: Vec<u64>,
+    /// Weight of each operator in the exploitation phases, if the weights
+    /// are adaptive
+    adaptive_weights: Option<Vec<u64>>,
+    /// Whether the weights were configured as adaptive
+    adaptive: bool,
+    /// Coverage plateau flag switching between the configured and the other
+    /// weights while set
+    plateau: Option<Rc<Cell<bool>>>,
+    /// State of the plateau flag the weights were last switched for
+    on_plateau: bool,
+    /// Whether the current phase is an exploitation phase
+    exploiting: bool,
+    /// Number of fuzz cases of the current phase
+    phase_cases: u64,
+    /// Maximum power of two of the number of stacked mutations
+    max_stack_pow: u64,
+    /// Maximum adjustment of the stack power to the entry speed, 0 disables it
+    speed_pow: u64,
+    /// Moving average of the execution time of the fuzzed entries
+    mean_exec_time: Option<Duration>,
+    /// Operators applied during the current fuzz case
+    mutation_log: Vec<usize>,
+    /// Counters of each operator
+    stats: Vec<OperatorStats>,
+    /// File receiving the operator counters
+    stats_path: Option<PathBuf>,
+    phantom: PhantomData<(I, S)>,
+}
+
+impl<I, MT, S> WeightedScheduledMutator<I, MT, S>
+where
+    I: Input,
+    MT: MutatorsTuple<I, S> + NamedTuple,
+{
+    /// Creates a mutator with uniform weights
+    pub fn new(mutations: MT, max_stack_pow: u64) -> Self {
+        let count = MT::LEN;
+
+        Self {
+            mutations,
+            weights: vec![1; count],
+            adaptive_weights: None,
+            adaptive: false,
+            plateau: None,
+            on_plateau: false,
+            exploiting: false,
+            phase_cases: 0,
+            max_stack_pow: max_stack_pow.max(1),
+            speed_pow: DEFAULT_SPEED_POW,
+            mean_exec_time: None,
+            mutation_log: Vec::new(),
+            stats: vec![OperatorStats::default(); count],
+            stats_path: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Sets operator weights from a `name=weight,...` list. Operators left out
+    /// keep a weight of 1.
+    pub fn set_weights(&mut self, spec: &str) -> Result<(), Error> {
+        for entry in spec.split(',').filter(|e| !e.is_empty()) {
+            let (name, weight) = entry
+                .split_once('=')
+                .ok_or_else(|| Error::illegal_argument(format!("Invalid weight {}", entry)))?;
+            let weight = weight
+                .trim()
+                .parse()
+                .map_err(|_| Error::illegal_argument(format!("Invalid weight {}", entry)))?;
+            let index = (0..MT::LEN)
+                .find(|&i| self.mutations.name(i) == Some(name.trim()))
+                .ok_or_else(|| Error::illegal_argument(format!("Unknown mutation {}", name)))?;
+
+            self.weights[index] = weight;
+        }
+
+        if self.weights.iter().all(|&w| w == 0) {
+            return Err(Error::illegal_argument("All mutations are disabled"));
+        }
+
+        Ok(())
+    }
+
+    /// Adapts the weights to the success rates of the operators, starting
+    /// with an exploration phase
+    pub fn set_adaptive(&mut self) {
+        self.adaptive = true;
+        self.adaptive_weights = Some(self.weights.clone());
+    }
+
+    /// Switches to the other weights than the configured ones while the
+    /// `plateau` flag is set, e.g. on a coverage plateau
+    pub fn set_plateau(&mut self, plateau: Rc<Cell<bool>>) {
+        self.plateau = Some(plateau);
+    }
+
+    /// Uses the configured weights off a plateau and the other ones on a
+    /// plateau, the adaptive weights starting over with an exploration phase
+    fn set_plateau_reached(&mut self, reached: bool) {
+        self.on_plateau = reached;
+        self.adaptive_weights = match self.adaptive != reached {
+            true => Some(self.weights.clone()),
+            false => None,
+        };
+        self.exploiting = false;
+        self.phase_cases = 0;
+    }
+
+    /// Sets the maximum adjustment of the stack power to the entry speed, 0
+    /// disabling it
+    pub fn set_speed_pow(&mut self, speed_pow: u64) {
+        self.speed_pow = speed_pow;
+    }
+
+    /// Writes the operator counters to `path` on every new corpus entry
+    pub fn set_stats_path(&mut self, path: PathBuf) {
+        self.stats_path = Some(path);
+    }
+
+    /// Returns the weights of the current phase
+    fn current_weights(&self) -> &[u64] {
+        match (&self.adaptive_weights, self.exploiting) {
+            (Some(weights), true) => weights,
+            _ => &self.weights,
+        }
+    }
+
+    /// Picks the next operator according to the weights
+    fn schedule(&self, state: &mut S) -> usize
+    where
+        S: HasRand,
+    {
+        let weights = self.current_weights();
+        let total: u64 = weights.iter().sum();
+        let mut pick = state.rand_mut().below(total);
+
+        for (index, &weight) in weights.iter().enumerate() {
+            if pick < weight {
+                return index;
+            }
+            pick -= weight;
+        }
+
+        unreachable!()
+    }
+
+    /// Counts a fuzz case in the current phase. At the end of the phase, the
+    /// success rates of the operators are updated with the ones of the phase
+    /// and the adaptive weights are derived from them.
+    fn end_case(&mut self) {
+        if self.adaptive_weights.is_none() {
+            return;
+        }
+
+        self.phase_cases += 1;
+        let length = match self.exploiting {
+            true => EXPLOIT_CASES,
+            false => EXPLORE_CASES,
+        };
+        if self.phase_cases < length {
+            return;
+        }
+
+        for stats in &mut self.stats {
+            if stats.phase_uses > 0 {
+                let rate = stats.phase_finds as f64 / stats.phase_uses as f64;
+                stats.rate = (stats.rate + rate) / 2.0;
+            }
+            stats.phase_uses = 0;
+            stats.phase_finds = 0;
+        }
+
+        let best = self.stats.iter().map(|s| s.rate).fold(0.0, f64::max);
+        let adaptive: Vec<u64> = self
+            .stats
+            .iter()
+            .zip(&self.weights)
+            .map(|(stats, &weight)| match (weight, best > 0.0) {
+                (0, _) => 0,
+                (_, true) => ((stats.rate / best * ADAPTIVE_SCALE) as u64).max(MIN_ADAPTIVE_WEIGHT),
+                (_, false) => weight,
+            })
+            .collect();
+
+        self.adaptive_weights = Some(adaptive);
+        self.exploiting = !self.exploiting;
+        self.phase_cases = 0;
+    }
+
+    /// Returns the stack power for the current entry, adjusted to how fast it
+    /// runs compared to the average
+    fn stack_pow(&mut self, state: &S) -> Result<u64, Error>
+    where
+        S: HasCorpus<I>,
+    {
+        let idx = match (self.speed_pow, *state.corpus().current()) {
+            (0, _) | (_, None) => return Ok(self.max_stack_pow),
+            (_, Some(idx)) => idx,
+        };
+        let exec_time = match *state.corpus().get(idx)?.borrow().exec_time() {
+            Some(exec_time) if !exec_time.is_zero() => exec_time,
+            _ => return Ok(self.max_stack_pow),
+        };
+
+        let mean = match self.mean_exec_time {
+            Some(mean) => (mean * 7 + exec_time) / 8,
+            None => exec_time,
+        };
+        self.mean_exec_time = Some(mean);
+
+        // One power of two per doubling of the speed
+        let ratio = mean.as_secs_f64() / exec_time.as_secs_f64();
+        let range = self.speed_pow as i64;
+        let adjustment = (ratio.log2().round() as i64).clamp(-range, range);
+
+        Ok((self.max_stack_pow as i64 + adjustment).clamp(1, 63) as u64)
+    }
+
+    /// Writes the operator counters, one `name uses finds rate` line each
+    fn write_stats(&self) -> Result<(), Error> {
+        let path = match &self.stats_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut content = String::new();
+        for (index, stats) in self.stats.iter().enumerate() {
+            let rate = match stats.uses {
+                0 => 0.0,
+                uses => stats.finds as f64 * 100.0 / uses as f64,
+            };
+            let weight = match &self.adaptive_weights {
+                Some(weights) => weights[index],
+                None => self.weights[index],
+            };
+            content.push_str(&format!(
+                "{:<28} weight {:>4} uses {:>12} finds {:>6} rate {:.4}%\n",
+                self.mutations.name(index).unwrap_or("?"),
+                weight,
+                stats.uses,
+                stats.finds,
+                rate
+            ));
+        }
+
+        fs::write(path, content)?;
+        Ok(())
+    }
+}
+
+impl<I, MT, S> Mutator<I, S> for WeightedScheduledMutator<I, MT, S>
+where
+    I: Input,
+    MT: MutatorsTuple<I, S> + NamedTuple,
+    S: HasRand + HasCorpus<I>,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut I,
+        stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        if let Some(reached) = self.plateau.as_ref().map(|p| p.get()) {
+            if reached != self.on_plateau {
+                self.set_plateau_reached(reached);
+            }
+        }
+
+        let mut result = MutationResult::Skipped;
+        let stack_pow = self.stack_pow(state)?;
+        let stack = 1u64 << (1 + state.rand_mut().below(stack_pow));
+
+        self.mutation_log.clear();
+        for _ in 0..stack {
+            let index = self.schedule(state);
+            self.mutation_log.push(index);
+            self.stats[index].uses += 1;
+            self.stats[index].phase_uses += 1;
+
+            let outcome = self
+                .mutations
+                .get_and_mutate(index, state, input, stage_idx)?;
+            if outcome == MutationResult::Mutated {
+                result = MutationResult::Mutated;
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn post_exec(
+        &mut self,
+        state: &mut S,
+        _stage_idx: i32,
+        corpus_idx: Option<usize>,
+    ) -> Result<(), Error> {
+        if let Some(idx) = corpus_idx {
+            // Credit the operators and log them in the entry metadata
+            let mut log = Vec::new();
+            for &index in &self.mutation_log {
+                self.stats[index].finds += 1;
+                self.stats[index].phase_finds += 1;
+                log.push(self.mutations.name(index).unwrap_or("?").to_string());
+            }
+
+            let mut testcase = state.corpus().get(idx)?.borrow_mut();
+            testcase.add_metadata(LogMutationMetadata::new(log));
+            drop(testcase);
+
+            self.write_stats()?;
+        }
+
+        // Always reset the log for each run
+        self.mutation_log.clear();
+        self.end_case();
+        Ok(())
+    }
+}
+
+/// Mutator adapting the effective maximum input size to the sizes which
+/// produce new coverage, bounded by the maximum size of the target.
+///
+/// The limit doubles when an entry is found with a size close to it or when
+/// a whole window of fuzz cases finds nothing, and halves when the entries of
+/// a window were all much smaller than it.
+pub struct SizeAdaptiveMutator<M> {
+    /// Wrapped mutator
+    inner: M,
+    /// Effective maximum input size
+    limit: usize,
+    /// Maximum input size of the target
+    max_size: usize,
+    /// Fuzz cases run in the current window
+    window_cases: u64,
+    /// Size of the largest entry found in the current window, if any
+    window_largest: Option<usize>,
+}
+
+impl<M> SizeAdaptiveMutator<M> {
+    /// Wraps a mutator, starting with a limit fitting inputs of
+    /// `initial_size` bytes
+    pub fn new(inner: M, initial_size: usize, max_size: usize) -> Self {
+        let limit = initial_size
+            .saturating_mul(2)
+            .max(MIN_SIZE_LIMIT)
+            .min(max_size);
+
+        Self {
+            inner,
+            limit,
+            max_size,
+            window_cases: 0,
+            window_largest: None,
+        }
+    }
+
+    /// Returns the effective maximum input size
+    #[inline]
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Doubles the limit, up to the maximum size of the target
+    fn grow(&mut self) {
+        let limit = self.limit.saturating_mul(2).min(self.max_size);
+        if limit != self.limit {
+            log::debug!("Max input size grown to {}", limit);
+            self.limit = limit;
+        }
+    }
+
+    /// Ends the current window, adapting the limit to its finds
+    fn end_window(&mut self) {
+        match self.window_largest {
+            None => self.grow(),
+            Some(largest) if largest < self.limit / 4 => {
+                let limit = (self.limit / 2).max(MIN_SIZE_LIMIT).min(self.max_size);
+                if limit != self.limit {
+                    log::debug!("Max input size shrunk to {}", limit);
+                    self.limit = limit;
+                }
+            }
+            Some(_) => {}
+        }
+
+        self.window_cases = 0;
+        self.window_largest = None;
+    }
+}
+
+impl<I, M, S> Mutator<I, S> for SizeAdaptiveMutator<M>
+where
+    I: Input + HasLen,
+    M: Mutator<I, S>,
+    S: HasCorpus<I> + HasMaxSize,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut I,
+        stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        state.set_max_size(self.limit);
+        self.inner.mutate(state, input, stage_idx)
+    }
+
+    fn post_exec(
+        &mut self,
+        state: &mut S,
+        stage_idx: i32,
+        corpus_idx: Option<usize>,
+    ) -> Result<(), Error> {
+        self.inner.post_exec(state, stage_idx, corpus_idx)?;
+
+        if let Some(idx) = corpus_idx {
+            let size = state.corpus().get(idx)?.borrow_mut().load_input()?.len();
+            self.window_largest = Some(self.window_largest.map_or(size, |l| l.max(size)));
+
+            // Sizes close to the limit pay off, allow larger ones
+            if size >= self.limit / 2 {
+                self.grow();
+            }
+        }
+
+        self.window_cases += 1;
+        if self.window_cases == SIZE_WINDOW {
+            self.end_window();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+//! Detection of the coverage plateaus of a campaign
+
+use crate::report::coverage;
+
+use libafl::{
+    bolts::format_duration_hms,
+    monitors::{ClientStats, Monitor},
+};
+use nix::sys::signal::{raise, Signal};
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Exit code of a session stopped on a coverage plateau
+pub const PLATEAU_EXIT_CODE: i32 = 3;
+
+/// Set in the broker once the session is stopped on a plateau
+static PLATEAU_REACHED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the session was stopped on a plateau
+pub fn plateau_reached() -> bool {
+    PLATEAU_REACHED.load(Ordering::Acquire)
+}
+
+/// Actions taken when the coverage reaches a plateau
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PlateauActions {
+    /// Log the plateau of the campaign
+    pub log: bool,
+    /// Toggle the adaptive mutation weights of the clients
+    pub mutations: bool,
+    /// Run the deterministic stage on the corpus entries
+    pub deterministic: bool,
+    /// Stop the session with `PLATEAU_EXIT_CODE`
+    pub exit: bool,
+}
+
+impl FromStr for PlateauActions {
+    type Err = String;
+
+    /// Parses a `log,mutations,deterministic,exit` list
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut actions = PlateauActions::default();
+
+        for action in s.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            match action {
+                "log" => actions.log = true,
+                "mutations" => actions.mutations = true,
+                "deterministic" => actions.deterministic = true,
+                "exit" => actions.exit = true,
+                _ => return Err(format!("Unknown plateau action {}", action)),
+            }
+        }
+
+        Ok(actions)
+    }
+}
+
+/// Change of the plateau state of a coverage
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PlateauEvent {
+    /// The coverage did not grow for the plateau duration
+    Reached,
+    /// The coverage grew again after a plateau
+    Left,
+}
+
+/// Tracks the growth of a coverage, reporting when it stalls for longer
+/// than the plateau duration and when it grows again
+#[derive(Debug, Clone)]
+pub struct PlateauDetector {
+    /// Duration without growth making a plateau
+    duration: Duration,
+    /// Largest coverage seen
+    coverage: u64,
+    /// Time the coverage last grew
+    last_growth: Instant,
+    /// Whether the coverage is on a plateau
+    reached: bool,
+}
+
+impl PlateauDetector {
+    /// Creates a detector of the plateaus lasting `duration`
+    pub fn new(duration: Duration) -> Self {
+        PlateauDetector {
+            duration,
+            coverage: 0,
+            last_growth: Instant::now(),
+            reached: false,
+        }
+    }
+
+    /// Records the current coverage, returning the change of plateau state
+    /// it causes if any
+    pub fn update(&mut self, coverage: u64) -> Option<PlateauEvent> {
+        if coverage > self.coverage {
+            self.coverage = coverage;
+            self.last_growth = Instant::now();
+            if self.reached {
+                self.reached = false;
+                return Some(PlateauEvent::Left);
+            }
+        } else if !self.reached && self.last_growth.elapsed() >= self.duration {
+            self.reached = true;
+            return Some(PlateauEvent::Reached);
+        }
+
+        None
+    }
+
+    /// Returns the time since the coverage last grew
+    pub fn stalled(&self) -> Duration {
+        self.last_growth.elapsed()
+    }
+}
+
+/// Monitor watching the coverage of the whole campaign, as reported by the
+/// clients, before handing the events to the wrapped monitor. On a plateau,
+/// it logs it and stops the session if configured to.
+#[derive(Clone)]
+pub struct PlateauMonitor<M: Monitor> {
+    /// Wrapped monitor
+    inner: M,
+    /// Detector of the plateaus, disabled if not set
+    detector: Option<PlateauDetector>,
+    /// Actions taken on a plateau
+    actions: PlateauActions,
+}
+
+impl<M: Monitor> PlateauMonitor<M> {
+    /// Wraps a monitor, detecting the plateaus lasting `duration` if set
+    pub fn new(inner: M, duration: Option<Duration>, actions: PlateauActions) -> Self {
+        PlateauMonitor {
+            inner,
+            detector: duration.map(PlateauDetector::new),
+            actions,
+        }
+    }
+}
+
+impl<M: Monitor> Monitor for PlateauMonitor<M> {
+    fn client_stats_mut(&mut self) -> &mut Vec<ClientStats> {
+        self.inner.client_stats_mut()
+    }
+
+    fn client_stats(&self) -> &[ClientStats] {
+        self.inner.client_stats()
+    }
+
+    fn start_time(&mut self) -> Duration {
+        self.inner.start_time()
+    }
+
+    fn display(&mut self, event_msg: String, sender_id: u32) {
+        self.inner.display(event_msg, sender_id);
+
+        let coverage = coverage(self.inner.client_stats());
+        let detector = match &mut self.detector {
+            Some(detector) => detector,
+            None => return,
+        };
+
+        match detector.update(coverage) {
+            Some(PlateauEvent::Reached) => {
+                if self.actions.log || self.actions.exit {
+                    log::warn!(
+                        "==== Coverage plateau: no new coverage for {} (coverage {}) ====",
+                        format_duration_hms(&detector.stalled()),
+                        coverage
+                    );
+                }
+
+                // The broker stops on SIGTERM, stopping the clients with it
+                if self.actions.exit && !plateau_reached() {
+                    log::info!("Stopping the session on the plateau");
+                    PLATEAU_REACHED.store(true, Ordering::Release);
+                    let _ = raise(Signal::SIGTERM);
+                }
+            }
+            Some(PlateauEvent::Left) if self.actions.log => {
+                log::info!(
+                    "Coverage grew again after a plateau (coverage {})",
+                    coverage
+                );
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Campaign report printed by the broker on the events of the clients
+
+use libafl::{
+    bolts::{current_time, format_duration_hms},
+    monitors::{ClientStats, Monitor, UserStats},
+};
+use serde_json::json;
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Minimum interval between two reports in quiet mode, besides the reports
+/// of new crashes
+const QUIET_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Format of the report lines
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Human readable `key value` pairs
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Returns a number user statistic of a client
+fn user_number(client: &ClientStats, name: &str) -> Option<u64> {
+    match client.user_monitor.get(name) {
+        Some(UserStats::Number(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+/// Returns the coverage of all the targets reported by the clients. The
+/// clients of a target share its coverage map, the largest coverage they
+/// reported is kept.
+pub(crate) fn coverage(clients: &[ClientStats]) -> u64 {
+    let mut targets: BTreeMap<&str, u64> = BTreeMap::new();
+
+    for client in clients {
+        let target = match client.user_monitor.get("target") {
+            Some(UserStats::String(name)) => name.as_str(),
+            _ => "",
+        };
+        if let Some(coverage) = user_number(client, "coverage") {
+            let entry = targets.entry(target).or_default();
+            *entry = (*entry).max(coverage);
+        }
+    }
+
+    targets.values().sum()
+}
+
+/// Monitor printing a line per event with the campaign totals: corpus,
+/// coverage, crashes, timeouts, age of the last new entry and time spent per
+/// execution in each phase (vm reset, input write and run), averaged over the
+/// clients.
+#[derive(Clone, Debug)]
+pub struct ReportMonitor {
+    /// Statistics of the clients
+    client_stats: Vec<ClientStats>,
+    /// Start of the session
+    start_time: Duration,
+    /// Format of the lines
+    format: ReportFormat,
+    /// Whether only the new crashes and a periodic summary are reported
+    quiet: bool,
+    /// Corpus size at the last event
+    corpus_size: u64,
+    /// Time the corpus last grew
+    last_find: Duration,
+    /// Number of crashes at the last report
+    reported_crashes: u64,
+    /// Time of the last report
+    reported_time: Duration,
+}
+
+impl ReportMonitor {
+    /// Creates a monitor printing its reports in `format`
+    pub fn new(format: ReportFormat, quiet: bool) -> Self {
+        let now = current_time();
+
+        ReportMonitor {
+            client_stats: Vec::new(),
+            start_time: now,
+            format,
+            quiet,
+            corpus_size: 0,
+            last_find: now,
+            reported_crashes: 0,
+            reported_time: Duration::ZERO,
+        }
+    }
+
+    /// Returns the sum of a number user statistic over the clients
+    fn total(&self, name: &str) -> u64 {
+        self.client_stats
+            .iter()
+            .filter_map(|client| user_number(client, name))
+            .sum()
+    }
+
+    /// Returns the average of a number user statistic over the clients
+    /// reporting it
+    fn average(&self, name: &str) -> u64 {
+        let values: Vec<u64> = self
+            .client_stats
+            .iter()
+            .filter_map(|client| user_number(client, name))
+            .collect();
+
+        match values.len() {
+            0 => 0,
+            count => values.iter().sum::<u64>() / count as u64,
+        }
+    }
+}
+
+impl Monitor for ReportMonitor {
+    fn client_stats_mut(&mut self) -> &mut Vec<ClientStats> {
+        &mut self.client_stats
+    }
+
+    fn client_stats(&self) -> &[ClientStats] {
+        &self.client_stats
+    }
+
+    fn start_time(&mut self) -> Duration {
+        self.start_time
+    }
+
+    fn display(&mut self, event_msg: String, sender_id: u32) {
+        let now = current_time();
+        let corpus = self.corpus_size();
+        if corpus > self.corpus_size {
+            self.last_find = now;
+        }
+        self.corpus_size = corpus;
+
+        let crashes = self.objective_size();
+        let new_crashes = crashes > self.reported_crashes;
+        if self.quiet && !new_crashes && now - self.reported_time < QUIET_INTERVAL {
+            return;
+        }
+        self.reported_crashes = crashes;
+        self.reported_time = now;
+
+        let run_time = now - self.start_time;
+        let last_find = now - self.last_find;
+        let line = match self.format {
+            ReportFormat::Text => format!(
+                "[{} #{}] run time: {}, clients: {}, corpus: {}, coverage: {}, crashes: {}, \
+                 timeouts: {}, last find: {}s ago, executions: {}, exec/sec: {}, \
+                 reset: {}us, write: {}us, run: {}us",
+                event_msg,
+                sender_id,
+                format_duration_hms(&run_time),
+                self.client_stats.len(),
+                corpus,
+                coverage(&self.client_stats),
+                crashes,
+                self.total("timeouts"),
+                last_find.as_secs(),
+                self.total_execs(),
+                self.execs_per_sec(),
+                self.average("reset us"),
+                self.average("write us"),
+                self.average("run us"),
+            ),
+            ReportFormat::Json => json!({
+                "event": event_msg,
+                "client": sender_id,
+                "run_time": run_time.as_secs(),
+                "clients": self.client_stats.len(),
+                "corpus": corpus,
+                "coverage": coverage(&self.client_stats),
+                "crashes": crashes,
+                "timeouts": self.total("timeouts"),
+                "last_find": last_find.as_secs(),
+                "executions": self.total_execs(),
+                "exec_per_sec": self.execs_per_sec(),
+                "reset_us": self.average("reset us"),
+                "write_us": self.average("write us"),
+                "run_us": self.average("run us"),
+            })
+            .to_string(),
+        };
+
+        println!("{}", line);
+    }
+}
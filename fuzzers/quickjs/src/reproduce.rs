@@ -0,0 +1,80 @@
+use crate::executor::install_alarm_handler;
+use crate::fuzz::FuzzerConfig;
+use crate::runner::{Runner, Verdict};
+use crate::sysemu::describe_syscall;
+use crate::targets::selected_target;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use tartiflette_vm::{write_access_trace, AccessKind, MemoryAccess, Symbolizer};
+
+/// Replays an input once under the vm and prints its triage report
+pub fn reproduce<P: AsRef<Path>>(config: FuzzerConfig, path: P) {
+    let input = std::fs::read(path).expect("Could not read input file");
+
+    install_alarm_handler();
+    let mut runner = Runner::new(
+        &selected_target(&config),
+        config.dictionary,
+        config.timeout,
+        false,
+    );
+    if config.access_trace.is_some() {
+        runner.set_input_access_log();
+    }
+
+    match runner.run(&input).0 {
+        Verdict::Ok => println!("No crash"),
+        Verdict::Crash(_, report) => print!("{}", report),
+        Verdict::Timeout => println!("Timeout after {:?}", config.timeout),
+        Verdict::Denied(syscall) => println!("Denied syscall {}", describe_syscall(syscall)),
+    }
+
+    if let Some(path) = config.access_trace {
+        let accesses = runner.take_input_accesses();
+        let file = File::create(path).expect("Could not create access trace");
+        write_access_trace(BufWriter::new(file), &accesses).expect("Could not write access trace");
+
+        print_access_sites(&runner, &accesses);
+        println!("Access trace written to {}", path);
+    }
+}
+
+/// Prints the instructions which touched the input area, with their number
+/// of reads and writes and the first offset they accessed in the area
+fn print_access_sites(runner: &Runner, accesses: &[MemoryAccess]) {
+    let input_start = match runner.input_start() {
+        Some(address) => address,
+        None => return,
+    };
+
+    let mut sites: BTreeMap<u64, (usize, usize, u64)> = BTreeMap::new();
+    for access in accesses {
+        let offset = access.address - input_start;
+        let (reads, writes, first) = sites.entry(access.pc).or_insert((0, 0, offset));
+        match access.kind {
+            AccessKind::Read => *reads += 1,
+            AccessKind::Write => *writes += 1,
+        }
+        *first = (*first).min(offset);
+    }
+
+    println!(
+        "Input accessed {} times by {} instructions:",
+        accesses.len(),
+        sites.len()
+    );
+    let mut symbolizer = Symbolizer::new(runner.modules());
+    for (pc, (reads, writes, first)) in sites {
+        println!(
+            "    {}: {} reads, {} writes, from offset 0x{:x}",
+            symbolizer.symbolize(pc),
+            reads,
+            writes,
+            first
+        );
+    }
+}
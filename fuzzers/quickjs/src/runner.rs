@@ -0,0 +1,531 @@
+use crate::executor::{record_recent, set_alarm, COVERAGE_TAIL};
+use crate::fuzz::{
+    load_breakpoints, load_tokens, load_vm, token_starts, write_input, TokenCache, MMAP_END,
+    MMAP_START,
+};
+use crate::shutdown::terminating;
+use crate::sysemu::{output_section, SysEmu, SyscallOutcome};
+use crate::targets::Target;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use tartiflette_vm::{
+    CmpOperands, Crash, InputDelivery, MemoryAccess, Register, SnapshotInfo, SnapshotModule, Vm,
+    VmError, VmExit,
+};
+
+const INT3: u8 = 0xCC;
+
+/// Functions comparing memory whose arguments are logged, by name, with
+/// whether they stop at a null byte and whether they take a length
+const COMPARISON_FUNCTIONS: [(&str, bool, bool); 5] = [
+    ("memcmp", false, true),
+    ("bcmp", false, true),
+    ("strcmp", true, false),
+    ("strncmp", true, true),
+    ("strcasecmp", true, false),
+];
+
+/// Maximum number of bytes logged for each side of a memory comparison
+const MAX_COMPARED_BYTES: usize = 32;
+
+/// Number of input reads kept before each coverage point hit for the first
+/// time, the most recent ones
+const FRONTIER_READS: usize = 16;
+
+/// Outcome of an execution
+pub enum Verdict {
+    /// The guest exited normally
+    Ok,
+    /// The guest crashed, with the triage report of the crash
+    Crash(Crash, String),
+    /// The execution timed out
+    Timeout,
+    /// The guest used a syscall denied by the policy of the target
+    Denied(u64),
+}
+
+/// Comparison run by the guest, logged at a comparison site
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoggedComparison {
+    /// `cmp` instruction and its operand values
+    Integer(CmpOperands),
+    /// Call to a memory or string comparison function, with the leading
+    /// bytes of its two buffers
+    Bytes(Vec<u8>, Vec<u8>),
+}
+
+/// Comparison site instrumented by a breakpoint
+#[derive(Debug, Copy, Clone)]
+struct ComparisonSite {
+    /// Original byte of the instruction
+    orig_byte: u8,
+    /// Memory comparison function at the site, with whether it stops at a
+    /// null byte and whether it takes a length, `None` for a `cmp`
+    /// instruction
+    function: Option<(bool, bool)>,
+}
+
+/// Standalone executor running single inputs outside of libafl
+pub struct Runner {
+    /// Snapshot information
+    snapshot_info: SnapshotInfo,
+    /// Vm used for the execution
+    exec_vm: Vm,
+    /// Vm used for reseting
+    reset_vm: Vm,
+    /// Syscall emulation layer
+    sysemu: SysEmu,
+    /// Encoded javascript tokens
+    token_cache: TokenCache,
+    /// Way the guest receives the input
+    delivery: InputDelivery,
+    /// Decoded javascript of the input being run
+    js_input: Vec<u8>,
+    /// Address of the exit call
+    exit_address: u64,
+    /// Map of coverage addresses to the corresponding original instruction byte
+    coverage: BTreeMap<u64, u8>,
+    /// Coverage addresses hit so far, in order
+    covered: Vec<u64>,
+    /// Whether coverage breakpoints stay armed to record every run's hits
+    persistent: bool,
+    /// Coverage addresses hit by the last run, with persistent coverage
+    hits: BTreeSet<u64>,
+    /// Whether the reads of the input area are recorded
+    watch_input: bool,
+    /// Input reads preceding the coverage points hit for the first time by
+    /// the last run, as offsets in the decoded javascript
+    frontier_reads: Vec<usize>,
+    /// Comparison sites logged, by address
+    comparisons: BTreeMap<u64, ComparisonSite>,
+    /// Comparisons logged by the last run, in order
+    logged: Vec<LoggedComparison>,
+    /// Execution timeout
+    timeout: Duration,
+}
+
+impl Runner {
+    /// Creates a runner from the snapshot of a target, optionally
+    /// instrumented with the coverage breakpoints
+    pub fn new(
+        target: &Target,
+        dictionary: Option<&str>,
+        timeout: Duration,
+        coverage: bool,
+    ) -> Runner {
+        // Load the snapshot info (contains mappings and symbols)
+        let snapshot_info = SnapshotInfo::from_file(target.snapshot_info())
+            .expect("Crash while parsing snapshot information");
+        let module_start = snapshot_info
+            .modules
+            .get(&target.module)
+            .expect("Could not find program module")
+            .start;
+
+        let mut reset_vm = load_vm(target);
+
+        // Stop on the exit call
+        let exit_address = module_start + target.exit_offset;
+        reset_vm
+            .write_value::<u8>(exit_address, INT3)
+            .expect("Could not install exit breakpoint");
+
+        // Install the coverage breakpoints
+        let mut breakpoints = BTreeMap::new();
+        if coverage {
+            for offset in load_breakpoints(target.breakpoints()) {
+                let address = module_start + offset;
+                let mut orig_byte = [0u8; 1];
+                reset_vm
+                    .read(address, &mut orig_byte)
+                    .expect("Could not read original byte");
+                reset_vm
+                    .write_value::<u8>(address, INT3)
+                    .expect("Could not install coverage breakpoint");
+                breakpoints.insert(address, orig_byte[0]);
+            }
+        }
+
+        Runner {
+            snapshot_info,
+            exec_vm: reset_vm.clone(),
+            reset_vm,
+            sysemu: SysEmu::new(MMAP_START, MMAP_END, target.syscalls.clone()),
+            token_cache: load_tokens(target, dictionary),
+            delivery: target.delivery(),
+            js_input: Vec::new(),
+            exit_address,
+            coverage: breakpoints,
+            covered: Vec::new(),
+            persistent: false,
+            hits: BTreeSet::new(),
+            watch_input: false,
+            frontier_reads: Vec::new(),
+            comparisons: BTreeMap::new(),
+            logged: Vec::new(),
+            timeout,
+        }
+    }
+
+    /// Logs the comparisons of every run (see `comparisons`): the `cmp`
+    /// instructions listed in the comparisons file of the target, if it has
+    /// one, and the calls to the memory comparison functions found in the
+    /// symbols of the modules. Coverage becomes persistent, the breakpoints
+    /// being stepped over. Returns the number of comparison sites.
+    pub fn set_comparison_log(&mut self, target: &Target) -> usize {
+        self.persistent = true;
+
+        // Step 1: Sites of the cmp instructions and comparison functions
+        let mut sites = Vec::new();
+        if target.comparisons().exists() {
+            let module_start = self.snapshot_info.modules[&target.module].start;
+            sites.extend(
+                load_breakpoints(target.comparisons())
+                    .into_iter()
+                    .map(|offset| (module_start + offset, None)),
+            );
+        }
+        for module in self.snapshot_info.modules.values() {
+            if let Ok(symbols) = module.load_symbols() {
+                sites.extend(
+                    COMPARISON_FUNCTIONS
+                        .iter()
+                        .filter_map(|&(name, string, sized)| {
+                            symbols
+                                .address_of(name)
+                                .map(|address| (address, Some((string, sized))))
+                        })
+                        .filter(|(address, _)| (module.start..module.end).contains(address)),
+                );
+            }
+        }
+
+        // Step 2: Breakpoints, sharing the original byte of the coverage
+        // breakpoints placed at the same address
+        for (address, function) in sites {
+            let orig_byte = match self.coverage.get(&address) {
+                Some(&orig_byte) => orig_byte,
+                None => {
+                    let mut orig_byte = [0u8; 1];
+                    if self.reset_vm.read(address, &mut orig_byte).is_err() {
+                        continue;
+                    }
+                    orig_byte[0]
+                }
+            };
+            for vm in [&mut self.reset_vm, &mut self.exec_vm] {
+                vm.write_value::<u8>(address, INT3)
+                    .expect("Could not install comparison breakpoint");
+            }
+            self.comparisons.insert(
+                address,
+                ComparisonSite {
+                    orig_byte,
+                    function,
+                },
+            );
+        }
+
+        self.comparisons.len()
+    }
+
+    /// Reads up to `MAX_COMPARED_BYTES` bytes at `address`, stopping after a
+    /// null byte for a string
+    fn read_compared(&self, address: u64, len: usize, string: bool) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(len);
+        let mut byte = [0u8; 1];
+        while bytes.len() < len
+            && self
+                .exec_vm
+                .read(address + bytes.len() as u64, &mut byte)
+                .is_ok()
+        {
+            bytes.push(byte[0]);
+            if string && byte[0] == 0 {
+                break;
+            }
+        }
+        bytes
+    }
+
+    /// Logs the comparison about to run at the comparison site `rip`
+    fn log_comparison(&mut self, rip: u64, site: ComparisonSite) {
+        let comparison = match site.function {
+            None => self
+                .exec_vm
+                .comparison_operands(rip, Some(site.orig_byte))
+                .map(LoggedComparison::Integer),
+            Some((string, sized)) => {
+                let len = match sized {
+                    true => (self.exec_vm.get_reg(Register::Rdx) as usize).min(MAX_COMPARED_BYTES),
+                    false => MAX_COMPARED_BYTES,
+                };
+                let left = self.read_compared(self.exec_vm.get_reg(Register::Rdi), len, string);
+                let right = self.read_compared(self.exec_vm.get_reg(Register::Rsi), len, string);
+                Some(LoggedComparison::Bytes(left, right))
+            }
+        };
+
+        if let Some(comparison) = comparison {
+            self.logged.push(comparison);
+        }
+    }
+
+    /// Keeps the coverage breakpoints armed, stepping over them, so that
+    /// every run records the coverage points it hits (see `hits`)
+    pub fn set_persistent_coverage(&mut self) {
+        self.persistent = true;
+    }
+
+    /// Returns the address of the decoded javascript in the guest, none if
+    /// the guest places it itself (hypercall delivery)
+    pub fn input_start(&self) -> Option<u64> {
+        self.delivery.buffer().map(|(address, _)| address)
+    }
+
+    /// Records the reads of the input area, to find the parts of the inputs
+    /// consumed before new coverage (see `frontier_tokens`). Not supported
+    /// by the hypercall delivery.
+    pub fn set_input_watch(&mut self) {
+        match self.delivery.buffer() {
+            Some((address, capacity)) => {
+                self.exec_vm
+                    .watch_reads(address, capacity)
+                    .expect("Could not watch the input area");
+                self.watch_input = true;
+            }
+            None => log::warn!("Input reads are not watched with the hypercall delivery"),
+        }
+    }
+
+    /// Logs the reads and writes of the input area, with the instructions
+    /// making them (see `take_input_accesses`). Not supported by the
+    /// hypercall delivery.
+    pub fn set_input_access_log(&mut self) {
+        match self.delivery.buffer() {
+            Some((address, capacity)) => self
+                .exec_vm
+                .log_accesses(&[address..address + capacity as u64])
+                .expect("Could not log the input area accesses"),
+            None => log::warn!("Input accesses are not logged with the hypercall delivery"),
+        }
+    }
+
+    /// Returns the accesses to the input area of the runs since the last
+    /// call, with the input access log
+    pub fn take_input_accesses(&mut self) -> Vec<MemoryAccess> {
+        self.exec_vm.take_accesses()
+    }
+
+    /// Keeps the last input reads before a coverage point hit for the first
+    /// time
+    fn record_frontier(&mut self) {
+        if !self.watch_input {
+            return;
+        }
+
+        let input_start = match self.input_start() {
+            Some(address) => address,
+            None => return,
+        };
+        let reads = self.exec_vm.take_watched_reads();
+        let start = reads.len().saturating_sub(FRONTIER_READS);
+        self.frontier_reads.extend(
+            reads[start..]
+                .iter()
+                .map(|&read| (read - input_start) as usize),
+        );
+    }
+
+    /// Runs an encoded input. Returns the verdict and the number of coverage
+    /// points hit for the first time.
+    pub fn run(&mut self, input: &[u8]) -> (Verdict, usize) {
+        self.sysemu.reset();
+        write_input(
+            &mut self.exec_vm,
+            &self.delivery,
+            &self.token_cache,
+            input,
+            &mut self.js_input,
+        );
+
+        let mut new_coverage = 0;
+        let mut recent_coverage = Vec::with_capacity(COVERAGE_TAIL);
+        self.hits.clear();
+        self.logged.clear();
+        self.frontier_reads.clear();
+        self.exec_vm.take_watched_reads();
+        set_alarm(self.timeout);
+        let start = Instant::now();
+
+        // Persistent coverage breakpoint being stepped over
+        let mut stepped: Option<u64> = None;
+
+        // Execution loop
+        let verdict = loop {
+            let vmexit = match self.exec_vm.run() {
+                Ok(vmexit) => vmexit,
+                // The guest can not run anymore, e.g. after a triple fault
+                Err(VmError::GuestFailure(failure)) => {
+                    let modules = &self.snapshot_info.modules;
+                    let crash = Crash::from_failure(&self.exec_vm, &failure, modules);
+                    let mut report =
+                        crash.report(&self.exec_vm, &self.reset_vm, modules, &recent_coverage);
+                    report += &format!("guest failure:\n{}\n", failure);
+                    report.push_str(&output_section(self.sysemu.output()));
+                    break Verdict::Crash(crash, report);
+                }
+                Err(err) => panic!("Unexpected vm error: {:?}", err),
+            };
+            let rip = self.exec_vm.get_reg(Register::Rip);
+
+            // Re-arm the breakpoint stepped over and disable the trap flag
+            if let Some(address) = stepped.take() {
+                self.exec_vm
+                    .write_value::<u8>(address, INT3)
+                    .expect("Error while restoring coverage");
+                let rflags = self.exec_vm.get_reg(Register::Rflags);
+                self.exec_vm.set_reg(Register::Rflags, rflags & !(1 << 8));
+
+                // The step is reported as a debug exception or a breakpoint
+                let armed = rip == self.exit_address
+                    || self.coverage.contains_key(&rip)
+                    || self.comparisons.contains_key(&rip);
+                if vmexit == VmExit::Exception(1) || (vmexit == VmExit::Breakpoint && !armed) {
+                    continue;
+                }
+            }
+
+            // Log the comparison sites, stepped over unless they are also
+            // coverage points
+            if vmexit == VmExit::Breakpoint {
+                if let Some(&site) = self.comparisons.get(&rip) {
+                    self.log_comparison(rip, site);
+                    if !self.coverage.contains_key(&rip) {
+                        self.exec_vm
+                            .write_value::<u8>(rip, site.orig_byte)
+                            .expect("Error while stepping over comparison");
+                        let rflags = self.exec_vm.get_reg(Register::Rflags);
+                        self.exec_vm.set_reg(Register::Rflags, rflags | (1 << 8));
+                        stepped = Some(rip);
+                        continue;
+                    }
+                }
+            }
+
+            match vmexit {
+                // A shutdown request lets the run finish
+                VmExit::Interrupted if terminating() && start.elapsed() < self.timeout => {}
+                VmExit::Interrupted => break Verdict::Timeout,
+                VmExit::Syscall
+                    if self
+                        .delivery
+                        .handle_syscall(&mut self.exec_vm, &self.js_input)
+                        .expect("Could not write fuzz case to vm memory") => {}
+                VmExit::Syscall => match self.sysemu.syscall(&mut self.exec_vm) {
+                    SyscallOutcome::Continue => {}
+                    SyscallOutcome::Exit => break Verdict::Ok,
+                    SyscallOutcome::Denied(syscall) => break Verdict::Denied(syscall),
+                },
+                VmExit::Breakpoint if rip == self.exit_address => break Verdict::Ok,
+                VmExit::Breakpoint if self.persistent && self.coverage.contains_key(&rip) => {
+                    // Execute the original instruction in singlestep, the
+                    // breakpoint is restored afterwards
+                    self.exec_vm
+                        .write_value::<u8>(rip, self.coverage[&rip])
+                        .expect("Error while stepping over coverage");
+                    let rflags = self.exec_vm.get_reg(Register::Rflags);
+                    self.exec_vm.set_reg(Register::Rflags, rflags | (1 << 8));
+                    stepped = Some(rip);
+
+                    if self.hits.insert(rip) {
+                        record_recent(&mut recent_coverage, rip);
+                        if !self.covered.contains(&rip) {
+                            new_coverage += 1;
+                            self.covered.push(rip);
+                            self.record_frontier();
+                        }
+                    }
+                }
+                VmExit::Breakpoint if self.coverage.contains_key(&rip) => {
+                    // Coverage is one-shot, restore the original byte
+                    let orig_byte = self.coverage.remove(&rip).unwrap();
+                    self.exec_vm
+                        .write_value::<u8>(rip, orig_byte)
+                        .expect("Error while removing exec_vm coverage");
+                    self.reset_vm
+                        .write_value::<u8>(rip, orig_byte)
+                        .expect("Error while removing reset_vm coverage");
+                    new_coverage += 1;
+                    self.covered.push(rip);
+                    self.record_frontier();
+                    record_recent(&mut recent_coverage, rip);
+                }
+                _ => {
+                    let modules = &self.snapshot_info.modules;
+                    match Crash::from_exit(&self.exec_vm, &vmexit, modules) {
+                        Some(crash) => {
+                            let mut report = crash.report(
+                                &self.exec_vm,
+                                &self.reset_vm,
+                                modules,
+                                &recent_coverage,
+                            );
+                            report.push_str(&output_section(self.sysemu.output()));
+                            break Verdict::Crash(crash, report);
+                        }
+                        None => panic!("Unexpected vm exit {:?}", vmexit),
+                    }
+                }
+            }
+        };
+
+        set_alarm(Duration::ZERO);
+        self.exec_vm.reset(&self.reset_vm);
+
+        (verdict, new_coverage)
+    }
+
+    /// Coverage addresses hit by the last run, with persistent coverage
+    pub fn hits(&self) -> &BTreeSet<u64> {
+        &self.hits
+    }
+
+    /// Comparisons logged by the last run, with the comparison log
+    pub fn comparisons(&self) -> &[LoggedComparison] {
+        &self.logged
+    }
+
+    /// Returns the offsets in an encoded input of the tokens read before the
+    /// coverage points the last run of this input hit for the first time,
+    /// with the input area watched
+    pub fn frontier_tokens(&self, input: &[u8]) -> Vec<usize> {
+        let starts = token_starts(&self.token_cache, input);
+        let mut tokens: Vec<usize> = self
+            .frontier_reads
+            .iter()
+            .filter_map(
+                |&read| match starts.partition_point(|&start| start <= read) {
+                    0 => None,
+                    idx => Some(2 * (idx - 1)),
+                },
+            )
+            .collect();
+
+        tokens.sort_unstable();
+        tokens.dedup();
+        tokens
+    }
+
+    /// Coverage addresses hit by all the runs so far
+    pub fn covered(&self) -> &[u64] {
+        &self.covered
+    }
+
+    /// Modules of the snapshot
+    pub fn modules(&self) -> &BTreeMap<String, SnapshotModule> {
+        &self.snapshot_info.modules
+    }
+}
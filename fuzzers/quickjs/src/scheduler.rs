@@ -0,0 +1,596 @@
+//! Weighted random corpus scheduling, with selectable power schedules
+
+use crate::shared_map::bucket;
+
+use libafl::{
+    bolts::{rands::Rand, tuples::Named, AsSlice, HasLen},
+    corpus::{Corpus, Testcase},
+    events::EventFirer,
+    executors::ExitKind,
+    feedbacks::Feedback,
+    inputs::Input,
+    observers::{ObserversTuple, StdMapObserver},
+    schedulers::Scheduler,
+    state::{HasClientPerfMonitor, HasCorpus, HasMetadata, HasRand},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hasher;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+/// Weight of an entry as fast and as large as the average
+const BASE_WEIGHT: f64 = 100.0;
+
+/// Maximum power of two of the number of picks of an entry in the fast
+/// schedule
+const MAX_FAST_POW: u64 = 16;
+
+/// Maximum factor of the fast schedule over the speed weight of an entry
+const MAX_FAST_FACTOR: f64 = 16.0;
+
+/// Energy assigned to the corpus entries
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PowerSchedule {
+    /// Weight favouring the entries faster and smaller than the average
+    Speed,
+    /// Every entry in turn, in the corpus order
+    RoundRobin,
+    /// Speed weight scaled by the rarity of the coverage map entries hit by
+    /// the entry, each counting for one over the number of corpus entries
+    /// hitting it
+    Rare,
+    /// AFLFast exponential schedule: speed weight scaled by two to the power
+    /// of the number of picks of the entry, over the number of runs hitting
+    /// the same path
+    Fast,
+}
+
+impl PowerSchedule {
+    /// Returns whether the schedule needs the path and coverage map entries
+    /// of the corpus entries, which only match across runs with repeatable
+    /// coverage
+    pub fn tracks_paths(&self) -> bool {
+        matches!(self, PowerSchedule::Rare | PowerSchedule::Fast)
+    }
+
+    /// Returns the weight of an entry
+    fn weight(&self, entry: &EntryEnergy, stats: &PathStats) -> u64 {
+        let speed = entry.speed as f64;
+        let weight = match self {
+            PowerSchedule::Speed => speed,
+            PowerSchedule::RoundRobin => 1.0,
+            PowerSchedule::Rare => {
+                let rarity: f64 = entry
+                    .points
+                    .iter()
+                    .map(|point| {
+                        1.0 / stats.point_entries.get(point).map_or(1, |&n| n.max(1)) as f64
+                    })
+                    .sum();
+                speed * rarity
+            }
+            PowerSchedule::Fast => {
+                let runs = stats.runs.get(&entry.path).copied().unwrap_or(1).max(1);
+                let factor = (1u64 << entry.scheduled.min(MAX_FAST_POW)) as f64 / runs as f64;
+                speed * factor.min(MAX_FAST_FACTOR)
+            }
+        };
+
+        (weight as u64).max(1)
+    }
+}
+
+impl FromStr for PowerSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "speed" => Ok(PowerSchedule::Speed),
+            "round-robin" => Ok(PowerSchedule::RoundRobin),
+            "rare" => Ok(PowerSchedule::Rare),
+            "fast" => Ok(PowerSchedule::Fast),
+            _ => Err(format!("unknown power schedule {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PowerSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerSchedule::Speed => write!(f, "speed"),
+            PowerSchedule::RoundRobin => write!(f, "round-robin"),
+            PowerSchedule::Rare => write!(f, "rare"),
+            PowerSchedule::Fast => write!(f, "fast"),
+        }
+    }
+}
+
+/// Energy of a corpus entry, saved with its metadata
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EntryEnergy {
+    /// Weight of the entry from its speed and size
+    pub speed: u64,
+    /// Number of times the entry was picked
+    pub scheduled: u64,
+    /// Hash of the hit count buckets of the run adding the entry
+    pub path: u64,
+    /// Coverage map entries hit by the run adding the entry
+    pub points: Vec<usize>,
+}
+
+libafl::impl_serdeany!(EntryEnergy);
+
+/// Paths and coverage map entries of the corpus entries
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PathStats {
+    /// Number of runs hitting the path of each corpus entry
+    runs: HashMap<u64, u64>,
+    /// Number of corpus entries hitting each coverage map entry
+    point_entries: HashMap<usize, u64>,
+}
+
+libafl::impl_serdeany!(PathStats);
+
+/// Returns the path stats of the state, created on first use
+fn path_stats_mut<S: HasMetadata>(state: &mut S) -> &mut PathStats {
+    if !state.has_metadata::<PathStats>() {
+        state.add_metadata(PathStats::default());
+    }
+    state.metadata_mut().get_mut::<PathStats>().unwrap()
+}
+
+/// Feedback recording the path of every run and the path and coverage map
+/// entries of the new corpus entries, for the rare and fast schedules. It
+/// never finds an input interesting by itself. Disabled for the other
+/// schedules.
+pub struct PathFeedback {
+    /// Name of the observed coverage map
+    observer_name: String,
+    /// Whether the paths are recorded
+    enabled: bool,
+    /// Path and coverage map entries of the last run
+    last: Option<(u64, Vec<usize>)>,
+}
+
+impl PathFeedback {
+    /// Creates a feedback hashing the map of `observer`, for `schedule`
+    pub fn new(observer: &StdMapObserver<u8>, schedule: PowerSchedule) -> Self {
+        PathFeedback {
+            observer_name: observer.name().to_string(),
+            enabled: schedule.tracks_paths(),
+            last: None,
+        }
+    }
+}
+
+impl<I, S> Feedback<I, S> for PathFeedback
+where
+    I: Input,
+    S: HasClientPerfMonitor + HasMetadata,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &I,
+        observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<I>,
+        OT: ObserversTuple<I, S>,
+    {
+        if !self.enabled {
+            return Ok(false);
+        }
+
+        let observer = observers
+            .match_name::<StdMapObserver<u8>>(&self.observer_name)
+            .expect("PathFeedback expects a StdMapObserver<u8>");
+
+        // The path is the hash of the hit count buckets of the map
+        let mut hash = DefaultHasher::new();
+        let mut points = self.last.take().map_or_else(Vec::new, |(_, points)| points);
+        points.clear();
+        for (point, &count) in observer.as_slice().iter().enumerate() {
+            if count != 0 {
+                hash.write_usize(point);
+                hash.write_u8(bucket(count));
+                points.push(point);
+            }
+        }
+        let path = hash.finish();
+
+        if let Some(runs) = path_stats_mut(state).runs.get_mut(&path) {
+            *runs += 1;
+        }
+        self.last = Some((path, points));
+
+        Ok(false)
+    }
+
+    fn append_metadata(&mut self, _state: &mut S, testcase: &mut Testcase<I>) -> Result<(), Error> {
+        if let Some((path, points)) = self.last.take() {
+            testcase.add_metadata(EntryEnergy {
+                path,
+                points,
+                ..EntryEnergy::default()
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Named for PathFeedback {
+    fn name(&self) -> &str {
+        "PathFeedback"
+    }
+}
+
+impl fmt::Debug for PathFeedback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PathFeedback")
+            .field("observer_name", &self.observer_name)
+            .field("enabled", &self.enabled)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Fenwick tree over the weights of the corpus entries, picking an entry
+/// with a probability proportional to its weight in `O(log n)`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CorpusWeights {
+    /// Weight of each entry
+    weights: Vec<u64>,
+    /// Partial sums, `tree[i - 1]` holds the sum of the weights in
+    /// `(i - lowbit(i), i]`
+    tree: Vec<u64>,
+    /// Sum of the execution times of the entries, in microseconds
+    total_exec_us: u64,
+    /// Sum of the sizes of the entries
+    total_len: u64,
+}
+
+libafl::impl_serdeany!(CorpusWeights);
+
+/// Lowest set bit of `i`
+#[inline]
+fn lowbit(i: usize) -> usize {
+    i & i.wrapping_neg()
+}
+
+impl CorpusWeights {
+    /// Returns the sum of the weights of the first `count` entries
+    fn prefix_sum(&self, mut count: usize) -> u64 {
+        let mut sum = 0;
+        while count > 0 {
+            sum += self.tree[count - 1];
+            count -= lowbit(count);
+        }
+        sum
+    }
+
+    /// Returns the sum of all the weights
+    #[inline]
+    pub fn total(&self) -> u64 {
+        self.prefix_sum(self.weights.len())
+    }
+
+    /// Appends the weight of a new entry
+    pub fn push(&mut self, weight: u64) {
+        let i = self.weights.len() + 1;
+        let node = weight + self.prefix_sum(i - 1) - self.prefix_sum(i - lowbit(i));
+
+        self.weights.push(weight);
+        self.tree.push(node);
+    }
+
+    /// Changes the weight of the entry at `idx`
+    pub fn set(&mut self, idx: usize, weight: u64) {
+        let previous = self.weights[idx];
+        self.weights[idx] = weight;
+
+        let mut i = idx + 1;
+        while i <= self.tree.len() {
+            self.tree[i - 1] = self.tree[i - 1] - previous + weight;
+            i += lowbit(i);
+        }
+    }
+
+    /// Removes the entry at `idx`, shifting the following ones
+    pub fn remove(&mut self, idx: usize) {
+        let mut weights = std::mem::take(&mut self.weights);
+        weights.remove(idx);
+
+        self.tree.clear();
+        for weight in weights {
+            self.push(weight);
+        }
+    }
+
+    /// Returns the entry holding the `target`th unit of weight, `target`
+    /// being below the total weight
+    pub fn find(&self, mut target: u64) -> usize {
+        let mut position = 0;
+        let mut step = self.tree.len().checked_next_power_of_two().unwrap_or(0);
+
+        // Descend the implicit tree, skipping the subtrees summing below
+        // the target
+        while step > 0 {
+            let next = position + step;
+            if next <= self.tree.len() && self.tree[next - 1] <= target {
+                target -= self.tree[next - 1];
+                position = next;
+            }
+            step >>= 1;
+        }
+
+        position
+    }
+
+    /// Computes the weight of a new entry, favouring entries faster and
+    /// smaller than the average
+    fn weight_of(&mut self, exec_us: u64, len: u64) -> u64 {
+        self.total_exec_us += exec_us;
+        self.total_len += len;
+
+        let count = (self.weights.len() + 1) as f64;
+        let time_factor = match exec_us {
+            0 => 1.0,
+            _ => (self.total_exec_us as f64 / count / exec_us as f64).clamp(0.25, 4.0),
+        };
+        let len_factor = match len {
+            0 => 1.0,
+            _ => (self.total_len as f64 / count / len as f64).clamp(0.5, 2.0),
+        };
+
+        (BASE_WEIGHT * time_factor * len_factor) as u64
+    }
+}
+
+/// Scheduler picking corpus entries at random, with a probability
+/// proportional to their weight given by a power schedule, or in turn with
+/// the round-robin schedule. The weight of an entry is updated when it is
+/// added and each time it is picked.
+#[derive(Debug, Clone)]
+pub struct WeightedCorpusScheduler<I, S> {
+    /// Schedule giving the weights of the entries
+    schedule: PowerSchedule,
+    phantom: PhantomData<(I, S)>,
+}
+
+impl<I, S> WeightedCorpusScheduler<I, S> {
+    /// Creates a new `WeightedCorpusScheduler` with the given schedule
+    pub fn new(schedule: PowerSchedule) -> Self {
+        WeightedCorpusScheduler {
+            schedule,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<I, S> Default for WeightedCorpusScheduler<I, S> {
+    fn default() -> Self {
+        Self::new(PowerSchedule::Speed)
+    }
+}
+
+impl<I, S> WeightedCorpusScheduler<I, S>
+where
+    I: Input + HasLen,
+    S: HasCorpus<I> + HasMetadata + HasRand,
+{
+    /// Returns the execution time in microseconds and the size of an entry
+    fn measure(&self, state: &S, idx: usize) -> Result<(u64, u64), Error> {
+        let mut testcase = state.corpus().get(idx)?.borrow_mut();
+        let exec_us = testcase.exec_time().map_or(0, |t| t.as_micros() as u64);
+        let len = testcase.load_input()?.len() as u64;
+
+        Ok((exec_us, len))
+    }
+
+    /// Returns the weights of the corpus, created on first use
+    fn weights_mut<'s>(&self, state: &'s mut S) -> &'s mut CorpusWeights {
+        if !state.has_metadata::<CorpusWeights>() {
+            state.add_metadata(CorpusWeights::default());
+        }
+        state.metadata_mut().get_mut::<CorpusWeights>().unwrap()
+    }
+
+    /// Updates the weight of the entry at `idx` from its energy, counting a
+    /// pick of the entry if `picked`
+    pub(crate) fn update_weight(
+        &self,
+        state: &mut S,
+        idx: usize,
+        picked: bool,
+    ) -> Result<(), Error> {
+        let weight = {
+            let mut testcase = state.corpus().get(idx)?.borrow_mut();
+            if !testcase.has_metadata::<EntryEnergy>() {
+                return Ok(());
+            }
+            let entry = testcase.metadata_mut().get_mut::<EntryEnergy>().unwrap();
+            if picked {
+                entry.scheduled += 1;
+            }
+            let stats = state.metadata().get::<PathStats>();
+            match stats {
+                Some(stats) => self.schedule.weight(entry, stats),
+                None => self.schedule.weight(entry, &PathStats::default()),
+            }
+        };
+
+        self.weights_mut(state).set(idx, weight);
+        Ok(())
+    }
+
+    /// Sets the weights of the entry added or replaced at `idx`, counting
+    /// its path and coverage map entries if `new`
+    fn add(&self, state: &mut S, idx: usize, new: bool) -> Result<(), Error> {
+        // Step 1: Speed weight
+        let (exec_us, len) = self.measure(state, idx)?;
+        let weights = self.weights_mut(state);
+        let speed = weights.weight_of(exec_us, len).max(1);
+
+        if idx == weights.weights.len() {
+            weights.push(speed);
+        } else {
+            weights.set(idx, speed);
+        }
+
+        // Step 2: Energy of the entry
+        let (path, points) = {
+            let mut testcase = state.corpus().get(idx)?.borrow_mut();
+            if !testcase.has_metadata::<EntryEnergy>() {
+                testcase.add_metadata(EntryEnergy::default());
+            }
+            let entry = testcase.metadata_mut().get_mut::<EntryEnergy>().unwrap();
+            entry.speed = speed;
+            if !new || !self.schedule.tracks_paths() {
+                (None, Vec::new())
+            } else {
+                (Some(entry.path), entry.points.clone())
+            }
+        };
+        if let Some(path) = path {
+            let stats = path_stats_mut(state);
+            stats.runs.entry(path).or_insert(1);
+            for point in points {
+                *stats.point_entries.entry(point).or_insert(0) += 1;
+            }
+        }
+
+        self.update_weight(state, idx, false)
+    }
+}
+
+impl<I, S> Scheduler<I, S> for WeightedCorpusScheduler<I, S>
+where
+    I: Input + HasLen,
+    S: HasCorpus<I> + HasMetadata + HasRand,
+{
+    fn on_add(&self, state: &mut S, idx: usize) -> Result<(), Error> {
+        self.add(state, idx, true)
+    }
+
+    fn on_replace(&self, state: &mut S, idx: usize, _prev: &Testcase<I>) -> Result<(), Error> {
+        self.add(state, idx, false)
+    }
+
+    fn on_remove(
+        &self,
+        state: &mut S,
+        idx: usize,
+        testcase: &Option<Testcase<I>>,
+    ) -> Result<(), Error> {
+        let weights = self.weights_mut(state);
+        if idx < weights.weights.len() {
+            weights.remove(idx);
+        }
+
+        // The coverage map entries of the removed entry are no longer hit
+        // by it
+        let energy = testcase
+            .as_ref()
+            .and_then(|testcase| testcase.metadata().get::<EntryEnergy>());
+        if let Some(entry) = energy.filter(|_| self.schedule.tracks_paths()) {
+            let stats = path_stats_mut(state);
+            for point in &entry.points {
+                if let Some(entries) = stats.point_entries.get_mut(point) {
+                    *entries = entries.saturating_sub(1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&self, state: &mut S) -> Result<usize, Error> {
+        let total = self.weights_mut(state).total();
+        if state.corpus().count() == 0 || total == 0 {
+            return Err(Error::empty("No entries in corpus".to_string()));
+        }
+
+        let idx = match self.schedule {
+            PowerSchedule::RoundRobin => state
+                .corpus()
+                .current()
+                .map_or(0, |current| (current + 1) % state.corpus().count()),
+            _ => {
+                let target = state.rand_mut().below(total);
+                self.weights_mut(state).find(target)
+            }
+        };
+        self.update_weight(state, idx, true)?;
+
+        *state.corpus_mut().current_mut() = Some(idx);
+        Ok(idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CorpusWeights;
+
+    /// Builds the weights of entries with the given weights
+    fn weights_of(weights: &[u64]) -> CorpusWeights {
+        let mut corpus_weights = CorpusWeights::default();
+        for &weight in weights {
+            corpus_weights.push(weight);
+        }
+        corpus_weights
+    }
+
+    /// Returns the entry picked for each unit of the total weight
+    fn picks(weights: &CorpusWeights) -> Vec<usize> {
+        (0..weights.total())
+            .map(|target| weights.find(target))
+            .collect()
+    }
+
+    #[test]
+    /// Tests that each entry is picked for exactly its range of the weight
+    fn test_find() {
+        let weights = weights_of(&[3, 1, 0, 4, 2, 5, 1]);
+        assert_eq!(weights.total(), 16);
+        assert_eq!(
+            picks(&weights),
+            [0, 0, 0, 1, 3, 3, 3, 3, 4, 4, 5, 5, 5, 5, 5, 6]
+        );
+    }
+
+    #[test]
+    /// Tests that changing a weight updates the total and the picks
+    fn test_set() {
+        let mut weights = weights_of(&[3, 1, 0, 4, 2]);
+        weights.set(1, 3);
+        assert_eq!(weights.total(), 12);
+        weights.set(3, 0);
+        assert_eq!(weights.total(), 8);
+        weights.set(2, 1);
+        assert_eq!(weights.total(), 9);
+
+        assert_eq!(picks(&weights), [0, 0, 0, 1, 1, 1, 2, 4, 4]);
+    }
+
+    #[test]
+    /// Tests that removing an entry shifts the following ones
+    fn test_remove() {
+        let mut weights = weights_of(&[3, 1, 0, 4, 2]);
+        weights.remove(1);
+        assert_eq!(weights.total(), 9);
+        assert_eq!(picks(&weights), [0, 0, 0, 2, 2, 2, 2, 3, 3]);
+
+        weights.remove(0);
+        assert_eq!(weights.total(), 6);
+        assert_eq!(picks(&weights), [1, 1, 1, 1, 2, 2]);
+
+        weights.push(1);
+        assert_eq!(picks(&weights), [1, 1, 1, 1, 2, 2, 3]);
+    }
+}
@@ -0,0 +1,107 @@
+//! Socket fuzzer mode: an external driver sends inputs and receives the
+//! verdict of their execution.
+//!
+//! Each request is an input prefixed by its length (u32 little endian). Each
+//! response is a status byte (0: ok, 1: crash, 2: timeout, 3: denied
+//! syscall), the number of new coverage points (u32 little endian) and the
+//! triage report of a crash or the denied syscall prefixed by its length (u32
+//! little endian, 0 when there is neither). Connections sending an input
+//! larger than the maximum input size are closed.
+
+use crate::executor::install_alarm_handler;
+use crate::fuzz::FuzzerConfig;
+use crate::runner::{Runner, Verdict};
+use crate::sysemu::describe_syscall;
+use crate::targets::selected_target;
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+
+/// Serves the requests of a connection until it is closed, rejecting the
+/// inputs larger than `max_size`
+fn serve<S: Read + Write>(runner: &mut Runner, mut stream: S, max_size: usize) -> io::Result<()> {
+    loop {
+        // Read the input
+        let mut size = [0u8; 4];
+        match stream.read_exact(&mut size) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let size = u32::from_le_bytes(size) as usize;
+        if size > max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "input of {} bytes above the maximum size {}",
+                    size, max_size
+                ),
+            ));
+        }
+        let mut input = vec![0u8; size];
+        stream.read_exact(&mut input)?;
+
+        // Run it and send the verdict
+        let (verdict, new_coverage) = runner.run(&input);
+        let (status, report) = match verdict {
+            Verdict::Ok => (0u8, String::new()),
+            Verdict::Crash(_, report) => (1u8, report),
+            Verdict::Timeout => (2u8, String::new()),
+            Verdict::Denied(syscall) => {
+                (3u8, format!("denied syscall {}", describe_syscall(syscall)))
+            }
+        };
+
+        let mut response = vec![status];
+        response.extend_from_slice(&(new_coverage as u32).to_le_bytes());
+        response.extend_from_slice(&(report.len() as u32).to_le_bytes());
+        response.extend_from_slice(report.as_bytes());
+        stream.write_all(&response)?;
+    }
+}
+
+/// Serves the connections accepted by a listener one at a time. Accept
+/// errors, e.g. running out of file descriptors or a connection aborted
+/// before being accepted, are only logged.
+fn serve_incoming<S, C>(runner: &mut Runner, incoming: C, max_size: usize)
+where
+    S: Read + Write,
+    C: Iterator<Item = io::Result<S>>,
+{
+    for stream in incoming {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Could not accept connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = serve(runner, stream, max_size) {
+            log::warn!("Connection error: {}", e);
+        }
+    }
+}
+
+/// Listens on a TCP address (`ip:port`) or a unix socket path and runs the
+/// inputs sent by the connected drivers, one connection at a time
+pub fn serve_socket(config: FuzzerConfig, address: &str) {
+    install_alarm_handler();
+    let mut runner = Runner::new(
+        &selected_target(&config),
+        config.dictionary,
+        config.timeout,
+        true,
+    );
+
+    match address.parse::<SocketAddr>() {
+        Ok(address) => {
+            let listener = TcpListener::bind(address).expect("Could not bind socket");
+            serve_incoming(&mut runner, listener.incoming(), config.max_input_size);
+        }
+        Err(_) => {
+            let listener = UnixListener::bind(address).expect("Could not bind socket");
+            serve_incoming(&mut runner, listener.incoming(), config.max_input_size);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Coverage map shared by the fuzzing clients
+
+use libafl::{
+    bolts::{tuples::Named, AsSlice},
+    events::EventFirer,
+    executors::ExitKind,
+    feedbacks::Feedback,
+    inputs::Input,
+    observers::{ObserversTuple, StdMapObserver},
+    state::HasClientPerfMonitor,
+    Error,
+};
+use nix::libc;
+
+use std::fmt::{self, Debug, Formatter};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Whether the executor ran an input rated by the feedback since the last
+/// feedback evaluation. Inputs evaluated without a local run come from
+/// another client.
+static LOCAL_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Records that the executor of this client ran an input which is going to
+/// be rated by the feedback
+#[inline]
+pub fn mark_local_run() {
+    LOCAL_RUN.store(true, Ordering::Relaxed);
+}
+
+/// Bit of the AFL hit count bucket of `count`
+#[inline]
+pub(crate) fn bucket(count: u8) -> u8 {
+    match count {
+        0 => 0,
+        1 => 1 << 0,
+        2 => 1 << 1,
+        3 => 1 << 2,
+        4..=7 => 1 << 3,
+        8..=15 => 1 << 4,
+        16..=31 => 1 << 5,
+        32..=127 => 1 << 6,
+        128..=255 => 1 << 7,
+    }
+}
+
+/// Map holding, for each coverage map entry, the hit count buckets seen by
+/// any client. It lives in an anonymous shared mapping created before the
+/// clients are forked and is only updated with atomic operations, so that
+/// the clients never wait on each other.
+pub struct SharedCoverageMap {
+    /// Entries of the map
+    entries: *mut AtomicU8,
+    /// Number of entries
+    len: usize,
+}
+
+// The entries are only accessed atomically
+unsafe impl Send for SharedCoverageMap {}
+unsafe impl Sync for SharedCoverageMap {}
+
+/// Maps `len` zeroed bytes shared with the processes forked afterwards
+pub(crate) fn shared_mapping(len: usize) -> *mut libc::c_void {
+    let mapping = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert!(mapping != libc::MAP_FAILED, "Could not map shared memory");
+
+    mapping
+}
+
+impl SharedCoverageMap {
+    /// Creates a map of `len` entries, shared with the processes forked
+    /// afterwards
+    pub fn new(len: usize) -> Self {
+        let entries = shared_mapping(len);
+
+        SharedCoverageMap {
+            entries: entries as *mut AtomicU8,
+            len,
+        }
+    }
+
+    /// Returns the entries of the map
+    #[inline]
+    fn entries(&self) -> &[AtomicU8] {
+        unsafe { slice::from_raw_parts(self.entries, self.len) }
+    }
+
+    /// Merges the hit counts of a run, returning the number of entries which
+    /// reached a hit count bucket for the first time
+    pub fn merge(&self, map: &[u8]) -> usize {
+        let mut new = 0;
+
+        for (entry, &count) in self.entries().iter().zip(map) {
+            let bit = bucket(count);
+            if bit != 0 && entry.fetch_or(bit, Ordering::Relaxed) & bit == 0 {
+                new += 1;
+            }
+        }
+
+        new
+    }
+
+    /// Returns a copy of the entries, the hit count buckets seen so far
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.entries()
+            .iter()
+            .map(|e| e.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Adds the hit count buckets of a snapshot of the map
+    pub fn restore(&self, snapshot: &[u8]) {
+        for (entry, &buckets) in self.entries().iter().zip(snapshot) {
+            entry.fetch_or(buckets, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of entries hit by any client
+    pub fn covered(&self) -> usize {
+        self.entries()
+            .iter()
+            .filter(|e| e.load(Ordering::Relaxed) != 0)
+            .count()
+    }
+}
+
+impl Drop for SharedCoverageMap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.entries as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// Feedback finding an input interesting if its coverage map reached new
+/// hit count buckets in the map shared by all the clients. Inputs received
+/// from another client were interesting to it and are always kept.
+pub struct SharedMapFeedback<'a> {
+    /// Map shared by the clients
+    shared: &'a SharedCoverageMap,
+    /// Name of the observed coverage map
+    observer_name: String,
+}
+
+impl<'a> SharedMapFeedback<'a> {
+    /// Creates a feedback merging the map of `observer` into `shared`
+    pub fn new(shared: &'a SharedCoverageMap, observer: &StdMapObserver<u8>) -> Self {
+        SharedMapFeedback {
+            shared,
+            observer_name: observer.name().to_string(),
+        }
+    }
+}
+
+impl<'a, I, S> Feedback<I, S> for SharedMapFeedback<'a>
+where
+    I: Input,
+    S: HasClientPerfMonitor,
+{
+    fn is_interesting<EM, OT>(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _input: &I,
+        observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error>
+    where
+        EM: EventFirer<I>,
+        OT: ObserversTuple<I, S>,
+    {
+        if !LOCAL_RUN.swap(false, Ordering::Relaxed) {
+            return Ok(true);
+        }
+
+        let observer = observers
+            .match_name::<StdMapObserver<u8>>(&self.observer_name)
+            .expect("SharedMapFeedback expects a StdMapObserver<u8>");
+        Ok(self.shared.merge(observer.as_slice()) > 0)
+    }
+}
+
+impl<'a> Named for SharedMapFeedback<'a> {
+    fn name(&self) -> &str {
+        "SharedMapFeedback"
+    }
+}
+
+impl<'a> Debug for SharedMapFeedback<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedMapFeedback")
+            .field("observer_name", &self.observer_name)
+            .finish_non_exhaustive()
+    }
+}
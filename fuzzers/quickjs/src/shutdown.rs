@@ -0,0 +1,61 @@
+//! Graceful shutdown on SIGINT and SIGTERM
+
+use nix::libc;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// Exit code of a session stopped by a signal, as if it was killed by
+/// SIGINT
+pub const INTERRUPTED_EXIT_CODE: i32 = 128 + libc::SIGINT;
+
+/// Set once a shutdown was requested
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+/// Signal which requested the shutdown
+static SHUTDOWN_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+// The handler only flips the flag, the fuzz loops poll it between two fuzz
+// cases. A signal landing in kvm_run(...) makes it fail with EINTR, the run
+// is resumed as long as its timeout is not over.
+extern "C" fn shutdown_handler(signal: i32) {
+    SHUTDOWN_SIGNAL.store(signal, Ordering::Relaxed);
+    TERMINATING.store(true, Ordering::Release);
+}
+
+/// Installs the handler of SIGINT and SIGTERM, requesting a shutdown
+/// instead of killing the process
+pub fn install_shutdown_handler() {
+    let action = SigAction::new(
+        SigHandler::Handler(shutdown_handler),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+
+    unsafe {
+        sigaction(Signal::SIGINT, &action).expect("Failed to setup SIGINT handler");
+        sigaction(Signal::SIGTERM, &action).expect("Failed to setup SIGTERM handler");
+    }
+}
+
+/// Requests a shutdown with SIGTERM when the parent process dies, e.g. the
+/// process restarting a client stopped by the broker
+pub fn terminate_with_parent() {
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+    }
+}
+
+/// Returns whether a shutdown was requested
+#[inline]
+pub fn terminating() -> bool {
+    TERMINATING.load(Ordering::Acquire)
+}
+
+/// Returns the exit code of a process stopped by a shutdown request,
+/// `128 + signal` like a process killed by the signal
+pub fn shutdown_exit_code() -> i32 {
+    match SHUTDOWN_SIGNAL.load(Ordering::Relaxed) {
+        0 => INTERRUPTED_EXIT_CODE,
+        signal => 128 + signal,
+    }
+}
@@ -0,0 +1,155 @@
+//! Mutations focused on the tokens the target consumed right before reaching
+//! new coverage
+
+use crate::runner::Runner;
+
+use libafl::{
+    bolts::{rands::Rand, tuples::Named},
+    corpus::Corpus,
+    inputs::{HasBytesVec, Input},
+    mutators::{MutationResult, Mutator},
+    stages::Stage,
+    state::{HasCorpus, HasMetadata, HasRand},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+
+use std::marker::PhantomData;
+
+/// Tokens of a corpus entry read by the target before the coverage points it
+/// reached first
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InputSlice {
+    /// Offsets of the tokens in the encoded input
+    pub offsets: Vec<usize>,
+}
+
+libafl::impl_serdeany!(InputSlice);
+
+/// Stage computing the slice of the corpus entries scheduled for the first
+/// time. The entry is run again with the reads of the input area recorded,
+/// by a runner keeping its own coverage frontier: the points an entry is the
+/// first to reach, in scheduling order, are the ones it is credited with.
+pub struct SliceStage<I> {
+    /// Runner with the input area watched
+    runner: Runner,
+    phantom: PhantomData<I>,
+}
+
+impl<I> SliceStage<I> {
+    /// Creates a stage slicing the entries with a runner instrumented with
+    /// the coverage breakpoints
+    pub fn new(mut runner: Runner) -> Self {
+        runner.set_input_watch();
+        SliceStage {
+            runner,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<E, EM, I, S, Z> Stage<E, EM, S, Z> for SliceStage<I>
+where
+    I: Input + HasBytesVec,
+    S: HasCorpus<I>,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut S,
+        _manager: &mut EM,
+        corpus_idx: usize,
+    ) -> Result<(), Error> {
+        let mut testcase = state.corpus().get(corpus_idx)?.borrow_mut();
+        if testcase.has_metadata::<InputSlice>() {
+            return Ok(());
+        }
+
+        let input = testcase.load_input()?.bytes().to_vec();
+        self.runner.run(&input);
+        let offsets = self.runner.frontier_tokens(&input);
+        testcase.add_metadata(InputSlice { offsets });
+
+        Ok(())
+    }
+}
+
+/// Mutator replacing a token of the slice of the entry being fuzzed with a
+/// random or a neighbouring token. Skipped for entries without a slice.
+#[derive(Default)]
+pub struct SliceMutator;
+
+impl SliceMutator {
+    /// Creates the mutator
+    pub fn new() -> Self {
+        SliceMutator
+    }
+}
+
+impl<I, S> Mutator<I, S> for SliceMutator
+where
+    I: Input + HasBytesVec,
+    S: HasRand + HasCorpus<I>,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut I,
+        _stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        let idx = match state.corpus().current() {
+            Some(idx) => *idx,
+            None => return Ok(MutationResult::Skipped),
+        };
+
+        // Earlier mutations of the stack may have moved the tokens, the
+        // offsets still falling in the input are kept
+        let len = input.bytes().len();
+        let offsets: Vec<usize> = match state
+            .corpus()
+            .get(idx)?
+            .borrow()
+            .metadata()
+            .get::<InputSlice>()
+        {
+            Some(slice) => slice
+                .offsets
+                .iter()
+                .copied()
+                .filter(|&offset| offset + 1 < len)
+                .collect(),
+            None => Vec::new(),
+        };
+        if offsets.is_empty() {
+            return Ok(MutationResult::Skipped);
+        }
+
+        let rand = state.rand_mut();
+        let offset = *rand.choose(&offsets);
+        let bytes = input.bytes_mut();
+        let token = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+
+        let token = match rand.below(2) {
+            // Random token
+            0 => rand.below(1 << 16) as u16,
+            // Neighbouring token
+            _ => {
+                let delta = 1 + rand.below(16) as u16;
+                match rand.below(2) {
+                    0 => token.wrapping_add(delta),
+                    _ => token.wrapping_sub(delta),
+                }
+            }
+        };
+        bytes[offset..offset + 2].copy_from_slice(&token.to_le_bytes());
+
+        Ok(MutationResult::Mutated)
+    }
+}
+
+impl Named for SliceMutator {
+    fn name(&self) -> &str {
+        "SliceMutator"
+    }
+}
@@ -0,0 +1,180 @@
+use libafl::{
+    bolts::current_time,
+    monitors::{ClientStats, Monitor, UserStats},
+};
+use serde_json::json;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Number of crashes listed in the status
+const RECENT_CRASHES: usize = 10;
+
+/// Monitor publishing the campaign status on a read-only HTTP endpoint,
+/// before handing the events to the wrapped monitor. The endpoint is started
+/// on the first event, in the broker process.
+#[derive(Clone)]
+pub struct StatusMonitor<M: Monitor> {
+    /// Wrapped monitor
+    inner: M,
+    /// Address of the endpoint, disabled if not set
+    address: Option<SocketAddr>,
+    /// Directories holding the unique crashes of the targets
+    crash_dirs: Vec<PathBuf>,
+    /// Last campaign status, shared with the endpoint thread
+    status: Arc<Mutex<serde_json::Value>>,
+    /// Whether the endpoint thread is running
+    started: bool,
+}
+
+impl<M: Monitor> StatusMonitor<M> {
+    /// Wraps a monitor, serving the status on `address` if set
+    pub fn new(inner: M, address: Option<&str>, crash_dirs: Vec<PathBuf>) -> Self {
+        StatusMonitor {
+            inner,
+            address: address.map(|a| a.parse().expect("Invalid status address")),
+            crash_dirs,
+            status: Arc::new(Mutex::new(json!({}))),
+            started: false,
+        }
+    }
+}
+
+impl<M: Monitor> Monitor for StatusMonitor<M> {
+    fn client_stats_mut(&mut self) -> &mut Vec<ClientStats> {
+        self.inner.client_stats_mut()
+    }
+
+    fn client_stats(&self) -> &[ClientStats] {
+        self.inner.client_stats()
+    }
+
+    fn start_time(&mut self) -> Duration {
+        self.inner.start_time()
+    }
+
+    fn display(&mut self, event_msg: String, sender_id: u32) {
+        if let Some(address) = self.address {
+            if !self.started {
+                serve_status(address, Arc::clone(&self.status), self.crash_dirs.clone());
+                self.started = true;
+            }
+
+            let run_time = current_time() - self.inner.start_time();
+            *self.status.lock().unwrap() = json!({
+                "run_time": run_time.as_secs(),
+                "clients": self.client_stats().len(),
+                "corpus": self.corpus_size(),
+                "objectives": self.objective_size(),
+                "executions": self.total_execs(),
+                "exec_per_sec": self.execs_per_sec(),
+                "targets": target_stats(self.client_stats()),
+                "last_event": event_msg,
+            });
+        }
+
+        self.inner.display(event_msg, sender_id);
+    }
+}
+
+/// Statistics of the clients summed by target, as reported by the clients
+/// of a multi-target session
+fn target_stats(clients: &[ClientStats]) -> serde_json::Value {
+    let mut targets = serde_json::Map::new();
+
+    for client in clients {
+        let name = match client.user_monitor.get("target") {
+            Some(UserStats::String(name)) => name,
+            _ => continue,
+        };
+
+        let stats = targets.entry(name.clone()).or_insert_with(
+            || json!({ "clients": 0, "corpus": 0, "objectives": 0, "executions": 0 }),
+        );
+        for (key, value) in [
+            ("clients", 1),
+            ("corpus", client.corpus_size),
+            ("objectives", client.objective_size),
+            ("executions", client.executions),
+        ] {
+            stats[key] = json!(stats[key].as_u64().unwrap_or(0) + value);
+        }
+    }
+
+    serde_json::Value::Object(targets)
+}
+
+/// Names of the most recent crashes of the targets, newest first
+fn recent_crashes(crash_dirs: &[PathBuf]) -> Vec<String> {
+    let mut crashes: Vec<_> = crash_dirs
+        .iter()
+        .flat_map(fs::read_dir)
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let modified = entry.metadata().and_then(|m| m.modified()).ok()?;
+            match name.ends_with(".txt") {
+                true => None,
+                false => Some((modified, name)),
+            }
+        })
+        .collect();
+
+    crashes.sort_unstable_by(|a, b| b.cmp(a));
+    crashes
+        .into_iter()
+        .take(RECENT_CRASHES)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+/// Answers a single HTTP request with the status as JSON
+fn answer(
+    stream: TcpStream,
+    status: &Mutex<serde_json::Value>,
+    crash_dirs: &[PathBuf],
+) -> std::io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let (code, body) = match request_line.split_whitespace().nth(1) {
+        Some("/") | Some("/status") => {
+            let mut body = status.lock().unwrap().clone();
+            body["recent_crashes"] = json!(recent_crashes(crash_dirs));
+            ("200 OK", body.to_string())
+        }
+        _ => ("404 Not Found", json!({ "error": "not found" }).to_string()),
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        body.len(),
+        body
+    )
+}
+
+/// Serves the status on `address` from a background thread
+fn serve_status(
+    address: SocketAddr,
+    status: Arc<Mutex<serde_json::Value>>,
+    crash_dirs: Vec<PathBuf>,
+) {
+    let listener = TcpListener::bind(address).expect("Could not bind status endpoint");
+    log::info!("Serving status on http://{}", address);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A failing client does not stop the endpoint
+            let _ = answer(stream, &status, &crash_dirs);
+        }
+    });
+}
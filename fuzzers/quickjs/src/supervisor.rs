@@ -0,0 +1,122 @@
+use crate::fuzz::FuzzerConfig;
+use crate::plateau::{plateau_reached, PLATEAU_EXIT_CODE};
+use crate::shutdown::{install_shutdown_handler, shutdown_exit_code, terminating};
+
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, setpgid, ForkResult, Pid};
+
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tartiflette_vm::CrashStore;
+
+/// Interval between two checks of the stop conditions
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time given to the processes of a stopped session to finish their fuzz
+/// case before they are killed
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+/// Number of unique crashes in the crash directories of the targets
+fn crash_count(crash_dirs: &[PathBuf]) -> usize {
+    crash_dirs
+        .iter()
+        .map(|dir| {
+            CrashStore::new(dir)
+                .expect("Could not open crash directory")
+                .len()
+        })
+        .sum()
+}
+
+/// Runs a fuzzing session in its own process group and stops it when the run
+/// time is over or enough new crashes were found in `crash_dirs`, or when it
+/// receives SIGINT or SIGTERM. Exits with 1 if the session found new crashes,
+/// otherwise with `PLATEAU_EXIT_CODE` if it stopped on a coverage plateau and
+/// 0 if not, and `128 + signal` if stopped by a signal.
+pub fn supervise<F: FnOnce()>(config: FuzzerConfig, crash_dirs: &[PathBuf], session: F) -> ! {
+    let initial_crashes = crash_count(crash_dirs);
+
+    // The broker and the clients all live in the group of the session
+    let child = match unsafe { fork() }.expect("Could not fork the session") {
+        ForkResult::Child => {
+            setpgid(Pid::from_raw(0), Pid::from_raw(0)).expect("Could not create process group");
+            session();
+            match plateau_reached() {
+                true => process::exit(PLATEAU_EXIT_CODE),
+                false => process::exit(0),
+            }
+        }
+        ForkResult::Parent { child } => {
+            let _ = setpgid(child, child);
+            child
+        }
+    };
+
+    // The session is in its own group and does not get the terminal signals
+    install_shutdown_handler();
+
+    let start = Instant::now();
+    let mut plateau = false;
+    let reason = loop {
+        thread::sleep(CHECK_INTERVAL);
+
+        if terminating() {
+            break Some("a signal".to_string());
+        }
+
+        match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {}
+            Ok(WaitStatus::Exited(_, PLATEAU_EXIT_CODE)) => {
+                log::info!("Session stopped on a coverage plateau");
+                plateau = true;
+                break None;
+            }
+            _ => break None,
+        }
+
+        let crashes = crash_count(crash_dirs) - initial_crashes;
+        if config.max_crashes.map_or(false, |max| crashes >= max) {
+            break Some(format!("{} new crashes", crashes));
+        }
+        if config
+            .run_time
+            .map_or(false, |time| start.elapsed() >= time)
+        {
+            break Some(format!("{:?}", start.elapsed()));
+        }
+    };
+
+    // Stop the whole session, letting its processes finish their fuzz case
+    if let Some(reason) = reason {
+        let _ = killpg(child, Signal::SIGTERM);
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        while killpg(child, None).is_ok() && Instant::now() < deadline {
+            let _ = waitpid(child, Some(WaitPidFlag::WNOHANG));
+            thread::sleep(CHECK_INTERVAL);
+        }
+        let _ = killpg(child, Signal::SIGKILL);
+        let _ = waitpid(child, None);
+        log::info!("Session stopped after {}", reason);
+    }
+
+    if terminating() {
+        process::exit(shutdown_exit_code());
+    }
+
+    match crash_count(crash_dirs) - initial_crashes {
+        0 if plateau => process::exit(PLATEAU_EXIT_CODE),
+        0 => process::exit(0),
+        crashes => {
+            let dirs: Vec<_> = crash_dirs
+                .iter()
+                .map(|dir| dir.display().to_string())
+                .collect();
+            log::info!("{} new crashes in {}", crashes, dirs.join(", "));
+            process::exit(1)
+        }
+    }
+}
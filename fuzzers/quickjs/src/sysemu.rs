@@ -1,6 +1,163 @@
-use std::convert::From;
+use serde::{Deserialize, Deserializer};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::convert::{From, TryInto};
 use tartiflette_vm::{Register, Vm};
 
+/// Number of trailing bytes of guest output kept for crash reports
+const OUTPUT_SIZE: usize = 4096;
+/// Maximum number of iovecs of a `writev` call
+const IOV_MAX: u64 = 1024;
+/// Error returned for writes to other files than stdout and stderr
+const EBADF: i64 = 9;
+/// Error returned for an iovec array wrapping around the address space
+const EFAULT: i64 = 14;
+
+/// Names of the linux syscalls accepted by the syscall policies
+const SYSCALL_NAMES: &[(&str, u64)] = &[
+    ("read", 0),
+    ("write", 1),
+    ("open", 2),
+    ("close", 3),
+    ("stat", 4),
+    ("fstat", 5),
+    ("lseek", 8),
+    ("mmap", 9),
+    ("mprotect", 10),
+    ("munmap", 11),
+    ("brk", 12),
+    ("rt_sigaction", 13),
+    ("rt_sigprocmask", 14),
+    ("ioctl", 16),
+    ("readv", 19),
+    ("writev", 20),
+    ("access", 21),
+    ("madvise", 28),
+    ("getpid", 39),
+    ("socket", 41),
+    ("connect", 42),
+    ("clone", 56),
+    ("fork", 57),
+    ("vfork", 58),
+    ("execve", 59),
+    ("exit", 60),
+    ("kill", 62),
+    ("uname", 63),
+    ("fcntl", 72),
+    ("getcwd", 79),
+    ("gettimeofday", 96),
+    ("ptrace", 101),
+    ("getuid", 102),
+    ("clock_gettime", 228),
+    ("exit_group", 231),
+    ("openat", 257),
+    ("getrandom", 318),
+];
+
+/// Linux syscall given by number or by name in a syscall policy
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SyscallId(pub u64);
+
+impl<'de> Deserialize<'de> for SyscallId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Id {
+            Number(u64),
+            Name(String),
+        }
+
+        // Object keys are always strings, numbers included
+        match Id::deserialize(deserializer)? {
+            Id::Number(number) => Ok(SyscallId(number)),
+            Id::Name(name) => name
+                .parse()
+                .ok()
+                .or_else(|| {
+                    SYSCALL_NAMES
+                        .iter()
+                        .find(|(n, _)| *n == name)
+                        .map(|(_, number)| *number)
+                })
+                .map(SyscallId)
+                .ok_or_else(|| serde::de::Error::custom(format!("unknown syscall {}", name))),
+        }
+    }
+}
+
+/// Syscalls a target is allowed to use. Denied syscalls stop the fuzz case
+/// instead of being emulated, and syscalls outside the emulated ones are
+/// denied rather than stopping the fuzzer.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyscallPolicy {
+    /// Syscalls allowed, all the emulated ones if empty
+    #[serde(default)]
+    pub allow: Vec<SyscallId>,
+    /// Syscalls denied
+    #[serde(default)]
+    pub deny: Vec<SyscallId>,
+    /// Syscalls returning a fixed result without being emulated
+    #[serde(default)]
+    pub fake: BTreeMap<SyscallId, i64>,
+}
+
+/// Decision of a syscall policy
+enum Decision {
+    /// The syscall is emulated
+    Emulate,
+    /// The syscall returns a fixed result
+    Fake(i64),
+    /// The syscall stops the fuzz case
+    Deny,
+}
+
+impl SyscallPolicy {
+    /// Returns the decision for a syscall. The denied syscalls take
+    /// precedence over the faked ones, which take precedence over the
+    /// allowed ones.
+    fn decide(&self, syscall: u64) -> Decision {
+        let id = SyscallId(syscall);
+        if self.deny.contains(&id) {
+            Decision::Deny
+        } else if let Some(&result) = self.fake.get(&id) {
+            Decision::Fake(result)
+        } else if !self.allow.is_empty() && !self.allow.contains(&id) {
+            Decision::Deny
+        } else {
+            Decision::Emulate
+        }
+    }
+}
+
+/// Outcome of a syscall of the guest
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyscallOutcome {
+    /// The guest goes on
+    Continue,
+    /// The guest exited
+    Exit,
+    /// The syscall is denied by the policy of the target
+    Denied(u64),
+}
+
+/// Describes a syscall by number, and by name if known
+pub fn describe_syscall(syscall: u64) -> String {
+    match SYSCALL_NAMES.iter().find(|(_, number)| *number == syscall) {
+        Some((name, _)) => format!("{} ({})", syscall, name),
+        None => syscall.to_string(),
+    }
+}
+
+/// Formats guest output as a crash report section
+pub fn output_section(output: &[u8]) -> String {
+    let mut section = String::from("guest output:\n");
+    for line in String::from_utf8_lossy(output).lines() {
+        section += &format!("  {}\n", line);
+    }
+    section
+}
+
 /// Linux syscall emulation state
 pub struct SysEmu {
     /// Base address of the mmap area
@@ -9,10 +166,17 @@ pub struct SysEmu {
     mmap_end: u64,
     /// Current address in the mmap are
     mmap_current: u64,
+    /// Last bytes written by the guest to stdout and stderr
+    output: Vec<u8>,
+    /// Syscall policy of the target, every emulated syscall being allowed if
+    /// not set
+    policy: Option<SyscallPolicy>,
 }
 
 /// Supported linux syscalls
 enum Syscall {
+    Write,
+    Writev,
     Mmap,
     Munmap,
     Ioctl,
@@ -24,9 +188,11 @@ enum Syscall {
 impl From<u64> for Syscall {
     fn from(value: u64) -> Self {
         match value {
+            1 => Syscall::Write,
             9 => Syscall::Mmap,
             11 => Syscall::Munmap,
             16 => Syscall::Ioctl,
+            20 => Syscall::Writev,
             28 => Syscall::Madvise,
             231 => Syscall::ExitGroup,
             _ => Syscall::Unknown,
@@ -35,20 +201,95 @@ impl From<u64> for Syscall {
 }
 
 impl SysEmu {
-    /// Creates a new state
-    pub fn new(start: u64, end: u64) -> SysEmu {
+    /// Creates a new state, enforcing `policy` if set
+    pub fn new(start: u64, end: u64, policy: Option<SyscallPolicy>) -> SysEmu {
         SysEmu {
             mmap_start: start,
             mmap_end: end,
             mmap_current: start,
+            output: Vec::new(),
+            policy,
         }
     }
 
-    /// Handles a syscall. Returns whether execution should continue
-    pub fn syscall(&mut self, vm: &mut Vm) -> bool {
+    /// Appends guest output, keeping only the last `OUTPUT_SIZE` bytes
+    fn capture(&mut self, vm: &Vm, address: u64, len: u64) {
+        let mut data = vec![0u8; cmp::min(len, OUTPUT_SIZE as u64) as usize];
+        if vm
+            .read(address.wrapping_add(len - data.len() as u64), &mut data)
+            .is_err()
+        {
+            return;
+        }
+        log::trace!("Guest output: {:?}", String::from_utf8_lossy(&data));
+
+        self.output.extend_from_slice(&data);
+        let excess = self.output.len().saturating_sub(OUTPUT_SIZE);
+        self.output.drain(..excess);
+    }
+
+    /// Handles a syscall according to the policy of the target
+    pub fn syscall(&mut self, vm: &mut Vm) -> SyscallOutcome {
         let syscall_code = vm.get_reg(Register::Rax);
 
+        match self.policy.as_ref().map(|p| p.decide(syscall_code)) {
+            Some(Decision::Deny) => return SyscallOutcome::Denied(syscall_code),
+            Some(Decision::Fake(result)) => {
+                vm.set_reg(Register::Rax, result as u64);
+                return SyscallOutcome::Continue;
+            }
+            Some(Decision::Emulate) | None => {}
+        }
+
         let result = match syscall_code.into() {
+            Syscall::Write => {
+                // Only stdout and stderr are captured
+                let fd = vm.get_reg(Register::Rdi);
+                let buf = vm.get_reg(Register::Rsi);
+                let count = vm.get_reg(Register::Rdx);
+
+                match fd {
+                    1 | 2 => {
+                        self.capture(vm, buf, count);
+                        vm.set_reg(Register::Rax, count);
+                    }
+                    _ => vm.set_reg(Register::Rax, -EBADF as u64),
+                }
+                true
+            }
+            Syscall::Writev => {
+                let fd = vm.get_reg(Register::Rdi);
+                let iov = vm.get_reg(Register::Rsi);
+                let iovcnt = cmp::min(vm.get_reg(Register::Rdx), IOV_MAX);
+
+                match fd {
+                    1 | 2 => {
+                        // Each iovec is a (base, len) pair, an array wrapping
+                        // around the address space is a bad address
+                        let mut total: u64 = 0;
+                        for i in 0..iovcnt {
+                            let address = match i.checked_mul(16).and_then(|o| iov.checked_add(o)) {
+                                Some(address) => address,
+                                None => {
+                                    total = -EFAULT as u64;
+                                    break;
+                                }
+                            };
+                            let mut iovec = [0u8; 16];
+                            if vm.read(address, &mut iovec).is_err() {
+                                break;
+                            }
+                            let base = u64::from_le_bytes(iovec[..8].try_into().unwrap());
+                            let len = u64::from_le_bytes(iovec[8..].try_into().unwrap());
+                            self.capture(vm, base, len);
+                            total = total.wrapping_add(len);
+                        }
+                        vm.set_reg(Register::Rax, total);
+                    }
+                    _ => vm.set_reg(Register::Rax, -EBADF as u64),
+                }
+                true
+            }
             Syscall::Mmap => {
                 // Get the arguments
                 let addr = vm.get_reg(Register::Rdi);
@@ -94,16 +335,30 @@ impl SysEmu {
                 // Stop the execution
                 false
             }
+            // Under a policy, the syscalls which cannot be emulated are
+            // denied like the others
+            Syscall::Unknown if self.policy.is_some() => {
+                return SyscallOutcome::Denied(syscall_code);
+            }
             Syscall::Unknown => {
                 panic!("Unhandled syscall: {}", syscall_code);
             }
         };
 
-        result
+        match result {
+            true => SyscallOutcome::Continue,
+            false => SyscallOutcome::Exit,
+        }
+    }
+
+    /// Last bytes written by the guest to stdout and stderr
+    pub fn output(&self) -> &[u8] {
+        &self.output
     }
 
     /// Resets the internal state of emulation layer
     pub fn reset(&mut self) {
         self.mmap_current = self.mmap_start;
+        self.output.clear();
     }
 }
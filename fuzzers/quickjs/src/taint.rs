@@ -0,0 +1,234 @@
+//! Inference of the tokens of the corpus entries influencing their coverage
+
+use crate::runner::{Runner, Verdict};
+use crate::shutdown::terminating;
+
+use libafl::{
+    bolts::{rands::Rand, tuples::Named},
+    corpus::Corpus,
+    inputs::{HasBytesVec, Input},
+    mutators::{MutationResult, Mutator},
+    stages::Stage,
+    state::{HasCorpus, HasMetadata, HasRand},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::marker::PhantomData;
+
+/// Number of leading tokens of an entry probed by the stage
+const MAX_PROBED_TOKENS: usize = 256;
+
+/// Coverage points whose hits change with each token of a corpus entry
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InputInfluence {
+    /// Coverage points gained or lost by changing the token, by offset of
+    /// the token in the encoded input
+    pub sites: BTreeMap<usize, Vec<u64>>,
+}
+
+libafl::impl_serdeany!(InputInfluence);
+
+/// Stage inferring the influence of the tokens of the corpus entries
+/// scheduled for the first time. Each token is replaced in turn by its
+/// neighbour and the entry run again by a runner with persistent coverage:
+/// the coverage points hit differently from the original entry are the
+/// code sites the token influences. The points hit differently by two runs
+/// of the original entry are ignored. Disabled without a runner.
+pub struct TaintStage<I> {
+    /// Runner with persistent coverage, if enabled
+    runner: Option<Runner>,
+    phantom: PhantomData<I>,
+}
+
+impl<I> TaintStage<I> {
+    /// Creates a stage probing the entries with a runner instrumented with
+    /// the coverage breakpoints, disabled if not set
+    pub fn new(mut runner: Option<Runner>) -> Self {
+        if let Some(runner) = &mut runner {
+            runner.set_persistent_coverage();
+        }
+
+        TaintStage {
+            runner,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the coverage points hit by a normal run of an input, none if
+    /// it did not exit normally
+    fn hits(runner: &mut Runner, input: &[u8]) -> Option<BTreeSet<u64>> {
+        match runner.run(input).0 {
+            Verdict::Ok => Some(runner.hits().clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<E, EM, I, S, Z> Stage<E, EM, S, Z> for TaintStage<I>
+where
+    I: Input + HasBytesVec,
+    S: HasCorpus<I>,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut S,
+        _manager: &mut EM,
+        corpus_idx: usize,
+    ) -> Result<(), Error> {
+        let runner = match &mut self.runner {
+            Some(runner) => runner,
+            None => return Ok(()),
+        };
+
+        let mut testcase = state.corpus().get(corpus_idx)?.borrow_mut();
+        if testcase.has_metadata::<InputInfluence>() {
+            return Ok(());
+        }
+        let input = testcase.load_input()?.bytes().to_vec();
+        let mut influence = InputInfluence::default();
+
+        // Step 1: Coverage of the entry, without its unstable points
+        let baseline = match (Self::hits(runner, &input), Self::hits(runner, &input)) {
+            (Some(first), Some(second)) => {
+                let unstable: BTreeSet<u64> =
+                    first.symmetric_difference(&second).copied().collect();
+                Some((first, unstable))
+            }
+            _ => None,
+        };
+
+        // Step 2: Coverage of the entry with each token replaced
+        if let Some((baseline, unstable)) = baseline {
+            let len = input.len() & !1;
+            for offset in (0..len).step_by(2).take(MAX_PROBED_TOKENS) {
+                let token = u16::from_le_bytes([input[offset], input[offset + 1]]);
+                let mut probe = input.clone();
+                probe[offset..offset + 2].copy_from_slice(&token.wrapping_add(1).to_le_bytes());
+
+                // Runs not exiting normally are not compared
+                if let Some(hits) = Self::hits(runner, &probe) {
+                    let sites: Vec<u64> = hits
+                        .symmetric_difference(&baseline)
+                        .filter(|site| !unstable.contains(site))
+                        .copied()
+                        .collect();
+                    if !sites.is_empty() {
+                        influence.sites.insert(offset, sites);
+                    }
+                }
+
+                // The probing of a large entry is cut short on shutdown
+                if terminating() {
+                    break;
+                }
+            }
+        }
+
+        log::debug!(
+            "Entry {}: {} influential tokens",
+            corpus_idx,
+            influence.sites.len()
+        );
+        testcase.add_metadata(influence);
+
+        Ok(())
+    }
+}
+
+/// Mutator replacing one of the tokens influencing the coverage of the entry
+/// being fuzzed with a random or a neighbouring token, the tokens influencing
+/// more code sites being picked more often. Skipped for entries without an
+/// influence.
+#[derive(Default)]
+pub struct TaintMutator;
+
+impl TaintMutator {
+    /// Creates the mutator
+    pub fn new() -> Self {
+        TaintMutator
+    }
+}
+
+impl<I, S> Mutator<I, S> for TaintMutator
+where
+    I: Input + HasBytesVec,
+    S: HasRand + HasCorpus<I>,
+{
+    fn mutate(
+        &mut self,
+        state: &mut S,
+        input: &mut I,
+        _stage_idx: i32,
+    ) -> Result<MutationResult, Error> {
+        let idx = match state.corpus().current() {
+            Some(idx) => *idx,
+            None => return Ok(MutationResult::Skipped),
+        };
+
+        // Earlier mutations of the stack may have moved the tokens, the
+        // offsets still falling in the input are kept, with their number of
+        // influenced sites
+        let len = input.bytes().len();
+        let offsets: Vec<(usize, usize)> = match state
+            .corpus()
+            .get(idx)?
+            .borrow()
+            .metadata()
+            .get::<InputInfluence>()
+        {
+            Some(influence) => influence
+                .sites
+                .iter()
+                .filter(|(&offset, _)| offset + 1 < len)
+                .map(|(&offset, sites)| (offset, sites.len()))
+                .collect(),
+            None => Vec::new(),
+        };
+        if offsets.is_empty() {
+            return Ok(MutationResult::Skipped);
+        }
+
+        // Pick an offset with a probability proportional to its sites
+        let rand = state.rand_mut();
+        let total: usize = offsets.iter().map(|&(_, sites)| sites).sum();
+        let mut pick = rand.below(total as u64) as usize;
+        let offset = offsets
+            .iter()
+            .find(|&&(_, sites)| {
+                if pick < sites {
+                    return true;
+                }
+                pick -= sites;
+                false
+            })
+            .map_or(offsets[0].0, |&(offset, _)| offset);
+
+        let bytes = input.bytes_mut();
+        let token = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let token = match rand.below(2) {
+            // Random token
+            0 => rand.below(1 << 16) as u16,
+            // Neighbouring token
+            _ => {
+                let delta = 1 + rand.below(16) as u16;
+                match rand.below(2) {
+                    0 => token.wrapping_add(delta),
+                    _ => token.wrapping_sub(delta),
+                }
+            }
+        };
+        bytes[offset..offset + 2].copy_from_slice(&token.to_le_bytes());
+
+        Ok(MutationResult::Mutated)
+    }
+}
+
+impl Named for TaintMutator {
+    fn name(&self) -> &str {
+        "TaintMutator"
+    }
+}
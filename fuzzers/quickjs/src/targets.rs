@@ -0,0 +1,472 @@
+//! Targets of a campaign and the scheduling of the workers across them
+
+use crate::fuzz::{FuzzerConfig, EXIT_OFFSET, INPUT_SIZE, INPUT_START};
+use crate::shared_map::shared_mapping;
+use crate::sysemu::SyscallPolicy;
+
+use nix::libc;
+use serde::{Deserialize, Deserializer};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tartiflette_vm::{CpuidResult, InputDelivery, Register};
+
+/// Default syscall number of the `get_input` requests of the hypercall
+/// input delivery
+pub const GET_INPUT_SYSCALL: u64 = 0x1337;
+
+/// Duration of the windows over which the coverage growth of the targets is
+/// measured, and interval between two rebalancing checks of a worker
+pub const REBALANCE_INTERVAL: Duration = Duration::from_secs(300);
+/// Factor by which the growth expected on another target must exceed the
+/// growth of the current one for a worker to move
+const REBALANCE_FACTOR: u64 = 2;
+
+/// Snapshot and harness settings of a target
+#[derive(Clone, Debug, Deserialize)]
+pub struct Target {
+    /// Name of the target, naming its directories in the session
+    pub name: String,
+    /// Directory holding the snapshot, breakpoints, tokens and seeds
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+    /// Program module, coverage breakpoints and exit call are relative to
+    #[serde(default = "default_module")]
+    pub module: String,
+    /// Offset of the exit call in the program module
+    #[serde(default = "default_exit_offset", deserialize_with = "offset")]
+    pub exit_offset: u64,
+    /// Syscalls the target is allowed to use, every emulated one if not set
+    #[serde(default)]
+    pub syscalls: Option<SyscallPolicy>,
+    /// Results of the guest CPUID replaced for the campaign
+    #[serde(default)]
+    pub cpuid: Vec<CpuidOverride>,
+    /// Way the harness receives the input, the one given on the command
+    /// line (registers by default) if not set
+    #[serde(default, deserialize_with = "delivery_mode")]
+    pub input_delivery: Option<DeliveryMode>,
+    /// Whether the target comes from a targets file, its outputs going to
+    /// subdirectories named after it
+    #[serde(skip)]
+    nested: bool,
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from("./data")
+}
+
+fn default_module() -> String {
+    "qjs".to_string()
+}
+
+fn default_exit_offset() -> u64 {
+    EXIT_OFFSET
+}
+
+/// Deserializes an offset given as a number or as a `0x` prefixed string
+fn offset<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Offset {
+        Number(u64),
+        Hex(String),
+    }
+
+    match Offset::deserialize(deserializer)? {
+        Offset::Number(offset) => Ok(offset),
+        Offset::Hex(hex) => u64::from_str_radix(hex.trim_start_matches("0x"), 16)
+            .map_err(|_| serde::de::Error::custom(format!("invalid offset {}", hex))),
+    }
+}
+
+/// Deserializes an optional offset, see `offset`
+fn optional_offset<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    offset(deserializer).map(Some)
+}
+
+/// Deserializes an input delivery mode, see `DeliveryMode::from_str`
+fn delivery_mode<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<DeliveryMode>, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Way the harness of a target receives the javascript of a fuzz case
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Written to the input area, its address in rsi and its length in rdx
+    Registers,
+    /// Written to the input area after its length, as a little endian u64
+    Shared,
+    /// Copied to the buffer given by the guest in a `get_input` request, a
+    /// syscall with the given number (rdi: buffer, rsi: buffer size)
+    /// returning the copied length
+    Hypercall(u64),
+}
+
+impl FromStr for DeliveryMode {
+    type Err = String;
+
+    /// Parses `registers`, `shared`, `hypercall` or `hypercall:<number>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "registers" => Ok(DeliveryMode::Registers),
+            None if s == "shared" => Ok(DeliveryMode::Shared),
+            None if s == "hypercall" => Ok(DeliveryMode::Hypercall(GET_INPUT_SYSCALL)),
+            Some(("hypercall", number)) => {
+                let number = match number.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => number.parse(),
+                };
+                number
+                    .map(DeliveryMode::Hypercall)
+                    .map_err(|_| format!("invalid get_input syscall number in {}", s))
+            }
+            _ => Err(format!("unknown input delivery {}", s)),
+        }
+    }
+}
+
+/// Replaced result of a CPUID leaf, the registers not given keeping the
+/// values of the CPUID table
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuidOverride {
+    /// Leaf, in eax
+    #[serde(deserialize_with = "offset")]
+    pub leaf: u64,
+    /// Subleaf, in ecx
+    #[serde(default, deserialize_with = "offset")]
+    pub subleaf: u64,
+    /// Result in eax, if replaced
+    #[serde(default, deserialize_with = "optional_offset")]
+    pub eax: Option<u64>,
+    /// Result in ebx, if replaced
+    #[serde(default, deserialize_with = "optional_offset")]
+    pub ebx: Option<u64>,
+    /// Result in ecx, if replaced
+    #[serde(default, deserialize_with = "optional_offset")]
+    pub ecx: Option<u64>,
+    /// Result in edx, if replaced
+    #[serde(default, deserialize_with = "optional_offset")]
+    pub edx: Option<u64>,
+}
+
+impl CpuidOverride {
+    /// Applies the override to the result of the CPUID table
+    pub fn apply(&self, table: CpuidResult) -> CpuidResult {
+        CpuidResult {
+            eax: self.eax.map_or(table.eax, |value| value as u32),
+            ebx: self.ebx.map_or(table.ebx, |value| value as u32),
+            ecx: self.ecx.map_or(table.ecx, |value| value as u32),
+            edx: self.edx.map_or(table.edx, |value| value as u32),
+        }
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target {
+            name: default_module(),
+            data_dir: default_data_dir(),
+            module: default_module(),
+            exit_offset: EXIT_OFFSET,
+            syscalls: None,
+            cpuid: Vec::new(),
+            input_delivery: None,
+            nested: false,
+        }
+    }
+}
+
+impl Target {
+    /// Snapshot information file
+    pub fn snapshot_info(&self) -> PathBuf {
+        self.data_dir.join("snapshot_info.json")
+    }
+
+    /// Snapshot memory dump
+    pub fn snapshot_data(&self) -> PathBuf {
+        self.data_dir.join("snapshot_data.bin")
+    }
+
+    /// Coverage breakpoints, relative to the program module
+    pub fn breakpoints(&self) -> PathBuf {
+        self.data_dir.join("breakpoints.txt")
+    }
+
+    /// Comparison instructions logged for the input-to-state stage,
+    /// relative to the program module, like the coverage breakpoints
+    pub fn comparisons(&self) -> PathBuf {
+        self.data_dir.join("comparisons.txt")
+    }
+
+    /// Delivery of the inputs to the harness, in the input area unless the
+    /// guest requests them
+    pub fn delivery(&self) -> InputDelivery {
+        match self.input_delivery.unwrap_or(DeliveryMode::Registers) {
+            // The last byte of the area is kept for the null terminator
+            DeliveryMode::Registers => InputDelivery::Registers {
+                address: INPUT_START,
+                capacity: INPUT_SIZE as usize - 1,
+                pointer: Register::Rsi,
+                length: Register::Rdx,
+            },
+            DeliveryMode::Shared => InputDelivery::SharedRegion {
+                address: INPUT_START,
+                capacity: INPUT_SIZE as usize - 1,
+            },
+            DeliveryMode::Hypercall(number) => InputDelivery::Hypercall { number },
+        }
+    }
+
+    /// Token mappings of the encoded inputs
+    pub fn tokens(&self) -> PathBuf {
+        self.data_dir.join("tokens.json")
+    }
+
+    /// Seeds of the target
+    pub fn seeds(&self) -> PathBuf {
+        self.data_dir.join("corpus")
+    }
+
+    /// Directory of the target under a session directory (output, crashes
+    /// or timeouts)
+    pub fn session_dir<P: AsRef<Path>>(&self, dir: P) -> PathBuf {
+        match self.nested {
+            true => dir.as_ref().join(&self.name),
+            false => dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+/// Reads a targets file, a JSON list of targets with unique names
+pub fn read_targets<P: AsRef<Path>>(path: P) -> io::Result<Vec<Target>> {
+    let mut targets: Vec<Target> = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+    for (idx, target) in targets.iter().enumerate() {
+        let valid_name = !target.name.is_empty()
+            && !target.name.contains(std::path::is_separator)
+            && target.name != "."
+            && target.name != "..";
+        if !valid_name || targets[..idx].iter().any(|t| t.name == target.name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid target name {}", target.name),
+            ));
+        }
+    }
+    if targets.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No targets"));
+    }
+
+    for target in &mut targets {
+        target.nested = true;
+    }
+
+    Ok(targets)
+}
+
+/// Returns the targets of the campaign, the snapshot of `./data` unless a
+/// targets file is configured
+pub fn load_targets(config: &FuzzerConfig) -> Vec<Target> {
+    let mut targets = match config.targets {
+        Some(path) => read_targets(path).expect("Could not load targets"),
+        None => vec![Target::default()],
+    };
+
+    for target in &mut targets {
+        target.input_delivery = target.input_delivery.or(config.input_delivery);
+    }
+    targets
+}
+
+/// Returns the target the subcommands run, the one named by `--target` or
+/// the first one
+pub fn selected_target(config: &FuzzerConfig) -> Target {
+    let mut targets = load_targets(config);
+
+    match config.target {
+        Some(name) => {
+            let idx = targets
+                .iter()
+                .position(|t| t.name == name)
+                .expect("Unknown target");
+            targets.swap_remove(idx)
+        }
+        None => targets.swap_remove(0),
+    }
+}
+
+/// Scheduling of the workers across the targets
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TargetSchedule {
+    /// Workers are split evenly across the targets once and for all
+    Static,
+    /// Workers start split evenly and move to the targets whose coverage
+    /// grows the fastest
+    Dynamic,
+}
+
+impl FromStr for TargetSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "static" => Ok(TargetSchedule::Static),
+            "dynamic" => Ok(TargetSchedule::Dynamic),
+            _ => Err(format!("unknown target schedule {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for TargetSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetSchedule::Static => write!(f, "static"),
+            TargetSchedule::Dynamic => write!(f, "dynamic"),
+        }
+    }
+}
+
+/// Seconds since the epoch, comparable across the processes
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Assignments of the workers to the targets and coverage growth of the
+/// targets, shared by the clients like the coverage maps. For each worker
+/// it holds its target index plus one (zero if unassigned), then for each
+/// target the covered entries and the time of the last sample, and the
+/// growth over the last window.
+pub struct TargetBoard {
+    /// Cells of the board
+    cells: *mut AtomicU64,
+    /// Number of workers
+    workers: usize,
+    /// Number of targets
+    targets: usize,
+}
+
+// The cells are only accessed atomically
+unsafe impl Send for TargetBoard {}
+unsafe impl Sync for TargetBoard {}
+
+impl TargetBoard {
+    /// Creates a board for `workers` workers and `targets` targets, shared
+    /// with the processes forked afterwards
+    pub fn new(workers: usize, targets: usize) -> Self {
+        let len = workers + 3 * targets;
+        let cells = shared_mapping(len * std::mem::size_of::<AtomicU64>());
+
+        TargetBoard {
+            cells: cells as *mut AtomicU64,
+            workers,
+            targets,
+        }
+    }
+
+    /// Returns the cells of the board
+    #[inline]
+    fn cells(&self) -> &[AtomicU64] {
+        unsafe { slice::from_raw_parts(self.cells, self.workers + 3 * self.targets) }
+    }
+
+    /// Returns the sample cells of a target: covered entries, sample time
+    /// and growth
+    #[inline]
+    fn sample(&self, target: usize) -> &[AtomicU64] {
+        let start = self.workers + 3 * target;
+        &self.cells()[start..start + 3]
+    }
+
+    /// Returns the target of a worker. Workers start split evenly, and keep
+    /// their target when they are restarted.
+    pub fn target_of(&self, worker: usize) -> usize {
+        match self.cells()[worker].load(Ordering::Relaxed) {
+            0 => worker % self.targets,
+            target => target as usize - 1,
+        }
+    }
+
+    /// Assigns a worker to a target
+    pub fn assign(&self, worker: usize, target: usize) {
+        self.cells()[worker].store(target as u64 + 1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of workers on a target
+    pub fn workers(&self, target: usize) -> u64 {
+        (0..self.workers)
+            .filter(|&worker| self.target_of(worker) == target)
+            .count() as u64
+    }
+
+    /// Records the entries covered on a target. Once per window, the first
+    /// worker of the target recording them updates its growth.
+    pub fn record(&self, target: usize, covered: usize) {
+        let cells = self.sample(target);
+        let now = now_secs();
+        let last = cells[1].load(Ordering::Relaxed);
+
+        if now.saturating_sub(last) < REBALANCE_INTERVAL.as_secs()
+            || cells[1]
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+
+        let previous = cells[0].swap(covered as u64, Ordering::Relaxed);
+        cells[2].store((covered as u64).saturating_sub(previous), Ordering::Relaxed);
+    }
+
+    /// Returns the coverage growth of a target over the last window
+    pub fn growth(&self, target: usize) -> u64 {
+        self.sample(target)[2].load(Ordering::Relaxed)
+    }
+
+    /// Returns the target a worker of `current` should move to, if any.
+    /// The growth of a target is assumed to be shared by its workers: the
+    /// worker moves if it would get `REBALANCE_FACTOR` times more of it on
+    /// another target. The last worker of a target never leaves it.
+    pub fn rebalance(&self, current: usize) -> Option<usize> {
+        let workers = self.workers(current);
+        if workers <= 1 {
+            return None;
+        }
+
+        // Growth per worker, compared as fractions
+        let own = (self.growth(current), workers);
+        let (target, growth, workers) = (0..self.targets)
+            .filter(|&target| target != current)
+            .map(|target| (target, self.growth(target), self.workers(target) + 1))
+            .max_by(|a, b| (a.1 * b.2).cmp(&(b.1 * a.2)))?;
+
+        match growth * own.1 > REBALANCE_FACTOR * own.0 * workers {
+            true => Some(target),
+            false => None,
+        }
+    }
+}
+
+impl Drop for TargetBoard {
+    fn drop(&mut self) {
+        let len = (self.workers + 3 * self.targets) * std::mem::size_of::<AtomicU64>();
+        unsafe {
+            libc::munmap(self.cells as *mut libc::c_void, len);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use crate::executor::install_alarm_handler;
+use crate::fuzz::FuzzerConfig;
+use crate::runner::{Runner, Verdict};
+use crate::sysemu::describe_syscall;
+use crate::targets::selected_target;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tartiflette_vm::Symbolizer;
+
+/// Lists the inputs of a directory, leaving out reports and hidden files
+pub(crate) fn list_inputs<P: AsRef<Path>>(dir: P) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .expect("Could not list directory")
+        .map(|entry| entry.expect("Could not list directory").path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            path.is_file() && !name.starts_with('.') && !name.ends_with(".txt")
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Replays each input of a crash directory and groups them by crash
+pub fn triage<P: AsRef<Path>>(config: FuzzerConfig, crash_dir: P) {
+    install_alarm_handler();
+    let mut runner = Runner::new(
+        &selected_target(&config),
+        config.dictionary,
+        config.timeout,
+        false,
+    );
+    let mut symbolizer = Symbolizer::new(runner.modules());
+
+    // Inputs of each crash name (or `ok` / `timeout`)
+    let mut classes: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for path in list_inputs(crash_dir) {
+        let input = fs::read(&path).expect("Could not read input file");
+        let class = match runner.run(&input).0 {
+            Verdict::Ok => "ok".to_string(),
+            Verdict::Crash(crash, _) => {
+                log::info!(
+                    "{}: crashed in {}",
+                    path.display(),
+                    symbolizer.symbolize(crash.pc)
+                );
+                crash.name()
+            }
+            Verdict::Timeout => "timeout".to_string(),
+            Verdict::Denied(syscall) => format!("denied syscall {}", describe_syscall(syscall)),
+        };
+
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        println!("{}: {}", name, class);
+        classes.entry(class).or_default().push(name);
+    }
+
+    println!();
+    for (class, inputs) in &classes {
+        println!("{} ({} inputs)", class, inputs.len());
+    }
+}
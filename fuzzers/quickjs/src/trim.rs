@@ -0,0 +1,168 @@
+//! Trimming of the corpus entries, keeping the coverage they reach
+
+use crate::runner::{Runner, Verdict};
+use crate::shutdown::terminating;
+
+use libafl::{
+    corpus::Corpus,
+    inputs::{HasBytesVec, Input},
+    stages::Stage,
+    state::{HasCorpus, HasMetadata},
+    Error,
+};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+use std::marker::PhantomData;
+
+/// Maximum number of runs spent trimming an entry
+const MAX_TRIM_RUNS: usize = 512;
+
+/// Size of an entry before it was trimmed, marking the entries already
+/// trimmed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TrimmedInput {
+    /// Size of the encoded input before trimming
+    pub original_len: usize,
+}
+
+libafl::impl_serdeany!(TrimmedInput);
+
+/// Stage trimming the corpus entries scheduled for the first time. Chunks
+/// of tokens are removed, halving their size down to one token, as long as
+/// the entry still exits normally and hits the same coverage points, run by
+/// a runner with persistent coverage. The points hit differently by two runs
+/// of the original entry are ignored. The smaller entry replaces the
+/// original one, on disk too. Disabled without a runner.
+pub struct TrimStage<I> {
+    /// Runner with persistent coverage, if enabled
+    runner: Option<Runner>,
+    phantom: PhantomData<I>,
+}
+
+impl<I> TrimStage<I> {
+    /// Creates a stage trimming the entries with a runner instrumented with
+    /// the coverage breakpoints, disabled if not set
+    pub fn new(mut runner: Option<Runner>) -> Self {
+        if let Some(runner) = &mut runner {
+            runner.set_persistent_coverage();
+        }
+
+        TrimStage {
+            runner,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the stable coverage points hit by a normal run of an input,
+    /// none if it did not exit normally
+    fn signature(
+        runner: &mut Runner,
+        input: &[u8],
+        unstable: &BTreeSet<u64>,
+    ) -> Option<BTreeSet<u64>> {
+        match runner.run(input).0 {
+            Verdict::Ok => Some(runner.hits().difference(unstable).copied().collect()),
+            _ => None,
+        }
+    }
+
+    /// Removes chunks of tokens of `input` as long as its coverage signature
+    /// stays `signature`, within `MAX_TRIM_RUNS` runs
+    fn trim(
+        runner: &mut Runner,
+        input: &mut Vec<u8>,
+        signature: &BTreeSet<u64>,
+        unstable: &BTreeSet<u64>,
+    ) {
+        let mut runs = 0;
+
+        let mut chunk = (input.len() / 2) & !1;
+        while chunk >= 2 {
+            let mut offset = 0;
+            while offset + chunk <= input.len() {
+                if runs == MAX_TRIM_RUNS || terminating() {
+                    return;
+                }
+                runs += 1;
+
+                let mut candidate = input[..offset].to_vec();
+                candidate.extend_from_slice(&input[offset + chunk..]);
+
+                match Self::signature(runner, &candidate, unstable).as_ref() == Some(signature) {
+                    true => *input = candidate,
+                    false => offset += chunk,
+                }
+            }
+            chunk = (chunk / 2) & !1;
+        }
+    }
+}
+
+impl<E, EM, I, S, Z> Stage<E, EM, S, Z> for TrimStage<I>
+where
+    I: Input + HasBytesVec,
+    S: HasCorpus<I>,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut S,
+        _manager: &mut EM,
+        corpus_idx: usize,
+    ) -> Result<(), Error> {
+        let runner = match &mut self.runner {
+            Some(runner) => runner,
+            None => return Ok(()),
+        };
+
+        let mut testcase = state.corpus().get(corpus_idx)?.borrow_mut();
+        if testcase.has_metadata::<TrimmedInput>() {
+            return Ok(());
+        }
+        let original = testcase.load_input()?.clone();
+        let original_len = original.bytes().len();
+
+        // Step 1: Coverage of the entry, without its unstable points
+        let none = BTreeSet::new();
+        let baseline = match (
+            Self::signature(runner, original.bytes(), &none),
+            Self::signature(runner, original.bytes(), &none),
+        ) {
+            (Some(first), Some(second)) => {
+                let unstable: BTreeSet<u64> =
+                    first.symmetric_difference(&second).copied().collect();
+                let signature = first.difference(&unstable).copied().collect();
+                Some((signature, unstable))
+            }
+            _ => None,
+        };
+
+        // Step 2: Removal of the chunks not changing the coverage
+        if let Some((signature, unstable)) = baseline {
+            let mut bytes = original.bytes().to_vec();
+            Self::trim(runner, &mut bytes, &signature, &unstable);
+
+            if bytes.len() < original_len {
+                let mut trimmed = original;
+                *trimmed.bytes_mut() = bytes;
+                if let Some(filename) = testcase.filename() {
+                    trimmed.to_file(filename)?;
+                }
+
+                log::debug!(
+                    "Entry {}: trimmed from {} to {} bytes",
+                    corpus_idx,
+                    original_len,
+                    trimmed.bytes().len()
+                );
+                testcase.set_input(trimmed);
+            }
+        }
+
+        testcase.add_metadata(TrimmedInput { original_len });
+
+        Ok(())
+    }
+}
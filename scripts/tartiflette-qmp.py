@@ -0,0 +1,304 @@
+import argparse
+import json
+import os
+import re
+import socket
+import struct
+import tempfile
+from typing import Optional, Dict, List, Tuple, Any
+
+
+PAGE_SIZE = 0x1000
+# Physical address bits of a page table entry
+ADDRESS_MASK = 0x000ffffffffff000
+
+# Page table entry flags
+PTE_PRESENT = 1 << 0
+PTE_WRITABLE = 1 << 1
+PTE_USER = 1 << 2
+PTE_HUGE = 1 << 7
+PTE_NX = 1 << 63
+
+# Region used by tartiflette-vm for its IDT, GDT and TSS, which cannot be
+# part of the snapshot
+RESERVED_START = 0xffffffffff000000
+RESERVED_END = RESERVED_START + 5 * PAGE_SIZE
+
+# Registers of the snapshot, as named by `info registers`
+qemu_registers = {
+    "RAX": "rax", "RBX": "rbx", "RCX": "rcx", "RDX": "rdx",
+    "RSI": "rsi", "RDI": "rdi", "RBP": "rbp", "RSP": "rsp",
+    "R8": "r8", "R9": "r9", "R10": "r10", "R11": "r11",
+    "R12": "r12", "R13": "r13", "R14": "r14", "R15": "r15",
+    "RIP": "rip", "RFL": "rflags", "EFER": "efer",
+}
+
+
+def parse_address(value: str) -> int:
+    return int(value, 0)
+
+
+def parse_endpoint(value: str) -> Tuple[str, int]:
+    host, port = value.rsplit(":", 1)
+    return host, int(port)
+
+
+class Qmp:
+    """Minimal QMP client"""
+
+    def __init__(self, address: str) -> None:
+        if ":" in address and not os.path.exists(address):
+            self.sock = socket.create_connection(parse_endpoint(address))
+        else:
+            self.sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+            self.sock.connect(address)
+        self.reader = self.sock.makefile("r")
+
+        # Greeting, then leave the capabilities negotiation mode
+        self.receive()
+        self.execute("qmp_capabilities")
+
+    def receive(self) -> Dict[str, Any]:
+        line = self.reader.readline()
+        if not line:
+            raise Exception("QMP connection closed")
+        return json.loads(line)
+
+    def execute(self, command: str, **arguments: Any) -> Any:
+        request: Dict[str, Any] = {"execute": command}
+        if arguments:
+            request["arguments"] = arguments
+        self.sock.sendall(json.dumps(request).encode() + b"\n")
+
+        # Skip the asynchronous events
+        while True:
+            response = self.receive()
+            if "error" in response:
+                raise Exception(f"{command}: {response['error']['desc']}")
+            if "return" in response:
+                return response["return"]
+
+    def hmp(self, command: str) -> str:
+        return self.execute("human-monitor-command", **{"command-line": command})
+
+
+class GdbStub:
+    """Minimal gdb remote protocol client, to stop the guest on a breakpoint"""
+
+    def __init__(self, address: str) -> None:
+        self.sock = socket.create_connection(parse_endpoint(address))
+
+    def send(self, packet: str) -> None:
+        checksum = sum(packet.encode()) & 0xff
+        self.sock.sendall(f"${packet}#{checksum:02x}".encode())
+
+    def receive(self) -> str:
+        data = b""
+        while b"#" not in data or len(data) < data.index(b"#") + 3:
+            chunk = self.sock.recv(4096)
+            if not chunk:
+                raise Exception("gdb connection closed")
+            data += chunk
+
+        # Acknowledge the packet
+        self.sock.sendall(b"+")
+        return data[data.index(b"$") + 1:data.index(b"#")].decode()
+
+    def command(self, packet: str) -> str:
+        self.send(packet)
+        return self.receive()
+
+    def run_until(self, address: int) -> None:
+        # Hardware breakpoints also work on pages not mapped yet
+        if self.command(f"Z1,{address:x},1") != "OK":
+            raise Exception(f"Could not set a breakpoint on 0x{address:x}")
+
+        print(f"Running until 0x{address:x}")
+        self.send("c")
+        stop = self.receive()
+        if not stop.startswith(("S", "T")):
+            raise Exception(f"Unexpected stop reply: {stop}")
+
+        self.command(f"z1,{address:x},1")
+
+
+class PhysicalMemory:
+    """Guest physical memory from a `dump-guest-memory` ELF file"""
+
+    def __init__(self, filename: str) -> None:
+        self.file = open(filename, "rb")
+
+        header = self.file.read(64)
+        if header[:4] != b"\x7fELF":
+            raise Exception("Memory dump is not an ELF file")
+
+        phoff, = struct.unpack_from("<Q", header, 0x20)
+        phentsize, phnum = struct.unpack_from("<HH", header, 0x36)
+
+        # (physical address, size, file offset) of the loaded segments
+        self.segments: List[Tuple[int, int, int]] = []
+        for i in range(phnum):
+            self.file.seek(phoff + i * phentsize)
+            p_type, _, p_offset, _, p_paddr, p_filesz = struct.unpack(
+                "<IIQQQQ", self.file.read(40))
+            if p_type == 1:
+                self.segments.append((p_paddr, p_filesz, p_offset))
+
+    def read(self, address: int, size: int) -> Optional[bytes]:
+        for start, length, offset in self.segments:
+            if start <= address and address + size <= start + length:
+                self.file.seek(offset + address - start)
+                return self.file.read(size)
+        return None
+
+    def read_u64(self, address: int) -> int:
+        data = self.read(address, 8)
+        return struct.unpack("<Q", data)[0] if data else 0
+
+
+def walk_page_tables(memory: PhysicalMemory, cr3: int) -> List[Tuple[int, int, str]]:
+    """Returns the (virtual address, physical address, permissions) of the
+    pages mapped by a 4-level page table. User pages are flagged with a 'u'
+    in place of the 'p'."""
+    pages = []
+
+    def walk(table: int, level: int, base: int, writable: bool, executable: bool,
+             user: bool) -> None:
+        shift = 12 + 9 * level
+
+        for index in range(512):
+            entry = memory.read_u64(table + index * 8)
+            if not entry & PTE_PRESENT:
+                continue
+
+            address = base | (index << shift)
+            entry_writable = writable and bool(entry & PTE_WRITABLE)
+            entry_executable = executable and not entry & PTE_NX
+            entry_user = user and bool(entry & PTE_USER)
+
+            if level == 0 or (level < 3 and entry & PTE_HUGE):
+                perms = "r" + ("w" if entry_writable else "-") + ("x" if entry_executable else "-")
+                perms += "u" if entry_user else "p"
+                frame = entry & ADDRESS_MASK & ~((1 << shift) - 1)
+                for offset in range(0, 1 << shift, PAGE_SIZE):
+                    pages.append((address + offset, frame + offset, perms))
+            else:
+                walk(entry & ADDRESS_MASK, level - 1, address, entry_writable, entry_executable,
+                     entry_user)
+
+    walk(cr3 & ADDRESS_MASK, 3, 0, True, True, True)
+
+    # Canonical form of the addresses
+    return [(v | 0xffff000000000000 if v & (1 << 47) else v, p, perms) for v, p, perms in pages]
+
+
+def dump_mappings(memory: PhysicalMemory, cr3: int, ranges: List[Tuple[int, int]],
+                  filename: str) -> List[Dict[str, str]]:
+    mappings: List[Dict[str, str]] = []
+    offset = 0
+
+    with open(filename, "wb") as data_out:
+        for vaddr, paddr, perms in sorted(walk_page_tables(memory, cr3)):
+            if RESERVED_START <= vaddr < RESERVED_END:
+                continue
+            if ranges and not any(start <= vaddr < end for start, end in ranges):
+                continue
+
+            # Pages missing from the dump (e.g. devices) are skipped
+            data = memory.read(paddr, PAGE_SIZE)
+            if data is None:
+                continue
+            data_out.write(data)
+
+            # Extend the last mapping when contiguous
+            last = mappings[-1] if mappings else None
+            if last and int(last["end"], 16) == vaddr and last["permissions"] == perms:
+                last["end"] = f"{vaddr + PAGE_SIZE:x}"
+            else:
+                mappings.append({
+                    "start": f"{vaddr:x}",
+                    "end": f"{vaddr + PAGE_SIZE:x}",
+                    "physical_offset": f"{offset:x}",
+                    "permissions": perms,
+                })
+            offset += PAGE_SIZE
+
+    for mapping in mappings:
+        print(f"Dumped range 0x{mapping['start']} -> 0x{mapping['end']} {mapping['permissions']}")
+
+    return mappings
+
+
+def dump_registers(qmp: Qmp) -> Tuple[Dict[str, str], int]:
+    info = qmp.hmp("info registers")
+    register_data = {}
+
+    for name, value in re.findall(r"\b([A-Z][A-Z0-9]*)\s*=\s*([0-9a-f]+)\b", info):
+        if name in qemu_registers:
+            register_data[qemu_registers[name]] = f"{int(value, 16):x}"
+
+    # Segment lines hold the selector before the base
+    for name in ("FS", "GS"):
+        match = re.search(rf"^{name}\s*=[0-9a-f]+ ([0-9a-f]+)", info, re.MULTILINE)
+        if match is None:
+            raise Exception(f"Could not find the {name} base")
+        register_data[f"{name.lower()}_base"] = f"{int(match.group(1), 16):x}"
+
+    match = re.search(r"\bCR3=([0-9a-f]+)", info)
+    if match is None:
+        raise Exception("Could not find CR3")
+
+    return register_data, int(match.group(1), 16)
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description="Capture a tartiflette snapshot of a QEMU guest")
+    parser.add_argument("qmp", help="QMP socket (unix socket path or host:port)")
+    parser.add_argument("--gdb", help="gdb stub (host:port), to run the guest until --break")
+    parser.add_argument("--break", dest="breakpoint", type=parse_address,
+                        help="address the snapshot is taken at")
+    parser.add_argument("--range", dest="ranges", action="append", default=[],
+                        help="only dump the virtual range start-end (can be repeated)")
+    parser.add_argument("--info", default="snapshot_info.json", help="output info file")
+    parser.add_argument("--memory", default="snapshot_data.bin", help="output memory file")
+    args = parser.parse_args()
+
+    if (args.gdb is None) != (args.breakpoint is None):
+        parser.error("--gdb and --break go together")
+
+    ranges = []
+    for value in args.ranges:
+        start, end = value.split("-")
+        ranges.append((parse_address(start), parse_address(end)))
+
+    qmp = Qmp(args.qmp)
+
+    # Stop the guest, on the breakpoint if any
+    if args.breakpoint is not None:
+        GdbStub(args.gdb).run_until(args.breakpoint)
+    elif qmp.execute("query-status")["running"]:
+        qmp.execute("stop")
+
+    # Dump registers
+    registers, cr3 = dump_registers(qmp)
+    print(f"Guest stopped at 0x{registers['rip']}, cr3 0x{cr3:x}")
+
+    # Dump the physical memory, then the pages mapped by the guest
+    with tempfile.TemporaryDirectory() as directory:
+        dump_file = os.path.join(directory, "memory.elf")
+        qmp.execute("dump-guest-memory", paging=False, protocol=f"file:{dump_file}")
+
+        memory = PhysicalMemory(dump_file)
+        snapshot_info: Dict[str, Any] = {
+            "memory_file": args.memory,
+            "mappings": dump_mappings(memory, cr3, ranges, args.memory),
+            "registers": registers,
+        }
+
+    # Write out the json information
+    with open(args.info, "w") as info_file:
+        info_file.write(json.dumps(snapshot_info))
+
+
+if __name__ == '__main__':
+    main()
@@ -0,0 +1,26 @@
+/*
+ * Guest side helpers of tartiflette-vm
+ *
+ * Calling tartiflette_snapshot() marks the point the fuzzing starts from:
+ * Vm::run_until_snapshot_point stops the guest there and freezes its state.
+ * Every fuzz case then resumes right after the call, which returns 0.
+ */
+
+#ifndef TARTIFLETTE_H
+#define TARTIFLETTE_H
+
+/* Syscall number of the snapshot hypercall ("tart") */
+#define TARTIFLETTE_SNAPSHOT_HYPERCALL 0x74726174
+
+static inline long tartiflette_snapshot(void)
+{
+    long ret;
+
+    __asm__ volatile("syscall"
+                     : "=a"(ret)
+                     : "a"(TARTIFLETTE_SNAPSHOT_HYPERCALL)
+                     : "rcx", "r11", "memory");
+    return ret;
+}
+
+#endif /* TARTIFLETTE_H */
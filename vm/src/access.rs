@@ -0,0 +1,157 @@
+//! Guest memory accesses recorded in the logged ranges
+
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+/// Magic starting an access trace
+const TRACE_MAGIC: &[u8; 8] = b"TARTACC1";
+
+/// Size of a record of an access trace: pc, address, size and kind
+const RECORD_SIZE: usize = 18;
+
+/// Kind of a memory access
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AccessKind {
+    /// The guest read the memory
+    Read,
+    /// The guest wrote the memory
+    Write,
+}
+
+/// Guest access to a logged range
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MemoryAccess {
+    /// Address of the accessing instruction
+    pub pc: u64,
+    /// First address accessed in the page that faulted
+    pub address: u64,
+    /// Size of the memory operand, 0 if the instruction could not be
+    /// decoded
+    pub size: u8,
+    /// Kind of the access
+    pub kind: AccessKind,
+}
+
+impl MemoryAccess {
+    /// Returns the accessed range, of one byte if the size is not known
+    pub fn range(&self) -> Range<u64> {
+        self.address..self.address + self.size.max(1) as u64
+    }
+}
+
+/// Returns the instructions which accessed a range, e.g. an input buffer
+pub fn accessing_sites(accesses: &[MemoryAccess], range: &Range<u64>) -> BTreeSet<u64> {
+    accesses
+        .iter()
+        .filter(|access| {
+            let accessed = access.range();
+            accessed.start < range.end && range.start < accessed.end
+        })
+        .map(|access| access.pc)
+        .collect()
+}
+
+/// Writes accesses as a compact binary trace: a magic, then fixed size
+/// records (pc: u64, address: u64, size: u8, kind: u8), little-endian
+pub fn write_access_trace<W: Write>(mut out: W, accesses: &[MemoryAccess]) -> io::Result<()> {
+    out.write_all(TRACE_MAGIC)?;
+
+    for access in accesses {
+        let mut record = [0u8; RECORD_SIZE];
+        record[0..8].copy_from_slice(&access.pc.to_le_bytes());
+        record[8..16].copy_from_slice(&access.address.to_le_bytes());
+        record[16] = access.size;
+        record[17] = match access.kind {
+            AccessKind::Read => 0,
+            AccessKind::Write => 1,
+        };
+        out.write_all(&record)?;
+    }
+
+    Ok(())
+}
+
+/// Reads a trace written by `write_access_trace`
+pub fn read_access_trace<R: Read>(mut input: R) -> io::Result<Vec<MemoryAccess>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != TRACE_MAGIC {
+        return Err(invalid("Not an access trace"));
+    }
+
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    if data.len() % RECORD_SIZE != 0 {
+        return Err(invalid("Truncated access trace"));
+    }
+
+    data.chunks_exact(RECORD_SIZE)
+        .map(|record| {
+            Ok(MemoryAccess {
+                pc: u64::from_le_bytes(record[0..8].try_into().unwrap()),
+                address: u64::from_le_bytes(record[8..16].try_into().unwrap()),
+                size: record[16],
+                kind: match record[17] {
+                    0 => AccessKind::Read,
+                    1 => AccessKind::Write,
+                    _ => return Err(invalid("Invalid access kind")),
+                },
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accessing_sites, read_access_trace, write_access_trace, AccessKind, MemoryAccess};
+
+    /// Returns accesses of two instructions to a buffer at 0x2000
+    fn accesses() -> Vec<MemoryAccess> {
+        vec![
+            MemoryAccess {
+                pc: 0x1000,
+                address: 0x2000,
+                size: 8,
+                kind: AccessKind::Read,
+            },
+            MemoryAccess {
+                pc: 0x1010,
+                address: 0x2010,
+                size: 0,
+                kind: AccessKind::Write,
+            },
+        ]
+    }
+
+    #[test]
+    /// Writes a trace and reads it back
+    fn test_access_trace() -> std::io::Result<()> {
+        let mut trace = Vec::new();
+        write_access_trace(&mut trace, &accesses())?;
+        assert_eq!(trace.len(), 8 + 2 * 18);
+
+        assert_eq!(read_access_trace(trace.as_slice())?, accesses());
+        assert!(read_access_trace(&trace[..trace.len() - 1]).is_err());
+        assert!(read_access_trace(&b"TARTACC0"[..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    /// Finds the instructions accessing parts of the buffer
+    fn test_accessing_sites() {
+        let accesses = accesses();
+
+        assert_eq!(
+            accessing_sites(&accesses, &(0x2004..0x2005))
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![0x1000]
+        );
+        assert_eq!(accessing_sites(&accesses, &(0x2000..0x2011)).len(), 2);
+        assert!(accessing_sites(&accesses, &(0x2008..0x2010)).is_empty());
+    }
+}
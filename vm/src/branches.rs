@@ -0,0 +1,170 @@
+//! Branches recorded by the processor while the guest runs
+
+use std::str::FromStr;
+
+/// Branch taken by the guest, from the address of the branch instruction to
+/// its target
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Branch {
+    /// Address of the branch instruction
+    pub from: u64,
+    /// Target of the branch
+    pub to: u64,
+}
+
+impl Branch {
+    /// Returns the AFL-style hash of the edge, `from >> 1 ^ to`, usable as an
+    /// index in an edge coverage map
+    #[inline]
+    pub fn edge_hash(&self) -> u64 {
+        (self.from >> 1) ^ self.to
+    }
+}
+
+/// Mechanism recording the branches of the guest
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BranchRecording {
+    /// Last Branch Record stack, holding the last few branches taken before
+    /// each exit. Near free at run time, but partial.
+    Lbr,
+}
+
+impl FromStr for BranchRecording {
+    type Err = ();
+
+    /// Parses the lowercase name of the mechanism
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lbr" => Ok(BranchRecording::Lbr),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Returns the canonical form of an address read from a LBR entry, whose
+/// upper bits can hold flags (misprediction, TSX) depending on the format
+pub(crate) fn canonical(address: u64) -> u64 {
+    ((address << 16) as i64 >> 16) as u64
+}
+
+/// Returns the branches of a LBR stack, oldest first. `tos` is the index of
+/// the most recent entry, `from` and `to` the entries of the stack. Empty
+/// entries, cleared since the last read, are skipped.
+pub(crate) fn lbr_branches(tos: u64, from: &[u64], to: &[u64]) -> Vec<Branch> {
+    let depth = from.len().min(to.len());
+    if depth == 0 {
+        return Vec::new();
+    }
+
+    (1..=depth)
+        .map(|offset| (tos as usize + offset) % depth)
+        .filter(|&index| from[index] != 0 || to[index] != 0)
+        .map(|index| Branch {
+            from: canonical(from[index]),
+            to: canonical(to[index]),
+        })
+        .collect()
+}
+
+/// Returns the branches of a LBR stack recorded after the entry `last`,
+/// oldest first. The entry `last` was cleared while it was the most recent:
+/// if it holds a branch again, the stack wrapped around and every entry is
+/// new.
+pub(crate) fn lbr_branches_since(tos: u64, last: u64, from: &[u64], to: &[u64]) -> Vec<Branch> {
+    let depth = from.len().min(to.len());
+    if depth == 0 {
+        return Vec::new();
+    }
+
+    let last = last as usize % depth;
+    let count = match from[last] != 0 || to[last] != 0 {
+        true => depth,
+        false => (tos as usize + depth - last) % depth,
+    };
+
+    let mut branches = lbr_branches(tos, from, to);
+    branches.drain(..branches.len().saturating_sub(count));
+    branches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical, lbr_branches, lbr_branches_since, Branch};
+
+    #[test]
+    /// Tests that the flags of the LBR entries are stripped
+    fn test_canonical() {
+        assert_eq!(canonical(0x4011_2233), 0x4011_2233);
+        assert_eq!(canonical(0x8000_0000_4011_2233), 0x4011_2233);
+        assert_eq!(canonical(0x6000_7fff_ffff_f000), 0x7fff_ffff_f000);
+        assert_eq!(canonical(0x0000_ffff_8000_1000), 0xffff_ffff_8000_1000);
+    }
+
+    #[test]
+    /// Tests that the LBR stack is read oldest first from the top of stack
+    fn test_lbr_branches() {
+        let from = [0x1000, 0x2000, 0, 0x4000];
+        let to = [0x1100, 0x2200, 0, 0x4400];
+
+        assert_eq!(
+            lbr_branches(1, &from, &to),
+            vec![
+                Branch {
+                    from: 0x4000,
+                    to: 0x4400
+                },
+                Branch {
+                    from: 0x1000,
+                    to: 0x1100
+                },
+                Branch {
+                    from: 0x2000,
+                    to: 0x2200
+                },
+            ]
+        );
+        assert!(lbr_branches(0, &[], &[]).is_empty());
+    }
+
+    #[test]
+    /// Tests that the edge hash depends on the direction of the branch
+    fn test_edge_hash() {
+        let forward = Branch {
+            from: 0x1000,
+            to: 0x2000,
+        };
+        let backward = Branch {
+            from: 0x2000,
+            to: 0x1000,
+        };
+
+        assert_ne!(forward.edge_hash(), backward.edge_hash());
+    }
+
+    #[test]
+    /// Tests that only the entries written after the cleared one are new
+    fn test_lbr_branches_since() {
+        let branch = |address| Branch {
+            from: address,
+            to: address + 0x100,
+        };
+
+        // Entries 2 and 3 written after entry 1 was cleared
+        let from = [0x1000, 0, 0x3000, 0x4000];
+        let to = [0x1100, 0, 0x3100, 0x4100];
+        assert_eq!(
+            lbr_branches_since(3, 1, &from, &to),
+            vec![branch(0x3000), branch(0x4000)]
+        );
+
+        // Nothing written since
+        assert!(lbr_branches_since(1, 1, &from, &to).is_empty());
+
+        // The stack wrapped around past the cleared entry
+        let from = [0x1000, 0x2000, 0x3000, 0x4000];
+        let to = [0x1100, 0x2100, 0x3100, 0x4100];
+        assert_eq!(lbr_branches_since(1, 1, &from, &to).len(), 4);
+        assert_eq!(lbr_branches_since(2, 1, &from, &to)[0], branch(0x4000));
+        assert!(lbr_branches_since(0, 0, &[], &[]).is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+//! Coverage relative to the snapshot modules
+
+use crate::crash::{resolve, ModuleOffset};
+use crate::snapshot::SnapshotModule;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Write};
+
+/// Coverage stored as (module id, offset) pairs instead of absolute
+/// addresses, so it survives ASLR differences between snapshots and can be
+/// merged across campaigns. Module ids index the module names in the order
+/// they were first seen.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleCoverage {
+    /// Module names, by module id
+    modules: Vec<String>,
+    /// Covered (module id, offset) pairs
+    points: BTreeSet<(usize, u64)>,
+}
+
+impl ModuleCoverage {
+    /// Creates an empty coverage
+    pub fn new() -> ModuleCoverage {
+        ModuleCoverage::default()
+    }
+
+    /// Resolves absolute addresses through the snapshot modules. Addresses
+    /// outside any module are dropped.
+    pub fn from_addresses<I: IntoIterator<Item = u64>>(
+        modules: &BTreeMap<String, SnapshotModule>,
+        addresses: I,
+    ) -> ModuleCoverage {
+        let mut coverage = ModuleCoverage::new();
+
+        for address in addresses {
+            coverage.insert_address(modules, address);
+        }
+
+        coverage
+    }
+
+    /// Returns the id of a module, assigning a new one if needed
+    fn module_id(&mut self, module: &str) -> usize {
+        match self.modules.iter().position(|m| m == module) {
+            Some(id) => id,
+            None => {
+                self.modules.push(module.to_string());
+                self.modules.len() - 1
+            }
+        }
+    }
+
+    /// Returns the name of a module, by id
+    pub fn module_name(&self, id: usize) -> Option<&str> {
+        self.modules.get(id).map(String::as_str)
+    }
+
+    /// Adds a point. Returns true if it was not covered yet.
+    pub fn insert(&mut self, point: &ModuleOffset) -> bool {
+        let id = self.module_id(&point.module);
+        self.points.insert((id, point.offset))
+    }
+
+    /// Adds an absolute address resolved through the snapshot modules.
+    /// Returns true if it is in a module and was not covered yet.
+    pub fn insert_address(
+        &mut self,
+        modules: &BTreeMap<String, SnapshotModule>,
+        address: u64,
+    ) -> bool {
+        match resolve(modules, address) {
+            Some(point) => self.insert(&point),
+            None => false,
+        }
+    }
+
+    /// Returns true if a point is covered
+    pub fn contains(&self, point: &ModuleOffset) -> bool {
+        match self.modules.iter().position(|m| *m == point.module) {
+            Some(id) => self.points.contains(&(id, point.offset)),
+            None => false,
+        }
+    }
+
+    /// Returns the number of covered points
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    /// Returns true if nothing is covered
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Iterates over the covered (module id, offset) pairs
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.points.iter().copied()
+    }
+
+    /// Iterates over the covered points, with module names
+    pub fn points(&self) -> impl Iterator<Item = ModuleOffset> + '_ {
+        self.points.iter().map(move |&(id, offset)| ModuleOffset {
+            module: self.modules[id].clone(),
+            offset,
+        })
+    }
+
+    /// Merges another coverage, possibly using different module ids.
+    /// Returns the number of new points.
+    pub fn merge(&mut self, other: &ModuleCoverage) -> usize {
+        other.points().filter(|point| self.insert(point)).count()
+    }
+
+    /// Returns the absolute addresses of the points in the snapshot modules.
+    /// Points of missing modules or beyond the end of their module are
+    /// skipped.
+    pub fn addresses(&self, modules: &BTreeMap<String, SnapshotModule>) -> Vec<u64> {
+        self.points
+            .iter()
+            .filter_map(|&(id, offset)| {
+                let module = modules.get(&self.modules[id])?;
+                let address = module.start.checked_add(offset)?;
+
+                if address < module.end {
+                    Some(address)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the coverage, one `module+0xoffset` point per line
+    pub fn write<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        for point in self.points() {
+            writeln!(output, "{}", point)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a coverage written by `write`
+    pub fn read<R: BufRead>(input: R) -> io::Result<ModuleCoverage> {
+        let mut coverage = ModuleCoverage::new();
+
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let point: ModuleOffset = line.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid coverage point {}", line),
+                )
+            })?;
+            coverage.insert(&point);
+        }
+
+        Ok(coverage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ModuleCoverage;
+    use crate::crash::ModuleOffset;
+    use crate::snapshot::SnapshotModule;
+
+    use std::collections::BTreeMap;
+    use std::io;
+
+    /// Returns the modules of a snapshot loaded at `base`
+    fn modules(base: u64) -> BTreeMap<String, SnapshotModule> {
+        let mut modules = BTreeMap::new();
+
+        for (index, name) in ["target", "libc.so.6"].iter().enumerate() {
+            let start = base + index as u64 * 0x10000;
+            modules.insert(
+                name.to_string(),
+                SnapshotModule {
+                    start,
+                    end: start + 0x10000,
+                    name: name.to_string(),
+                    path: format!("/lib/{}", name),
+                    debug_file: None,
+                },
+            );
+        }
+
+        modules
+    }
+
+    #[test]
+    /// Merges coverage of snapshots with different module bases
+    fn test_module_coverage() -> io::Result<()> {
+        let first = modules(0x555555550000);
+        let second = modules(0x7ffff7a00000);
+
+        let mut coverage =
+            ModuleCoverage::from_addresses(&first, vec![0x555555550010, 0x555555560020, 0x1337]);
+        assert_eq!(coverage.len(), 2);
+        assert!(coverage.contains(&ModuleOffset {
+            module: "libc.so.6".to_string(),
+            offset: 0x20,
+        }));
+
+        // Same offsets under another base, in another module order
+        let other = ModuleCoverage::from_addresses(&second, vec![0x7ffff7a10020, 0x7ffff7a00030]);
+        assert_eq!(coverage.merge(&other), 1);
+        assert_eq!(coverage.len(), 3);
+        assert_eq!(
+            coverage.addresses(&second),
+            vec![0x7ffff7a00010, 0x7ffff7a00030, 0x7ffff7a10020]
+        );
+
+        // Round trip through the text form
+        let mut text = Vec::new();
+        coverage.write(&mut text)?;
+        assert_eq!(ModuleCoverage::read(&text[..])?.points().count(), 3);
+        assert!(ModuleCoverage::read(&b"target+zz\n"[..]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,634 @@
+//! Crash classification and deduplication
+
+use crate::snapshot::SnapshotModule;
+use crate::vm::{GuestFailure, Register, Vm, VmExit};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Maximum number of return addresses collected from the stack
+const MAX_FRAMES: usize = 8;
+/// Number of code bytes dumped in reports
+const CODE_DUMP_SIZE: usize = 0x10;
+/// Number of stack bytes dumped in reports
+const STACK_DUMP_SIZE: usize = 0x80;
+
+/// Type of memory access which caused a page fault
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FaultAccess {
+    /// Data read
+    Read,
+    /// Data write
+    Write,
+    /// Instruction fetch
+    Execute,
+}
+
+/// Kind of fault which ended an execution
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CrashKind {
+    /// Page fault on an unmapped or protected page
+    PageFault {
+        /// Faulting access
+        access: FaultAccess,
+        /// Whether the page was unmapped
+        unmapped: bool,
+    },
+    /// Invalid instruction
+    InvalidInstruction,
+    /// Other cpu exception
+    Exception(u64),
+    /// Double fault
+    DoubleFault,
+    /// Crash reported by a user hook
+    Hook,
+    /// Vmexit unhandled by tartiflette
+    Unhandled,
+    /// Kvm could not run the guest anymore (triple fault, invalid state)
+    GuestFailure,
+}
+
+impl fmt::Display for CrashKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CrashKind::PageFault { access, unmapped } => {
+                let access = match access {
+                    FaultAccess::Read => "read",
+                    FaultAccess::Write => "write",
+                    FaultAccess::Execute => "exec",
+                };
+                match unmapped {
+                    true => write!(f, "pagefault_{}_unmapped", access),
+                    false => write!(f, "pagefault_{}", access),
+                }
+            }
+            CrashKind::InvalidInstruction => write!(f, "invalid_instruction"),
+            CrashKind::Exception(code) => write!(f, "exception_{}", code),
+            CrashKind::DoubleFault => write!(f, "double_fault"),
+            CrashKind::Hook => write!(f, "hook"),
+            CrashKind::Unhandled => write!(f, "unhandled"),
+            CrashKind::GuestFailure => write!(f, "guest_failure"),
+        }
+    }
+}
+
+/// Address resolved relative to the module containing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleOffset {
+    /// Name of the module
+    pub module: String,
+    /// Offset from the module start
+    pub offset: u64,
+}
+
+impl fmt::Display for ModuleOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+0x{:x}", self.module, self.offset)
+    }
+}
+
+impl FromStr for ModuleOffset {
+    type Err = ();
+
+    /// Parses the `module+0xoffset` form
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (module, offset) = s.rsplit_once("+0x").ok_or(())?;
+        let offset = u64::from_str_radix(offset, 16).map_err(|_| ())?;
+
+        Ok(ModuleOffset {
+            module: module.to_string(),
+            offset,
+        })
+    }
+}
+
+/// Classified crash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crash {
+    /// Kind of fault
+    pub kind: CrashKind,
+    /// Faulting instruction address
+    pub pc: u64,
+    /// Faulting access address, for page faults
+    pub address: Option<u64>,
+    /// Faulting instruction relative to its module
+    pub location: Option<ModuleOffset>,
+    /// Return addresses found by walking the frame pointers
+    pub frames: Vec<u64>,
+    /// Register state when the crash happened
+    pub registers: Vec<(Register, u64)>,
+}
+
+/// Formats an address along with its module offset
+fn describe(modules: &BTreeMap<String, SnapshotModule>, address: u64) -> String {
+    match resolve(modules, address) {
+        Some(location) => format!("0x{:x} ({})", address, location),
+        None => format!("0x{:x}", address),
+    }
+}
+
+/// Appends a hexdump of guest memory to a report, stopping at the first
+/// unreadable line
+fn hexdump(report: &mut String, vm: &Vm, address: u64, size: usize) {
+    let mut line = [0u8; 16];
+
+    for offset in (0..size as u64).step_by(line.len()) {
+        if vm.read(address + offset, &mut line).is_err() {
+            report.push_str("  <unreadable>\n");
+            break;
+        }
+
+        *report += &format!("  0x{:016x}:", address + offset);
+        for byte in &line {
+            *report += &format!(" {:02x}", byte);
+        }
+        report.push('\n');
+    }
+}
+
+/// Resolves an address to its module
+pub(crate) fn resolve(
+    modules: &BTreeMap<String, SnapshotModule>,
+    address: u64,
+) -> Option<ModuleOffset> {
+    modules
+        .values()
+        .find(|m| m.start <= address && address < m.end)
+        .map(|m| ModuleOffset {
+            module: m.name.clone(),
+            offset: address - m.start,
+        })
+}
+
+impl Crash {
+    /// Classifies the exit of a vm. Returns `None` if the exit is not a crash.
+    pub fn from_exit(
+        vm: &Vm,
+        exit: &VmExit,
+        modules: &BTreeMap<String, SnapshotModule>,
+    ) -> Option<Crash> {
+        let (kind, address) = match exit {
+            VmExit::PageFault(detail) => {
+                let access = if detail.instruction_fetch() {
+                    FaultAccess::Execute
+                } else if detail.write() {
+                    FaultAccess::Write
+                } else {
+                    FaultAccess::Read
+                };
+                let kind = CrashKind::PageFault {
+                    access,
+                    unmapped: detail.unmapped(),
+                };
+                (kind, Some(detail.address))
+            }
+            VmExit::InvalidInstruction => (CrashKind::InvalidInstruction, None),
+            // Debug exceptions are raised by singlesteps
+            VmExit::Exception(code) if *code != 1 => (CrashKind::Exception(*code), None),
+            VmExit::DoubleFault => (CrashKind::DoubleFault, None),
+            VmExit::Unhandled => (CrashKind::Unhandled, None),
+            _ => return None,
+        };
+
+        Some(Crash::new(vm, kind, address, modules))
+    }
+
+    /// Classifies a crash reported by a user hook
+    pub fn from_hook(vm: &Vm, modules: &BTreeMap<String, SnapshotModule>) -> Crash {
+        Crash::new(vm, CrashKind::Hook, None, modules)
+    }
+
+    /// Classifies a guest kvm could not run anymore, at its last rip
+    pub fn from_failure(
+        vm: &Vm,
+        failure: &GuestFailure,
+        modules: &BTreeMap<String, SnapshotModule>,
+    ) -> Crash {
+        let mut crash = Crash::new(vm, CrashKind::GuestFailure, None, modules);
+        crash.pc = failure.rip;
+        crash.location = resolve(modules, failure.rip);
+        crash
+    }
+
+    /// Builds a crash from the current vm state
+    fn new(
+        vm: &Vm,
+        kind: CrashKind,
+        address: Option<u64>,
+        modules: &BTreeMap<String, SnapshotModule>,
+    ) -> Crash {
+        let pc = vm.get_reg(Register::Rip);
+        let mut frames = Vec::new();
+
+        // Walk the frame pointers, only keeping return addresses inside
+        // modules as code compiled without frame pointers yields garbage
+        let mut rbp = vm.get_reg(Register::Rbp);
+        let mut entry = [0u8; 16];
+        while frames.len() < MAX_FRAMES && vm.read(rbp, &mut entry).is_ok() {
+            let next = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let ret = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+
+            if resolve(modules, ret).is_none() {
+                break;
+            }
+            frames.push(ret);
+
+            // The stack grows down, callers frames are at higher addresses
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+
+        Crash {
+            kind,
+            pc,
+            address,
+            location: resolve(modules, pc),
+            frames,
+            registers: Register::ALL.iter().map(|r| (*r, vm.get_reg(*r))).collect(),
+        }
+    }
+
+    /// Returns the deduplication hash of the crash, computed from its kind,
+    /// faulting instruction and return addresses
+    pub fn hash(&self) -> u64 {
+        // FNV-1a, stable between runs and builds
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut feed = |bytes: &[u8]| {
+            for b in bytes {
+                hash ^= *b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+
+        feed(self.kind.to_string().as_bytes());
+        feed(&self.pc.to_le_bytes());
+        for frame in &self.frames {
+            feed(&frame.to_le_bytes());
+        }
+
+        hash
+    }
+
+    /// Returns a triage report of the crash: its classification, call
+    /// stack, registers (along with their value in the `origin` vm, usually
+    /// the state execution started from), code and stack memory, memory
+    /// regions and the last coverage points reached. `vm` must still be in
+    /// the crashing state.
+    pub fn report(
+        &self,
+        vm: &Vm,
+        origin: &Vm,
+        modules: &BTreeMap<String, SnapshotModule>,
+        coverage: &[u64],
+    ) -> String {
+        let mut report = format!("{}\nhash: {:016x}\n", self, self.hash());
+
+        report.push_str("call stack:\n");
+        for (i, frame) in self.frames.iter().enumerate() {
+            report += &format!("  #{} {}\n", i, describe(modules, *frame));
+        }
+
+        report.push_str("registers:\n");
+        for (register, value) in &self.registers {
+            let orig = origin.get_reg(*register);
+            match orig != *value {
+                true => report += &format!("  {:?}: 0x{:x} (was 0x{:x})\n", register, value, orig),
+                false => report += &format!("  {:?}: 0x{:x}\n", register, value),
+            }
+        }
+
+        report.push_str("code:\n");
+        hexdump(&mut report, vm, self.pc, CODE_DUMP_SIZE);
+
+        report.push_str("stack memory:\n");
+        hexdump(&mut report, vm, vm.get_reg(Register::Rsp), STACK_DUMP_SIZE);
+
+        // Merge contiguous pages into regions
+        report.push_str("memory regions:\n");
+        let mut regions: Vec<(u64, u64)> = Vec::new();
+        for mapping in vm.mappings() {
+            match regions.last_mut() {
+                Some((_, end)) if *end == mapping.address => *end += mapping.size as u64,
+                _ => regions.push((mapping.address, mapping.address + mapping.size as u64)),
+            }
+        }
+        for (start, end) in regions {
+            report += &format!("  0x{:x}-0x{:x}", start, end);
+            if let Some(module) = modules.values().find(|m| m.start < end && start < m.end) {
+                report += &format!(" {}", module.name);
+            }
+            report.push('\n');
+        }
+
+        report.push_str("coverage tail:\n");
+        for address in coverage {
+            report += &format!("  {}\n", describe(modules, *address));
+        }
+
+        report
+    }
+
+    /// Returns a file name describing the crash: its kind, pc, faulting
+    /// address if any and hash (`<kind>.PC.<pc>[.ADDR.<address>].<hash>`)
+    pub fn name(&self) -> String {
+        let mut name = match &self.location {
+            Some(location) => format!("{}.PC.{}", self.kind, location),
+            None => format!("{}.PC.0x{:x}", self.kind, self.pc),
+        };
+        if let Some(address) = self.address {
+            name += &format!(".ADDR.0x{:x}", address);
+        }
+
+        format!("{}.{:016x}", name, self.hash())
+    }
+}
+
+impl fmt::Display for Crash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at 0x{:x}", self.kind, self.pc)?;
+
+        if let Some(location) = &self.location {
+            write!(f, " ({})", location)?;
+        }
+        if let Some(address) = self.address {
+            write!(f, " accessing 0x{:x}", address)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the hash of a crash file name written by `CrashStore::register`
+/// (see `Crash::name`), `None` for any other file
+fn name_hash(name: &str) -> Option<u64> {
+    let (kind, rest) = name.split_once(".PC.")?;
+    let (_, hash) = rest.rsplit_once('.')?;
+
+    let kind_valid = !kind.is_empty()
+        && kind
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_');
+    if !kind_valid || hash.len() != 16 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u64::from_str_radix(hash, 16).ok()
+}
+
+/// Directory of deduplicated crashing inputs, which can be shared by the
+/// stores of several clients
+pub struct CrashStore {
+    /// Directory where the crashing inputs are written
+    directory: PathBuf,
+    /// Hashes of the crashes already recorded
+    seen: BTreeSet<u64>,
+}
+
+impl CrashStore {
+    /// Creates a crash store in a directory, creating it if needed. Crashes
+    /// already recorded in the directory are not recorded again.
+    pub fn new<P: AsRef<Path>>(directory: P) -> io::Result<CrashStore> {
+        fs::create_dir_all(&directory)?;
+
+        // Only the crash files are counted, not the reports or other files
+        let mut seen = BTreeSet::new();
+        for entry in fs::read_dir(&directory)? {
+            let name = entry?.file_name();
+            if let Some(hash) = name.to_str().and_then(name_hash) {
+                seen.insert(hash);
+            }
+        }
+
+        Ok(CrashStore {
+            directory: directory.as_ref().to_path_buf(),
+            seen,
+        })
+    }
+
+    /// Registers a crash. Returns the path where its input should be written
+    /// if this crash was not seen before. The input file is created empty,
+    /// so that the other stores of the directory see the crash as recorded.
+    pub fn register(&mut self, crash: &Crash) -> io::Result<Option<PathBuf>> {
+        if !self.seen.insert(crash.hash()) {
+            return Ok(None);
+        }
+
+        // Only one store creates the file of a crash found concurrently
+        let path = self.directory.join(crash.name());
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(Some(path)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Records the input of a crash. Returns true if the crash was new.
+    pub fn record(&mut self, crash: &Crash, input: &[u8]) -> io::Result<bool> {
+        match self.register(crash)? {
+            Some(path) => fs::write(path, input).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Writes the triage report of a crash next to its input
+    pub fn save_report(&self, crash: &Crash, report: &str) -> io::Result<()> {
+        let path = self.directory.join(format!("{}.txt", crash.name()));
+        fs::write(path, report)
+    }
+
+    /// Returns the number of unique crashes
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Returns true if no crash was recorded
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{name_hash, Crash, CrashKind, CrashStore, FaultAccess};
+    use crate::snapshot::SnapshotModule;
+    use crate::vm::{FailureReason, GuestFailure, PageFaultDetail, Register, Vm, VmError, VmExit};
+    use crate::PagePermissions;
+
+    use std::collections::BTreeMap;
+
+    type Result<T> = std::result::Result<T, VmError>;
+
+    #[test]
+    /// Classifies and deduplicates a write page fault
+    fn test_crash_dedup() -> Result<()> {
+        let mut vm = Vm::new(512 * 0x1000)?;
+        vm.mmap(
+            0x1000,
+            0x1000,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.mmap(0x400000, 0x1000, PagePermissions::EXECUTE)?;
+
+        // Single frame pointing back into the module
+        vm.write_value::<u64>(0x1800, 0)?;
+        vm.write_value::<u64>(0x1808, 0x400123)?;
+        vm.set_reg(Register::Rbp, 0x1800);
+        vm.set_reg(Register::Rip, 0x400010);
+
+        let mut modules = BTreeMap::new();
+        modules.insert(
+            "target".to_string(),
+            SnapshotModule {
+                start: 0x400000,
+                end: 0x401000,
+                name: "target".to_string(),
+                path: "/bin/target".to_string(),
+                debug_file: None,
+            },
+        );
+
+        // Read access to a mapped page
+        let exit = VmExit::PageFault(PageFaultDetail {
+            status: 1 << 1,
+            address: 0xdead,
+        });
+        assert!(Crash::from_exit(&vm, &VmExit::Hlt, &modules).is_none());
+
+        let crash = Crash::from_exit(&vm, &exit, &modules).unwrap();
+        assert_eq!(
+            crash.kind,
+            CrashKind::PageFault {
+                access: FaultAccess::Read,
+                unmapped: false
+            }
+        );
+        assert_eq!(crash.address, Some(0xdead));
+        assert_eq!(crash.frames, vec![0x400123]);
+        assert_eq!(crash.location.as_ref().unwrap().to_string(), "target+0x10");
+        assert_eq!(
+            crash.name(),
+            format!(
+                "pagefault_read.PC.target+0x10.ADDR.0xdead.{:016x}",
+                crash.hash()
+            )
+        );
+
+        // Only the changed registers are reported
+        let origin = Vm::new(512 * 0x1000)?;
+        vm.set_reg(Register::Rsp, 0x1800);
+        let report = crash.report(&vm, &origin, &modules, &[0x400008]);
+        assert!(report.contains("#0 0x400123 (target+0x123)"));
+        assert!(report.contains("Rip: 0x400010 (was "));
+        assert!(report.contains("Rax: 0x0\n"));
+        assert!(
+            report.contains("0x0000000000001800: 00 00 00 00 00 00 00 00 23 01 40 00 00 00 00 00")
+        );
+        assert!(report.contains("0x400000-0x401000 target\n"));
+        assert!(report.contains("coverage tail:\n  0x400008 (target+0x8)\n"));
+
+        // Same crash is only recorded once
+        let dir = std::env::temp_dir().join(format!("tartiflette_crashes_{}", std::process::id()));
+        let mut store = CrashStore::new(&dir)?;
+        assert!(store.record(&crash, b"AAAA")?);
+        assert!(!store.record(&crash, b"BBBB")?);
+        assert!(store.record(&Crash::from_hook(&vm, &modules), b"CCCC")?);
+        assert_eq!(store.len(), 2);
+
+        // Reopening the directory keeps the known crashes
+        let mut store = CrashStore::new(&dir)?;
+        assert!(!store.record(&crash, b"DDDD")?);
+        std::fs::remove_dir_all(&dir)?;
+
+        // Stores sharing a directory record a crash once
+        let mut first = CrashStore::new(&dir)?;
+        let mut second = CrashStore::new(&dir)?;
+        assert!(first.record(&crash, b"EEEE")?);
+        assert!(!second.record(&crash, b"FFFF")?);
+        assert_eq!(std::fs::read(dir.join(crash.name()))?, b"EEEE");
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    /// Classifies a guest kvm could not run anymore at its last rip
+    fn test_crash_failure() -> Result<()> {
+        let vm = Vm::new(512 * 0x1000)?;
+        let mut modules = BTreeMap::new();
+        modules.insert(
+            "target".to_string(),
+            SnapshotModule {
+                start: 0x400000,
+                end: 0x401000,
+                name: "target".to_string(),
+                path: "/bin/target".to_string(),
+                debug_file: None,
+            },
+        );
+
+        let failure = GuestFailure {
+            reason: FailureReason::Shutdown,
+            rip: 0x400020,
+            rsp: 0,
+            rflags: 2,
+            cr: [0; 4],
+            efer: 0,
+            cs: 8,
+            ss: 0,
+            idt: (0, 0),
+            gdt: (0, 0),
+            pending_interrupt: false,
+        };
+        let crash = Crash::from_failure(&vm, &failure, &modules);
+        assert_eq!(crash.kind, CrashKind::GuestFailure);
+        assert_eq!(crash.pc, 0x400020);
+        assert!(crash.name().starts_with("guest_failure.PC.target+0x20."));
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests that only the crash files are counted in a crash directory
+    fn test_crash_names() -> Result<()> {
+        assert_eq!(
+            name_hash("pagefault_read.PC.target+0x10.ADDR.0xdead.00000000cafebabe"),
+            Some(0xcafebabe)
+        );
+        assert_eq!(
+            name_hash("exception_13.PC.0x401000.0123456789abcdef"),
+            Some(0x0123456789abcdef)
+        );
+        assert_eq!(name_hash("hook.PC.0x401000.0123456789abcdef.txt"), None);
+        assert_eq!(name_hash("hook.PC.0x401000.cafebabe"), None);
+        assert_eq!(name_hash("hook.0x401000.0123456789abcdef"), None);
+        assert_eq!(name_hash(".PC.0x401000.0123456789abcdef"), None);
+        assert_eq!(name_hash("Crash.PC.0x401000.0123456789abcdef"), None);
+        assert_eq!(name_hash("notes.0123456789abcdef"), None);
+        assert_eq!(name_hash("id_00000000000000ff"), None);
+
+        let dir =
+            std::env::temp_dir().join(format!("tartiflette_crash_names_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        for name in [
+            "hook.PC.0x401000.0123456789abcdef",
+            "hook.PC.0x401000.0123456789abcdef.txt",
+            "README",
+            "dead.beef",
+            "id_00000000000000ff",
+        ] {
+            std::fs::write(dir.join(name), b"")?;
+        }
+        let store = CrashStore::new(&dir)?;
+        assert_eq!(store.len(), 1);
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
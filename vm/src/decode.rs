@@ -0,0 +1,605 @@
+//! Minimal x86-64 decoding of the memory operand size of an instruction and
+//! of the operands of the comparisons
+
+/// Legacy prefixes of an instruction, before the REX prefix
+const LEGACY_PREFIXES: [u8; 11] = [
+    0x66, 0x67, 0xf0, 0xf2, 0xf3, 0x26, 0x2e, 0x36, 0x3e, 0x64, 0x65,
+];
+
+/// Prefixes of an instruction, as far as they change its operand size
+#[derive(Debug, Default, Copy, Clone)]
+struct Prefixes {
+    /// Operand size override (0x66)
+    operand_size: bool,
+    /// Scalar single / repeat prefix (0xf3)
+    rep: bool,
+    /// Scalar double / repeat-not-equal prefix (0xf2)
+    repne: bool,
+    /// REX.W, 64-bit operand size
+    rex_w: bool,
+    /// REX prefix, 0 if there is none
+    rex: u8,
+    /// fs (0x64) or gs (0x65) segment override
+    segment: Option<u8>,
+}
+
+impl Prefixes {
+    /// Parses the legacy and REX prefixes at the start of `code`, returning
+    /// them with the offset of the opcode
+    fn parse(code: &[u8]) -> (Prefixes, usize) {
+        let mut prefixes = Prefixes::default();
+        let mut position = 0;
+
+        while let Some(&byte) = code.get(position) {
+            if !LEGACY_PREFIXES.contains(&byte) {
+                break;
+            }
+            match byte {
+                0x66 => prefixes.operand_size = true,
+                0xf2 => prefixes.repne = true,
+                0xf3 => prefixes.rep = true,
+                0x64 | 0x65 => prefixes.segment = Some(byte),
+                _ => {}
+            }
+            position += 1;
+        }
+        if let Some(&rex @ 0x40..=0x4f) = code.get(position) {
+            prefixes.rex_w = rex & 8 != 0;
+            prefixes.rex = rex;
+            position += 1;
+        }
+
+        (prefixes, position)
+    }
+
+    /// Returns the size of a general purpose operand
+    fn operand_size(&self) -> u8 {
+        match (self.rex_w, self.operand_size) {
+            (true, _) => 8,
+            (false, true) => 2,
+            (false, false) => 4,
+        }
+    }
+
+    /// Returns the size of a stack operand
+    fn stack_size(&self) -> u8 {
+        if self.operand_size {
+            2
+        } else {
+            8
+        }
+    }
+
+    /// Returns the size of a SSE operand: scalar single, scalar double,
+    /// or packed
+    fn sse_size(&self) -> u8 {
+        if self.rep {
+            4
+        } else if self.repne {
+            8
+        } else {
+            16
+        }
+    }
+
+    /// Returns the size of a MMX or SSE integer operand
+    fn packed_size(&self) -> u8 {
+        if self.operand_size {
+            16
+        } else {
+            8
+        }
+    }
+}
+
+/// Returns the `reg` field of the ModR/M byte following the opcode
+fn modrm_reg(code: &[u8]) -> Option<u8> {
+    code.first().map(|modrm| modrm >> 3 & 7)
+}
+
+/// Returns the size, in bytes, of the memory operand of the 64-bit
+/// instruction at the start of `code`, for the common general purpose, x87,
+/// SSE and AVX instructions. Returns `None` for the instructions without a
+/// memory access, or not known.
+pub(crate) fn memory_operand_size(code: &[u8]) -> Option<u8> {
+    // Step 1: Legacy prefixes, then REX
+    let (prefixes, position) = Prefixes::parse(code);
+
+    // Step 2: Opcode
+    let opcode = *code.get(position)?;
+    let rest = &code[position + 1..];
+    match opcode {
+        0x0f => two_byte_operand_size(rest, &prefixes),
+        0xc4 | 0xc5 => vex_operand_size(opcode, rest),
+        _ => one_byte_operand_size(opcode, rest, &prefixes),
+    }
+}
+
+/// Returns the memory operand size of a one byte opcode
+fn one_byte_operand_size(opcode: u8, rest: &[u8], prefixes: &Prefixes) -> Option<u8> {
+    let size = prefixes.operand_size();
+
+    match opcode {
+        // Arithmetic on r/m, the accumulator forms have no memory operand
+        0x00..=0x3f => match opcode & 7 {
+            0 | 2 => Some(1),
+            1 | 3 => Some(size),
+            _ => None,
+        },
+        // push and pop
+        0x50..=0x5f | 0x68 | 0x6a | 0x8f | 0x9c | 0x9d | 0xc2 | 0xc3 | 0xc8 | 0xc9 | 0xe8 => {
+            Some(prefixes.stack_size())
+        }
+        // movsxd
+        0x63 => Some(4),
+        // imul
+        0x69 | 0x6b => Some(size),
+        // Immediate group 1
+        0x80 => Some(1),
+        0x81 | 0x83 => Some(size),
+        // test, xchg, mov
+        0x84 | 0x86 | 0x88 | 0x8a => Some(1),
+        0x85 | 0x87 | 0x89 | 0x8b => Some(size),
+        // mov to and from segment registers
+        0x8c | 0x8e => Some(2),
+        // mov with an absolute address
+        0xa0 | 0xa2 => Some(1),
+        0xa1 | 0xa3 => Some(size),
+        // String instructions
+        0xa4 | 0xa6 | 0xaa | 0xac | 0xae => Some(1),
+        0xa5 | 0xa7 | 0xab | 0xad | 0xaf => Some(size),
+        // Shift groups, mov immediate
+        0xc0 | 0xc6 | 0xd0 | 0xd2 => Some(1),
+        0xc1 | 0xc7 | 0xd1 | 0xd3 => Some(size),
+        // x87
+        0xd8..=0xdf => x87_operand_size(opcode, modrm_reg(rest)?),
+        // Unary groups
+        0xf6 | 0xfe => Some(1),
+        0xf7 => Some(size),
+        0xff => match modrm_reg(rest)? {
+            0 | 1 => Some(size),
+            2 | 4 | 6 => Some(8),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns the memory operand size of a x87 instruction
+fn x87_operand_size(opcode: u8, reg: u8) -> Option<u8> {
+    match (opcode, reg) {
+        (0xd8, _) | (0xda, _) => Some(4),
+        (0xd9, 0..=3) | (0xdb, 0..=3) => Some(4),
+        (0xdb, 5) | (0xdb, 7) => Some(10),
+        (0xdc, _) => Some(8),
+        (0xdd, 0..=3) => Some(8),
+        (0xde, _) => Some(2),
+        (0xdf, 0..=3) => Some(2),
+        (0xdf, 5) | (0xdf, 7) => Some(8),
+        _ => None,
+    }
+}
+
+/// Returns the memory operand size of a 0x0f opcode
+fn two_byte_operand_size(code: &[u8], prefixes: &Prefixes) -> Option<u8> {
+    let opcode = *code.first()?;
+    let rest = &code[1..];
+    let size = prefixes.operand_size();
+
+    match opcode {
+        // movups, movss, movupd, movsd
+        0x10 | 0x11 => Some(prefixes.sse_size()),
+        // movlps, movhps
+        0x12 | 0x13 | 0x16 | 0x17 => Some(8),
+        // movaps, movntps
+        0x28 | 0x29 | 0x2b => Some(16),
+        // ucomiss, comiss, ucomisd, comisd
+        0x2e | 0x2f if prefixes.operand_size => Some(8),
+        0x2e | 0x2f => Some(4),
+        // SSE4 and SSSE3, only movbe and crc32 on general purpose registers
+        0x38 => match *rest.first()? {
+            0xf0 if prefixes.repne => Some(1),
+            0xf0 | 0xf1 => Some(size),
+            _ if prefixes.operand_size => Some(16),
+            _ => None,
+        },
+        0x3a if prefixes.operand_size => Some(16),
+        // cmovcc
+        0x40..=0x4f => Some(size),
+        // SSE arithmetic
+        0x51..=0x5f => Some(prefixes.sse_size()),
+        // movd, movq
+        0x6e => Some(if prefixes.rex_w { 8 } else { 4 }),
+        0x7e if prefixes.rep => Some(8),
+        0x7e => Some(if prefixes.rex_w { 8 } else { 4 }),
+        // movdqa, movdqu, movq
+        0x6f | 0x7f if prefixes.rep => Some(16),
+        0x60..=0x7f => Some(prefixes.packed_size()),
+        // setcc
+        0x90..=0x9f => Some(1),
+        // push and pop fs and gs
+        0xa0 | 0xa1 | 0xa8 | 0xa9 => Some(prefixes.stack_size()),
+        // Bit tests, double shifts, imul
+        0xa3 | 0xa4 | 0xa5 | 0xab | 0xac | 0xad | 0xaf | 0xb3 | 0xbb | 0xbc | 0xbd => Some(size),
+        // cmpxchg, xadd
+        0xb0 | 0xc0 => Some(1),
+        0xb1 | 0xc1 => Some(size),
+        // movzx, movsx
+        0xb6 | 0xbe => Some(1),
+        0xb7 | 0xbf => Some(2),
+        // movnti
+        0xc3 => Some(size),
+        // cmpxchg8b, cmpxchg16b
+        0xc7 if modrm_reg(rest)? == 1 => Some(if prefixes.rex_w { 16 } else { 8 }),
+        // movq, movntdq
+        0xd6 => Some(8),
+        0xd0..=0xff => Some(prefixes.packed_size()),
+        _ => None,
+    }
+}
+
+/// Returns the memory operand size of a VEX encoded instruction, the size
+/// of its vector unless it is a scalar move
+fn vex_operand_size(prefix: u8, code: &[u8]) -> Option<u8> {
+    // Step 1: Vector length, implied prefix and opcode map
+    let (map, payload, opcode) = match prefix {
+        0xc5 => (1, *code.first()?, *code.get(1)?),
+        _ => (*code.first()? & 0x1f, *code.get(1)?, *code.get(2)?),
+    };
+    let vector = if payload & 4 != 0 { 32 } else { 16 };
+
+    // Step 2: Scalar moves and arithmetic
+    if map == 1 && (opcode == 0x10 || opcode == 0x11 || (0x51..=0x5f).contains(&opcode)) {
+        match payload & 3 {
+            2 => return Some(4),
+            3 => return Some(8),
+            _ => {}
+        }
+    }
+
+    Some(vector)
+}
+
+/// Operand of a decoded comparison
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Operand {
+    /// General purpose register by number, from rax (0) to r15 (15). `high`
+    /// selects the ah, ch, dh or bh byte register.
+    Register { number: u8, high: bool },
+    /// Memory at `base + index * scale + displacement`, the base being the
+    /// next instruction if `rip_relative`
+    Memory {
+        base: Option<u8>,
+        index: Option<u8>,
+        scale: u8,
+        displacement: i64,
+        rip_relative: bool,
+        /// fs (0x64) or gs (0x65) segment override
+        segment: Option<u8>,
+    },
+    /// Immediate, sign extended and truncated to the operand size
+    Immediate(u64),
+}
+
+/// Decoded `cmp` instruction
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Comparison {
+    /// Size of the operands, in bytes
+    pub size: u8,
+    /// First operand
+    pub left: Operand,
+    /// Second operand
+    pub right: Operand,
+    /// Length of the instruction
+    pub len: usize,
+}
+
+/// Operand values of a comparison run by the guest
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CmpOperands {
+    /// Size of the operands, in bytes
+    pub size: u8,
+    /// Value of the first operand
+    pub left: u64,
+    /// Value of the second operand
+    pub right: u64,
+}
+
+/// Returns `value` truncated to `size` bytes
+#[inline]
+pub(crate) fn truncate(value: u64, size: u8) -> u64 {
+    match size {
+        8 => value,
+        _ => value & ((1 << (size as u64 * 8)) - 1),
+    }
+}
+
+/// Decodes the ModR/M operand at the start of `code`. Returns the r/m
+/// operand, the `reg` field extended by REX.R and the number of bytes of the
+/// ModR/M, SIB and displacement.
+fn modrm_operand(code: &[u8], prefixes: &Prefixes) -> Option<(Operand, u8, usize)> {
+    let modrm = *code.first()?;
+    let mode = modrm >> 6;
+    let reg = (modrm >> 3 & 7) | (prefixes.rex & 4) << 1;
+    let rm = modrm & 7;
+    let rex_b = (prefixes.rex & 1) << 3;
+
+    if mode == 3 {
+        let operand = Operand::Register {
+            number: rm | rex_b,
+            high: false,
+        };
+        return Some((operand, reg, 1));
+    }
+
+    // Step 1: SIB byte, base and index registers
+    let mut len = 1;
+    let (mut base, mut index, mut scale, mut rip_relative) = (Some(rm | rex_b), None, 1, false);
+    if rm == 4 {
+        let sib = *code.get(1)?;
+        len += 1;
+        scale = 1 << (sib >> 6);
+        index = Some((sib >> 3 & 7) | (prefixes.rex & 2) << 2).filter(|&index| index != 4);
+        base = Some((sib & 7) | rex_b);
+        if sib & 7 == 5 && mode == 0 {
+            base = None;
+        }
+    } else if rm == 5 && mode == 0 {
+        base = None;
+        rip_relative = true;
+    }
+
+    // Step 2: Displacement
+    let displacement = match (mode, base) {
+        (1, _) => {
+            let disp = *code.get(len)? as i8 as i64;
+            len += 1;
+            disp
+        }
+        (2, _) | (0, None) => {
+            let bytes = code.get(len..len + 4)?;
+            len += 4;
+            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64
+        }
+        _ => 0,
+    };
+
+    let operand = Operand::Memory {
+        base,
+        index,
+        scale,
+        displacement,
+        rip_relative,
+        segment: prefixes.segment,
+    };
+    Some((operand, reg, len))
+}
+
+/// Returns a byte register operand: without REX, numbers 4 to 7 are the
+/// ah, ch, dh and bh registers
+fn byte_register(operand: Operand, prefixes: &Prefixes) -> Operand {
+    match operand {
+        Operand::Register { number, .. } if prefixes.rex == 0 && (4..8).contains(&number) => {
+            Operand::Register {
+                number: number - 4,
+                high: true,
+            }
+        }
+        _ => operand,
+    }
+}
+
+/// Reads a little-endian immediate of `len` bytes, sign extended and
+/// truncated to `size` bytes
+fn immediate(code: &[u8], len: usize, size: u8) -> Option<u64> {
+    let bytes = code.get(..len)?;
+    let value = match len {
+        1 => bytes[0] as i8 as i64,
+        2 => i16::from_le_bytes([bytes[0], bytes[1]]) as i64,
+        _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+    };
+
+    Some(truncate(value as u64, size))
+}
+
+/// Decodes the `cmp` instruction at the start of `code`, in any of its
+/// register, memory and immediate forms. Returns `None` for the other
+/// instructions.
+pub(crate) fn decode_comparison(code: &[u8]) -> Option<Comparison> {
+    // Step 1: Legacy prefixes, then REX
+    let (prefixes, position) = Prefixes::parse(code);
+    let opcode = *code.get(position)?;
+    let rest = &code[position + 1..];
+    let size = match opcode {
+        0x38 | 0x3a | 0x3c | 0x80 => 1,
+        _ => prefixes.operand_size(),
+    };
+    // Immediates are at most 32 bits, sign extended to 64 bits
+    let imm_len = match size {
+        1 => 1,
+        2 => 2,
+        _ => 4,
+    };
+
+    // Step 2: Operands
+    let (left, right, len) = match opcode {
+        // cmp r/m, reg
+        0x38 | 0x39 => {
+            let (rm, reg, len) = modrm_operand(rest, &prefixes)?;
+            let reg = Operand::Register {
+                number: reg,
+                high: false,
+            };
+            (rm, reg, len)
+        }
+        // cmp reg, r/m
+        0x3a | 0x3b => {
+            let (rm, reg, len) = modrm_operand(rest, &prefixes)?;
+            let reg = Operand::Register {
+                number: reg,
+                high: false,
+            };
+            (reg, rm, len)
+        }
+        // cmp al/ax/eax/rax, imm
+        0x3c | 0x3d => {
+            let accumulator = Operand::Register {
+                number: 0,
+                high: false,
+            };
+            let imm = Operand::Immediate(immediate(rest, imm_len, size)?);
+            (accumulator, imm, imm_len)
+        }
+        // Immediate group 1, cmp is /7
+        0x80 | 0x81 | 0x83 => {
+            let (rm, reg, len) = modrm_operand(rest, &prefixes)?;
+            if reg & 7 != 7 {
+                return None;
+            }
+            let imm_len = if opcode == 0x83 { 1 } else { imm_len };
+            let imm = Operand::Immediate(immediate(&rest[len..], imm_len, size)?);
+            (rm, imm, len + imm_len)
+        }
+        _ => return None,
+    };
+
+    let (left, right) = match size {
+        1 => (
+            byte_register(left, &prefixes),
+            byte_register(right, &prefixes),
+        ),
+        _ => (left, right),
+    };
+
+    Some(Comparison {
+        size,
+        left,
+        right,
+        len: position + 1 + len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_comparison, memory_operand_size, Comparison, Operand};
+
+    #[test]
+    /// Tests the sizes of the general purpose instructions
+    fn test_general_purpose() {
+        // mov al, [rdi]
+        assert_eq!(memory_operand_size(&[0x8a, 0x07]), Some(1));
+        // mov eax, [rdi]
+        assert_eq!(memory_operand_size(&[0x8b, 0x07]), Some(4));
+        // mov ax, [rdi]
+        assert_eq!(memory_operand_size(&[0x66, 0x8b, 0x07]), Some(2));
+        // mov rax, [rdi]
+        assert_eq!(memory_operand_size(&[0x48, 0x8b, 0x07]), Some(8));
+        // movzx eax, word [rdi]
+        assert_eq!(memory_operand_size(&[0x0f, 0xb7, 0x07]), Some(2));
+        // add dword [rdi], 1
+        assert_eq!(memory_operand_size(&[0x83, 0x07, 0x01]), Some(4));
+        // rep stosq
+        assert_eq!(memory_operand_size(&[0xf3, 0x48, 0xab]), Some(8));
+        // call [rax]
+        assert_eq!(memory_operand_size(&[0xff, 0x10]), Some(8));
+        // lea rax, [rdi]
+        assert_eq!(memory_operand_size(&[0x48, 0x8d, 0x07]), None);
+    }
+
+    #[test]
+    /// Tests the sizes of the x87, SSE and AVX instructions
+    fn test_vector() {
+        // fld qword [rdi]
+        assert_eq!(memory_operand_size(&[0xdd, 0x07]), Some(8));
+        // movss xmm0, [rdi]
+        assert_eq!(memory_operand_size(&[0xf3, 0x0f, 0x10, 0x07]), Some(4));
+        // movdqu xmm0, [rdi]
+        assert_eq!(memory_operand_size(&[0xf3, 0x0f, 0x6f, 0x07]), Some(16));
+        // movq mm0, [rdi]
+        assert_eq!(memory_operand_size(&[0x0f, 0x6f, 0x07]), Some(8));
+        // vmovdqu ymm0, [rdi]
+        assert_eq!(memory_operand_size(&[0xc5, 0xfe, 0x6f, 0x07]), Some(32));
+        // vmovsd xmm0, [rdi]
+        assert_eq!(memory_operand_size(&[0xc5, 0xfb, 0x10, 0x07]), Some(8));
+    }
+
+    #[test]
+    /// Tests the decoding of the register, memory and immediate comparisons
+    fn test_decode_comparison() {
+        let register = |number| Operand::Register {
+            number,
+            high: false,
+        };
+
+        // cmp rax, rbx
+        assert_eq!(
+            decode_comparison(&[0x48, 0x39, 0xd8]),
+            Some(Comparison {
+                size: 8,
+                left: register(0),
+                right: register(3),
+                len: 3,
+            })
+        );
+        // cmp ah, 0x41
+        assert_eq!(
+            decode_comparison(&[0x80, 0xfc, 0x41]),
+            Some(Comparison {
+                size: 1,
+                left: Operand::Register {
+                    number: 0,
+                    high: true,
+                },
+                right: Operand::Immediate(0x41),
+                len: 3,
+            })
+        );
+        // cmp r9d, [rdi + rcx * 4 + 0x10]
+        assert_eq!(
+            decode_comparison(&[0x44, 0x3b, 0x4c, 0x8f, 0x10]),
+            Some(Comparison {
+                size: 4,
+                left: register(9),
+                right: Operand::Memory {
+                    base: Some(7),
+                    index: Some(1),
+                    scale: 4,
+                    displacement: 0x10,
+                    rip_relative: false,
+                    segment: None,
+                },
+                len: 5,
+            })
+        );
+        // cmp qword [rip + 0x100], -1
+        assert_eq!(
+            decode_comparison(&[0x48, 0x83, 0x3d, 0x00, 0x01, 0x00, 0x00, 0xff]),
+            Some(Comparison {
+                size: 8,
+                left: Operand::Memory {
+                    base: None,
+                    index: None,
+                    scale: 1,
+                    displacement: 0x100,
+                    rip_relative: true,
+                    segment: None,
+                },
+                right: Operand::Immediate(u64::MAX),
+                len: 8,
+            })
+        );
+        // cmp ax, 0x1337
+        assert_eq!(
+            decode_comparison(&[0x66, 0x3d, 0x37, 0x13]),
+            Some(Comparison {
+                size: 2,
+                left: register(0),
+                right: Operand::Immediate(0x1337),
+                len: 4,
+            })
+        );
+        // add dword [rdi], 1 and truncated instructions are not comparisons
+        assert_eq!(decode_comparison(&[0x83, 0x07, 0x01]), None);
+        assert_eq!(decode_comparison(&[0x48, 0x81, 0xf8, 0x37]), None);
+    }
+}
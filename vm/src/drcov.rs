@@ -0,0 +1,101 @@
+//! Coverage export in the drcov format (Lighthouse, Ghidra Dragondance...)
+
+use crate::snapshot::SnapshotModule;
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Writes covered addresses as a drcov (version 2) file. Blocks are recorded
+/// with a size of one byte, addresses outside of the modules are ignored.
+pub fn write_drcov<W, I>(
+    mut out: W,
+    modules: &BTreeMap<String, SnapshotModule>,
+    blocks: I,
+) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = u64>,
+{
+    let modules: Vec<&SnapshotModule> = modules.values().collect();
+
+    // Resolve the blocks to their module
+    let entries: Vec<(u32, u16)> = blocks
+        .into_iter()
+        .filter_map(|addr| {
+            modules
+                .iter()
+                .position(|m| m.start <= addr && addr < m.end)
+                .map(|id| ((addr - modules[id].start) as u32, id as u16))
+        })
+        .collect();
+
+    // Header and module table
+    writeln!(out, "DRCOV VERSION: 2")?;
+    writeln!(out, "DRCOV FLAVOR: tartiflette")?;
+    writeln!(out, "Module Table: version 2, count {}", modules.len())?;
+    writeln!(
+        out,
+        "Columns: id, base, end, entry, checksum, timestamp, path"
+    )?;
+    for (id, module) in modules.iter().enumerate() {
+        writeln!(
+            out,
+            "{:3}, 0x{:016x}, 0x{:016x}, 0x{:016x}, 0x{:08x}, 0x{:08x}, {}",
+            id, module.start, module.end, 0, 0, 0, module.path
+        )?;
+    }
+
+    // Basic block table, entries are (start offset: u32, size: u16, module id: u16)
+    writeln!(out, "BB Table: {} bbs", entries.len())?;
+    for (offset, id) in entries {
+        out.write_all(&offset.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&id.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_drcov;
+    use crate::snapshot::{SnapshotError, SnapshotModule};
+
+    use std::collections::BTreeMap;
+
+    #[test]
+    /// Exports blocks of two modules
+    fn test_write_drcov() -> Result<(), SnapshotError> {
+        let mut modules = BTreeMap::new();
+        for (name, start) in [("a", 0x400000), ("b", 0x7f0000)] {
+            modules.insert(
+                name.to_string(),
+                SnapshotModule {
+                    start,
+                    end: start + 0x1000,
+                    name: name.to_string(),
+                    path: format!("/lib/{}", name),
+                    debug_file: None,
+                },
+            );
+        }
+
+        let mut out = Vec::new();
+        write_drcov(&mut out, &modules, vec![0x400010, 0x7f0020, 0x1337])?;
+
+        let header = b"BB Table: 2 bbs\n";
+        let table = out.windows(header.len()).position(|w| w == header).unwrap() + header.len();
+        let text = String::from_utf8_lossy(&out[..table]);
+
+        assert!(text.starts_with("DRCOV VERSION: 2\n"));
+        assert!(text.contains("Module Table: version 2, count 2\n"));
+        assert!(text.contains("  1, 0x00000000007f0000, 0x00000000007f1000"));
+        assert!(text.contains("/lib/b\n"));
+        assert_eq!(
+            &out[table..],
+            &[0x10, 0, 0, 0, 1, 0, 0, 0, 0x20, 0, 0, 0, 1, 0, 1, 0]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,369 @@
+//! Minimal ELF64 parsing for self-contained x86_64 executables
+
+use crate::memory::PagePermissions;
+use crate::snapshot::SnapshotError;
+
+use std::convert::TryInto;
+
+/// Result type in elf manipulation
+type Result<T> = std::result::Result<T, SnapshotError>;
+
+/// ELF header size
+const EHDR_SIZE: usize = 64;
+/// Program header size
+const PHDR_SIZE: usize = 56;
+/// Dynamic entry size
+const DYN_SIZE: usize = 16;
+/// Relocation with addend entry size
+const RELA_SIZE: usize = 24;
+
+/// Executable file
+const ET_EXEC: u16 = 2;
+/// Shared object file (PIE)
+const ET_DYN: u16 = 3;
+/// AMD x86-64 architecture
+const EM_X86_64: u16 = 62;
+
+/// Loadable segment
+const PT_LOAD: u32 = 1;
+/// Dynamic linking information
+const PT_DYNAMIC: u32 = 2;
+/// Program interpreter
+const PT_INTERP: u32 = 3;
+/// Program header table
+const PT_PHDR: u32 = 6;
+
+/// Execute segment permission
+const PF_X: u32 = 1 << 0;
+/// Write segment permission
+const PF_W: u32 = 1 << 1;
+
+/// End of the dynamic section
+const DT_NULL: u64 = 0;
+/// Address of the relocation table
+const DT_RELA: u64 = 7;
+/// Size of the relocation table
+const DT_RELASZ: u64 = 8;
+/// Size of a relocation entry
+const DT_RELAENT: u64 = 9;
+
+/// No relocation
+const R_X86_64_NONE: u32 = 0;
+/// Adjust by program base
+const R_X86_64_RELATIVE: u32 = 8;
+/// Adjust indirectly by program base, left to the program itself
+const R_X86_64_IRELATIVE: u32 = 37;
+
+/// Loadable ELF segment
+#[derive(Debug, Clone)]
+pub struct ElfSegment {
+    /// Virtual address of the segment (before relocation)
+    pub vaddr: u64,
+    /// Size of the segment in memory
+    pub memsz: u64,
+    /// Offset of the segment data in the file
+    pub offset: u64,
+    /// Size of the segment data in the file
+    pub filesz: u64,
+    /// Segment permissions
+    pub permissions: PagePermissions,
+}
+
+/// Parsed ELF executable
+#[derive(Debug)]
+pub struct Elf {
+    /// Whether the executable is position independent
+    pub pie: bool,
+    /// Entry point (before relocation)
+    pub entry: u64,
+    /// Address of the program headers in memory (before relocation)
+    pub phdr: u64,
+    /// Number of program headers
+    pub phnum: u16,
+    /// Loadable segments
+    pub segments: Vec<ElfSegment>,
+    /// Offsets (before relocation) of the `R_X86_64_RELATIVE` relocations with their addend
+    pub relocations: Vec<(u64, u64)>,
+}
+
+/// Returns a parsing error
+fn parsing_error<T>(msg: &str) -> Result<T> {
+    Err(SnapshotError::ParsingError(format!("elf: {}", msg)))
+}
+
+/// Returns the offset of the table of `count` entries of `size` bytes at
+/// `offset`, checking the whole table lies in the file
+fn table_offset(data: &[u8], offset: u64, count: u64, size: usize) -> Result<usize> {
+    let end = count
+        .checked_mul(size as u64)
+        .and_then(|len| offset.checked_add(len));
+    match end {
+        Some(end) if end <= data.len() as u64 => Ok(offset as usize),
+        _ => parsing_error("truncated file"),
+    }
+}
+
+/// Reads a little endian u16 at a given offset
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    match offset.checked_add(2).and_then(|end| data.get(offset..end)) {
+        Some(bytes) => Ok(u16::from_le_bytes(bytes.try_into().unwrap())),
+        None => parsing_error("truncated file"),
+    }
+}
+
+/// Reads a little endian u32 at a given offset
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    match offset.checked_add(4).and_then(|end| data.get(offset..end)) {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into().unwrap())),
+        None => parsing_error("truncated file"),
+    }
+}
+
+/// Reads a little endian u64 at a given offset
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    match offset.checked_add(8).and_then(|end| data.get(offset..end)) {
+        Some(bytes) => Ok(u64::from_le_bytes(bytes.try_into().unwrap())),
+        None => parsing_error("truncated file"),
+    }
+}
+
+impl Elf {
+    /// Parses a static or position independent x86_64 ELF executable
+    pub fn parse(data: &[u8]) -> Result<Elf> {
+        // Check the identification bytes
+        if data.len() < EHDR_SIZE || data[0..4] != [0x7f, b'E', b'L', b'F'] {
+            return parsing_error("invalid magic");
+        }
+        if data[4] != 2 || data[5] != 1 {
+            return parsing_error("only 64 bits little endian files are supported");
+        }
+        if read_u16(data, 18)? != EM_X86_64 {
+            return parsing_error("only x86_64 files are supported");
+        }
+
+        let pie = match read_u16(data, 16)? {
+            ET_EXEC => false,
+            ET_DYN => true,
+            _ => return parsing_error("not an executable"),
+        };
+
+        let entry = read_u64(data, 24)?;
+        let phoff = read_u64(data, 32)?;
+        let phentsize = read_u16(data, 54)? as usize;
+        let phnum = read_u16(data, 56)?;
+
+        if phentsize != PHDR_SIZE {
+            return parsing_error("invalid program header size");
+        }
+        let phoff = table_offset(data, phoff, phnum as u64, PHDR_SIZE)?;
+
+        let mut segments = Vec::new();
+        let mut dynamic: Option<(u64, u64)> = None;
+        let mut phdr: Option<u64> = None;
+
+        // Loop through program headers
+        for i in 0..phnum as usize {
+            let ph = phoff + i * PHDR_SIZE;
+
+            let p_type = read_u32(data, ph)?;
+            let p_flags = read_u32(data, ph + 4)?;
+            let p_offset = read_u64(data, ph + 8)?;
+            let p_vaddr = read_u64(data, ph + 16)?;
+            let p_filesz = read_u64(data, ph + 32)?;
+            let p_memsz = read_u64(data, ph + 40)?;
+
+            match p_type {
+                PT_LOAD => {
+                    let end = p_offset.checked_add(p_filesz);
+                    if p_filesz > p_memsz || end.is_none_or(|end| end > data.len() as u64) {
+                        return parsing_error("invalid loadable segment");
+                    }
+
+                    // No execute only in x64
+                    let mut permissions = PagePermissions::READ;
+                    permissions.set_writable(p_flags & PF_W != 0);
+                    permissions.set_executable(p_flags & PF_X != 0);
+
+                    segments.push(ElfSegment {
+                        vaddr: p_vaddr,
+                        memsz: p_memsz,
+                        offset: p_offset,
+                        filesz: p_filesz,
+                        permissions,
+                    });
+                }
+                PT_DYNAMIC => dynamic = Some((p_offset, p_filesz)),
+                PT_INTERP => {
+                    return parsing_error("dynamically linked executables are not supported")
+                }
+                PT_PHDR => phdr = Some(p_vaddr),
+                _ => {}
+            }
+        }
+
+        if segments.is_empty() {
+            return parsing_error("no loadable segment");
+        }
+
+        // Without PT_PHDR, find the segment covering the program headers
+        let phdr = match phdr {
+            Some(addr) => addr,
+            None => segments
+                .iter()
+                .find(|s| s.offset <= phoff as u64 && phoff as u64 <= s.offset + s.filesz)
+                .map(|s| s.vaddr + (phoff as u64 - s.offset))
+                .unwrap_or(0),
+        };
+
+        let mut elf = Elf {
+            pie,
+            entry,
+            phdr,
+            phnum,
+            segments,
+            relocations: Vec::new(),
+        };
+
+        if let Some((offset, size)) = dynamic {
+            let count = size / DYN_SIZE as u64;
+            let offset = table_offset(data, offset, count, DYN_SIZE)?;
+            elf.parse_relocations(data, offset, count as usize)?;
+        }
+
+        Ok(elf)
+    }
+
+    /// Converts a virtual address to its offset in the file
+    fn vaddr_to_offset(&self, vaddr: u64) -> Option<usize> {
+        self.segments
+            .iter()
+            .find(|s| s.vaddr <= vaddr && vaddr - s.vaddr < s.filesz)
+            .map(|s| (s.offset + (vaddr - s.vaddr)) as usize)
+    }
+
+    /// Collects the relocations referenced by the `count` entries of the
+    /// dynamic section
+    fn parse_relocations(&mut self, data: &[u8], offset: usize, count: usize) -> Result<()> {
+        let mut rela: Option<u64> = None;
+        let mut relasz: u64 = 0;
+        let mut relaent: u64 = RELA_SIZE as u64;
+
+        // Loop through dynamic entries
+        for entry in (offset..offset + count * DYN_SIZE).step_by(DYN_SIZE) {
+            let tag = read_u64(data, entry)?;
+            let value = read_u64(data, entry + 8)?;
+
+            match tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(value),
+                DT_RELASZ => relasz = value,
+                DT_RELAENT => relaent = value,
+                _ => {}
+            }
+        }
+
+        let rela = match rela {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+
+        if relaent != RELA_SIZE as u64 {
+            return parsing_error("invalid relocation entry size");
+        }
+
+        let table = match self.vaddr_to_offset(rela) {
+            Some(offset) => offset,
+            None => return parsing_error("relocation table outside of loaded segments"),
+        };
+        let count = relasz / RELA_SIZE as u64;
+        let table = table_offset(data, table as u64, count, RELA_SIZE)?;
+
+        // Loop through relocation entries
+        for entry in (table..table + count as usize * RELA_SIZE).step_by(RELA_SIZE) {
+            let r_offset = read_u64(data, entry)?;
+            let r_type = read_u64(data, entry + 8)? as u32;
+            let r_addend = read_u64(data, entry + 16)?;
+
+            match r_type {
+                R_X86_64_RELATIVE => self.relocations.push((r_offset, r_addend)),
+                // IRELATIVE need to call into the program, static-pie programs
+                // apply them themselves.
+                R_X86_64_NONE | R_X86_64_IRELATIVE => {}
+                _ => return parsing_error("unsupported relocation type"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{Elf, Result};
+
+    /// Builds a static executable made of a single RX segment holding `code`
+    pub(crate) fn build_elf(code: &[u8], vaddr: u64) -> Vec<u8> {
+        let mut data = vec![0u8; 0x78];
+
+        // Identification
+        data[0..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        // Type, machine, version, entry, phoff
+        data[16..18].copy_from_slice(&2u16.to_le_bytes());
+        data[18..20].copy_from_slice(&62u16.to_le_bytes());
+        data[20..24].copy_from_slice(&1u32.to_le_bytes());
+        data[24..32].copy_from_slice(&(vaddr + 0x78).to_le_bytes());
+        data[32..40].copy_from_slice(&64u64.to_le_bytes());
+        // Header size, program header size and count
+        data[52..54].copy_from_slice(&64u16.to_le_bytes());
+        data[54..56].copy_from_slice(&56u16.to_le_bytes());
+        data[56..58].copy_from_slice(&1u16.to_le_bytes());
+
+        // Single PT_LOAD R+X covering the whole file
+        let size = (0x78 + code.len()) as u64;
+        data[64..68].copy_from_slice(&1u32.to_le_bytes());
+        data[68..72].copy_from_slice(&5u32.to_le_bytes());
+        data[80..88].copy_from_slice(&vaddr.to_le_bytes());
+        data[88..96].copy_from_slice(&vaddr.to_le_bytes());
+        data[96..104].copy_from_slice(&size.to_le_bytes());
+        data[104..112].copy_from_slice(&size.to_le_bytes());
+        data[112..120].copy_from_slice(&0x1000u64.to_le_bytes());
+
+        data.extend_from_slice(code);
+        data
+    }
+
+    #[test]
+    /// Parses a minimal static executable
+    fn test_parse_static() -> Result<()> {
+        let elf = Elf::parse(&build_elf(&[0xf4], 0x400000))?;
+
+        assert!(!elf.pie);
+        assert_eq!(elf.entry, 0x400078);
+        assert_eq!(elf.phdr, 0x400040);
+        assert_eq!(elf.segments.len(), 1);
+        assert!(elf.segments[0].permissions.executable());
+        assert!(!elf.segments[0].permissions.writable());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Rejects files which are not ELF
+    fn test_parse_invalid() {
+        assert!(Elf::parse(b"#!/bin/sh\n").is_err());
+    }
+
+    #[test]
+    /// Rejects offsets and sizes overflowing instead of panicking
+    fn test_parse_overflow() {
+        // Program headers at the end of the address space
+        let mut data = build_elf(&[0xf4], 0x400000);
+        data[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Elf::parse(&data).is_err());
+
+        // Loadable segment data at the end of the address space
+        let mut data = build_elf(&[0xf4], 0x400000);
+        data[72..80].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        assert!(Elf::parse(&data).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Delivery of fuzz inputs to the guest
+
+use crate::vm::{Register, Vm, VmError};
+
+use std::cmp;
+
+/// Result type in input delivery
+type Result<T> = std::result::Result<T, VmError>;
+
+/// Way a fuzz input is handed to the guest
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InputDelivery {
+    /// Input written to a buffer whose address and length are placed in a
+    /// register pair
+    Registers {
+        /// Address of the input buffer
+        address: u64,
+        /// Size of the input buffer
+        capacity: usize,
+        /// Register receiving the buffer address
+        pointer: Register,
+        /// Register receiving the input length
+        length: Register,
+    },
+    /// Input copied on request, when the guest issues a syscall with number
+    /// `number` (rdi: buffer, rsi: buffer size). The copied length is
+    /// returned in rax.
+    Hypercall {
+        /// Syscall number of the `get_input` request
+        number: u64,
+    },
+    /// Input written to a region shared with the guest, prefixed with its
+    /// length as a little endian u64
+    SharedRegion {
+        /// Address of the region
+        address: u64,
+        /// Size of the region (including the length prefix)
+        capacity: usize,
+    },
+}
+
+impl InputDelivery {
+    /// Places an input in the guest before execution. Inputs larger than the
+    /// buffer are truncated. Does nothing for the `Hypercall` mode, where the
+    /// guest fetches the input with `handle_syscall`.
+    pub fn deliver(&self, vm: &mut Vm, input: &[u8]) -> Result<()> {
+        match *self {
+            InputDelivery::Registers {
+                address,
+                capacity,
+                pointer,
+                length,
+            } => {
+                let input = &input[..cmp::min(input.len(), capacity)];
+                vm.write(address, input)?;
+                vm.set_reg(pointer, address);
+                vm.set_reg(length, input.len() as u64);
+            }
+            InputDelivery::SharedRegion { address, capacity } => {
+                let size = cmp::min(input.len(), capacity.saturating_sub(8));
+                vm.write_value::<u64>(address, size as u64)?;
+                vm.write(address + 8, &input[..size])?;
+            }
+            InputDelivery::Hypercall { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    /// Returns the address and size of the guest buffer receiving the
+    /// input, `None` in the `Hypercall` mode where the guest provides it
+    pub fn buffer(&self) -> Option<(u64, usize)> {
+        match *self {
+            InputDelivery::Registers {
+                address, capacity, ..
+            } => Some((address, capacity)),
+            InputDelivery::SharedRegion { address, capacity } => {
+                Some((address + 8, capacity.saturating_sub(8)))
+            }
+            InputDelivery::Hypercall { .. } => None,
+        }
+    }
+
+    /// Serves a `get_input` request after a syscall exit. Returns false if
+    /// the syscall was not an input request and must be handled elsewhere.
+    pub fn handle_syscall(&self, vm: &mut Vm, input: &[u8]) -> Result<bool> {
+        match *self {
+            InputDelivery::Hypercall { number } if vm.get_reg(Register::Rax) == number => {
+                let buffer = vm.get_reg(Register::Rdi);
+                let capacity = vm.get_reg(Register::Rsi) as usize;
+                let input = &input[..cmp::min(input.len(), capacity)];
+
+                vm.write(buffer, input)?;
+                vm.set_reg(Register::Rax, input.len() as u64);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InputDelivery, Result};
+    use crate::vm::{Register, Vm};
+    use crate::PagePermissions;
+
+    #[test]
+    /// Delivers inputs in each mode
+    fn test_delivery() -> Result<()> {
+        let mut vm = Vm::new(512 * 0x1000)?;
+        vm.mmap(
+            0x1000,
+            0x1000,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        let mut data = [0u8; 6];
+
+        // Buffer and register pair, truncated to the buffer size
+        let registers = InputDelivery::Registers {
+            address: 0x1000,
+            capacity: 4,
+            pointer: Register::Rdi,
+            length: Register::Rsi,
+        };
+        registers.deliver(&mut vm, b"ABCDEF")?;
+        vm.read(0x1000, &mut data[..4])?;
+        assert_eq!(&data[..4], b"ABCD");
+        assert_eq!(vm.get_reg(Register::Rdi), 0x1000);
+        assert_eq!(vm.get_reg(Register::Rsi), 4);
+        assert!(!registers.handle_syscall(&mut vm, b"ABCDEF")?);
+        assert_eq!(registers.buffer(), Some((0x1000, 4)));
+
+        // Length prefixed shared region
+        let shared = InputDelivery::SharedRegion {
+            address: 0x1800,
+            capacity: 0x100,
+        };
+        shared.deliver(&mut vm, b"GHI")?;
+        let mut size = [0u8; 8];
+        vm.read(0x1800, &mut size)?;
+        vm.read(0x1808, &mut data[..3])?;
+        assert_eq!(u64::from_le_bytes(size), 3);
+        assert_eq!(&data[..3], b"GHI");
+        assert_eq!(shared.buffer(), Some((0x1808, 0xf8)));
+
+        // Guest request
+        let hypercall = InputDelivery::Hypercall { number: 0x1337 };
+        vm.set_reg(Register::Rax, 0x1337);
+        vm.set_reg(Register::Rdi, 0x1100);
+        vm.set_reg(Register::Rsi, 0x10);
+        assert!(hypercall.handle_syscall(&mut vm, b"JKLMNO")?);
+        vm.read(0x1100, &mut data)?;
+        assert_eq!(&data, b"JKLMNO");
+        assert_eq!(vm.get_reg(Register::Rax), 6);
+        assert_eq!(hypercall.buffer(), None);
+
+        // Other syscalls are left to the caller
+        vm.set_reg(Register::Rax, 60);
+        assert!(!hypercall.handle_syscall(&mut vm, b"JKLMNO")?);
+
+        Ok(())
+    }
+}
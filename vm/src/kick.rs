@@ -0,0 +1,120 @@
+//! Preemption of running vcpus
+//!
+//! A vcpu is kicked out of guest mode by sending `KICK_SIGNAL` to the thread
+//! running it. The signal handler sets the `immediate_exit` field of the
+//! thread `kvm_run` region, so that a signal landing right before `KVM_RUN`
+//! is not lost.
+
+use kvm_bindings::kvm_run;
+use nix::sys::pthread::{pthread_kill, pthread_self};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use std::cell::Cell;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Once};
+
+/// Signal used to kick a vcpu thread
+pub const KICK_SIGNAL: Signal = Signal::SIGUSR1;
+
+thread_local! {
+    /// `kvm_run` region of the vcpu running on this thread, if any
+    static RUNNING: Cell<*mut kvm_run> = const { Cell::new(ptr::null_mut()) };
+}
+
+/// Installs the kick signal handler once for the process
+static INSTALL: Once = Once::new();
+
+/// Makes the vcpu running on the current thread, if any, exit with
+/// `VmExit::Interrupted`. Async signal safe, so it can be called from signal
+/// handlers (e.g. a timeout alarm).
+pub fn kick_current_thread() {
+    RUNNING.with(|running| {
+        let run = running.get();
+        if !run.is_null() {
+            unsafe { (*run).immediate_exit = 1 };
+        }
+    });
+}
+
+extern "C" fn kick_handler(_: i32) {
+    kick_current_thread();
+}
+
+/// Kick state shared between a `Vm` and its `VcpuKicker`s
+#[derive(Default)]
+pub(crate) struct KickState {
+    /// Thread running the vcpu, 0 if not running
+    thread: AtomicU64,
+    /// Whether a kick was not consumed by a run yet
+    pending: AtomicBool,
+}
+
+impl KickState {
+    /// Consumes a pending kick
+    pub(crate) fn take_pending(&self) -> bool {
+        self.pending.swap(false, Ordering::SeqCst)
+    }
+
+    /// Registers the current thread as running the vcpu of `run`, until the
+    /// returned guard is dropped
+    pub(crate) fn enter(&self, run: &mut kvm_run) -> RunGuard<'_> {
+        INSTALL.call_once(|| {
+            let action = SigAction::new(
+                SigHandler::Handler(kick_handler),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            unsafe {
+                sigaction(KICK_SIGNAL, &action).expect("Failed to setup kick signal handler");
+            }
+        });
+
+        let run = run as *mut kvm_run;
+        RUNNING.with(|running| running.set(run));
+        self.thread.store(pthread_self() as u64, Ordering::SeqCst);
+
+        RunGuard { state: self, run }
+    }
+}
+
+/// Registration of a running vcpu thread
+pub(crate) struct RunGuard<'a> {
+    /// Shared kick state
+    state: &'a KickState,
+    /// `kvm_run` region of the vcpu
+    run: *mut kvm_run,
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        self.state.thread.store(0, Ordering::SeqCst);
+        RUNNING.with(|running| running.set(ptr::null_mut()));
+
+        // A kick landing after the exit is kept pending for the next run
+        unsafe { (*self.run).immediate_exit = 0 };
+    }
+}
+
+/// Handle kicking a `Vm` out of guest mode from another thread. A kick
+/// received while the `Vm` is not running interrupts its next run.
+#[derive(Clone)]
+pub struct VcpuKicker {
+    /// Shared kick state
+    pub(crate) state: Arc<KickState>,
+}
+
+impl VcpuKicker {
+    /// Makes the current or next run of the `Vm` exit with
+    /// `VmExit::Interrupted`
+    pub fn kick(&self) {
+        self.state.pending.store(true, Ordering::SeqCst);
+
+        let thread = self.state.thread.load(Ordering::SeqCst);
+        if thread != 0 {
+            // The thread may have left the run in the meantime, the kick
+            // then stays pending
+            let _ = pthread_kill(thread as _, KICK_SIGNAL);
+        }
+    }
+}
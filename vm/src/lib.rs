@@ -1,16 +1,48 @@
 //! Virtual Machine low-level management
 
+mod access;
 mod bits;
+mod branches;
+mod coverage;
+mod crash;
+mod decode;
+mod drcov;
+mod elf;
+mod input;
+mod kick;
 mod memory;
 mod snapshot;
+mod stubs;
+mod symbols;
+mod syscalls;
 mod vm;
 mod x64;
 
 #[macro_use]
 extern crate vmm_sys_util;
 
+pub use access::{
+    accessing_sites, read_access_trace, write_access_trace, AccessKind, MemoryAccess,
+};
+pub use branches::{Branch, BranchRecording};
+pub use coverage::ModuleCoverage;
+pub use crash::{Crash, CrashKind, CrashStore, FaultAccess, ModuleOffset};
+pub use decode::CmpOperands;
+pub use drcov::write_drcov;
+pub use input::InputDelivery;
+pub use kick::{kick_current_thread, VcpuKicker, KICK_SIGNAL};
 pub use memory::{Mapping, PagePermissions};
 pub use snapshot::{
-    SnapshotError, SnapshotInfo, SnapshotMapping, SnapshotModule, SnapshotRegisters,
+    MappingFilter, MappingRule, Snapshot, SnapshotDiff, SnapshotError, SnapshotInfo,
+    SnapshotMapping, SnapshotModule, SnapshotRegisters,
+};
+pub use stubs::{DeterminismStubs, Stub};
+pub use symbols::{ModuleSymbols, SymbolLocation, Symbolizer};
+pub use syscalls::{SyscallModel, SyscallOutcome};
+pub use vm::{
+    CpuidHandler, CpuidResult, DirtyPages, ExitAction, ExitCallback, FailureReason, GuestFailure,
+    PageFaultDetail, PreRunCallback, PristineVm, Register, SegmentRegister, SupervisorProfile,
+    Timer, Trace, TraceStep, VirtualizationCpuid, VirtualizationInstruction, Vm, VmError, VmExit,
+    VmStats, SNAPSHOT_HYPERCALL, XCR0_AVX, XCR0_AVX512, XCR0_SSE,
 };
-pub use vm::{PageFaultDetail, Register, Vm, VmError, VmExit};
+pub use x64::{GdtBuilder, PrivilegeLevel, Tss};
@@ -7,6 +7,7 @@ mod phys;
 mod virt;
 
 pub use paging::{PagePermissions, PAGE_SIZE};
+pub use phys::FrozenMemory;
 pub use virt::{Mapping, VirtualMemory};
 
 use std::{error, fmt};
@@ -31,6 +32,10 @@ pub enum MemoryError {
     PhysWriteOutOfBounds(u64, usize),
     /// An integer overflow occured
     IntegerOverflow,
+    /// The frame at the physical `address` is past the end of the memory
+    FrameOutOfBounds(u64),
+    /// The mapping at `address` would be writable and executable
+    WritableExecutable(u64),
 }
 
 impl fmt::Display for MemoryError {
@@ -61,6 +66,12 @@ impl fmt::Display for MemoryError {
             MemoryError::IntegerOverflow => {
                 write!(f, "An integer overflow occured")
             }
+            MemoryError::FrameOutOfBounds(addr) => {
+                write!(f, "Physical frame out of bounds 0x{:x}", addr)
+            }
+            MemoryError::WritableExecutable(addr) => {
+                write!(f, "Writable and executable mapping at 0x{:x}", addr)
+            }
         }
     }
 }
@@ -75,6 +86,8 @@ impl error::Error for MemoryError {
             MemoryError::PhysWriteOutOfBounds(_, _) => "Physical write out of bounds",
             MemoryError::AddressUnmapped(_) => "Tried to access unmapped memory",
             MemoryError::IntegerOverflow => "An integer overflow occured",
+            MemoryError::FrameOutOfBounds(_) => "Physical frame out of bounds",
+            MemoryError::WritableExecutable(_) => "Writable and executable mapping",
         }
     }
 }
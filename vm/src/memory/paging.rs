@@ -29,6 +29,8 @@ impl PagePermissions {
     pub const WRITE: PagePermissions = PagePermissions(1 << 1);
     /// The page is executable
     pub const EXECUTE: PagePermissions = PagePermissions(1 << 2);
+    /// The page is accessible from user mode (CPL3)
+    pub const USER: PagePermissions = PagePermissions(1 << 3);
 
     // Readble bit field
     const READ_BIT: usize = 0;
@@ -36,6 +38,8 @@ impl PagePermissions {
     const WRITE_BIT: usize = 1;
     // Executable bit field
     const EXECUTE_BIT: usize = 2;
+    // User bit field
+    const USER_BIT: usize = 3;
 
     /// Creates a new PagePermissions object
     pub fn new(flags: usize) -> PagePermissions {
@@ -77,6 +81,24 @@ impl PagePermissions {
     pub fn set_executable(&mut self, executable: bool) {
         self.0.set_bit(Self::EXECUTE_BIT, executable)
     }
+
+    /// Gets the user mode access status
+    #[inline]
+    pub fn user(&self) -> bool {
+        self.0.is_bit_set(Self::USER_BIT)
+    }
+
+    /// Sets the user mode access status
+    #[inline]
+    pub fn set_user(&mut self, user: bool) {
+        self.0.set_bit(Self::USER_BIT, user)
+    }
+
+    /// Checks whether all the permissions of `other` are granted
+    #[inline]
+    pub fn contains(&self, other: PagePermissions) -> bool {
+        self.0 & other.0 == other.0
+    }
 }
 
 impl core::ops::BitOr<PagePermissions> for PagePermissions {
@@ -158,6 +180,7 @@ impl PageTable {
 
             self.entries[entry_index].set_writable(perms.writable());
             self.entries[entry_index].set_executable(perms.executable());
+            self.entries[entry_index].set_user_accessible(perms.user());
 
             let table = self.next_table(entry_index, allocator).unwrap();
             table.wipe();
@@ -172,6 +195,10 @@ impl PageTable {
                 self.entries[entry_index].set_executable(true);
             }
 
+            if perms.user() && !self.entries[entry_index].user_accessible() {
+                self.entries[entry_index].set_user_accessible(true);
+            }
+
             self.next_table(entry_index, allocator).unwrap()
         }
     }
@@ -256,6 +283,12 @@ impl PageTableEntry {
         self.0.is_bit_set(Self::USER_ACCESSIBLE_BIT)
     }
 
+    /// Set whether or not the page is accessible by a user
+    #[inline]
+    pub fn set_user_accessible(&mut self, user_accessible: bool) {
+        self.0.set_bit(Self::USER_ACCESSIBLE_BIT, user_accessible);
+    }
+
     /// Whether or not the write go directly to memory on this page
     #[inline]
     pub fn write_caching(&self) -> bool {
@@ -5,7 +5,7 @@ use super::MemoryError;
 use super::{Result, PAGE_SIZE};
 
 use crate::bits::Alignement;
-use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::mman::{mmap, mprotect, munmap, MapFlags, ProtFlags};
 
 /// Virtual machine physical memory
 #[derive(Debug)]
@@ -56,6 +56,18 @@ impl PhysicalMemory {
         self.size
     }
 
+    /// Return the top offset of the frame allocator
+    #[inline]
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    /// Set the top offset of the frame allocator
+    #[inline]
+    pub fn set_top(&mut self, top: usize) {
+        self.top = top;
+    }
+
     /// Returns a slice covering an asked area
     #[inline]
     pub fn raw_slice(&self, pa: usize, length: usize) -> Result<&[u8]> {
@@ -150,3 +162,74 @@ impl Drop for PhysicalMemory {
         unsafe { munmap(self.raw_data.cast(), self.size).unwrap() }
     }
 }
+
+/// Read-only copy of a virtual machine physical memory. It can be shared
+/// between threads and stays shared between processes after a fork.
+#[derive(Debug)]
+pub struct FrozenMemory {
+    /// Point to the start of the frozen memory
+    raw_data: *const u8,
+    /// Size of the frozen memory
+    size: usize,
+    /// Top offset of the heap allocation at freeze time
+    top: usize,
+}
+
+// The memory is never written after its creation
+unsafe impl Send for FrozenMemory {}
+unsafe impl Sync for FrozenMemory {}
+
+impl FrozenMemory {
+    /// Create a new instance of `FrozenMemory` from a `PhysicalMemory` state
+    pub fn new(pmem: &PhysicalMemory) -> Result<Self> {
+        // Mmap a private copy
+        let raw_data = unsafe {
+            mmap(
+                core::ptr::null_mut(),
+                pmem.size(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_ANONYMOUS | MapFlags::MAP_PRIVATE,
+                -1,
+                0,
+            )
+        }
+        .map_err(|_| MemoryError::PhysmemAlloc)?;
+
+        // Copy the memory and seal it
+        unsafe {
+            core::ptr::copy_nonoverlapping(pmem.raw_data, raw_data as *mut u8, pmem.size());
+            mprotect(raw_data, pmem.size(), ProtFlags::PROT_READ)
+                .map_err(|_| MemoryError::PhysmemAlloc)?;
+        }
+
+        Ok(Self {
+            raw_data: raw_data as *const u8,
+            size: pmem.size(),
+            top: pmem.top(),
+        })
+    }
+
+    /// Return the total size of the region
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Return the top offset of the frame allocator at freeze time
+    #[inline]
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    /// Returns a slice covering the whole memory
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.raw_data, self.size) }
+    }
+}
+
+impl Drop for FrozenMemory {
+    fn drop(&mut self) {
+        unsafe { munmap(self.raw_data as *mut _, self.size).unwrap() }
+    }
+}
@@ -6,6 +6,8 @@ use super::paging::{
 use super::phys::PhysicalMemory;
 use super::{MemoryError, Result, PAGE_SIZE};
 
+use crate::bits::Alignement;
+
 use std::cmp::min;
 
 /// Virtual machine memory manager
@@ -15,6 +17,8 @@ pub struct VirtualMemory {
     pub(crate) pmem: PhysicalMemory,
     /// Current page_directory
     page_directory: usize,
+    /// Whether writable and executable mappings are rejected
+    wx_enforced: bool,
 }
 
 impl VirtualMemory {
@@ -37,11 +41,17 @@ impl VirtualMemory {
         Ok(VirtualMemory {
             pmem: pmem,
             page_directory: frame,
+            wx_enforced: false,
         })
     }
 
-    /// Map a page to a frame
-    fn map_page(&mut self, addr: VirtAddr, perms: PagePermissions) -> Result<()> {
+    /// Map a page to a frame, allocated if not given
+    fn map_page(
+        &mut self,
+        addr: VirtAddr,
+        frame: Option<usize>,
+        perms: PagePermissions,
+    ) -> Result<()> {
         let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
         let p3 = p4.next_table_create(addr.p4_index(), &mut self.pmem, perms);
         let p2 = p3.next_table_create(addr.p3_index(), &mut self.pmem, perms);
@@ -52,19 +62,46 @@ impl VirtualMemory {
         }
 
         // Get a frame to map page to
-        let frame = self.pmem.allocate_frame().ok_or(MemoryError::OutOfMemory)?;
+        let frame = match frame {
+            Some(frame) => frame,
+            None => self.pmem.allocate_frame().ok_or(MemoryError::OutOfMemory)?,
+        };
 
         // Set p1 entry
         p1.entries[addr.p1_index()].set_address(frame as u64);
         p1.entries[addr.p1_index()].set_present(true);
         p1.entries[addr.p1_index()].set_writable(perms.writable());
         p1.entries[addr.p1_index()].set_executable(perms.executable());
+        p1.entries[addr.p1_index()].set_user_accessible(perms.user());
+
+        Ok(())
+    }
+
+    /// Rejects, or not, the mappings which are both writable and executable
+    #[inline]
+    pub fn set_wx_enforced(&mut self, enforced: bool) {
+        self.wx_enforced = enforced;
+    }
+
+    /// Returns whether writable and executable mappings are rejected
+    #[inline]
+    pub fn wx_enforced(&self) -> bool {
+        self.wx_enforced
+    }
+
+    /// Checks the permissions of a new mapping against the W^X policy
+    fn check_wx(&self, addr: u64, perms: PagePermissions) -> Result<()> {
+        if self.wx_enforced && perms.writable() && perms.executable() {
+            return Err(MemoryError::WritableExecutable(addr));
+        }
 
         Ok(())
     }
 
     /// Map virtual memory area
     pub fn mmap(&mut self, addr: u64, size: usize, perms: PagePermissions) -> Result<()> {
+        self.check_wx(addr, perms)?;
+
         // Compute pages range
         let start = VirtAddr::new(addr);
         assert!(start.aligned(), "Page address must be aligned");
@@ -74,12 +111,182 @@ impl VirtualMemory {
 
         // Loop through pages to map
         for page in pages {
-            self.map_page(page, perms)?;
+            self.map_page(page, None, perms)?;
+        }
+
+        Ok(())
+    }
+
+    /// Map virtual memory area to the given physical frames. Frames past the
+    /// allocator top are reserved, frames below it are shared with their
+    /// current users (e.g. identity maps).
+    pub fn map_phys(
+        &mut self,
+        addr: u64,
+        phys: u64,
+        size: usize,
+        perms: PagePermissions,
+    ) -> Result<()> {
+        self.check_wx(addr, perms)?;
+
+        // Compute pages range
+        let start = VirtAddr::new(addr);
+        assert!(start.aligned(), "Page address must be aligned");
+        assert!(
+            phys & (PAGE_SIZE as u64 - 1) == 0,
+            "Frame address must be aligned"
+        );
+
+        let end = VirtAddr::new(start.address() + size as u64);
+        let pages = VirtRange::new(start, end);
+
+        // Check the frames fit in physical memory
+        let frames_end = (phys as usize)
+            .checked_add(size.align_power2(PAGE_SIZE))
+            .ok_or(MemoryError::IntegerOverflow)?;
+        if frames_end > self.pmem.size() {
+            return Err(MemoryError::FrameOutOfBounds(phys));
+        }
+
+        // Keep the page tables out of the frames
+        if frames_end > self.pmem.top() {
+            self.pmem.set_top(frames_end);
+        }
+
+        // Loop through pages to map
+        for (index, page) in pages.enumerate() {
+            self.map_page(page, Some(phys as usize + index * PAGE_SIZE), perms)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the last level entry of a mapped page, after merging
+    /// `perms` into the page directories
+    fn page_entry_mut(
+        &mut self,
+        page: VirtAddr,
+        perms: PagePermissions,
+    ) -> Result<&'static mut PageTableEntry> {
+        let unmapped = MemoryError::AddressUnmapped(page.address());
+
+        // Walk the page directories
+        let mut table = PageTable::from_addr(self.pmem.translate(self.page_directory));
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            let next = table.next_table_address(index).ok_or(unmapped)?;
+
+            let entry = &mut table.entries[index];
+            if perms.writable() {
+                entry.set_writable(true);
+            }
+            if perms.executable() {
+                entry.set_executable(true);
+            }
+            if perms.user() {
+                entry.set_user_accessible(true);
+            }
+
+            table = PageTable::from_addr(self.pmem.translate(next));
+        }
+
+        let entry = &mut table.entries[page.p1_index()];
+        if entry.unused() {
+            return Err(unmapped);
+        }
+
+        Ok(entry)
+    }
+
+    /// Changes the permissions of an already mapped virtual memory area
+    pub fn mprotect(&mut self, addr: u64, size: usize, perms: PagePermissions) -> Result<()> {
+        self.check_wx(addr, perms)?;
+
+        // Compute pages range
+        let start = VirtAddr::new(addr);
+        assert!(start.aligned(), "Page address must be aligned");
+
+        let end = VirtAddr::new(start.address() + size as u64);
+        let pages = VirtRange::new(start, end);
+
+        for page in pages {
+            let entry = self.page_entry_mut(page, perms)?;
+            entry.set_writable(perms.writable());
+            entry.set_executable(perms.executable());
+            entry.set_user_accessible(perms.user());
         }
 
         Ok(())
     }
 
+    /// Marks a mapped page present, or not. Accesses from the guest to a
+    /// page which is not present fault, its frame staying allocated.
+    pub fn set_present(&mut self, addr: u64, present: bool) -> Result<()> {
+        self.page_entry_mut(VirtAddr::new(addr), PagePermissions::new(0))?
+            .set_present(present);
+
+        Ok(())
+    }
+
+    /// Returns whether the page of an address is mapped and present
+    pub fn present(&self, addr: u64) -> bool {
+        self.page_table_address(addr)
+            .map(|table| {
+                let p1 = PageTable::from_addr(self.pmem.translate(table as usize));
+                p1.entries[VirtAddr::new(addr).p1_index()].present()
+            })
+            .unwrap_or(false)
+    }
+
+    /// Returns the physical address of the last level page table holding
+    /// the entry of an address
+    pub fn page_table_address(&self, addr: u64) -> Option<u64> {
+        let address = VirtAddr::new(addr);
+        let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
+        let p3 = p4.next_table(address.p4_index(), &self.pmem)?;
+        let p2 = p3.next_table(address.p3_index(), &self.pmem)?;
+
+        p2.next_table_address(address.p2_index()).map(|a| a as u64)
+    }
+
+    /// Makes an already mapped virtual memory area accessible, or not, from
+    /// user mode. Page directories are left user accessible, as the last
+    /// level entries are enough to restrict the accesses.
+    pub fn set_user_accessible(&mut self, addr: u64, size: usize, user: bool) -> Result<()> {
+        // Compute pages range
+        let start = VirtAddr::new(addr);
+        assert!(start.aligned(), "Page address must be aligned");
+
+        let end = VirtAddr::new(start.address() + size as u64);
+        let pages = VirtRange::new(start, end);
+
+        for page in pages {
+            self.page_entry_mut(page, PagePermissions::USER)?
+                .set_user_accessible(user);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the ranges (start, end) of the pages which are both writable
+    /// and executable
+    pub fn wx_regions(&self) -> Vec<(u64, u64)> {
+        let mut regions: Vec<(u64, u64)> = Vec::new();
+
+        for (address, page) in PageIterator::new(self) {
+            if !page.writable() || !page.executable() {
+                continue;
+            }
+
+            // Extend the last region when contiguous
+            match regions.last_mut() {
+                Some((_, end)) if *end == address => *end += PAGE_SIZE as u64,
+                _ => regions.push((address, address + PAGE_SIZE as u64)),
+            }
+        }
+
+        regions
+    }
+
     /// Returns the physical address of a page. Or nothing if the address is not mapped.
     fn get_page_pa(&self, address: VirtAddr) -> Option<usize> {
         let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
@@ -90,6 +297,30 @@ impl VirtualMemory {
         p1.next_table_address(address.p1_index())
     }
 
+    /// Translates a virtual address to its physical address (page offset
+    /// included) and the permissions of its page. Or nothing if the address
+    /// is not mapped.
+    pub fn virt_to_phys(&self, addr: u64) -> Option<(u64, PagePermissions)> {
+        let address = VirtAddr::new(addr);
+        let p4 = PageTable::from_addr(self.pmem.translate(self.page_directory));
+        let p3 = p4.next_table(address.p4_index(), &self.pmem)?;
+        let p2 = p3.next_table(address.p3_index(), &self.pmem)?;
+        let p1 = p2.next_table(address.p2_index(), &self.pmem)?;
+
+        let entry = p1.entries[address.p1_index()];
+        if entry.unused() {
+            return None;
+        }
+
+        // Mapped pages are always readable
+        let mut perms = PagePermissions::READ;
+        perms.set_writable(entry.writable());
+        perms.set_executable(entry.executable());
+        perms.set_user(entry.user_accessible());
+
+        Some((entry.address() + (addr & (PAGE_SIZE as u64 - 1)), perms))
+    }
+
     /// Reads data from the virtual address space
     pub fn read(&self, addr: u64, output: &mut [u8]) -> Result<()> {
         // Compute the range of pages between VA and VA + read_size
@@ -160,6 +391,81 @@ impl VirtualMemory {
         Ok(())
     }
 
+    /// Fills a virtual range with a byte, page by page and without
+    /// intermediate buffer
+    pub fn write_bytes(&mut self, addr: u64, byte: u8, len: usize) -> Result<()> {
+        // Compute the range of pages between VA and VA + len
+        let start = VirtAddr::new(addr);
+        let end = VirtAddr::new(addr + len as u64);
+        let pages = VirtRange::new(start, end);
+
+        let mut index = 0;
+        let mut page_off = addr & (PAGE_SIZE as u64 - 1);
+
+        // Loop through pages to fill
+        for page in pages {
+            // Get physical page for given VA
+            let pa = self
+                .get_page_pa(page)
+                .ok_or(MemoryError::AddressUnmapped(page.address()))?;
+
+            let remaining_bytes = (len - index) as u64;
+            let page_bytes = PAGE_SIZE as u64 - page_off;
+            let bytes_to_fill = min(remaining_bytes, page_bytes);
+
+            // Partial fill of the page
+            self.pmem
+                .raw_slice_mut(pa + page_off as usize, bytes_to_fill as usize)?
+                .fill(byte);
+
+            // Update cursor
+            page_off = 0;
+            index += bytes_to_fill as usize;
+        }
+
+        Ok(())
+    }
+
+    /// Copies data from the virtual address space of another `VirtualMemory`,
+    /// page by page and without intermediate buffer
+    pub fn copy_from(
+        &mut self,
+        other: &VirtualMemory,
+        src_addr: u64,
+        dst_addr: u64,
+        len: usize,
+    ) -> Result<()> {
+        let mut index = 0;
+
+        while index < len {
+            let src = src_addr + index as u64;
+            let dst = dst_addr + index as u64;
+
+            // Get physical addresses on both sides
+            let (src_pa, _) = other
+                .virt_to_phys(src)
+                .ok_or(MemoryError::AddressUnmapped(src))?;
+            let (dst_pa, _) = self
+                .virt_to_phys(dst)
+                .ok_or(MemoryError::AddressUnmapped(dst))?;
+
+            // Copy up to the closest page boundary
+            let src_bytes = PAGE_SIZE - (src_pa as usize & (PAGE_SIZE - 1));
+            let dst_bytes = PAGE_SIZE - (dst_pa as usize & (PAGE_SIZE - 1));
+            let bytes_to_copy = min(len - index, min(src_bytes, dst_bytes));
+
+            let input = other.pmem.raw_slice(src_pa as usize, bytes_to_copy)?;
+            self.pmem
+                .raw_slice_mut(dst_pa as usize, bytes_to_copy)?
+                .copy_from_slice(input);
+
+            // Update cursor
+            index += bytes_to_copy;
+        }
+
+        Ok(())
+    }
+
     /// Writes a passed value to memory
     #[inline]
     pub fn write_val<T>(&mut self, address: u64, val: T) -> Result<()> {
@@ -348,7 +654,7 @@ impl<'a> Iterator for PageIteratorMut<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::{PagePermissions, Result};
+    use super::{MemoryError, PagePermissions, Result};
     use super::{VirtualMemory, PAGE_SIZE};
 
     #[test]
@@ -408,6 +714,152 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_virt_to_phys() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::EXECUTE;
+
+        vm.mmap(0x1337000, PAGE_SIZE, perms)?;
+        vm.write(0x1337444, &[0x41])?;
+
+        let (pa, page_perms) = vm.virt_to_phys(0x1337444).unwrap();
+        assert_eq!(pa & (PAGE_SIZE as u64 - 1), 0x444);
+        assert_eq!(page_perms, perms);
+
+        let mut byte = [0u8; 1];
+        vm.pmem.read(pa as usize, &mut byte)?;
+        assert_eq!(byte, [0x41]);
+
+        assert!(vm.virt_to_phys(0x1338000).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_from() -> Result<()> {
+        let mut src = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let mut dst = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        src.mmap(0x1337000, PAGE_SIZE * 2, perms)?;
+        dst.mmap(0x4000, PAGE_SIZE * 3, perms)?;
+
+        // Both sides cross page boundaries at different offsets
+        let magic: Vec<u8> = (0..PAGE_SIZE + 0x10).map(|i| i as u8).collect();
+        src.write(0x1337ff0, &magic)?;
+        dst.copy_from(&src, 0x1337ff0, 0x4800, magic.len())?;
+
+        let mut magic_result = vec![0u8; magic.len()];
+        dst.read(0x4800, &mut magic_result)?;
+        assert_eq!(magic, magic_result, "Read after copy failed");
+
+        assert!(dst.copy_from(&src, 0x1339000, 0x4000, 1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_bytes() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        vm.mmap(0x1337000, PAGE_SIZE * 3, perms)?;
+        vm.write(0x1337000, &[0x41; PAGE_SIZE * 3])?;
+        vm.write_bytes(0x1337ffd, 0, PAGE_SIZE + 6)?;
+
+        let mut result = vec![0u8; PAGE_SIZE * 3];
+        vm.read(0x1337000, &mut result)?;
+        assert!(result[..0xffd].iter().all(|&b| b == 0x41));
+        assert!(result[0xffd..0x2003].iter().all(|&b| b == 0));
+        assert!(result[0x2003..].iter().all(|&b| b == 0x41));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_phys() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        // The frames are reserved, later mappings do not use them
+        vm.map_phys(0x1337000, 0x100000, PAGE_SIZE * 2, perms)?;
+        vm.mmap(0x4000, PAGE_SIZE, perms)?;
+        assert_eq!(vm.virt_to_phys(0x1338010).unwrap().0, 0x101010);
+        assert!(vm.virt_to_phys(0x4000).unwrap().0 >= 0x102000);
+
+        // Aliases of the same frame share their content
+        vm.map_phys(0x8000, 0x100000, PAGE_SIZE, perms)?;
+        vm.write(0x1337000, &[0x41])?;
+        assert_eq!(vm.read_val::<u8>(0x8000)?, 0x41);
+
+        assert!(vm.map_phys(0x9000, 0x200000, PAGE_SIZE, perms).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_user_accessible() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+
+        vm.mmap(0x1337000, PAGE_SIZE * 2, perms)?;
+        vm.mmap(0x4000, PAGE_SIZE, perms | PagePermissions::USER)?;
+        assert!(!vm.virt_to_phys(0x1337000).unwrap().1.user());
+        assert!(vm.virt_to_phys(0x4000).unwrap().1.user());
+
+        vm.set_user_accessible(0x1338000, PAGE_SIZE, true)?;
+        assert!(!vm.virt_to_phys(0x1337000).unwrap().1.user());
+        assert!(vm.virt_to_phys(0x1338000).unwrap().1.user());
+
+        assert!(vm.set_user_accessible(0x1339000, PAGE_SIZE, true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wx_enforced() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let rw = PagePermissions::READ | PagePermissions::WRITE;
+        let rx = PagePermissions::READ | PagePermissions::EXECUTE;
+        let rwx = rw | PagePermissions::EXECUTE;
+
+        vm.set_wx_enforced(true);
+        assert_eq!(
+            vm.mmap(0x1337000, PAGE_SIZE, rwx),
+            Err(MemoryError::WritableExecutable(0x1337000))
+        );
+
+        vm.mmap(0x1337000, PAGE_SIZE, rw)?;
+        assert!(vm.mprotect(0x1337000, PAGE_SIZE, rwx).is_err());
+        vm.mprotect(0x1337000, PAGE_SIZE, rx)?;
+        assert_eq!(vm.virt_to_phys(0x1337000).unwrap().1, rx);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wx_regions() -> Result<()> {
+        let mut vm = VirtualMemory::new(512 * PAGE_SIZE)?;
+        let rw = PagePermissions::READ | PagePermissions::WRITE;
+        let rwx = rw | PagePermissions::EXECUTE;
+
+        vm.mmap(0x1337000, PAGE_SIZE * 2, rwx)?;
+        vm.mmap(0x1339000, PAGE_SIZE, rw)?;
+        vm.mmap(0x133a000, PAGE_SIZE, rwx)?;
+        assert_eq!(
+            vm.wx_regions(),
+            vec![(0x1337000, 0x1339000), (0x133a000, 0x133b000)]
+        );
+
+        vm.mprotect(0x1337000, PAGE_SIZE, rw)?;
+        assert_eq!(
+            vm.wx_regions(),
+            vec![(0x1338000, 0x1339000), (0x133a000, 0x133b000)]
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_write_huge() -> Result<()> {
         let mut vm = VirtualMemory::new(6 * PAGE_SIZE).expect("Could not allocate Vm memory");
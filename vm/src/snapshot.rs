@@ -1,9 +1,13 @@
-use crate::memory::PagePermissions;
+use crate::memory::{PagePermissions, PAGE_SIZE};
 use serde::{de::Error, Deserialize};
 use std::cmp;
 use std::collections::BTreeMap;
-use std::fs;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 /// Error during snapshot manipulation
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -23,6 +27,9 @@ impl From<std::io::Error> for SnapshotError {
 /// Result type in snapshot manipulation
 type Result<T> = std::result::Result<T, SnapshotError>;
 
+/// Start of the kernel half of the address space
+const KERNEL_BASE: u64 = 0xffff_8000_0000_0000;
+
 /// Parse an unsigned 64 bits number in hex form
 fn parse_u64<'de, D>(d: D) -> std::result::Result<u64, D::Error>
 where
@@ -32,6 +39,35 @@ where
     u64::from_str_radix(s, 16).map_err(D::Error::custom)
 }
 
+/// Parse an optional unsigned 64 bits number in hex form
+fn parse_u64_opt<'de, D>(d: D) -> std::result::Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    parse_u64(d).map(Some)
+}
+
+/// Parse an optional hex encoded byte buffer
+fn parse_bytes_opt<'de, D>(d: D) -> std::result::Result<Option<Vec<u8>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: &str = Deserialize::deserialize(d)?;
+
+    if !s.len().is_multiple_of(2) {
+        return Err(D::Error::custom("odd length hex buffer"));
+    }
+
+    // Convert each pair of hex digits into a byte
+    let bytes = (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .map_err(D::Error::custom)?;
+
+    Ok(Some(bytes))
+}
+
 /// Parse permission in string form
 fn parse_perms<'de, D>(d: D) -> std::result::Result<PagePermissions, D::Error>
 where
@@ -44,6 +80,8 @@ where
     perms.set_readable(true); // No execute only in x64 iirc
     perms.set_writable(s.contains('w'));
     perms.set_executable(s.contains('x'));
+    // Kernel snapshots flag the user pages with a 'u' in place of 'p'
+    perms.set_user(s.contains('u'));
 
     Ok(perms)
 }
@@ -111,6 +149,37 @@ pub struct SnapshotRegisters {
     /// GS BASE
     #[serde(deserialize_with = "parse_u64")]
     pub gs_base: u64,
+    /// KERNEL GS BASE (per-cpu data of kernel snapshots, swapped in by swapgs)
+    #[serde(default, deserialize_with = "parse_u64_opt")]
+    pub kernel_gs_base: Option<u64>,
+    /// IA32_EFER (only the NXE bit is honored, the others are fixed by the vm)
+    #[serde(default, deserialize_with = "parse_u64_opt")]
+    pub efer: Option<u64>,
+    /// DR0
+    #[serde(default, deserialize_with = "parse_u64_opt")]
+    pub dr0: Option<u64>,
+    /// DR1
+    #[serde(default, deserialize_with = "parse_u64_opt")]
+    pub dr1: Option<u64>,
+    /// DR2
+    #[serde(default, deserialize_with = "parse_u64_opt")]
+    pub dr2: Option<u64>,
+    /// DR3
+    #[serde(default, deserialize_with = "parse_u64_opt")]
+    pub dr3: Option<u64>,
+    /// DR6
+    #[serde(default, deserialize_with = "parse_u64_opt")]
+    pub dr6: Option<u64>,
+    /// DR7
+    #[serde(default, deserialize_with = "parse_u64_opt")]
+    pub dr7: Option<u64>,
+    /// Raw XSAVE area (as returned by PTRACE_GETREGSET NT_X86_XSTATE)
+    #[serde(default, deserialize_with = "parse_bytes_opt")]
+    pub xsave: Option<Vec<u8>>,
+    /// XCR0, the extended state components enabled by the guest (AVX,
+    /// AVX-512...), x87 and SSE only if not recorded
+    #[serde(default, deserialize_with = "parse_u64_opt")]
+    pub xcr0: Option<u64>,
 }
 
 /// Snapshot mapping
@@ -132,6 +201,70 @@ pub struct SnapshotMapping {
     pub image: Option<String>,
 }
 
+/// Rule selecting snapshot mappings
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MappingRule {
+    /// Mappings of a file image, by path or file name
+    Module(String),
+    /// Mappings overlapping an address range
+    Range(Range<u64>),
+    /// Mappings granting at least these permissions
+    Permissions(PagePermissions),
+}
+
+impl MappingRule {
+    /// Checks whether the rule selects `mapping`
+    pub fn matches(&self, mapping: &SnapshotMapping) -> bool {
+        match self {
+            MappingRule::Module(name) => match &mapping.image {
+                Some(image) => {
+                    image == name || Path::new(image).file_name() == Some(OsStr::new(name))
+                }
+                None => false,
+            },
+            MappingRule::Range(range) => mapping.start < range.end && range.start < mapping.end,
+            MappingRule::Permissions(permissions) => mapping.permissions.contains(*permissions),
+        }
+    }
+}
+
+/// Selection of the snapshot mappings loaded in a `Vm`, e.g. to skip huge
+/// regions irrelevant to the target and fit in a smaller memory size. A
+/// mapping is loaded if it matches one of the include rules, or there are
+/// none, and none of the exclude rules.
+#[derive(Clone, Debug, Default)]
+pub struct MappingFilter {
+    /// Rules selecting the loaded mappings
+    include: Vec<MappingRule>,
+    /// Rules selecting the skipped mappings
+    exclude: Vec<MappingRule>,
+}
+
+impl MappingFilter {
+    /// Creates a filter loading all the mappings
+    pub fn new() -> MappingFilter {
+        MappingFilter::default()
+    }
+
+    /// Loads the mappings selected by `rule`, instead of all of them
+    pub fn include(mut self, rule: MappingRule) -> MappingFilter {
+        self.include.push(rule);
+        self
+    }
+
+    /// Skips the mappings selected by `rule`
+    pub fn exclude(mut self, rule: MappingRule) -> MappingFilter {
+        self.exclude.push(rule);
+        self
+    }
+
+    /// Checks whether `mapping` must be loaded
+    pub fn matches(&self, mapping: &SnapshotMapping) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|rule| rule.matches(mapping)))
+            && !self.exclude.iter().any(|rule| rule.matches(mapping))
+    }
+}
+
 /// Snapshot raw information contained in JSON form
 #[derive(Deserialize)]
 struct SnapshotInfoRaw {
@@ -141,10 +274,71 @@ struct SnapshotInfoRaw {
     pub registers: SnapshotRegisters,
     /// Map of symbols
     pub symbols: Option<BTreeMap<String, String>>,
+    /// Map of module names to their debug information file
+    pub debug_info: Option<BTreeMap<String, String>>,
 }
 
-/// Mapped code object
+/// Pages of a memory dump read on demand, on the first access of the
+/// guest, and shared by the vms loaded from the same snapshot
 #[derive(Debug)]
+pub(crate) struct SnapshotPages {
+    /// Memory dump
+    dump: File,
+    /// Offset in the dump of each page, by virtual address
+    offsets: BTreeMap<u64, u64>,
+    /// Contents of the pages read so far, by virtual address
+    cache: Mutex<BTreeMap<u64, Arc<[u8]>>>,
+}
+
+impl SnapshotPages {
+    /// Creates an empty set of pages of a memory dump
+    pub(crate) fn new(dump: File) -> SnapshotPages {
+        SnapshotPages {
+            dump,
+            offsets: BTreeMap::new(),
+            cache: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Adds the page at `address`, found at `offset` in the dump
+    pub(crate) fn insert(&mut self, address: u64, offset: u64) {
+        self.offsets.insert(address, offset);
+    }
+
+    /// Checks whether the page at `address` comes from the dump
+    pub(crate) fn contains(&self, address: u64) -> bool {
+        self.offsets.contains_key(&address)
+    }
+
+    /// Returns the contents of the page at `address`, zero padded past the
+    /// end of the dump
+    pub(crate) fn read(&self, address: u64) -> Result<Arc<[u8]>> {
+        let offset = *self.offsets.get(&address).ok_or_else(|| {
+            SnapshotError::ParsingError(format!("Page {:#x} not in the memory dump", address))
+        })?;
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(page) = cache.get(&address) {
+            return Ok(Arc::clone(page));
+        }
+
+        let mut page = vec![0u8; PAGE_SIZE];
+        let mut read = 0;
+        while read < PAGE_SIZE {
+            match self.dump.read_at(&mut page[read..], offset + read as u64)? {
+                0 => break,
+                size => read += size,
+            }
+        }
+
+        let page: Arc<[u8]> = page.into();
+        cache.insert(address, Arc::clone(&page));
+        Ok(page)
+    }
+}
+
+/// Mapped code object
+#[derive(Debug, Clone)]
 pub struct SnapshotModule {
     /// Starting address of the module
     pub start: u64,
@@ -152,6 +346,10 @@ pub struct SnapshotModule {
     pub end: u64,
     /// Name of the loaded object
     pub name: String,
+    /// Path of the loaded object
+    pub path: String,
+    /// Path of a file holding the object debug information
+    pub debug_file: Option<String>,
 }
 
 /// Tartiflette snapshot info
@@ -197,35 +395,7 @@ impl SnapshotInfo {
         }
 
         // Process the modules
-        let mut modules: BTreeMap<String, SnapshotModule> = BTreeMap::new();
-
-        // Loop through mappings
-        for mapping in info.mappings.iter() {
-            if let Some(module_path) = mapping.image.as_deref() {
-                // Get the module name, equivalent ton path basename
-                let module_name = module_path.split("/").last().unwrap().to_string();
-
-                // Handle module
-                match modules.get_mut(&module_name) {
-                    Some(module) => {
-                        // Update module mapping region
-                        module.start = cmp::min(module.start, mapping.start);
-                        module.end = cmp::max(module.end, mapping.end);
-                    }
-                    None => {
-                        // Add module
-                        modules.insert(
-                            module_name.clone(),
-                            SnapshotModule {
-                                start: mapping.start,
-                                end: mapping.end,
-                                name: module_name,
-                            },
-                        );
-                    }
-                }
-            }
-        }
+        let modules = collect_modules(&info.mappings, info.debug_info.as_ref());
 
         // Return a new `SnapshotInfo`
         Ok(SnapshotInfo {
@@ -235,4 +405,504 @@ impl SnapshotInfo {
             symbols: symbols,
         })
     }
+
+    /// Create a new `SnapshotInfo` from `/proc/<pid>/maps` style text and
+    /// register text (see `Snapshot::from_maps_files`)
+    pub fn from_maps<S: AsRef<str>>(maps: S, registers: S) -> Result<SnapshotInfo> {
+        let mappings = parse_maps(maps.as_ref())?;
+        let registers = parse_registers(registers.as_ref())?;
+        let modules = collect_modules(&mappings, None);
+
+        Ok(SnapshotInfo {
+            mappings,
+            registers,
+            modules,
+            symbols: BTreeMap::new(),
+        })
+    }
+}
+
+/// Groups the mappings of each file image in a module
+fn collect_modules(
+    mappings: &[SnapshotMapping],
+    debug_info: Option<&BTreeMap<String, String>>,
+) -> BTreeMap<String, SnapshotModule> {
+    let mut modules: BTreeMap<String, SnapshotModule> = BTreeMap::new();
+
+    // Loop through mappings
+    for mapping in mappings.iter() {
+        if let Some(module_path) = mapping.image.as_deref() {
+            // Get the module name, equivalent ton path basename
+            let module_name = module_path.split("/").last().unwrap().to_string();
+
+            // Handle module
+            match modules.get_mut(&module_name) {
+                Some(module) => {
+                    // Update module mapping region
+                    module.start = cmp::min(module.start, mapping.start);
+                    module.end = cmp::max(module.end, mapping.end);
+                }
+                None => {
+                    // Add module
+                    let debug_file = debug_info.and_then(|d| d.get(&module_name)).cloned();
+
+                    modules.insert(
+                        module_name.clone(),
+                        SnapshotModule {
+                            start: mapping.start,
+                            end: mapping.end,
+                            name: module_name,
+                            path: module_path.to_string(),
+                            debug_file,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    modules
+}
+
+/// Parses `/proc/<pid>/maps` style lines, the mappings being dumped back to
+/// back in their order. Kernel mappings (e.g. `[vsyscall]`) are left out, as
+/// they cannot be dumped from user mode.
+fn parse_maps(maps: &str) -> Result<Vec<SnapshotMapping>> {
+    let mut mappings = Vec::new();
+    let mut offset = 0;
+
+    for line in maps.lines().filter(|l| !l.trim().is_empty()) {
+        let invalid = || SnapshotError::ParsingError(format!("Invalid maps line: {}", line));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        // Range and permissions are mandatory
+        let (start, end) = fields
+            .first()
+            .and_then(|range| range.split_once('-'))
+            .ok_or_else(invalid)?;
+        let start = u64::from_str_radix(start, 16).map_err(|_| invalid())?;
+        let end = u64::from_str_radix(end, 16).map_err(|_| invalid())?;
+        let permissions = fields.get(1).ok_or_else(invalid)?;
+        if start >= end {
+            return Err(invalid());
+        }
+
+        if start >= KERNEL_BASE {
+            continue;
+        }
+
+        let mut perms = PagePermissions::new(0);
+        perms.set_readable(true);
+        perms.set_writable(permissions.contains('w'));
+        perms.set_executable(permissions.contains('x'));
+
+        mappings.push(SnapshotMapping {
+            start,
+            end,
+            physical_offset: offset,
+            permissions: perms,
+            // Same heuristic as the gdb script for file mappings
+            image: fields
+                .get(5)
+                .filter(|path| path.contains('/'))
+                .map(|path| path.to_string()),
+        });
+        offset += end - start;
+    }
+
+    Ok(mappings)
+}
+
+/// Parses register text with one register per line, its name followed by
+/// its value in hex (e.g. the output of gdb `info registers`). Lines of
+/// unknown registers or without a hex value are ignored.
+fn parse_registers(registers: &str) -> Result<SnapshotRegisters> {
+    let mut values = serde_json::Map::new();
+
+    for line in registers.lines() {
+        let mut fields = line
+            .split(|c: char| c.is_whitespace() || c == ':' || c == '=')
+            .filter(|f| !f.is_empty());
+
+        let (name, value) = match (fields.next(), fields.next()) {
+            (Some(name), Some(value)) => (name.to_lowercase(), value),
+            _ => continue,
+        };
+        let value = value.trim_start_matches("0x");
+        if u64::from_str_radix(value, 16).is_err() {
+            continue;
+        }
+
+        let name = match name.as_str() {
+            "eflags" => "rflags".to_string(),
+            _ => name,
+        };
+        values.insert(name, serde_json::Value::String(value.to_string()));
+    }
+
+    serde_json::from_str(&serde_json::Value::Object(values).to_string())
+        .map_err(|e| SnapshotError::ParsingError(e.to_string()))
+}
+
+impl SnapshotRegisters {
+    /// Returns the name and value of every register, `None` when it was
+    /// not recorded
+    pub fn values(&self) -> Vec<(&'static str, Option<u64>)> {
+        vec![
+            ("rax", Some(self.rax)),
+            ("rbx", Some(self.rbx)),
+            ("rcx", Some(self.rcx)),
+            ("rdx", Some(self.rdx)),
+            ("rsi", Some(self.rsi)),
+            ("rdi", Some(self.rdi)),
+            ("rsp", Some(self.rsp)),
+            ("rbp", Some(self.rbp)),
+            ("r8", Some(self.r8)),
+            ("r9", Some(self.r9)),
+            ("r10", Some(self.r10)),
+            ("r11", Some(self.r11)),
+            ("r12", Some(self.r12)),
+            ("r13", Some(self.r13)),
+            ("r14", Some(self.r14)),
+            ("r15", Some(self.r15)),
+            ("rip", Some(self.rip)),
+            ("rflags", Some(self.rflags)),
+            ("fs_base", Some(self.fs_base)),
+            ("gs_base", Some(self.gs_base)),
+            ("kernel_gs_base", self.kernel_gs_base),
+            ("efer", self.efer),
+            ("dr0", self.dr0),
+            ("dr1", self.dr1),
+            ("dr2", self.dr2),
+            ("dr3", self.dr3),
+            ("dr6", self.dr6),
+            ("dr7", self.dr7),
+            ("xcr0", self.xcr0),
+        ]
+    }
+}
+
+/// Differences between two snapshots
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    /// Mappings (start, end) only present in the other snapshot
+    pub added_mappings: Vec<(u64, u64)>,
+    /// Mappings (start, end) only present in this snapshot
+    pub removed_mappings: Vec<(u64, u64)>,
+    /// Registers whose value changed (name, this value, other value)
+    pub changed_registers: Vec<(&'static str, Option<u64>, Option<u64>)>,
+    /// Addresses of the pages mapped in both snapshots with different contents
+    pub changed_pages: Vec<u64>,
+}
+
+impl SnapshotDiff {
+    /// Returns whether or not the snapshots are identical
+    pub fn is_empty(&self) -> bool {
+        self.added_mappings.is_empty()
+            && self.removed_mappings.is_empty()
+            && self.changed_registers.is_empty()
+            && self.changed_pages.is_empty()
+    }
+}
+
+/// Tartiflette snapshot (information and memory dump)
+#[derive(Debug)]
+pub struct Snapshot {
+    /// Snapshot information
+    pub info: SnapshotInfo,
+    /// Memory dump referenced by the mappings physical offsets
+    pub data: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Create a new `Snapshot` from its information and memory dump
+    pub fn new(info: SnapshotInfo, data: Vec<u8>) -> Snapshot {
+        Snapshot { info, data }
+    }
+
+    /// Create a new `Snapshot` from the information and memory dump paths
+    pub fn from_files<P: AsRef<Path>>(snapshot_info: P, memory_dump: P) -> Result<Snapshot> {
+        let info = SnapshotInfo::from_file(snapshot_info)?;
+        let data = fs::read(memory_dump)?;
+
+        Ok(Snapshot::new(info, data))
+    }
+
+    /// Create a new `Snapshot` from a simple capture: a `/proc/<pid>/maps`
+    /// style file, a register file with one `<name> <hex value>` line per
+    /// register (e.g. gdb `info registers` output) and a flat memory dump
+    /// holding the mappings back to back, in their order
+    pub fn from_maps_files<P: AsRef<Path>>(
+        maps: P,
+        registers: P,
+        memory_dump: P,
+    ) -> Result<Snapshot> {
+        let info =
+            SnapshotInfo::from_maps(fs::read_to_string(maps)?, fs::read_to_string(registers)?)?;
+        let data = fs::read(memory_dump)?;
+
+        // The dump must hold every mapping
+        let size: u64 = info.mappings.iter().map(|m| m.end - m.start).sum();
+        if (data.len() as u64) < size {
+            return Err(SnapshotError::ParsingError(format!(
+                "Memory dump of {:#x} bytes, the mappings need {:#x}",
+                data.len(),
+                size
+            )));
+        }
+
+        Ok(Snapshot::new(info, data))
+    }
+
+    /// Returns the contents of the page at a given address, if mapped and dumped
+    pub fn page(&self, address: u64) -> Option<&[u8]> {
+        let page = address & !(PAGE_SIZE as u64 - 1);
+        let mapping = self
+            .info
+            .mappings
+            .iter()
+            .find(|m| m.start <= page && page < m.end)?;
+
+        let offset = (mapping.physical_offset + (page - mapping.start)) as usize;
+        self.data.get(offset..offset + PAGE_SIZE)
+    }
+
+    /// Computes the differences between this snapshot and an other one
+    pub fn diff(&self, other: &Snapshot) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+
+        // Compare mappings by range
+        let ranges = |s: &Snapshot| -> Vec<(u64, u64)> {
+            s.info.mappings.iter().map(|m| (m.start, m.end)).collect()
+        };
+        let (ours, theirs) = (ranges(self), ranges(other));
+
+        diff.added_mappings = theirs
+            .iter()
+            .filter(|r| !ours.contains(r))
+            .cloned()
+            .collect();
+        diff.removed_mappings = ours
+            .iter()
+            .filter(|r| !theirs.contains(r))
+            .cloned()
+            .collect();
+
+        // Compare registers
+        for ((name, ours), (_, theirs)) in self
+            .info
+            .registers
+            .values()
+            .into_iter()
+            .zip(other.info.registers.values())
+        {
+            if ours != theirs {
+                diff.changed_registers.push((name, ours, theirs));
+            }
+        }
+
+        // Compare the contents of pages present in both snapshots
+        for mapping in self.info.mappings.iter() {
+            for page in (mapping.start..mapping.end).step_by(PAGE_SIZE) {
+                if let (Some(ours), Some(theirs)) = (self.page(page), other.page(page)) {
+                    if ours != theirs {
+                        diff.changed_pages.push(page);
+                    }
+                }
+            }
+        }
+
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MappingFilter, MappingRule, Result, Snapshot, SnapshotInfo};
+    use crate::memory::{PagePermissions, PAGE_SIZE};
+
+    /// Minimal register set shared by the tests
+    const REGISTERS: &str = r#""rax": "0", "rbx": "0", "rcx": "0", "rdx": "0", "rsi": "0",
+        "rdi": "0", "rsp": "0", "rbp": "0", "r8": "0", "r9": "0", "r10": "0", "r11": "0",
+        "r12": "0", "r13": "0", "r14": "0", "r15": "0", "rip": "1337", "rflags": "202",
+        "fs_base": "7f0000001000", "gs_base": "0""#;
+
+    #[test]
+    /// Parses a snapshot without any extended cpu state
+    fn test_parse_minimal() -> Result<()> {
+        let data = format!(r#"{{"mappings": [], "registers": {{{}}}}}"#, REGISTERS);
+        let info = SnapshotInfo::from_string(data)?;
+
+        assert_eq!(info.registers.rip, 0x1337);
+        assert_eq!(info.registers.fs_base, 0x7f0000001000);
+        assert!(info.registers.kernel_gs_base.is_none());
+        assert!(info.registers.efer.is_none());
+        assert!(info.registers.xsave.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Parses a snapshot carrying debug registers and an xsave area
+    fn test_parse_extended() -> Result<()> {
+        let data = format!(
+            r#"{{"mappings": [], "registers": {{{}, "efer": "d01", "dr7": "400",
+                "xsave": "7f0300000000"}}}}"#,
+            REGISTERS
+        );
+        let info = SnapshotInfo::from_string(data)?;
+
+        assert_eq!(info.registers.efer, Some(0xd01));
+        assert_eq!(info.registers.dr7, Some(0x400));
+        assert!(info.registers.dr0.is_none());
+        assert_eq!(
+            info.registers.xsave.as_deref(),
+            Some(&[0x7f, 0x03, 0, 0, 0, 0][..])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Diffs two snapshots of the same process
+    fn test_diff() -> Result<()> {
+        let mappings = r#"{"start": "1000", "end": "3000", "physical_offset": "0",
+            "permissions": "rw-p"}"#;
+        let before = format!(
+            r#"{{"mappings": [{}], "registers": {{{}}}}}"#,
+            mappings, REGISTERS
+        );
+        let after = format!(
+            r#"{{"mappings": [{}, {{"start": "8000", "end": "9000",
+                "physical_offset": "2000", "permissions": "r--p"}}],
+                "registers": {{{}}}}}"#,
+            mappings,
+            REGISTERS.replace(r#""rip": "1337""#, r#""rip": "1339""#)
+        );
+
+        let mut data = vec![0u8; 3 * PAGE_SIZE];
+        let before = Snapshot::new(SnapshotInfo::from_string(before)?, data.clone());
+        data[PAGE_SIZE + 0x10] = 0x41;
+        let after = Snapshot::new(SnapshotInfo::from_string(after)?, data);
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.added_mappings, vec![(0x8000, 0x9000)]);
+        assert!(diff.removed_mappings.is_empty());
+        assert_eq!(
+            diff.changed_registers,
+            vec![("rip", Some(0x1337), Some(0x1339))]
+        );
+        assert_eq!(diff.changed_pages, vec![0x2000]);
+        assert!(before.diff(&before).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Selects mappings by module, address range and permissions
+    fn test_mapping_filter() -> Result<()> {
+        let data = format!(
+            r#"{{"mappings": [
+                {{"start": "1000", "end": "3000", "physical_offset": "0",
+                    "permissions": "r-xp", "image": "/usr/lib/libc.so.6"}},
+                {{"start": "8000", "end": "9000", "physical_offset": "2000",
+                    "permissions": "rw-p"}},
+                {{"start": "7f0000000000", "end": "7f0040000000", "physical_offset": "3000",
+                    "permissions": "r--s", "image": "/var/cache/shared"}}],
+                "registers": {{{}}}}}"#,
+            REGISTERS
+        );
+        let info = SnapshotInfo::from_string(data)?;
+        let loaded = |filter: &MappingFilter| -> Vec<u64> {
+            info.mappings
+                .iter()
+                .filter(|m| filter.matches(m))
+                .map(|m| m.start)
+                .collect()
+        };
+
+        assert_eq!(loaded(&MappingFilter::new()).len(), 3);
+        assert_eq!(
+            loaded(&MappingFilter::new().exclude(MappingRule::Module("shared".to_string()))),
+            vec![0x1000, 0x8000]
+        );
+        assert_eq!(
+            loaded(
+                &MappingFilter::new()
+                    .include(MappingRule::Module("/usr/lib/libc.so.6".to_string()))
+            ),
+            vec![0x1000]
+        );
+        assert_eq!(
+            loaded(
+                &MappingFilter::new()
+                    .include(MappingRule::Permissions(PagePermissions::READ))
+                    .exclude(MappingRule::Range(0x2fff..0x8001))
+            ),
+            vec![0x7f0000000000]
+        );
+        assert_eq!(
+            loaded(&MappingFilter::new().include(MappingRule::Permissions(
+                PagePermissions::READ | PagePermissions::WRITE
+            ))),
+            vec![0x8000]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Assembles a snapshot from maps and register text
+    fn test_parse_maps() -> Result<()> {
+        let maps = "\
+55d0c2a00000-55d0c2a02000 r-xp 00000000 fd:01 1234    /usr/bin/cat
+55d0c2c00000-55d0c2c01000 rw-p 00002000 fd:01 1234    /usr/bin/cat
+7ffd1c000000-7ffd1c021000 rw-p 00000000 00:00 0       [stack]
+ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0 [vsyscall]
+";
+        let registers = "\
+rax            0x1c                28
+rip            0x55d0c2a00040      0x55d0c2a00040 <main>
+eflags         0x246               [ IF ZF PF ]
+cs             0x33                51
+fs_base        0x7f0000001000      139637976731648
+gs_base        0x0                 0
+rbx 0
+rcx 0
+rdx 0
+rsi 0
+rdi 0
+rsp 0x7ffd1c020000
+rbp 0
+r8 0
+r9 0
+r10 0
+r11 0
+r12 0
+r13 0
+r14 0
+r15 0
+";
+        let info = SnapshotInfo::from_maps(maps, registers)?;
+
+        assert_eq!(info.mappings.len(), 3);
+        assert_eq!(info.mappings[1].physical_offset, 0x2000);
+        assert_eq!(info.mappings[2].physical_offset, 0x3000);
+        assert!(info.mappings[0].permissions.executable());
+        assert!(info.mappings[2].image.is_none());
+        assert_eq!(info.modules["cat"].start, 0x55d0c2a00000);
+        assert_eq!(info.modules["cat"].end, 0x55d0c2c01000);
+
+        assert_eq!(info.registers.rax, 0x1c);
+        assert_eq!(info.registers.rip, 0x55d0c2a00040);
+        assert_eq!(info.registers.rflags, 0x246);
+        assert_eq!(info.registers.rsp, 0x7ffd1c020000);
+        assert!(info.registers.efer.is_none());
+
+        // Registers are mandatory, mappings must be ranges
+        assert!(SnapshotInfo::from_maps(maps, "rax 0x1c").is_err());
+        assert!(SnapshotInfo::from_maps("1000 r-xp", registers).is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,283 @@
+//! Hooks neutralizing the common sources of nondeterminism of guests
+
+use crate::vm::{Register, Vm, VmError};
+
+use std::collections::BTreeMap;
+
+/// Result type in stub handling
+type Result<T> = std::result::Result<T, VmError>;
+
+/// Breakpoint instruction
+const INT3: u8 = 0xcc;
+/// Time returned to the guest, in seconds since the epoch (2021-01-01)
+const FIXED_TIME: u64 = 1_609_459_200;
+/// Default seed of the pseudo random generator
+const DEFAULT_SEED: u64 = 0x7461_7274_6966_6c65;
+/// Largest value returned by `rand`
+const RAND_MAX: u64 = 0x7fff_ffff;
+/// Largest request served by `getrandom`
+const GETRANDOM_MAX: u64 = 0x100_0000;
+
+/// Function replaced by a stub
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stub {
+    /// `time(tloc)`, returning a fixed time
+    Time,
+    /// `gettimeofday(tv, tz)`, returning a fixed time
+    Gettimeofday,
+    /// `clock_gettime(clockid, tp)`, returning a fixed time for any clock
+    ClockGettime,
+    /// `rand()` and `random()`, returning a seeded sequence
+    Rand,
+    /// `srand(seed)` and `srandom(seed)`, ignored
+    Srand,
+    /// `getrandom(buf, buflen, flags)`, filling the buffer from a seeded
+    /// sequence
+    Getrandom,
+}
+
+impl Stub {
+    /// Returns the stub replacing a function, by symbol name
+    pub fn from_symbol(name: &str) -> Option<Stub> {
+        match name {
+            "time" | "__time" => Some(Stub::Time),
+            "gettimeofday" | "__gettimeofday" => Some(Stub::Gettimeofday),
+            "clock_gettime" | "__clock_gettime" => Some(Stub::ClockGettime),
+            "rand" | "random" | "__random" => Some(Stub::Rand),
+            "srand" | "srandom" | "__srandom" => Some(Stub::Srand),
+            "getrandom" | "__getrandom" => Some(Stub::Getrandom),
+            _ => None,
+        }
+    }
+
+    /// Returns the stub replacing a syscall, by number
+    pub fn from_syscall(number: u64) -> Option<Stub> {
+        match number {
+            96 => Some(Stub::Gettimeofday),
+            201 => Some(Stub::Time),
+            228 => Some(Stub::ClockGettime),
+            318 => Some(Stub::Getrandom),
+            _ => None,
+        }
+    }
+}
+
+/// Ready-made stubs of the functions making guests nondeterministic (time
+/// and random sources). They are installed in one call by symbol name as
+/// breakpoints, serviced by `handle_breakpoint`, and the matching syscalls
+/// are serviced by `handle_syscall`.
+///
+/// Guests using RDRAND or RDSEED directly can be moved to their fallback
+/// paths with `Vm::hide_rdrand`.
+#[derive(Debug, Clone)]
+pub struct DeterminismStubs {
+    /// Stubs by function address
+    stubs: BTreeMap<u64, Stub>,
+    /// Seed of the pseudo random generator
+    seed: u64,
+    /// Pseudo random generator state
+    state: u64,
+}
+
+impl DeterminismStubs {
+    /// Installs breakpoints on the functions of `symbols` known to be
+    /// nondeterministic, before the vm is used as a reset source
+    pub fn install(vm: &mut Vm, symbols: &BTreeMap<String, u64>) -> Result<DeterminismStubs> {
+        let mut stubs = BTreeMap::new();
+
+        for (name, &address) in symbols.iter() {
+            if let Some(stub) = Stub::from_symbol(name) {
+                vm.write_value::<u8>(address, INT3)?;
+                stubs.insert(address, stub);
+            }
+        }
+
+        Ok(DeterminismStubs {
+            stubs,
+            seed: DEFAULT_SEED,
+            state: DEFAULT_SEED,
+        })
+    }
+
+    /// Returns the installed stubs, by function address
+    pub fn stubs(&self) -> &BTreeMap<u64, Stub> {
+        &self.stubs
+    }
+
+    /// Seeds the pseudo random generator, restarting its sequence
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.state = seed;
+    }
+
+    /// Restarts the pseudo random sequence, along with a reset of the vm
+    pub fn reset(&mut self) {
+        self.state = self.seed;
+    }
+
+    /// Returns the next value of the pseudo random sequence (splitmix64)
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Services a `VmExit::Breakpoint` on a stubbed function: the result is
+    /// placed in rax and the guest returns to the caller. Returns false if
+    /// the breakpoint is not a stub and must be handled elsewhere.
+    pub fn handle_breakpoint(&mut self, vm: &mut Vm) -> Result<bool> {
+        let stub = match self.stubs.get(&vm.get_reg(Register::Rip)) {
+            Some(&stub) => stub,
+            None => return Ok(false),
+        };
+
+        let result = self.emulate(vm, stub)?;
+        vm.set_reg(Register::Rax, result);
+
+        // Return to the caller
+        let rsp = vm.get_reg(Register::Rsp);
+        let mut return_address = [0u8; 8];
+        vm.read(rsp, &mut return_address)?;
+        vm.set_reg(Register::Rip, u64::from_le_bytes(return_address));
+        vm.set_reg(Register::Rsp, rsp + 8);
+
+        Ok(true)
+    }
+
+    /// Services a `VmExit::Syscall` of a stubbed syscall, the result being
+    /// placed in rax. Returns false if the syscall is not stubbed and must
+    /// be handled elsewhere.
+    pub fn handle_syscall(&mut self, vm: &mut Vm) -> Result<bool> {
+        let stub = match Stub::from_syscall(vm.get_reg(Register::Rax)) {
+            Some(stub) => stub,
+            None => return Ok(false),
+        };
+
+        let result = self.emulate(vm, stub)?;
+        vm.set_reg(Register::Rax, result);
+
+        Ok(true)
+    }
+
+    /// Emulates a stub with the arguments in rdi, rsi and rdx, returning
+    /// its result. Buffers the guest cannot write are left untouched.
+    fn emulate(&mut self, vm: &mut Vm, stub: Stub) -> Result<u64> {
+        let args = [
+            vm.get_reg(Register::Rdi),
+            vm.get_reg(Register::Rsi),
+            vm.get_reg(Register::Rdx),
+        ];
+
+        let result = match stub {
+            Stub::Time => {
+                if args[0] != 0 {
+                    vm.write_value::<u64>(args[0], FIXED_TIME)?;
+                }
+                FIXED_TIME
+            }
+            Stub::Gettimeofday => {
+                if args[0] != 0 {
+                    vm.write_value::<[u64; 2]>(args[0], [FIXED_TIME, 0])?;
+                }
+                0
+            }
+            Stub::ClockGettime => {
+                vm.write_value::<[u64; 2]>(args[1], [FIXED_TIME, 0])?;
+                0
+            }
+            Stub::Rand => (self.next() >> 33) & RAND_MAX,
+            Stub::Srand => 0,
+            Stub::Getrandom => {
+                let len = args[1].min(GETRANDOM_MAX) as usize;
+                let mut data = Vec::with_capacity(len + 8);
+                while data.len() < len {
+                    data.extend_from_slice(&self.next().to_le_bytes());
+                }
+                vm.write(args[0], &data[..len])?;
+                len as u64
+            }
+        };
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeterminismStubs, Result, Stub, FIXED_TIME, RAND_MAX};
+    use crate::vm::{Register, Vm, VmExit};
+    use crate::PagePermissions;
+
+    use std::collections::BTreeMap;
+
+    /// Runs the guest, servicing the stubs, until it halts
+    fn run(vm: &mut Vm, stubs: &mut DeterminismStubs) -> Result<()> {
+        loop {
+            match vm.run()? {
+                VmExit::Hlt => return Ok(()),
+                VmExit::Breakpoint => assert!(stubs.handle_breakpoint(vm)?),
+                exit => panic!("Unexpected exit {:?}", exit),
+            }
+        }
+    }
+
+    #[test]
+    /// Replaces time and random sources by fixed values and sequences
+    fn test_stubs() -> Result<()> {
+        let mut vm = Vm::new(512 * 0x1000)?;
+
+        // Call time(NULL) then rand()
+        let shellcode: &[u8] = &[
+            0xe8, 0xfb, 0x0f, 0x00, 0x00, // call 0x1338000
+            0x48, 0x89, 0xc3, // mov rbx, rax
+            0xe8, 0x03, 0x10, 0x00, 0x00, // call 0x1338010
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, 0x2000, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x2000000,
+            0x1000,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        let mut symbols = BTreeMap::new();
+        symbols.insert("time".to_string(), 0x1338000);
+        symbols.insert("rand".to_string(), 0x1338010);
+        symbols.insert("main".to_string(), 0x1337000);
+        let mut stubs = DeterminismStubs::install(&mut vm, &symbols)?;
+        assert_eq!(stubs.stubs().len(), 2);
+        assert_eq!(stubs.stubs()[&0x1338010], Stub::Rand);
+
+        vm.set_reg(Register::Rdi, 0);
+        vm.set_reg(Register::Rsp, 0x2001000);
+        vm.set_reg(Register::Rip, 0x1337000);
+        run(&mut vm, &mut stubs)?;
+
+        assert_eq!(vm.get_reg(Register::Rbx), FIXED_TIME);
+        assert_eq!(vm.get_reg(Register::Rsp), 0x2001000);
+        let first = vm.get_reg(Register::Rax);
+        assert!(first <= RAND_MAX);
+
+        // The sequence starts over after a reset
+        stubs.reset();
+        vm.set_reg(Register::Rip, 0x1337000);
+        run(&mut vm, &mut stubs)?;
+        assert_eq!(vm.get_reg(Register::Rax), first);
+
+        // Syscalls are stubbed as well
+        vm.set_reg(Register::Rax, 318);
+        vm.set_reg(Register::Rdi, 0x2000000);
+        vm.set_reg(Register::Rsi, 12);
+        assert!(stubs.handle_syscall(&mut vm)?);
+        assert_eq!(vm.get_reg(Register::Rax), 12);
+        vm.set_reg(Register::Rax, 60);
+        assert!(!stubs.handle_syscall(&mut vm)?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! Debug information ingestion for snapshot modules
+
+use crate::snapshot::{SnapshotError, SnapshotModule};
+
+use addr2line::Loader;
+use object::{Object, ObjectSegment};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Result type in symbols manipulation
+type Result<T> = std::result::Result<T, SnapshotError>;
+
+/// Source information of an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolLocation {
+    /// Name of the function (demangled when possible)
+    pub function: Option<String>,
+    /// Source file
+    pub file: Option<String>,
+    /// Source line
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for SymbolLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.function.as_deref().unwrap_or("??"))?;
+
+        if let Some(file) = &self.file {
+            write!(f, " ({}:{})", file, self.line.unwrap_or(0))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Function and line tables of a module
+pub struct ModuleSymbols {
+    /// Debug information loader
+    loader: Loader,
+    /// Difference between runtime addresses and the file addresses
+    bias: u64,
+    /// Symbol table entries by file address
+    symbols: Vec<(u64, String)>,
+}
+
+impl ModuleSymbols {
+    /// Loads the function and line tables of a module from a file carrying
+    /// its debug information (the module image itself or a separate debug file)
+    pub fn load<P: AsRef<Path>>(module: &SnapshotModule, path: P) -> Result<ModuleSymbols> {
+        // Compute the module load bias from its lowest segment
+        let data = fs::read(path.as_ref())?;
+        let object = object::File::parse(data.as_slice())
+            .map_err(|e| SnapshotError::ParsingError(e.to_string()))?;
+        let base = object.segments().map(|s| s.address()).min().unwrap_or(0) & !0xfff;
+        let symbols = object
+            .symbol_map()
+            .symbols()
+            .iter()
+            .map(|s| (s.address(), s.name().to_string()))
+            .collect();
+
+        let loader = Loader::new(path).map_err(|e| SnapshotError::ParsingError(e.to_string()))?;
+
+        Ok(ModuleSymbols {
+            loader,
+            bias: module.start.wrapping_sub(base),
+            symbols,
+        })
+    }
+
+    /// Returns the source information of a runtime address
+    pub fn lookup(&self, address: u64) -> Option<SymbolLocation> {
+        let probe = address.wrapping_sub(self.bias);
+        let mut result = SymbolLocation {
+            function: None,
+            file: None,
+            line: None,
+        };
+
+        // The first frame is the innermost inlined function
+        if let Ok(mut frames) = self.loader.find_frames(probe) {
+            if let Ok(Some(frame)) = frames.next() {
+                result.function = frame
+                    .function
+                    .and_then(|f| f.demangle().ok().map(|n| n.into_owned()));
+
+                if let Some(location) = frame.location {
+                    result.file = location.file.map(String::from);
+                    result.line = location.line;
+                }
+            }
+        }
+
+        // Fallback to the symbol table
+        if result.function.is_none() {
+            result.function = self.loader.find_symbol(probe).map(String::from);
+        }
+
+        match result.function.is_some() || result.file.is_some() {
+            true => Some(result),
+            false => None,
+        }
+    }
+
+    /// Returns the symbol containing a runtime address and the offset of the
+    /// address from the symbol start
+    pub fn symbol(&self, address: u64) -> Option<(&str, u64)> {
+        let probe = address.wrapping_sub(self.bias);
+        let index = match self.symbols.binary_search_by_key(&probe, |s| s.0) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+
+        let (start, name) = &self.symbols[index];
+        Some((name.as_str(), probe - start))
+    }
+
+    /// Returns the runtime address of the symbol named `name`
+    pub fn address_of(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|(_, symbol)| symbol == name)
+            .map(|(address, _)| address.wrapping_add(self.bias))
+    }
+}
+
+/// Symbolizes runtime addresses as `module!function+0xoffset`, loading the
+/// symbols of each module the first time one of its addresses is seen
+pub struct Symbolizer {
+    /// Modules of the snapshot
+    modules: BTreeMap<String, SnapshotModule>,
+    /// Symbols of the modules, `None` if they could not be loaded
+    symbols: BTreeMap<String, Option<ModuleSymbols>>,
+}
+
+impl Symbolizer {
+    /// Creates a symbolizer for the modules of a snapshot
+    pub fn new(modules: &BTreeMap<String, SnapshotModule>) -> Symbolizer {
+        Symbolizer {
+            modules: modules.clone(),
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    /// Formats an address as `module!function+0xoffset`, falling back to
+    /// `module+0xoffset` without symbols and to the raw address outside of
+    /// the modules
+    pub fn symbolize(&mut self, address: u64) -> String {
+        let module = match self
+            .modules
+            .values()
+            .find(|m| m.start <= address && address < m.end)
+        {
+            Some(module) => module,
+            None => return format!("0x{:x}", address),
+        };
+
+        // Modules without readable symbols are only tried once
+        let symbols = self
+            .symbols
+            .entry(module.name.clone())
+            .or_insert_with(|| module.load_symbols().ok());
+
+        match symbols.as_ref().and_then(|s| s.symbol(address)) {
+            Some((function, offset)) => format!("{}!{}+0x{:x}", module.name, function, offset),
+            None => format!("{}+0x{:x}", module.name, address - module.start),
+        }
+    }
+}
+
+impl SnapshotModule {
+    /// Loads the function and line tables of the module, from its debug
+    /// file if one is referenced, from its image otherwise
+    pub fn load_symbols(&self) -> Result<ModuleSymbols> {
+        match &self.debug_file {
+            Some(path) => ModuleSymbols::load(self, path),
+            None => ModuleSymbols::load(self, &self.path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ModuleSymbols, Result, Symbolizer};
+    use crate::snapshot::SnapshotModule;
+
+    use std::collections::BTreeMap;
+
+    #[test]
+    /// Symbolizes an address of a PIE executable
+    fn test_lookup() -> Result<()> {
+        let module = SnapshotModule {
+            start: 0x555555554000,
+            end: 0x55555555d000,
+            name: "giftext_fuzz".to_string(),
+            path: "/home/sideway/giflib/giftext_fuzz".to_string(),
+            debug_file: None,
+        };
+        let symbols = ModuleSymbols::load(&module, "../fuzzers/giflib/data/giftext_fuzz")?;
+
+        let location = symbols.lookup(0x555555554000 + 0x17ee).unwrap();
+        assert_eq!(location.function.as_deref(), Some("_start_c"));
+        assert!(location.file.unwrap().ends_with("crt1.c"));
+        assert_eq!(location.line, Some(17));
+
+        let start = symbols.address_of("_start_c").unwrap();
+        assert_eq!(symbols.symbol(start), Some(("_start_c", 0)));
+        assert_eq!(symbols.address_of("no_such_symbol"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Symbolizes addresses relative to their function and module
+    fn test_symbolizer() {
+        let module = SnapshotModule {
+            start: 0x555555554000,
+            end: 0x55555555d000,
+            name: "giftext_fuzz".to_string(),
+            path: "../fuzzers/giflib/data/giftext_fuzz".to_string(),
+            debug_file: None,
+        };
+        let mut missing = module.clone();
+        missing.start = 0x7ffff7a00000;
+        missing.end = 0x7ffff7a10000;
+        missing.name = "missing.so".to_string();
+        missing.path = "/nonexistent/missing.so".to_string();
+
+        let mut modules = BTreeMap::new();
+        modules.insert(module.name.clone(), module);
+        modules.insert(missing.name.clone(), missing);
+        let mut symbolizer = Symbolizer::new(&modules);
+
+        assert!(symbolizer
+            .symbolize(0x555555554000 + 0x17ee)
+            .starts_with("giftext_fuzz!_start_c+0x"));
+        assert_eq!(symbolizer.symbolize(0x7ffff7a00042), "missing.so+0x42");
+        assert_eq!(symbolizer.symbolize(0x1337), "0x1337");
+    }
+}
@@ -0,0 +1,372 @@
+//! Host-side model of common Linux syscalls
+
+use crate::memory::{PagePermissions, PAGE_SIZE};
+use crate::vm::{Register, Vm, VmError};
+
+use std::cmp;
+use std::convert::TryInto;
+use std::ops::Range;
+
+/// Result type in syscall emulation
+type Result<T> = std::result::Result<T, VmError>;
+
+/// Result of a modeled syscall, errors being errno values
+type SyscallResult = std::result::Result<u64, i64>;
+
+/// Number of trailing bytes of guest output kept
+const OUTPUT_SIZE: usize = 4096;
+/// Maximum number of iovecs of a `writev` call
+const IOV_MAX: u64 = 1024;
+/// Size of a `struct stat`
+const STAT_SIZE: usize = 144;
+
+/// Bad file descriptor
+const EBADF: i64 = 9;
+/// Out of memory
+const ENOMEM: i64 = 12;
+/// Bad address
+const EFAULT: i64 = 14;
+/// Invalid argument
+const EINVAL: i64 = 22;
+
+/// `mmap` flag asking for the exact address
+const MAP_FIXED: u64 = 0x10;
+/// `mmap` flag asking for memory without backing file
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Regular file mode of stdin
+const S_IFREG: u32 = 0o100644;
+/// Character device mode of stdout and stderr
+const S_IFCHR: u32 = 0o020620;
+
+/// Outcome of a syscall exit
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyscallOutcome {
+    /// The syscall was serviced, its result is in rax
+    Handled,
+    /// The guest exited with a status code
+    Exit(u64),
+    /// The syscall with this number is not modeled and must be handled
+    /// elsewhere
+    Unhandled(u64),
+}
+
+/// Model of the Linux syscalls frequently issued by snapshotted processes:
+/// `read` from stdin, `write`/`writev` to stdout and stderr, `fstat` of
+/// these files, anonymous `mmap`, `brk` and `exit_group`.
+///
+/// Memory is carved from areas mapped once by `new`, before the vm is used
+/// as a reset source, as mappings created from the host during a run would
+/// not be undone by `Vm::reset`.
+#[derive(Debug, Clone)]
+pub struct SyscallModel {
+    /// Area backing the anonymous mappings
+    mmap_area: Range<u64>,
+    /// Next free address of the mmap area
+    mmap_current: u64,
+    /// Area backing the program break
+    brk_area: Range<u64>,
+    /// Current program break
+    brk_current: u64,
+    /// Contents of stdin
+    stdin: Vec<u8>,
+    /// Offset of the next read from stdin
+    stdin_offset: usize,
+    /// Last bytes written by the guest to stdout and stderr
+    output: Vec<u8>,
+}
+
+impl SyscallModel {
+    /// Creates a model serving anonymous mappings from `mmap_area` and the
+    /// program break from `brk_area`, both mapped in `vm` with `perms`
+    /// (e.g. with `PagePermissions::USER` for user mode snapshots)
+    pub fn new(
+        vm: &mut Vm,
+        mmap_area: Range<u64>,
+        brk_area: Range<u64>,
+        perms: PagePermissions,
+    ) -> Result<SyscallModel> {
+        for area in [&mmap_area, &brk_area] {
+            if !area.start.is_multiple_of(PAGE_SIZE as u64)
+                || !area.end.is_multiple_of(PAGE_SIZE as u64)
+            {
+                return Err(VmError::HvError("Syscall model areas must be page aligned"));
+            }
+            if area.start < area.end {
+                vm.mmap(area.start, (area.end - area.start) as usize, perms)?;
+            }
+        }
+
+        Ok(SyscallModel {
+            mmap_current: mmap_area.start,
+            mmap_area,
+            brk_current: brk_area.start,
+            brk_area,
+            stdin: Vec::new(),
+            stdin_offset: 0,
+            output: Vec::new(),
+        })
+    }
+
+    /// Sets the contents of stdin, e.g. the fuzz input, read again from its
+    /// start
+    pub fn set_stdin(&mut self, data: &[u8]) {
+        self.stdin.clear();
+        self.stdin.extend_from_slice(data);
+        self.stdin_offset = 0;
+    }
+
+    /// Last bytes written by the guest to stdout and stderr
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Resets the allocations, the stdin offset and the output, along with
+    /// a reset of the vm
+    pub fn reset(&mut self) {
+        self.mmap_current = self.mmap_area.start;
+        self.brk_current = self.brk_area.start;
+        self.stdin_offset = 0;
+        self.output.clear();
+    }
+
+    /// Services the syscall of a `VmExit::Syscall`, its number being in rax
+    /// and its arguments in rdi, rsi, rdx, r10, r8 and r9. Errors are
+    /// returned to the guest as negated errno values.
+    pub fn handle(&mut self, vm: &mut Vm) -> Result<SyscallOutcome> {
+        let number = vm.get_reg(Register::Rax);
+        let args = [
+            vm.get_reg(Register::Rdi),
+            vm.get_reg(Register::Rsi),
+            vm.get_reg(Register::Rdx),
+            vm.get_reg(Register::R10),
+            vm.get_reg(Register::R8),
+        ];
+
+        let result = match number {
+            // read
+            0 => self.read(vm, args[0], args[1], args[2]),
+            // write
+            1 => self.write(vm, args[0], args[1], args[2]),
+            // close
+            3 => Ok(0),
+            // fstat
+            5 => self.fstat(vm, args[0], args[1]),
+            // mmap
+            9 => self.mmap(args[0], args[1], args[3], args[4] as i64),
+            // mprotect, munmap, madvise: memory is only reclaimed by reset
+            10 | 11 | 28 => Ok(0),
+            // brk
+            12 => Ok(self.brk(args[0])),
+            // writev
+            20 => self.writev(vm, args[0], args[1], args[2]),
+            // exit, exit_group
+            60 | 231 => return Ok(SyscallOutcome::Exit(args[0])),
+            _ => return Ok(SyscallOutcome::Unhandled(number)),
+        };
+
+        let rax = match result {
+            Ok(value) => value,
+            Err(errno) => -errno as u64,
+        };
+        vm.set_reg(Register::Rax, rax);
+
+        Ok(SyscallOutcome::Handled)
+    }
+
+    /// Reads from stdin
+    fn read(&mut self, vm: &mut Vm, fd: u64, buf: u64, count: u64) -> SyscallResult {
+        if fd != 0 {
+            return Err(EBADF);
+        }
+
+        let remaining = &self.stdin[self.stdin_offset..];
+        let data = &remaining[..cmp::min(remaining.len() as u64, count) as usize];
+        vm.write(buf, data).map_err(|_| EFAULT)?;
+        self.stdin_offset += data.len();
+
+        Ok(data.len() as u64)
+    }
+
+    /// Appends guest output, keeping only the last `OUTPUT_SIZE` bytes
+    fn capture(&mut self, vm: &Vm, address: u64, len: u64) -> std::result::Result<(), i64> {
+        let mut data = vec![0u8; cmp::min(len, OUTPUT_SIZE as u64) as usize];
+        vm.read(address.wrapping_add(len - data.len() as u64), &mut data)
+            .map_err(|_| EFAULT)?;
+
+        self.output.extend_from_slice(&data);
+        let excess = self.output.len().saturating_sub(OUTPUT_SIZE);
+        self.output.drain(..excess);
+
+        Ok(())
+    }
+
+    /// Writes to stdout or stderr
+    fn write(&mut self, vm: &Vm, fd: u64, buf: u64, count: u64) -> SyscallResult {
+        match fd {
+            1 | 2 => self.capture(vm, buf, count).map(|_| count),
+            _ => Err(EBADF),
+        }
+    }
+
+    /// Writes a vector of (base, len) pairs to stdout or stderr
+    fn writev(&mut self, vm: &Vm, fd: u64, iov: u64, iovcnt: u64) -> SyscallResult {
+        if fd != 1 && fd != 2 {
+            return Err(EBADF);
+        }
+        if iovcnt > IOV_MAX {
+            return Err(EINVAL);
+        }
+
+        let mut total: u64 = 0;
+        for i in 0..iovcnt {
+            let mut iovec = [0u8; 16];
+            vm.read(iov + i * 16, &mut iovec).map_err(|_| EFAULT)?;
+
+            let base = u64::from_le_bytes(iovec[..8].try_into().unwrap());
+            let len = u64::from_le_bytes(iovec[8..].try_into().unwrap());
+            self.capture(vm, base, len)?;
+            total = total.wrapping_add(len);
+        }
+
+        Ok(total)
+    }
+
+    /// Describes stdin as a regular file holding its contents, stdout and
+    /// stderr as terminals
+    fn fstat(&mut self, vm: &mut Vm, fd: u64, statbuf: u64) -> SyscallResult {
+        let (mode, size) = match fd {
+            0 => (S_IFREG, self.stdin.len() as u64),
+            1 | 2 => (S_IFCHR, 0),
+            _ => return Err(EBADF),
+        };
+
+        let mut stat = [0u8; STAT_SIZE];
+        stat[16..24].copy_from_slice(&1u64.to_le_bytes()); // st_nlink
+        stat[24..28].copy_from_slice(&mode.to_le_bytes()); // st_mode
+        stat[48..56].copy_from_slice(&size.to_le_bytes()); // st_size
+        stat[56..64].copy_from_slice(&(PAGE_SIZE as u64).to_le_bytes()); // st_blksize
+        stat[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes()); // st_blocks
+        vm.write(statbuf, &stat).map_err(|_| EFAULT)?;
+
+        Ok(0)
+    }
+
+    /// Carves an anonymous mapping from the mmap area. Fixed mappings are
+    /// only granted inside the area, at or past the next free address.
+    fn mmap(&mut self, addr: u64, len: u64, flags: u64, fd: i64) -> SyscallResult {
+        if flags & MAP_ANONYMOUS == 0 || fd != -1 || len == 0 {
+            return Err(EINVAL);
+        }
+
+        let len = len.checked_add(PAGE_SIZE as u64 - 1).ok_or(ENOMEM)? & !(PAGE_SIZE as u64 - 1);
+        let start = match flags & MAP_FIXED {
+            0 => self.mmap_current,
+            _ if addr >= self.mmap_current && addr.is_multiple_of(PAGE_SIZE as u64) => addr,
+            _ => return Err(EINVAL),
+        };
+
+        match start.checked_add(len) {
+            Some(end) if end <= self.mmap_area.end => {
+                self.mmap_current = end;
+                Ok(start)
+            }
+            _ => Err(ENOMEM),
+        }
+    }
+
+    /// Moves the program break inside the brk area, returning the new break
+    /// or the current one if the request cannot be granted
+    fn brk(&mut self, addr: u64) -> u64 {
+        if self.brk_area.start <= addr && addr <= self.brk_area.end {
+            self.brk_current = addr;
+        }
+        self.brk_current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Result, SyscallModel, SyscallOutcome, STAT_SIZE};
+    use crate::vm::{Register, Vm};
+    use crate::PagePermissions;
+
+    /// Issues a syscall to the model
+    fn syscall(model: &mut SyscallModel, vm: &mut Vm, number: u64, args: &[u64]) -> Result<u64> {
+        let registers = [
+            Register::Rdi,
+            Register::Rsi,
+            Register::Rdx,
+            Register::R10,
+            Register::R8,
+        ];
+        vm.set_reg(Register::Rax, number);
+        for (register, value) in registers.iter().zip(args) {
+            vm.set_reg(*register, *value);
+        }
+
+        assert_eq!(model.handle(vm)?, SyscallOutcome::Handled);
+        Ok(vm.get_reg(Register::Rax))
+    }
+
+    #[test]
+    /// Services reads, writes, fstat, mmap and brk
+    fn test_syscall_model() -> Result<()> {
+        let mut vm = Vm::new(512 * 0x1000)?;
+        let perms = PagePermissions::READ | PagePermissions::WRITE;
+        vm.mmap(0x1000, 0x1000, perms)?;
+        let mut model = SyscallModel::new(&mut vm, 0x10000..0x14000, 0x20000..0x22000, perms)?;
+
+        // Reads from stdin, up to its end
+        model.set_stdin(b"ABCDEF");
+        assert_eq!(syscall(&mut model, &mut vm, 0, &[0, 0x1000, 4])?, 4);
+        assert_eq!(syscall(&mut model, &mut vm, 0, &[0, 0x1004, 4])?, 2);
+        assert_eq!(syscall(&mut model, &mut vm, 0, &[0, 0x1006, 4])?, 0);
+        assert_eq!(
+            syscall(&mut model, &mut vm, 0, &[3, 0x1000, 4])?,
+            -9i64 as u64
+        );
+
+        // Writes to stdout
+        assert_eq!(syscall(&mut model, &mut vm, 1, &[1, 0x1000, 6])?, 6);
+        assert_eq!(model.output(), b"ABCDEF");
+        assert_eq!(
+            syscall(&mut model, &mut vm, 1, &[1, 0xdead000, 6])?,
+            -14i64 as u64
+        );
+
+        // Stdin is a regular file holding the input
+        assert_eq!(syscall(&mut model, &mut vm, 5, &[0, 0x1100])?, 0);
+        let mut stat = [0u8; STAT_SIZE];
+        vm.read(0x1100, &mut stat)?;
+        assert_eq!(&stat[24..28], &0o100644u32.to_le_bytes());
+        assert_eq!(&stat[48..56], &6u64.to_le_bytes());
+
+        // Anonymous mappings until the area is exhausted
+        let anonymous = [0, 0x1800, 3, 0x22, -1i64 as u64];
+        assert_eq!(syscall(&mut model, &mut vm, 9, &anonymous)?, 0x10000);
+        assert_eq!(syscall(&mut model, &mut vm, 9, &anonymous)?, 0x12000);
+        assert_eq!(syscall(&mut model, &mut vm, 9, &anonymous)?, -12i64 as u64);
+        vm.write(0x12000, b"GHI")?;
+
+        // Program break
+        assert_eq!(syscall(&mut model, &mut vm, 12, &[0])?, 0x20000);
+        assert_eq!(syscall(&mut model, &mut vm, 12, &[0x21000])?, 0x21000);
+        assert_eq!(syscall(&mut model, &mut vm, 12, &[0x30000])?, 0x21000);
+
+        // Exits and unknown syscalls are left to the caller
+        vm.set_reg(Register::Rax, 231);
+        vm.set_reg(Register::Rdi, 3);
+        assert_eq!(model.handle(&mut vm)?, SyscallOutcome::Exit(3));
+        vm.set_reg(Register::Rax, 57);
+        assert_eq!(model.handle(&mut vm)?, SyscallOutcome::Unhandled(57));
+
+        // The state starts over after a reset
+        model.reset();
+        assert!(model.output().is_empty());
+        assert_eq!(syscall(&mut model, &mut vm, 9, &anonymous)?, 0x10000);
+        assert_eq!(syscall(&mut model, &mut vm, 0, &[0, 0x1000, 4])?, 4);
+
+        Ok(())
+    }
+}
@@ -1,35 +1,135 @@
-use crate::bits::BitField;
-use crate::memory::{Mapping, MemoryError, PagePermissions, VirtualMemory, PAGE_SIZE};
-use crate::snapshot::{SnapshotError, SnapshotInfo, SnapshotRegisters};
+use crate::access::{AccessKind, MemoryAccess};
+use crate::bits::{Alignement, BitField};
+use crate::branches::{lbr_branches_since, Branch, BranchRecording};
+use crate::coverage::ModuleCoverage;
+use crate::decode::{decode_comparison, memory_operand_size, truncate, CmpOperands, Operand};
+use crate::elf::Elf;
+use crate::kick::{KickState, VcpuKicker};
+use crate::memory::{
+    FrozenMemory, Mapping, MemoryError, PagePermissions, VirtualMemory, PAGE_SIZE,
+};
+use crate::snapshot::{
+    MappingFilter, Snapshot, SnapshotError, SnapshotInfo, SnapshotModule, SnapshotPages,
+    SnapshotRegisters,
+};
 use crate::x64::{
-    ExceptionFrame, ExceptionType, IdtEntry, IdtEntryBuilder, IdtEntryType, PrivilegeLevel, Tss,
-    TssEntry,
+    ExceptionFrame, ExceptionType, GdtBuilder, IdtEntry, IdtEntryBuilder, IdtEntryType,
+    PrivilegeLevel, Tss,
 };
 
 use kvm_bindings::{
-    kvm_clear_dirty_log, kvm_enable_cap, kvm_guest_debug, kvm_msr_entry, kvm_regs, kvm_segment,
-    kvm_sregs, kvm_userspace_memory_region, Msrs, KVMIO, KVM_API_VERSION,
-    KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2, KVM_DIRTY_LOG_MANUAL_PROTECT_ENABLE, KVM_GUESTDBG_ENABLE,
-    KVM_GUESTDBG_USE_SW_BP, KVM_MEM_LOG_DIRTY_PAGES, KVM_SYNC_X86_REGS, KVM_SYNC_X86_SREGS,
+    kvm_clear_dirty_log, kvm_cpuid_entry2, kvm_debugregs, kvm_dirty_log, kvm_enable_cap,
+    kvm_guest_debug, kvm_lapic_state, kvm_msr_entry, kvm_regs, kvm_segment, kvm_sregs,
+    kvm_userspace_memory_region, kvm_xcrs, kvm_xsave, CpuId, Msrs, KVMIO, KVM_API_VERSION,
+    KVM_CAP_MANUAL_DIRTY_LOG_PROTECT2, KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+    KVM_DIRTY_LOG_MANUAL_PROTECT_ENABLE, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP,
+    KVM_GUESTDBG_USE_SW_BP, KVM_MAX_CPUID_ENTRIES, KVM_MEM_LOG_DIRTY_PAGES, KVM_SYNC_X86_REGS,
+    KVM_SYNC_X86_SREGS,
 };
 use kvm_ioctls::{Cap, Kvm, KvmRunWrapper, VcpuExit, VcpuFd, VmFd};
 use nix::errno::Errno;
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use vmm_sys_util::ioctl;
 
 type Result<T> = std::result::Result<T, VmError>;
 
 ioctl_iowr_nr!(KVM_CLEAR_DIRTY_LOG, KVMIO, 0xC0, kvm_clear_dirty_log);
+ioctl_iow_nr!(KVM_GET_DIRTY_LOG, KVMIO, 0x42, kvm_dirty_log);
 ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
 
 /// FS base MSR number
 const IA32_FS_BASE: u32 = 0xC0000100;
 /// GS base MSR numebr
 const IA32_GS_BASE: u32 = 0xC0000101;
+/// GS base MSR number swapped in by swapgs
+const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
+/// Syscall segments MSR number
+const IA32_STAR: u32 = 0xC0000081;
+/// Syscall entry point MSR number
+const IA32_LSTAR: u32 = 0xC0000082;
+/// Syscall rflags mask MSR number
+const IA32_FMASK: u32 = 0xC0000084;
+/// MSR enabling CPUID faulting
+const MSR_MISC_FEATURES_ENABLES: u32 = 0x140;
+/// Bit of `MSR_MISC_FEATURES_ENABLES` making CPUID raise #GP outside of ring 0
+const CPUID_FAULT: u64 = 1 << 0;
+/// Debug control MSR number
+const IA32_DEBUGCTL: u32 = 0x1d9;
+/// Bit of `IA32_DEBUGCTL` enabling the Last Branch Record stack
+const DEBUGCTL_LBR: u64 = 1 << 0;
+/// MSR filtering the branches recorded in the LBR stack
+const MSR_LBR_SELECT: u32 = 0x1c8;
+/// MSR holding the index of the most recent LBR entry
+const MSR_LBR_TOS: u32 = 0x1c9;
+/// First MSR holding the source of a LBR entry
+const MSR_LBR_FROM: u32 = 0x680;
+/// First MSR holding the target of a LBR entry
+const MSR_LBR_TO: u32 = 0x6c0;
+/// Largest LBR stack probed
+const MAX_LBR_DEPTH: usize = 32;
+/// Syscall enable bit of IA32_EFER
+const IA32_EFER_SCE: u64 = 1 << 0;
+/// No-execute enable bit of IA32_EFER
+const IA32_EFER_NXE: u64 = 1 << 11;
+
+/// Start of the region holding the IDT, exception handlers, GDT, TSS and
+/// exception stack
+const RESERVED_ADDRESS: u64 = 0xffff_ffff_ff00_0000;
+/// Size of the region holding the IDT, exception handlers, GDT, TSS and
+/// exception stack
+const RESERVED_SIZE: u64 = (PAGE_SIZE * 5) as u64;
+
+/// Breakpoint instruction
+const INT3: u8 = 0xcc;
+/// Trap flag of rflags, singlestepping the guest
+const TRAP_FLAG: u64 = 1 << 8;
+
+/// I/O port written by the exception handlers to exit to the hypervisor,
+/// with an in-kernel irqchip
+const HYPERCALL_PORT: u16 = 0xf4;
+/// I/O port written by the syscall entry stub to exit to the hypervisor
+const SYSCALL_PORT: u16 = 0xf5;
+/// Offset of the syscall entry stub in the hypercall region, after the
+/// exception handlers
+const SYSCALL_ENTRY_OFFSET: u64 = 32 * 32;
+/// Offset of the timer interrupt handler in the hypercall region, after the
+/// syscall entry stub
+const TIMER_ENTRY_OFFSET: u64 = SYSCALL_ENTRY_OFFSET + 32;
+
+/// Local APIC spurious interrupt vector register offset
+const APIC_SVR: usize = 0xf0;
+/// Local APIC in-service register offset, 8 registers of 32 vectors
+const APIC_ISR: usize = 0x100;
+/// Local APIC interrupt request register offset, 8 registers of 32 vectors
+const APIC_IRR: usize = 0x200;
+/// Local APIC timer local vector table entry offset
+const APIC_LVT_TIMER: usize = 0x320;
+/// Local APIC timer initial count register offset
+const APIC_TIMER_INITIAL_COUNT: usize = 0x380;
+/// Local APIC timer current count register offset
+const APIC_TIMER_CURRENT_COUNT: usize = 0x390;
+/// Local APIC timer divide configuration register offset
+const APIC_TIMER_DIVIDE: usize = 0x3e0;
+
+/// Syscall number of the "take snapshot here" hypercall, issued by the
+/// `tartiflette_snapshot()` helper of `guest/tartiflette.h`
+pub const SNAPSHOT_HYPERCALL: u64 = 0x7472_6174;
+
+/// XCR0 state components of x87 and SSE, enabled by default
+pub const XCR0_SSE: u64 = 0b11;
+/// XCR0 state components up to AVX (x87, SSE and the upper YMM halves)
+pub const XCR0_AVX: u64 = XCR0_SSE | 1 << 2;
+/// XCR0 state components up to AVX-512 (opmask, ZMM_Hi256 and Hi16_ZMM)
+pub const XCR0_AVX512: u64 = XCR0_AVX | 0b111 << 5;
 
 /// Vm manipulation error
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -40,6 +140,77 @@ pub enum VmError {
     SnapshotError(SnapshotError),
     /// Hypervisor error
     HvError(&'static str),
+    /// Kvm could not run the guest anymore
+    GuestFailure(Box<GuestFailure>),
+}
+
+/// Reason why kvm could not run the guest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FailureReason {
+    /// The vm entry was rejected by the hardware (invalid guest state)
+    FailEntry {
+        /// Hardware specific reason (VMX basic exit reason on Intel)
+        hardware_reason: u64,
+        /// Physical cpu of the failed entry
+        cpu: u32,
+    },
+    /// Kvm could not emulate or deliver an event
+    InternalError {
+        /// Kvm suberror (e.g. 1 for an emulation failure)
+        suberror: u32,
+        /// Suberror specific data
+        data: Vec<u64>,
+    },
+    /// The guest triple faulted
+    Shutdown,
+}
+
+/// State of a guest kvm could not run anymore
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GuestFailure {
+    /// Reason of the failure
+    pub reason: FailureReason,
+    /// Last guest rip
+    pub rip: u64,
+    /// Last guest rsp
+    pub rsp: u64,
+    /// Last guest rflags
+    pub rflags: u64,
+    /// Control registers cr0, cr2, cr3 and cr4
+    pub cr: [u64; 4],
+    /// IA32_EFER value
+    pub efer: u64,
+    /// Code segment selector
+    pub cs: u16,
+    /// Stack segment selector
+    pub ss: u16,
+    /// Base and limit of the IDT
+    pub idt: (u64, u16),
+    /// Base and limit of the GDT
+    pub gdt: (u64, u16),
+    /// Whether an external interrupt was pending
+    pub pending_interrupt: bool,
+}
+
+impl std::fmt::Display for GuestFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:x?}", self.reason)?;
+        writeln!(
+            f,
+            "rip={:#x} rsp={:#x} rflags={:#x} cs={:#x} ss={:#x}",
+            self.rip, self.rsp, self.rflags, self.cs, self.ss
+        )?;
+        writeln!(
+            f,
+            "cr0={:#x} cr2={:#x} cr3={:#x} cr4={:#x} efer={:#x}",
+            self.cr[0], self.cr[1], self.cr[2], self.cr[3], self.efer
+        )?;
+        write!(
+            f,
+            "idt={:#x}/{:#x} gdt={:#x}/{:#x} pending_interrupt={}",
+            self.idt.0, self.idt.1, self.gdt.0, self.gdt.1, self.pending_interrupt
+        )
+    }
 }
 
 impl From<MemoryError> for VmError {
@@ -103,6 +274,92 @@ pub enum Register {
     FsBase,
     /// GS BASE
     GsBase,
+    /// KERNEL GS BASE (swapped with GS BASE by swapgs)
+    KernelGsBase,
+}
+
+impl Register {
+    /// All the available registers
+    pub const ALL: [Register; 21] = [
+        Register::Rax,
+        Register::Rbx,
+        Register::Rcx,
+        Register::Rdx,
+        Register::Rsi,
+        Register::Rdi,
+        Register::Rsp,
+        Register::Rbp,
+        Register::R8,
+        Register::R9,
+        Register::R10,
+        Register::R11,
+        Register::R12,
+        Register::R13,
+        Register::R14,
+        Register::R15,
+        Register::Rip,
+        Register::Rflags,
+        Register::FsBase,
+        Register::GsBase,
+        Register::KernelGsBase,
+    ];
+
+    /// General purpose registers in the order of their encoding in the
+    /// instructions
+    pub const ENCODED: [Register; 16] = [
+        Register::Rax,
+        Register::Rcx,
+        Register::Rdx,
+        Register::Rbx,
+        Register::Rsp,
+        Register::Rbp,
+        Register::Rsi,
+        Register::Rdi,
+        Register::R8,
+        Register::R9,
+        Register::R10,
+        Register::R11,
+        Register::R12,
+        Register::R13,
+        Register::R14,
+        Register::R15,
+    ];
+}
+
+/// Segment registers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SegmentRegister {
+    /// Code segment
+    Cs,
+    /// Data segment
+    Ds,
+    /// Extra segment
+    Es,
+    /// FS segment
+    Fs,
+    /// GS segment
+    Gs,
+    /// Stack segment
+    Ss,
+}
+
+/// Instruction executed during a traced run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceStep {
+    /// Address of the instruction
+    pub rip: u64,
+    /// Registers modified by the instruction (besides rip) with their new
+    /// value, if requested
+    pub changes: Vec<(Register, u64)>,
+}
+
+/// Result of a traced run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trace {
+    /// Executed instructions, in order
+    pub steps: Vec<TraceStep>,
+    /// Exit which stopped the execution, `None` if the limit was reached
+    pub exit: Option<VmExit>,
 }
 
 /// Additional details behind a PageFault exception
@@ -140,6 +397,47 @@ impl PageFaultDetail {
     }
 }
 
+/// Periodic timer interrupt delivered by the local APIC
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timer {
+    /// Interrupt vector delivered to the guest, past the exception vectors
+    pub vector: u8,
+    /// Time between two interrupts, from 1ns to about 4s
+    pub period: Duration,
+}
+
+/// Execution statistics of a `Vm`, accumulated since its creation or the
+/// last `clear_stats`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmStats {
+    /// Number of kvm runs
+    pub runs: u64,
+    /// Time spent running the guest
+    pub run_time: Duration,
+    /// Number of resets
+    pub resets: u64,
+    /// Pages restored by the last reset
+    pub last_reset_pages: u64,
+    /// Pages restored by all the resets
+    pub reset_pages: u64,
+    /// Time spent resetting
+    pub reset_time: Duration,
+    /// Time spent writing to the guest memory (e.g. inputs or breakpoints)
+    pub memory_write_time: Duration,
+    /// Pages read from the snapshot on their first access
+    pub demand_loads: u64,
+}
+
+/// Configuration for running kernel mode snapshots
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SupervisorProfile {
+    /// Supervisor mode execution prevention: executing user pages faults
+    pub smep: bool,
+    /// Supervisor mode access prevention: accessing user pages faults,
+    /// unless rflags.AC is set
+    pub smap: bool,
+}
+
 /// Vm exit reason
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum VmExit {
@@ -155,12 +453,211 @@ pub enum VmExit {
     PageFault(PageFaultDetail),
     /// Vm stopped on an unhandled exception
     Exception(u64),
+    /// Vm stopped on a double fault, the faulting rip is undefined
+    DoubleFault,
     /// Vm stopped on a syscall instruction
     Syscall,
+    /// Vm stopped on a virtualization instruction it could not execute, rip
+    /// pointing to the instruction
+    Virtualization(VirtualizationInstruction),
     /// Vmexit unhandled by tartiflette
     Unhandled,
 }
 
+/// Virtualization capabilities reported by the guest CPUID
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VirtualizationCpuid {
+    /// VMX and SVM are hidden
+    Hidden,
+    /// VMX and SVM are reported as kvm supports them (nested virtualization
+    /// enabled on the host)
+    Passthrough,
+    /// VMX and SVM are reported whether kvm supports them or not, for the
+    /// guest to reach its hypervisor code paths. The instructions then stop
+    /// the vm with `VmExit::Virtualization` when not supported.
+    Fake,
+}
+
+/// Virtualization instruction or configuration change, for hypervisor code
+/// running without (or beyond) nested virtualization
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VirtualizationInstruction {
+    /// VMXON
+    Vmxon,
+    /// VMXOFF
+    Vmxoff,
+    /// VMCLEAR
+    Vmclear,
+    /// VMPTRLD
+    Vmptrld,
+    /// VMPTRST
+    Vmptrst,
+    /// VMREAD
+    Vmread,
+    /// VMWRITE
+    Vmwrite,
+    /// VMLAUNCH
+    Vmlaunch,
+    /// VMRESUME
+    Vmresume,
+    /// VMCALL
+    Vmcall,
+    /// VMFUNC
+    Vmfunc,
+    /// INVEPT
+    Invept,
+    /// INVVPID
+    Invvpid,
+    /// VMRUN
+    Vmrun,
+    /// VMMCALL
+    Vmmcall,
+    /// VMLOAD
+    Vmload,
+    /// VMSAVE
+    Vmsave,
+    /// STGI
+    Stgi,
+    /// CLGI
+    Clgi,
+    /// SKINIT
+    Skinit,
+    /// INVLPGA
+    Invlpga,
+    /// Write to CR4 setting VMXE
+    EnableVmx,
+    /// Write to EFER setting SVME
+    EnableSvm,
+    /// Access to a VMX capability or control MSR
+    VmxMsr(u32),
+}
+
+/// Action taken by `run` after an exit callback
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExitAction {
+    /// Return the exit to the caller of `run`
+    Return,
+    /// Resume the guest, the callback having handled the exit
+    Resume,
+}
+
+/// Callback invoked right before each vcpu entry of `run`
+pub type PreRunCallback = Box<dyn FnMut(&mut Vm) + Send>;
+
+/// Callback invoked right after each exit of `run`, deciding whether it is
+/// returned to the caller
+pub type ExitCallback = Box<dyn FnMut(&mut Vm, &VmExit) -> ExitAction + Send>;
+
+/// Registers written by a CPUID instruction
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Handler of the CPUID instructions of the guest, given the leaf, the
+/// subleaf and the result from the CPUID table of the vm, returning the
+/// result seen by the guest
+pub type CpuidHandler = Box<dyn FnMut(u32, u32, CpuidResult) -> CpuidResult + Send>;
+
+/// Iterator over the pages dirtied by the guest since the last reset,
+/// yielding their guest physical address and their current contents
+pub struct DirtyPages<'a> {
+    /// Vm memory
+    memory: &'a VirtualMemory,
+    /// Dirty log bitmap, one bit per physical page
+    bitmap: Vec<u64>,
+    /// Index of the bitmap entry being walked
+    index: usize,
+    /// Remaining dirty bits of the entry being walked
+    bits: u64,
+}
+
+impl<'a> Iterator for DirtyPages<'a> {
+    type Item = (u64, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Skip the entries without dirty pages left
+        while self.bits == 0 {
+            self.index += 1;
+            self.bits = *self.bitmap.get(self.index)?;
+        }
+
+        let pa = (self.index * 64 + self.bits.trailing_zeros() as usize) * PAGE_SIZE;
+        self.bits &= self.bits - 1;
+
+        let page = self
+            .memory
+            .pmem
+            .raw_slice(pa, PAGE_SIZE)
+            .expect("Dirty page out of the physical memory");
+        Some((pa as u64, page))
+    }
+}
+
+/// Read-only `Vm` state used as a reset source. It holds no kvm resources,
+/// so it can be shared between threads (e.g. behind an `Arc`) and inherited
+/// by forked workers, which all reset from the same memory copy.
+pub struct PristineVm {
+    /// Registers
+    registers: kvm_regs,
+    /// Special registers
+    special_registers: kvm_sregs,
+    /// fs_base register
+    fs_base: u64,
+    /// gs_base register
+    gs_base: u64,
+    /// kernel_gs_base register
+    kernel_gs_base: u64,
+    /// Debug registers
+    debug_registers: kvm_debugregs,
+    /// Xsave area, if one was loaded
+    xsave: Option<Box<kvm_xsave>>,
+    /// Whether debug registers and xsave area were loaded
+    extended_state: bool,
+    /// Extended state components enabled in XCR0
+    xcr0: u64,
+    /// Whether the in-kernel interrupt controller is used
+    irqchip: bool,
+    /// Periodic timer interrupt, if any
+    timer: Option<Timer>,
+    /// User code and stack selectors, when running in user mode
+    user_segments: Option<(u16, u16)>,
+    /// Kernel mode snapshot configuration
+    supervisor: SupervisorProfile,
+    /// Whether RDRAND and RDSEED are hidden from the guest CPUID
+    rdrand_hidden: bool,
+    /// Virtualization capabilities of the guest CPUID, if configured
+    virtualization: Option<VirtualizationCpuid>,
+    /// Results of the guest CPUID replaced by leaf and subleaf
+    cpuid_overrides: BTreeMap<(u32, u32), CpuidResult>,
+    /// Coverage points not hit yet, with their original instruction byte
+    coverage_points: BTreeMap<u64, u8>,
+    /// Whether coverage points are re-armed after being hit
+    persistent_coverage: bool,
+    /// Whether the pages holding coverage points are write-protected
+    coverage_write_protection: bool,
+    /// Pages write-protected because they hold coverage points
+    protected_pages: BTreeSet<u64>,
+    /// Snapshot pages loaded on their first access, if any
+    demand_pages: Option<Arc<SnapshotPages>>,
+    /// Virtual address and page table of the frames loaded on demand, by
+    /// physical address
+    demand_frames: BTreeMap<u64, (u64, u64)>,
+    /// Pages loaded on demand, by physical address of their page table
+    demand_tables: BTreeMap<u64, Vec<u64>>,
+    /// Whether writable and executable mappings are rejected
+    wx_enforced: bool,
+    /// Page directory physical address
+    page_directory: usize,
+    /// Starting address of the hypercall region
+    hypercall_page: u64,
+    /// Read-only physical memory
+    memory: FrozenMemory,
+}
+
 /// Tartiflette vm state
 pub struct Vm {
     /// Kvm device file descriptor
@@ -179,8 +676,96 @@ pub struct Vm {
     fs_base: u64,
     /// gs_base register
     gs_base: u64,
+    /// kernel_gs_base register
+    kernel_gs_base: u64,
+    /// Local copy of kvm debug registers
+    debug_registers: kvm_debugregs,
+    /// Local copy of the xsave area, if one was loaded
+    xsave: Option<Box<kvm_xsave>>,
+    /// Whether debug registers and xsave area were loaded and must be restored on reset
+    extended_state: bool,
+    /// Whether debug registers and xsave area must be committed on the next run
+    extended_state_dirty: bool,
+    /// Extended state components enabled in XCR0
+    xcr0: u64,
+    /// Whether the in-kernel interrupt controller is used
+    irqchip: bool,
+    /// Periodic timer interrupt, if any
+    timer: Option<Timer>,
+    /// User code and stack selectors, when running in user mode
+    user_segments: Option<(u16, u16)>,
+    /// Kernel mode snapshot configuration
+    supervisor: SupervisorProfile,
+    /// Whether RDRAND and RDSEED are hidden from the guest CPUID
+    rdrand_hidden: bool,
+    /// Virtualization capabilities of the guest CPUID, if configured
+    virtualization: Option<VirtualizationCpuid>,
+    /// Results of the guest CPUID replaced by leaf and subleaf
+    cpuid_overrides: BTreeMap<(u32, u32), CpuidResult>,
+    /// CPUID table given to kvm, if any
+    cpuid: Option<CpuId>,
+    /// Handler of the CPUID instructions run in user mode, if any
+    cpuid_handler: Option<CpuidHandler>,
+    /// Mechanism recording the branches of the guest, if any
+    branch_recording: Option<BranchRecording>,
+    /// Number of entries of the LBR stack
+    lbr_depth: usize,
+    /// Top of the LBR stack at the last read, its entry cleared
+    lbr_tos: u64,
+    /// Branches recorded since the last `take_branches`
+    branches: Vec<Branch>,
     /// Starting address of the hypercall region
     hypercall_page: u64,
+    /// Exception vector of the last debug exit (1 for a singlestep)
+    debug_exception: u32,
+    /// Kick state shared with the `VcpuKicker`s
+    kick_state: Arc<KickState>,
+    /// Execution statistics
+    stats: VmStats,
+    /// Coverage points not hit yet, with their original instruction byte
+    coverage_points: BTreeMap<u64, u8>,
+    /// Whether coverage points are re-armed after being hit
+    persistent_coverage: bool,
+    /// Coverage point being stepped over, re-armed on the next exit
+    stepped_coverage_point: Option<u64>,
+    /// Number of hits of each coverage point, in persistent mode
+    coverage_hits: BTreeMap<u64, u64>,
+    /// Whether the pages holding coverage points are write-protected
+    coverage_write_protection: bool,
+    /// Pages write-protected because they hold coverage points
+    protected_pages: BTreeSet<u64>,
+    /// Snapshot pages loaded on their first access, if any
+    demand_pages: Option<Arc<SnapshotPages>>,
+    /// Virtual address and page table of the frames loaded on demand, by
+    /// physical address
+    demand_frames: BTreeMap<u64, (u64, u64)>,
+    /// Pages loaded on demand, by physical address of their page table
+    demand_tables: BTreeMap<u64, Vec<u64>>,
+    /// Protected pages the guest is writing to in singlestep
+    written_pages: Vec<u64>,
+    /// Coverage points retired because the guest overwrote them
+    retired_coverage_points: u64,
+    /// Range whose guest reads are recorded, if any
+    watched_range: Option<Range<u64>>,
+    /// Pages of the watched range and of the logged ranges, kept not present
+    watched_pages: BTreeSet<u64>,
+    /// Addresses of the watched range read by the guest, in order
+    watched_reads: Vec<u64>,
+    /// Ranges whose guest reads and writes are logged
+    logged_ranges: Vec<Range<u64>>,
+    /// Accesses of the guest to the logged ranges, in order
+    accesses: Vec<MemoryAccess>,
+    /// Watched pages made present for a singlestep, and whether the trap
+    /// flag was already set by someone else
+    watch_step: Option<(Vec<u64>, bool)>,
+    /// Runs of dirty frames of the last reset, kept to reuse the allocation
+    dirty_runs: Vec<Range<usize>>,
+    /// Dirty log bitmap of the last reset, kept to reuse the allocation
+    dirty_log: Vec<u64>,
+    /// Callback invoked before each vcpu entry, if any
+    pre_run_callback: Option<PreRunCallback>,
+    /// Callback invoked after each exit, if any
+    exit_callback: Option<ExitCallback>,
     /// Vm Memory
     pub memory: VirtualMemory,
 }
@@ -189,8 +774,22 @@ impl Vm {
     /// Creates a new `Vm` instance with a given memory size
     /// (the size will be aligned to the nearest page multiple).
     pub fn new(memory_size: usize) -> Result<Vm> {
+        Vm::create(memory_size, false)
+    }
+
+    /// Creates a new `Vm` instance with an in-kernel interrupt controller,
+    /// delivering the interrupts of the local APIC (see `set_timer`).
+    /// Guest `hlt` instructions then wait for the next interrupt instead of
+    /// exiting with `VmExit::Hlt`.
+    pub fn with_irqchip(memory_size: usize) -> Result<Vm> {
+        Vm::create(memory_size, true)
+    }
+
+    /// Creates a new `Vm` instance, with or without an in-kernel interrupt
+    /// controller
+    fn create(memory_size: usize, irqchip: bool) -> Result<Vm> {
         // Create minimal vm
-        let mut vm = Vm::setup_barebones(memory_size)?;
+        let mut vm = Vm::setup_barebones(memory_size, irqchip)?;
 
         // Setup special registers
         vm.setup_registers()?;
@@ -206,7 +805,7 @@ impl Vm {
 
     /// Sets up a minimal working vm environnement.
     /// (kvm init + memory + sregs)
-    fn setup_barebones(memory_size: usize) -> Result<Vm> {
+    fn setup_barebones(memory_size: usize, irqchip: bool) -> Result<Vm> {
         // 1 - Allocate the memory
         let vm_memory = VirtualMemory::new(memory_size)?;
 
@@ -250,6 +849,13 @@ impl Vm {
             .enable_cap(&cap)
             .expect("Could not enable KVM_DIRTY_LOG_MANUAL_PROTECT");
 
+        // The interrupt controller must exist before the vcpu
+        if irqchip {
+            vm_fd
+                .create_irq_chip()
+                .map_err(|_| VmError::HvError("Could not create irqchip"))?;
+        }
+
         // 4 - Ask kvm to create a new vcpu for our vm
         let vcpu_fd = vm_fd
             .create_vcpu(0)
@@ -285,6 +891,11 @@ impl Vm {
             .get_sregs()
             .map_err(|_| VmError::HvError("Could not get special registers"))?;
 
+        // Get debug registers
+        let debug_regs = vcpu_fd
+            .get_debug_regs()
+            .map_err(|_| VmError::HvError("Could not get debug registers"))?;
+
         // Construct the new `Vm` object
         Ok(Vm {
             _kvm: kvm_fd,
@@ -297,6 +908,49 @@ impl Vm {
             hypercall_page: 0,
             fs_base: 0,
             gs_base: 0,
+            kernel_gs_base: 0,
+            debug_registers: debug_regs,
+            xsave: None,
+            extended_state: false,
+            extended_state_dirty: false,
+            xcr0: XCR0_SSE,
+            irqchip,
+            timer: None,
+            user_segments: None,
+            supervisor: SupervisorProfile::default(),
+            rdrand_hidden: false,
+            virtualization: None,
+            cpuid_overrides: BTreeMap::new(),
+            cpuid: None,
+            cpuid_handler: None,
+            branch_recording: None,
+            lbr_depth: 0,
+            lbr_tos: 0,
+            branches: Vec::new(),
+            debug_exception: 0,
+            kick_state: Arc::default(),
+            stats: VmStats::default(),
+            coverage_points: BTreeMap::new(),
+            persistent_coverage: false,
+            stepped_coverage_point: None,
+            coverage_hits: BTreeMap::new(),
+            coverage_write_protection: false,
+            protected_pages: BTreeSet::new(),
+            written_pages: Vec::new(),
+            retired_coverage_points: 0,
+            watched_range: None,
+            watched_pages: BTreeSet::new(),
+            watched_reads: Vec::new(),
+            logged_ranges: Vec::new(),
+            accesses: Vec::new(),
+            watch_step: None,
+            dirty_runs: Vec::new(),
+            dirty_log: Vec::new(),
+            demand_pages: None,
+            demand_frames: BTreeMap::new(),
+            demand_tables: BTreeMap::new(),
+            pre_run_callback: None,
+            exit_callback: None,
         })
     }
 
@@ -314,7 +968,6 @@ impl Vm {
         const CR4_OSFXSR: u64 = 1 << 9;
         const IA32_EFER_LME: u64 = 1 << 8;
         const IA32_EFER_LMA: u64 = 1 << 10;
-        const IA32_EFER_NXE: u64 = 1 << 11;
 
         // Set the 64 bits code segment
         let mut seg = kvm_segment {
@@ -360,22 +1013,31 @@ impl Vm {
             .map_err(|_| VmError::HvError("Could not set tss address"))?;
 
         // Enable vm exit on software breakpoints
+        self.set_guest_debug(false)
+    }
+
+    /// Enables vm exits on software breakpoints and, if `singlestep` is set,
+    /// after each instruction
+    fn set_guest_debug(&self, singlestep: bool) -> Result<()> {
+        let mut control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP;
+        if singlestep {
+            control |= KVM_GUESTDBG_SINGLESTEP;
+        }
+
         let debug_struct = kvm_guest_debug {
-            control: KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP,
+            control,
             pad: 0,
             arch: Default::default(),
         };
         self.kvm_vcpu
             .set_guest_debug(&debug_struct)
-            .map_err(|_| VmError::HvError("Could not set debug registers"))?;
-
-        Ok(())
+            .map_err(|_| VmError::HvError("Could not set debug registers"))
     }
 
     /// Setups the necessary pieces for handling interrupts (TSS, TSS Stack, GDT slots, IDT)
     fn setup_exception_handling(&mut self) -> Result<()> {
         // Defines usefull regions
-        const IDT_ADDRESS: u64 = 0xffff_ffff_ff00_0000;
+        const IDT_ADDRESS: u64 = RESERVED_ADDRESS;
         const IDT_HANDLERS: u64 = IDT_ADDRESS + PAGE_SIZE as u64;
         const GDT_ADDRESS: u64 = IDT_ADDRESS + (PAGE_SIZE * 2) as u64;
         const TSS_ADDRESS: u64 = IDT_ADDRESS + (PAGE_SIZE * 3) as u64;
@@ -391,28 +1053,22 @@ impl Vm {
             PagePermissions::READ | PagePermissions::WRITE,
         )?;
 
-        // Setting up the null segment
-        self.memory.write_val(GDT_ADDRESS, 0u64)?;
-        // Setting up the 64 bits code segment
-        self.memory
-            .write_val(GDT_ADDRESS + 8, 0x00209a0000000000u64)?;
-        // Setting up the TSS entry
-        self.memory.write_val(
-            GDT_ADDRESS + 16,
-            TssEntry::new(TSS_ADDRESS, PrivilegeLevel::Ring0),
-        )?;
-
         // Set the sepecial registers to reference the GDT
         self.special_registers.gdt.base = GDT_ADDRESS;
-        self.special_registers.gdt.limit = (8 * 3) - 1;
+
+        // Setting up the null segment, the 64 bits code segment and the TSS
+        // entry
+        self.set_gdt(&Vm::tartiflette_gdt(TSS_ADDRESS))?;
 
         // Setting up the TSS
         self.memory
             .mmap(TSS_ADDRESS, PAGE_SIZE, PagePermissions::READ)?;
 
-        // Create the TSS with an IST alternative stack at index 1
+        // Create the TSS with an IST alternative stack at index 1, and one
+        // for double faults at index 2 in the lower half of the stack
         let mut tss = Tss::new();
         tss.set_ist(1, STACK_ADDRESS + (STACK_SIZE - 0x100) as u64);
+        tss.set_ist(2, STACK_ADDRESS + (STACK_SIZE / 2 - 0x100) as u64);
         // Write the structure in memory
         self.memory.write_val(TSS_ADDRESS, tss)?;
 
@@ -443,14 +1099,16 @@ impl Vm {
 
         // Loop through IDT handlers
         for i in 0..32 {
-            let handler_code: &[u8] = &[
-                0x6a, i as u8, // push <exception index>
-                0xf4,    // hlt -> our hypercall
-            ];
-
-            self.memory.write(IDT_HANDLERS + (i * 32), handler_code)?;
+            self.memory
+                .write(IDT_HANDLERS + (i * 32), &self.hypercall_stub(i as u8))?;
         }
 
+        // Syscall entry stub, used in user mode
+        self.memory.write(
+            IDT_HANDLERS + SYSCALL_ENTRY_OFFSET,
+            &[0xe6, SYSCALL_PORT as u8], // out SYSCALL_PORT, al
+        )?;
+
         // Setting up the IDT
         self.memory
             .mmap(IDT_ADDRESS, PAGE_SIZE, PagePermissions::READ)?;
@@ -460,19 +1118,29 @@ impl Vm {
 
         // Loop through IDT entries
         for i in 0..32 {
+            // Double faults get their own stack
+            let stack = match ExceptionType::from(i as u64) {
+                ExceptionType::DoubleFault => 2,
+                _ => 1,
+            };
+
             entries[i] = IdtEntryBuilder::new()
                 .base(IDT_HANDLERS + (i * 32) as u64)
                 .dpl(PrivilegeLevel::Ring0)
                 .segment_selector(1, PrivilegeLevel::Ring0)
                 .gate_type(IdtEntryType::Trap)
-                .ist(1)
+                .ist(stack)
                 .collect();
         }
         self.memory.write_val(IDT_ADDRESS, entries)?;
 
-        // Set the sepecial registers to reference the IDT
+        // Set the sepecial registers to reference the IDT. With an irqchip,
+        // it spans the whole page, for the timer vector (see `set_timer`)
         self.special_registers.idt.base = IDT_ADDRESS;
-        self.special_registers.idt.limit = (entries_size - 1) as u16;
+        self.special_registers.idt.limit = match self.irqchip {
+            true => PAGE_SIZE - 1,
+            false => entries_size - 1,
+        } as u16;
 
         // Setting up the alternativ stack by allocating it for exception handling
         self.memory.mmap(
@@ -484,6 +1152,162 @@ impl Vm {
         Ok(())
     }
 
+    /// Layout of the GDT used by the exception handling: 64 bits code
+    /// segment at index 1 and TSS at index 2
+    fn tartiflette_gdt(tss: u64) -> GdtBuilder {
+        let mut gdt = GdtBuilder::new();
+        gdt.code_segment(PrivilegeLevel::Ring0);
+        gdt.tss(tss, PrivilegeLevel::Ring0);
+        gdt
+    }
+
+    /// Returns a GDT builder holding the segments the `Vm` relies on, to
+    /// which additional segments can be added before calling `set_gdt`
+    pub fn default_gdt(&self) -> GdtBuilder {
+        Vm::tartiflette_gdt(self.special_registers.tr.base)
+    }
+
+    /// Replaces the GDT of the `Vm`. It must start with the segments of
+    /// `default_gdt`.
+    pub fn set_gdt(&mut self, gdt: &GdtBuilder) -> Result<()> {
+        let entries = gdt.collect();
+        if entries.len() * 8 > PAGE_SIZE {
+            return Err(VmError::HvError("GDT does not fit in a page"));
+        }
+
+        for (index, entry) in entries.iter().enumerate() {
+            self.memory
+                .write_val(self.special_registers.gdt.base + index as u64 * 8, *entry)?;
+        }
+        self.special_registers.gdt.limit = (entries.len() * 8 - 1) as u16;
+
+        Ok(())
+    }
+
+    /// Loads a segment register from a selector of the GDT
+    pub fn set_segment(&mut self, register: SegmentRegister, selector: u16) -> Result<()> {
+        let segment = match register {
+            SegmentRegister::Cs => &mut self.special_registers.cs,
+            SegmentRegister::Ds => &mut self.special_registers.ds,
+            SegmentRegister::Es => &mut self.special_registers.es,
+            SegmentRegister::Fs => &mut self.special_registers.fs,
+            SegmentRegister::Gs => &mut self.special_registers.gs,
+            SegmentRegister::Ss => &mut self.special_registers.ss,
+        };
+
+        // Null selectors leave the segment unusable
+        let index = (selector >> 3) as u64;
+        if index == 0 {
+            segment.selector = selector;
+            segment.present = 0;
+            segment.unusable = 1;
+            return Ok(());
+        }
+
+        if index * 8 + 7 > self.special_registers.gdt.limit as u64 {
+            return Err(VmError::HvError("Selector out of the GDT"));
+        }
+        let descriptor: u64 = self
+            .memory
+            .read_val(self.special_registers.gdt.base + index * 8)?;
+
+        segment.selector = selector;
+        segment.limit = 0xffff_ffff;
+        segment.type_ = ((descriptor >> 40) & 0xf) as u8 | 1; // Accessed
+        segment.s = ((descriptor >> 44) & 1) as u8;
+        segment.dpl = ((descriptor >> 45) & 0b11) as u8;
+        segment.present = ((descriptor >> 47) & 1) as u8;
+        segment.avl = ((descriptor >> 52) & 1) as u8;
+        segment.l = ((descriptor >> 53) & 1) as u8;
+        segment.db = ((descriptor >> 54) & 1) as u8;
+        segment.g = 1;
+        segment.unusable = 0;
+
+        // fs and gs bases are kept, as they come from the msrs
+        if !matches!(register, SegmentRegister::Fs | SegmentRegister::Gs) {
+            segment.base = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the guest in user mode (CPL3) from now on, with user code and
+    /// data segments added to `default_gdt`. The memory mapped so far
+    /// becomes user accessible, later mappings need `PagePermissions::USER`.
+    /// Syscalls exit through a kernel entry stub as `VmExit::Syscall`, and
+    /// privileged instructions fault with `VmExit::Exception`.
+    pub fn enter_user_mode(&mut self) -> Result<()> {
+        // Step 1: Load the user segments
+        let mut gdt = self.default_gdt();
+        let code = gdt.code_segment(PrivilegeLevel::Ring3);
+        let data = gdt.data_segment(PrivilegeLevel::Ring3);
+        self.set_gdt(&gdt)?;
+
+        self.set_segment(SegmentRegister::Cs, code)?;
+        for register in [
+            SegmentRegister::Ds,
+            SegmentRegister::Es,
+            SegmentRegister::Fs,
+            SegmentRegister::Gs,
+            SegmentRegister::Ss,
+        ] {
+            self.set_segment(register, data)?;
+        }
+        self.user_segments = Some((code, data));
+
+        // Step 2: Open the guest memory, but our own region, to user mode
+        let reserved = RESERVED_ADDRESS..RESERVED_ADDRESS + RESERVED_SIZE;
+        let pages: Vec<u64> = self
+            .memory
+            .mappings()
+            .map(|mapping| mapping.address)
+            .filter(|address| !reserved.contains(address))
+            .collect();
+        for page in pages {
+            self.memory.set_user_accessible(page, PAGE_SIZE, true)?;
+        }
+
+        // Step 3: Enable the syscall instruction
+        self.special_registers.efer |= IA32_EFER_SCE;
+        self.commit_syscall_msrs()
+    }
+
+    /// Points the syscall instruction to the syscall entry stub
+    fn commit_syscall_msrs(&self) -> Result<()> {
+        // Kernel code segment of `tartiflette_gdt`, the stack segment loaded
+        // by syscall is never used by the entry stub
+        const KERNEL_CODE_SELECTOR: u64 = 1 << 3;
+
+        let msrs = Msrs::from_entries(&[
+            kvm_msr_entry {
+                index: IA32_STAR,
+                data: KERNEL_CODE_SELECTOR << 32,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: IA32_LSTAR,
+                data: self.hypercall_page + SYSCALL_ENTRY_OFFSET,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: IA32_FMASK,
+                data: 0,
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+
+        let count = self
+            .kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|_| VmError::HvError("Could not set syscall msrs"))?;
+        if count != 3 {
+            return Err(VmError::HvError("Could not set syscall msrs"));
+        }
+
+        Ok(())
+    }
+
     /// Gets a register from the vm state
     #[inline]
     pub fn get_reg(&self, regid: Register) -> u64 {
@@ -508,6 +1332,7 @@ impl Vm {
             Register::Rflags => self.registers.rflags,
             Register::FsBase => self.fs_base,
             Register::GsBase => self.gs_base,
+            Register::KernelGsBase => self.kernel_gs_base,
         }
     }
 
@@ -535,6 +1360,7 @@ impl Vm {
             Register::Rflags => self.registers.rflags = regval,
             Register::FsBase => self.fs_base = regval,
             Register::GsBase => self.gs_base = regval,
+            Register::KernelGsBase => self.kernel_gs_base = regval,
         }
     }
 
@@ -546,541 +1372,4196 @@ impl Vm {
             .map_err(VmError::MemoryError)
     }
 
-    /// Writes given data to the vm memory
+    /// Maps a virtual area to the given physical frames of the vm memory
     #[inline]
-    pub fn write(&mut self, vaddr: u64, data: &[u8]) -> Result<()> {
-        self.memory.write(vaddr, data).map_err(VmError::MemoryError)
+    pub fn map_phys(
+        &mut self,
+        vaddr: u64,
+        paddr: u64,
+        size: usize,
+        perms: PagePermissions,
+    ) -> Result<()> {
+        self.memory
+            .map_phys(vaddr, paddr, size, perms)
+            .map_err(VmError::MemoryError)
     }
 
-    /// Writes a value to the vm memory
+    /// Changes the permissions of mapped memory in the vm address space
     #[inline]
-    pub fn write_value<T>(&mut self, address: u64, val: T) -> Result<()> {
+    pub fn mprotect(&mut self, vaddr: u64, size: usize, perms: PagePermissions) -> Result<()> {
         self.memory
-            .write_val::<T>(address, val)
+            .mprotect(vaddr, size, perms)
             .map_err(VmError::MemoryError)
     }
 
-    /// Reads data from the given vm memory
+    /// Rejects, or not, the mappings which are both writable and executable
     #[inline]
-    pub fn read(&self, vaddr: u64, data: &mut [u8]) -> Result<()> {
-        self.memory.read(vaddr, data).map_err(VmError::MemoryError)
+    pub fn set_wx_enforced(&mut self, enforced: bool) {
+        self.memory.set_wx_enforced(enforced);
     }
 
-    /// Returns an iterator over all mappings
+    /// Returns the ranges (start, end) of the vm memory which are both
+    /// writable and executable
     #[inline]
-    pub fn mappings(&self) -> impl Iterator<Item = Mapping> + '_ {
-        self.memory.mappings()
+    pub fn wx_regions(&self) -> Vec<(u64, u64)> {
+        self.memory.wx_regions()
     }
 
-    /// Returns an iterator over all dirty mappings
+    /// Writes given data to the vm memory
     #[inline]
-    pub fn dirty_mappings(&self) -> impl Iterator<Item = Mapping> + '_ {
-        self.mappings().filter(|m| m.dirty)
+    pub fn write(&mut self, vaddr: u64, data: &[u8]) -> Result<()> {
+        self.load_demand_range(vaddr, data.len())?;
+        let start = Instant::now();
+        let result = self.memory.write(vaddr, data).map_err(VmError::MemoryError);
+        self.stats.memory_write_time += start.elapsed();
+        result
     }
 
-    /// Clear dirty mappings status
+    /// Fills the vm memory with a byte
     #[inline]
-    pub fn clear_dirty_mappings(&mut self) {
-        for (_, pte) in self.memory.raw_pages_mut() {
-            pte.set_dirty(false);
-        }
+    pub fn write_bytes(&mut self, vaddr: u64, byte: u8, len: usize) -> Result<()> {
+        self.load_demand_range(vaddr, len)?;
+        let start = Instant::now();
+        let result = self
+            .memory
+            .write_bytes(vaddr, byte, len)
+            .map_err(VmError::MemoryError);
+        self.stats.memory_write_time += start.elapsed();
+        result
     }
 
-    fn flush_registers(&mut self) -> Result<()> {
-        // The second bit of rflags must always be set.
-        self.registers.rflags |= 1 << 1;
-
-        // Set registers and special registers
-        self.kvm_vcpu
-            .set_regs(&self.registers)
-            .map_err(|_| VmError::HvError("Could not commit registers"))?;
-        self.kvm_vcpu
-            .set_sregs(&self.special_registers)
-            .map_err(|_| VmError::HvError("Could not commit special registers"))?;
+    /// Writes a value to the vm memory
+    #[inline]
+    pub fn write_value<T>(&mut self, address: u64, val: T) -> Result<()> {
+        self.load_demand_range(address, core::mem::size_of::<T>())?;
+        let start = Instant::now();
+        let result = self
+            .memory
+            .write_val::<T>(address, val)
+            .map_err(VmError::MemoryError);
+        self.stats.memory_write_time += start.elapsed();
+        result
+    }
 
-        // Set gs_base and fs_base through msrs
-        let msrs = Msrs::from_entries(&[
-            kvm_msr_entry {
-                index: IA32_FS_BASE,
-                data: self.fs_base,
-                ..Default::default()
-            },
-            kvm_msr_entry {
-                index: IA32_GS_BASE,
-                data: self.gs_base,
-                ..Default::default()
-            },
-        ])
-        .unwrap();
-        self.kvm_vcpu
-            .set_msrs(&msrs)
-            .map_err(|_| VmError::HvError("Could not commit fsbase and gsbase"))?;
+    /// Reads data from the given vm memory
+    #[inline]
+    pub fn read(&self, vaddr: u64, data: &mut [u8]) -> Result<()> {
+        self.memory
+            .read(vaddr, data)
+            .map_err(VmError::MemoryError)?;
 
-        // Get registers and special registers
-        self.registers = self
-            .kvm_vcpu
-            .get_regs()
-            .map_err(|_| VmError::HvError("Could not get special registers"))?;
-        self.special_registers = self
-            .kvm_vcpu
-            .get_sregs()
-            .map_err(|_| VmError::HvError("Could not get general registers"))?;
+        match &self.demand_pages {
+            Some(pages) => self.read_demand_range(pages, vaddr, data),
+            None => Ok(()),
+        }
+    }
 
-        // Update kvm vcpu run region
-        self.kvm_vcpu_run.as_mut_ref().s.regs.regs = self.registers;
-        self.kvm_vcpu_run.as_mut_ref().s.regs.sregs = self.special_registers;
-        self.kvm_vcpu_run.as_mut_ref().kvm_dirty_regs = 0;
+    /// Overwrites the data read from the pages not loaded yet by their
+    /// contents in the snapshot
+    fn read_demand_range(&self, pages: &SnapshotPages, vaddr: u64, data: &mut [u8]) -> Result<()> {
+        let mut page = vaddr & !(PAGE_SIZE as u64 - 1);
+
+        while page < vaddr + data.len() as u64 {
+            if pages.contains(page)
+                && !self.memory.present(page)
+                && !self.watched_pages.contains(&page)
+            {
+                let contents = pages.read(page)?;
+                let start = page.max(vaddr);
+                let end = (page + PAGE_SIZE as u64).min(vaddr + data.len() as u64);
+                data[(start - vaddr) as usize..(end - vaddr) as usize]
+                    .copy_from_slice(&contents[(start - page) as usize..(end - page) as usize]);
+            }
+            page += PAGE_SIZE as u64;
+        }
 
         Ok(())
     }
 
-    /// Commit local copy of registers to kvm
-    #[inline]
-    fn commit_registers(&mut self) -> Result<()> {
-        // The second bit of rflags must always be set.
-        self.registers.rflags |= 1 << 1;
-
-        self.kvm_vcpu_run.as_mut_ref().s.regs.regs = self.registers;
-        self.kvm_vcpu_run.as_mut_ref().s.regs.sregs = self.special_registers;
-        self.kvm_vcpu_run.as_mut_ref().kvm_dirty_regs |=
-            KVM_SYNC_X86_SREGS as u64 | KVM_SYNC_X86_REGS as u64;
-
-        // gs_base and fs_base need to go through msrs
-        let msrs = Msrs::from_entries(&[
-            kvm_msr_entry {
-                index: IA32_FS_BASE,
-                data: self.fs_base,
-                ..Default::default()
-            },
-            kvm_msr_entry {
-                index: IA32_GS_BASE,
-                data: self.gs_base,
-                ..Default::default()
-            },
-        ])
-        .unwrap();
+    /// Loads the pages of a range not loaded yet, before writing to them
+    fn load_demand_range(&mut self, vaddr: u64, len: usize) -> Result<()> {
+        if self.demand_pages.is_none() {
+            return Ok(());
+        }
 
-        self.kvm_vcpu
-            .set_msrs(&msrs)
-            .map_err(|_| VmError::HvError("Could not commit fsbase and gsbase"))?;
+        let mut page = vaddr & !(PAGE_SIZE as u64 - 1);
+        while page < vaddr + len as u64 {
+            self.load_demand_page(page)?;
+            page += PAGE_SIZE as u64;
+        }
 
         Ok(())
     }
 
-    /// Run the `Vm` instance until the first `Vm` that cannot be
-    /// handled directly
-    pub fn run(&mut self) -> Result<VmExit> {
-        let result = loop {
-            // Commit potential modification done on registers
-            self.commit_registers()?;
+    /// Reads a snapshot page not loaded yet and makes it present. Returns
+    /// false if the page is not one of the snapshot or already loaded.
+    fn load_demand_page(&mut self, page: u64) -> Result<bool> {
+        let pages = match &self.demand_pages {
+            Some(pages)
+                if pages.contains(page)
+                    && !self.memory.present(page)
+                    && !self.watched_pages.contains(&page) =>
+            {
+                Arc::clone(pages)
+            }
+            _ => return Ok(false),
+        };
 
-            // Set the valid synchronised registers
-            self.kvm_vcpu_run.as_mut_ref().kvm_valid_regs |=
-                KVM_SYNC_X86_REGS as u64 | KVM_SYNC_X86_SREGS as u64;
+        // The frame was allocated with the mapping, only its contents and
+        // presence are missing
+        let contents = pages.read(page)?;
+        self.memory.write(page, &contents)?;
+        self.memory.set_present(page, true)?;
 
-            // Ask kvm to run the vm's vcpu
-            let exit = self.kvm_vcpu.run();
+        // Remembered to restore it, the reset sources possibly lacking it
+        let (frame, _) = self
+            .memory
+            .virt_to_phys(page)
+            .ok_or(VmError::MemoryError(MemoryError::AddressUnmapped(page)))?;
+        let table = self
+            .memory
+            .page_table_address(page)
+            .ok_or(VmError::MemoryError(MemoryError::AddressUnmapped(page)))?;
+        self.demand_frames.insert(frame, (page, table));
+        self.demand_tables.entry(table).or_default().push(page);
+        self.stats.demand_loads += 1;
 
-            // Pull registers and special registers
-            unsafe {
-                self.registers = self.kvm_vcpu_run.as_mut_ref().s.regs.regs;
-                self.special_registers = self.kvm_vcpu_run.as_mut_ref().s.regs.sregs;
+        Ok(true)
+    }
+
+    /// Loads the snapshot page whose first access caused `exit`, if any.
+    /// Returns whether the exit was consumed.
+    fn fault_demand_page(&mut self, exit: &VmExit) -> Result<bool> {
+        match exit {
+            VmExit::PageFault(detail) => {
+                self.load_demand_page(detail.address & !(PAGE_SIZE as u64 - 1))
             }
+            _ => Ok(false),
+        }
+    }
 
-            // Pull fs_base and gs_base
-            let mut msrs = Msrs::from_entries(&[
-                kvm_msr_entry {
-                    index: IA32_FS_BASE,
-                    ..Default::default()
-                },
-                kvm_msr_entry {
-                    index: IA32_GS_BASE,
-                    ..Default::default()
-                },
-            ])
-            .unwrap();
+    /// Adds a one-shot coverage point: a breakpoint replacing the first
+    /// byte of the instruction at `address`, until it is hit. Returns false
+    /// if the point was already there.
+    pub fn add_coverage_point(&mut self, address: u64) -> Result<bool> {
+        if self.coverage_points.contains_key(&address) {
+            return Ok(false);
+        }
 
-            let count = self
-                .kvm_vcpu
-                .get_msrs(&mut msrs)
-                .map_err(|_| VmError::HvError("Could not read fs_base and gs_base"))?;
-            assert_eq!(count, 2, "Invalid number of msrs returned");
+        let mut byte = [0u8; 1];
+        self.read(address, &mut byte)?;
+        self.write_value::<u8>(address, INT3)?;
+        self.coverage_points.insert(address, byte[0]);
 
-            let msrs_res = msrs.as_slice();
-            self.fs_base = msrs_res[0].data;
-            self.gs_base = msrs_res[1].data;
+        if self.coverage_write_protection {
+            self.protect_coverage_page(address & !(PAGE_SIZE as u64 - 1))?;
+        }
 
-            // Handle possible interrupts (timeout)
-            if let Err(err) = exit {
-                match Errno::from_i32(err.errno()) {
-                    Errno::EINTR | Errno::EAGAIN => break VmExit::Interrupted,
-                    _ => return Err(VmError::HvError("Unexpected errno in KVM_RUN")),
-                }
+        Ok(true)
+    }
+
+    /// Adds coverage points at each address, returning the number of new
+    /// ones
+    pub fn add_coverage_points(&mut self, addresses: &[u64]) -> Result<usize> {
+        let mut added = 0;
+        for &address in addresses {
+            if self.add_coverage_point(address)? {
+                added += 1;
             }
+        }
 
-            match exit.unwrap() {
-                VcpuExit::Debug(_) => {
-                    break VmExit::Breakpoint;
-                }
-                VcpuExit::Hlt => {
-                    // If code is outside of hypercall region, forward the hlt
-                    if (self.registers.rip < self.hypercall_page)
-                        || (self.registers.rip >= self.hypercall_page + PAGE_SIZE as u64)
-                    {
-                        break VmExit::Hlt;
-                    }
+        Ok(added)
+    }
 
-                    // If we are within the hypercall region, handle the
-                    // exception forwarding.
-                    let exception_code: u64 = self.memory.read_val(self.registers.rsp)?;
-
-                    let error_code: Option<u64> = match ExceptionType::from(exception_code) {
-                        ExceptionType::DoubleFault
-                        | ExceptionType::InvalidTSS
-                        | ExceptionType::SegmentNotPresent
-                        | ExceptionType::StackFault
-                        | ExceptionType::GeneralProtection
-                        | ExceptionType::PageFault
-                        | ExceptionType::AlignmentCheck
-                        | ExceptionType::ControlProtection => {
-                            Some(self.memory.read_val(self.registers.rsp + 8)?)
-                        }
-                        _ => None,
-                    };
-
-                    let exception_frame: ExceptionFrame = if error_code.is_some() {
-                        self.memory.read_val(self.registers.rsp + 16)?
-                    } else {
-                        self.memory.read_val(self.registers.rsp + 8)?
-                    };
-
-                    // Reset register context to before exception
-                    self.registers.rsp = exception_frame.rsp;
-                    self.registers.rip = exception_frame.rip;
-
-                    match ExceptionType::from(exception_code) {
-                        ExceptionType::PageFault => {
-                            break VmExit::PageFault(PageFaultDetail {
-                                status: error_code.unwrap() as u32,
-                                address: self.special_registers.cr2,
-                            });
-                        }
-                        ExceptionType::InvalidOpcode => {
-                            // As IA32_EFER.SCE is not enabled, a syscall instruction will trigger
-                            // a #UD exception. We cannot enable the SCE bit in EFER as it would
-                            // require us to setup the whole syscall machinery as well as the LSTAR
-                            // register.
-                            // To give the opportunity to the Vm user to emulate the syscall, we try
-                            // to detect the instruction bytes, set the rip to after the syscall
-                            // and return with a special `Syscall` VmExit.
-                            let mut code_bytes: [u8; 2] = [0; 2];
-
-                            if self
-                                .memory
-                                .read(self.registers.rip, &mut code_bytes)
-                                .is_ok()
-                            {
-                                //  0f 05 -> syscall
-                                if code_bytes == [0x0f, 0x05] {
-                                    // We advance rip by two bytes to move over the syscall
-                                    // instruction.
-                                    self.registers.rip += 2;
-                                    break VmExit::Syscall;
-                                }
-                            }
-
-                            break VmExit::InvalidInstruction;
-                        }
-                        _ => break VmExit::Exception(exception_code),
-                    }
+    /// Adds coverage points at the module-relative points of `coverage`,
+    /// resolved against the modules of the loaded snapshot. Returns the
+    /// number of new ones.
+    pub fn add_module_coverage_points(
+        &mut self,
+        coverage: &ModuleCoverage,
+        modules: &BTreeMap<String, SnapshotModule>,
+    ) -> Result<usize> {
+        self.add_coverage_points(&coverage.addresses(modules))
+    }
+
+    /// Removes a coverage point, restoring the original instruction byte.
+    /// Returns false if there was no point at `address`.
+    pub fn remove_coverage_point(&mut self, address: u64) -> Result<bool> {
+        match self.coverage_points.remove(&address) {
+            Some(byte) => {
+                self.write_value::<u8>(address, byte)?;
+                self.release_coverage_page(address & !(PAGE_SIZE as u64 - 1))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Removes all the coverage points
+    pub fn clear_coverage_points(&mut self) -> Result<()> {
+        while let Some((&address, _)) = self.coverage_points.iter().next() {
+            self.remove_coverage_point(address)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether a coverage point is set at `address`
+    #[inline]
+    pub fn has_coverage_point(&self, address: u64) -> bool {
+        self.coverage_points.contains_key(&address)
+    }
+
+    /// Returns the number of coverage points not hit yet
+    #[inline]
+    pub fn coverage_points_remaining(&self) -> usize {
+        self.coverage_points.len()
+    }
+
+    /// Handles a `VmExit::Breakpoint` on a coverage point so that the guest
+    /// can resume. Returns the hit address, or `None` if the breakpoint is
+    /// not a coverage point.
+    ///
+    /// One-shot points are removed, and must also be removed from the reset
+    /// source, or they would be restored by the next reset while no longer
+    /// being known. Persistent points are counted and stepped over, `run`
+    /// re-arming them once the instruction executed.
+    pub fn hit_coverage_point(&mut self) -> Result<Option<u64>> {
+        let rip = self.registers.rip;
+        if !self.persistent_coverage {
+            return match self.remove_coverage_point(rip)? {
+                true => Ok(Some(rip)),
+                false => Ok(None),
+            };
+        }
+
+        let byte = match self.coverage_points.get(&rip) {
+            Some(&byte) => byte,
+            None => return Ok(None),
+        };
+
+        // Execute the original instruction in singlestep
+        self.write_value::<u8>(rip, byte)?;
+        self.registers.rflags |= TRAP_FLAG;
+        self.stepped_coverage_point = Some(rip);
+        *self.coverage_hits.entry(rip).or_insert(0) += 1;
+
+        Ok(Some(rip))
+    }
+
+    /// Makes coverage points re-armed after each hit instead of removed,
+    /// for hit count and edge coverage schemes. Points then stay in place
+    /// and their hits are counted (see `coverage_hits`).
+    pub fn set_persistent_coverage(&mut self, persistent: bool) {
+        self.persistent_coverage = persistent;
+    }
+
+    /// Returns the number of hits of each coverage point in persistent mode,
+    /// since the last `clear_coverage_hits`
+    #[inline]
+    pub fn coverage_hits(&self) -> &BTreeMap<u64, u64> {
+        &self.coverage_hits
+    }
+
+    /// Clears the hit counts of the coverage points, e.g. between runs
+    #[inline]
+    pub fn clear_coverage_hits(&mut self) {
+        self.coverage_hits.clear();
+    }
+
+    /// Re-arms the coverage point stepped over, and the coverage points of
+    /// the pages written in singlestep, if `exit` is the singlestep
+    /// following them. Returns whether the exit was consumed.
+    fn rearm_coverage_point(&mut self, exit: &VmExit) -> Result<bool> {
+        if self.stepped_coverage_point.is_none() && self.written_pages.is_empty() {
+            return Ok(false);
+        }
+
+        let singlestep = match exit {
+            VmExit::Exception(1) => true,
+            VmExit::Breakpoint => self.debug_exception == 1,
+            _ => false,
+        };
+        if !singlestep {
+            return Ok(false);
+        }
+
+        self.registers.rflags &= !TRAP_FLAG;
+
+        // Settle the written pages first, the stepped point byte being the
+        // original one there unless the guest replaced it
+        while let Some(page) = self.written_pages.pop() {
+            self.settle_written_page(page, true)?;
+        }
+
+        if let Some(address) = self.stepped_coverage_point.take() {
+            if self.has_coverage_point(address) {
+                self.write_value::<u8>(address, INT3)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Write-protects the pages holding coverage points, or lifts the
+    /// protection. Guests writing over their own code (JITs, unpackers)
+    /// then fault on the pages of the points instead of silently replacing
+    /// them: the write is replayed in singlestep over the original bytes,
+    /// the points whose byte was left untouched are re-installed and the
+    /// others retired (see `retired_coverage_points`).
+    pub fn set_coverage_write_protection(&mut self, enabled: bool) -> Result<()> {
+        self.coverage_write_protection = enabled;
+
+        let pages: BTreeSet<u64> = match enabled {
+            true => self
+                .coverage_points
+                .keys()
+                .map(|address| address & !(PAGE_SIZE as u64 - 1))
+                .collect(),
+            false => self.protected_pages.clone(),
+        };
+
+        for page in pages {
+            match enabled {
+                true => self.protect_coverage_page(page)?,
+                false => self.set_page_writable(page, true)?,
+            }
+        }
+
+        if !enabled {
+            self.protected_pages.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of coverage points retired because the guest
+    /// overwrote their instruction
+    #[inline]
+    pub fn retired_coverage_points(&self) -> u64 {
+        self.retired_coverage_points
+    }
+
+    /// Write-protects a page holding coverage points, if it is writable
+    fn protect_coverage_page(&mut self, page: u64) -> Result<()> {
+        if self.protected_pages.contains(&page) {
+            return Ok(());
+        }
+
+        match self.memory.virt_to_phys(page) {
+            Some((_, perms)) if perms.writable() => {
+                self.set_page_writable(page, false)?;
+                self.protected_pages.insert(page);
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Lifts the write protection of a page without coverage points left
+    fn release_coverage_page(&mut self, page: u64) -> Result<()> {
+        let end = page + PAGE_SIZE as u64;
+        if self.coverage_points.range(page..end).next().is_some()
+            || !self.protected_pages.remove(&page)
+        {
+            return Ok(());
+        }
+
+        self.set_page_writable(page, true)
+    }
+
+    /// Changes the write permission of a mapped page, keeping the others
+    fn set_page_writable(&mut self, page: u64, writable: bool) -> Result<()> {
+        let mut perms = match self.memory.virt_to_phys(page) {
+            Some((_, perms)) => perms,
+            None => return Ok(()),
+        };
+
+        // The wx check only concerns the mappings made by the user
+        let wx_enforced = self.memory.wx_enforced();
+        self.memory.set_wx_enforced(false);
+        perms.set_writable(writable);
+        let result = self.mprotect(page, PAGE_SIZE, perms);
+        self.memory.set_wx_enforced(wx_enforced);
+
+        result
+    }
+
+    /// Lets the guest write to a protected page holding coverage points if
+    /// `exit` is the resulting page fault: the original bytes are restored
+    /// and the faulting instruction is replayed in singlestep. Returns
+    /// whether the exit was consumed.
+    fn unprotect_written_page(&mut self, exit: &VmExit) -> Result<bool> {
+        let detail = match exit {
+            VmExit::PageFault(detail) if detail.write() => detail,
+            _ => return Ok(false),
+        };
+
+        let page = detail.address & !(PAGE_SIZE as u64 - 1);
+        if !self.protected_pages.contains(&page) || self.written_pages.contains(&page) {
+            return Ok(false);
+        }
+
+        let end = page + PAGE_SIZE as u64;
+        let points: Vec<(u64, u8)> = self
+            .coverage_points
+            .range(page..end)
+            .map(|(&address, &byte)| (address, byte))
+            .collect();
+        for (address, byte) in points {
+            self.write_value::<u8>(address, byte)?;
+        }
+
+        self.set_page_writable(page, true)?;
+        self.registers.rflags |= TRAP_FLAG;
+        self.written_pages.push(page);
+
+        Ok(true)
+    }
+
+    /// Re-installs the coverage points of a page written in singlestep and
+    /// protects it again. If `check` is set, the points whose original byte
+    /// was overwritten are retired instead.
+    fn settle_written_page(&mut self, page: u64, check: bool) -> Result<()> {
+        let end = page + PAGE_SIZE as u64;
+        let points: Vec<(u64, u8)> = self
+            .coverage_points
+            .range(page..end)
+            .map(|(&address, &byte)| (address, byte))
+            .collect();
+
+        for (address, byte) in points {
+            let mut current = [0u8; 1];
+            self.read(address, &mut current)?;
+
+            if check && current[0] != byte {
+                self.coverage_points.remove(&address);
+                self.retired_coverage_points += 1;
+            } else {
+                self.write_value::<u8>(address, INT3)?;
+            }
+        }
+
+        // Pages without points left stay writable
+        match self.coverage_points.range(page..end).next() {
+            Some(_) => self.set_page_writable(page, false),
+            None => {
+                self.protected_pages.remove(&page);
+                Ok(())
+            }
+        }
+    }
+
+    /// Re-installs the coverage points of the pages interrupted by a reset
+    /// in the middle of a write, whose result is discarded
+    fn abort_written_pages(&mut self) {
+        while let Some(page) = self.written_pages.pop() {
+            self.settle_written_page(page, false)
+                .expect("Could not re-install coverage points");
+        }
+    }
+
+    /// Records the guest reads of a memory range, e.g. an input buffer, to
+    /// find which parts of it the guest consumed (see `take_watched_reads`).
+    /// The pages of the range are made not present: each access to them
+    /// faults and is replayed in singlestep, only the first byte of each
+    /// read being recorded. The watch survives the resets, and replaces any
+    /// previous one.
+    pub fn watch_reads(&mut self, address: u64, size: usize) -> Result<()> {
+        self.unwatch_reads()?;
+        if size == 0 {
+            return Ok(());
+        }
+
+        self.watched_range = Some(self.hideable_range(address, size)?);
+        self.hide_watched_pages()
+    }
+
+    /// Stops recording the guest reads, dropping the ones not taken yet
+    pub fn unwatch_reads(&mut self) -> Result<()> {
+        self.cancel_watch_step();
+        self.watched_range = None;
+        self.watched_reads.clear();
+
+        self.hide_watched_pages()
+    }
+
+    /// Logs the guest reads and writes of memory ranges, e.g. to find which
+    /// code touched an input buffer (see `take_accesses`). As with
+    /// `watch_reads`, each access to the pages of the ranges faults and is
+    /// replayed in singlestep, the faulting instruction being decoded for
+    /// the size of its memory operand. The log survives the resets, and
+    /// replaces any previous one.
+    pub fn log_accesses(&mut self, ranges: &[Range<u64>]) -> Result<()> {
+        self.unlog_accesses()?;
+
+        let mut logged_ranges = Vec::new();
+        for range in ranges.iter().filter(|range| !range.is_empty()) {
+            let size = (range.end - range.start) as usize;
+            logged_ranges.push(self.hideable_range(range.start, size)?);
+        }
+        self.logged_ranges = logged_ranges;
+
+        self.hide_watched_pages()
+    }
+
+    /// Stops logging the guest accesses, dropping the ones not taken yet
+    pub fn unlog_accesses(&mut self) -> Result<()> {
+        self.cancel_watch_step();
+        self.logged_ranges.clear();
+        self.accesses.clear();
+
+        self.hide_watched_pages()
+    }
+
+    /// Returns the accesses of the guest to the logged ranges since the last
+    /// call, in order
+    #[inline]
+    pub fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        std::mem::take(&mut self.accesses)
+    }
+
+    /// Returns a range of mapped memory which can be hidden from the guest,
+    /// loading its pages if they are loaded on demand
+    fn hideable_range(&mut self, address: u64, size: usize) -> Result<Range<u64>> {
+        // Pages loaded on demand have to be there before they are hidden
+        self.load_demand_range(address, size)?;
+
+        let end = address
+            .checked_add(size as u64)
+            .ok_or(VmError::MemoryError(MemoryError::AddressUnmapped(address)))?;
+        let mut page = address & !(PAGE_SIZE as u64 - 1);
+        while page < end {
+            if self.memory.virt_to_phys(page).is_none() {
+                return Err(VmError::MemoryError(MemoryError::AddressUnmapped(page)));
+            }
+            page += PAGE_SIZE as u64;
+        }
+
+        Ok(address..end)
+    }
+
+    /// Hides the pages of the watched range and of the logged ranges, and
+    /// makes the pages no longer in them present again
+    fn hide_watched_pages(&mut self) -> Result<()> {
+        let mut pages = BTreeSet::new();
+        for range in self.watched_range.iter().chain(self.logged_ranges.iter()) {
+            let mut page = range.start & !(PAGE_SIZE as u64 - 1);
+            while page < range.end {
+                pages.insert(page);
+                page += PAGE_SIZE as u64;
+            }
+        }
+
+        for &page in self.watched_pages.difference(&pages) {
+            self.memory.set_present(page, true)?;
+        }
+        for &page in pages.difference(&self.watched_pages) {
+            self.memory.set_present(page, false)?;
+        }
+        self.watched_pages = pages;
+
+        Ok(())
+    }
+
+    /// Gives up the singlestep over an access to the watched pages, if any
+    fn cancel_watch_step(&mut self) {
+        if let Some((_, trap)) = self.watch_step.take() {
+            if !trap {
+                self.registers.rflags &= !TRAP_FLAG;
+            }
+        }
+    }
+
+    /// Returns the addresses of the watched range read by the guest since
+    /// the last call, in order
+    #[inline]
+    pub fn take_watched_reads(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.watched_reads)
+    }
+
+    /// Lets the guest access a watched page if `exit` is the resulting page
+    /// fault, recording the address if it is a read of the watched range.
+    /// The page is present until the faulting instruction was stepped.
+    /// Returns whether the exit was consumed.
+    fn fault_watched_page(&mut self, exit: &VmExit) -> Result<bool> {
+        let detail = match exit {
+            VmExit::PageFault(detail) => detail,
+            _ => return Ok(false),
+        };
+
+        let page = detail.address & !(PAGE_SIZE as u64 - 1);
+        if !self.watched_pages.contains(&page) {
+            return Ok(false);
+        }
+
+        let in_range = self
+            .watched_range
+            .as_ref()
+            .is_some_and(|range| range.contains(&detail.address));
+        // Bit 1 of the error code is set by writes
+        let write = detail.status.is_bit_set(1);
+        if in_range && !write {
+            self.watched_reads.push(detail.address);
+        }
+
+        // Bit 4 is set by instruction fetches, which are not logged
+        let logged = self
+            .logged_ranges
+            .iter()
+            .any(|range| range.contains(&detail.address));
+        if logged && !detail.status.is_bit_set(4) {
+            let pc = self.registers.rip;
+            self.accesses.push(MemoryAccess {
+                pc,
+                address: detail.address,
+                size: self.instruction_operand_size(pc),
+                kind: if write {
+                    AccessKind::Write
+                } else {
+                    AccessKind::Read
+                },
+            });
+        }
+
+        // An access can span two watched pages
+        let trap = self.registers.rflags & TRAP_FLAG != 0;
+        let (pages, _) = self.watch_step.get_or_insert((Vec::new(), trap));
+        pages.push(page);
+        self.memory.set_present(page, true)?;
+        self.registers.rflags |= TRAP_FLAG;
+
+        Ok(true)
+    }
+
+    /// Reads the instruction at `pc` into `code`, returning its readable
+    /// length
+    fn read_instruction(&self, pc: u64, code: &mut [u8; 15]) -> usize {
+        // An instruction is at most 15 bytes, possibly cut by the end of the
+        // mapping
+        let in_page = PAGE_SIZE - (pc as usize & (PAGE_SIZE - 1));
+        [code.len(), in_page.min(code.len())]
+            .iter()
+            .copied()
+            .find(|&len| self.memory.read(pc, &mut code[..len]).is_ok())
+            .unwrap_or(0)
+    }
+
+    /// Returns the size of the memory operand of the instruction at `pc`, 0
+    /// if it cannot be decoded
+    fn instruction_operand_size(&self, pc: u64) -> u8 {
+        let mut code = [0u8; 15];
+        let len = self.read_instruction(pc, &mut code);
+
+        memory_operand_size(&code[..len]).unwrap_or(0)
+    }
+
+    /// Returns the value of an operand of `size` bytes of the instruction
+    /// ending at `next_pc`, `None` if its memory is not mapped
+    fn operand_value(&self, operand: &Operand, size: u8, next_pc: u64) -> Option<u64> {
+        let value = match *operand {
+            Operand::Register { number, high } => {
+                let value = self.get_reg(Register::ENCODED[number as usize]);
+                if high {
+                    value >> 8
+                } else {
+                    value
                 }
-                _ => break VmExit::Unhandled,
             }
+            Operand::Memory {
+                base,
+                index,
+                scale,
+                displacement,
+                rip_relative,
+                segment,
+            } => {
+                let base = match (base, rip_relative) {
+                    (_, true) => next_pc,
+                    (Some(base), false) => self.get_reg(Register::ENCODED[base as usize]),
+                    (None, false) => 0,
+                };
+                let index = index.map_or(0, |index| {
+                    self.get_reg(Register::ENCODED[index as usize])
+                        .wrapping_mul(scale as u64)
+                });
+                let segment = match segment {
+                    Some(0x64) => self.fs_base,
+                    Some(0x65) => self.gs_base,
+                    _ => 0,
+                };
+                let address = segment
+                    .wrapping_add(base)
+                    .wrapping_add(index)
+                    .wrapping_add(displacement as u64);
+
+                let mut bytes = [0u8; 8];
+                self.read(address, &mut bytes[..size as usize]).ok()?;
+                u64::from_le_bytes(bytes)
+            }
+            Operand::Immediate(value) => value,
         };
 
-        Ok(result)
+        Some(truncate(value, size))
     }
 
-    // Set `Vm` registers from a `SnapshotRegisters` instance
+    /// Returns the operand values of the `cmp` instruction at `pc`, about to
+    /// be run, `None` if it is not a comparison or its operands cannot be
+    /// read. `first` replaces the first byte of the instruction, e.g. with
+    /// the original byte of a breakpoint placed on it.
+    pub fn comparison_operands(&self, pc: u64, first: Option<u8>) -> Option<CmpOperands> {
+        let mut code = [0u8; 15];
+        let len = self.read_instruction(pc, &mut code);
+        if let Some(first) = first.filter(|_| len > 0) {
+            code[0] = first;
+        }
+
+        let comparison = decode_comparison(&code[..len])?;
+        let next_pc = pc.wrapping_add(comparison.len as u64);
+        Some(CmpOperands {
+            size: comparison.size,
+            left: self.operand_value(&comparison.left, comparison.size, next_pc)?,
+            right: self.operand_value(&comparison.right, comparison.size, next_pc)?,
+        })
+    }
+
+    /// Hides again the watched pages accessed by the instruction stepped
+    /// before `exit`. The exit is consumed unless the trap flag was set by
+    /// someone else, who expects the step.
+    fn hide_watched_page(&mut self, exit: &VmExit) -> Result<bool> {
+        let singlestep = match exit {
+            VmExit::Exception(1) => true,
+            VmExit::Breakpoint => self.debug_exception == 1,
+            _ => false,
+        };
+        if self.watch_step.is_none() || !singlestep {
+            return Ok(false);
+        }
+
+        let (pages, trap) = self.watch_step.take().unwrap();
+        for page in pages {
+            self.memory.set_present(page, false)?;
+        }
+        if trap {
+            return Ok(false);
+        }
+
+        self.registers.rflags &= !TRAP_FLAG;
+        Ok(true)
+    }
+
+    /// Returns an iterator over all mappings
     #[inline]
-    pub fn set_regs_snapshot(&mut self, regs: &SnapshotRegisters) {
-        self.set_reg(Register::Rax, regs.rax);
-        self.set_reg(Register::Rbx, regs.rbx);
-        self.set_reg(Register::Rcx, regs.rcx);
-        self.set_reg(Register::Rdx, regs.rdx);
-        self.set_reg(Register::Rsi, regs.rsi);
-        self.set_reg(Register::Rdi, regs.rdi);
-        self.set_reg(Register::Rsp, regs.rsp);
-        self.set_reg(Register::Rbp, regs.rbp);
-        self.set_reg(Register::R8, regs.r8);
-        self.set_reg(Register::R9, regs.r9);
-        self.set_reg(Register::R10, regs.r10);
-        self.set_reg(Register::R11, regs.r11);
-        self.set_reg(Register::R12, regs.r12);
-        self.set_reg(Register::R13, regs.r13);
-        self.set_reg(Register::R14, regs.r14);
-        self.set_reg(Register::R15, regs.r15);
-        self.set_reg(Register::Rip, regs.rip);
-        self.set_reg(Register::Rflags, regs.rflags);
-        self.set_reg(Register::FsBase, regs.fs_base);
-        self.set_reg(Register::GsBase, regs.gs_base);
+    pub fn mappings(&self) -> impl Iterator<Item = Mapping> + '_ {
+        self.memory.mappings()
     }
 
-    /// Loads a vm state from snapshot files
-    pub fn from_snapshot<T: AsRef<Path>>(
-        snapshot_info: T,
-        memory_dump: T,
-        memory_size: usize,
-    ) -> Result<Vm> {
-        // Create a new VN instance
-        let mut vm = Vm::new(memory_size)?;
+    /// Returns an iterator over all dirty mappings
+    #[inline]
+    pub fn dirty_mappings(&self) -> impl Iterator<Item = Mapping> + '_ {
+        self.mappings().filter(|m| m.dirty)
+    }
+
+    /// Clear dirty mappings status
+    #[inline]
+    pub fn clear_dirty_mappings(&mut self) {
+        for (_, pte) in self.memory.raw_pages_mut() {
+            pte.set_dirty(false);
+        }
+    }
+
+    fn flush_registers(&mut self) -> Result<()> {
+        // The second bit of rflags must always be set.
+        self.registers.rflags |= 1 << 1;
+
+        // Set registers and special registers
+        self.kvm_vcpu
+            .set_regs(&self.registers)
+            .map_err(|_| VmError::HvError("Could not commit registers"))?;
+        self.kvm_vcpu
+            .set_sregs(&self.special_registers)
+            .map_err(|_| VmError::HvError("Could not commit special registers"))?;
+
+        // Set gs_base, kernel_gs_base and fs_base through msrs
+        let msrs = Msrs::from_entries(&[
+            kvm_msr_entry {
+                index: IA32_FS_BASE,
+                data: self.fs_base,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: IA32_GS_BASE,
+                data: self.gs_base,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: IA32_KERNEL_GS_BASE,
+                data: self.kernel_gs_base,
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+        self.kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|_| VmError::HvError("Could not commit fsbase and gsbases"))?;
+
+        // Set debug registers and xsave area
+        self.commit_extended_state()?;
+
+        // Get registers and special registers
+        self.registers = self
+            .kvm_vcpu
+            .get_regs()
+            .map_err(|_| VmError::HvError("Could not get special registers"))?;
+        self.special_registers = self
+            .kvm_vcpu
+            .get_sregs()
+            .map_err(|_| VmError::HvError("Could not get general registers"))?;
+
+        // Update kvm vcpu run region
+        self.kvm_vcpu_run.as_mut_ref().s.regs.regs = self.registers;
+        self.kvm_vcpu_run.as_mut_ref().s.regs.sregs = self.special_registers;
+        self.kvm_vcpu_run.as_mut_ref().kvm_dirty_regs = 0;
+
+        Ok(())
+    }
+
+    /// Commit local copy of registers to kvm
+    #[inline]
+    fn commit_registers(&mut self) -> Result<()> {
+        // The second bit of rflags must always be set.
+        self.registers.rflags |= 1 << 1;
+
+        // The segment bases of the synced special registers are applied
+        // after the msrs, and would overwrite them
+        self.special_registers.fs.base = self.fs_base;
+        self.special_registers.gs.base = self.gs_base;
+
+        self.kvm_vcpu_run.as_mut_ref().s.regs.regs = self.registers;
+        self.kvm_vcpu_run.as_mut_ref().s.regs.sregs = self.special_registers;
+        self.kvm_vcpu_run.as_mut_ref().kvm_dirty_regs |=
+            KVM_SYNC_X86_SREGS as u64 | KVM_SYNC_X86_REGS as u64;
+
+        // gs_base, kernel_gs_base and fs_base need to go through msrs
+        let msrs = Msrs::from_entries(&[
+            kvm_msr_entry {
+                index: IA32_FS_BASE,
+                data: self.fs_base,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: IA32_GS_BASE,
+                data: self.gs_base,
+                ..Default::default()
+            },
+            kvm_msr_entry {
+                index: IA32_KERNEL_GS_BASE,
+                data: self.kernel_gs_base,
+                ..Default::default()
+            },
+        ])
+        .unwrap();
+
+        self.kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|_| VmError::HvError("Could not commit fsbase and gsbases"))?;
+
+        // Debug registers and xsave area are only committed when modified
+        if self.extended_state_dirty {
+            self.commit_extended_state()?;
+        }
+
+        Ok(())
+    }
+
+    /// Commit local copy of debug registers and xsave area to kvm
+    fn commit_extended_state(&mut self) -> Result<()> {
+        self.kvm_vcpu
+            .set_debug_regs(&self.debug_registers)
+            .map_err(|_| VmError::HvError("Could not commit debug registers"))?;
+
+        if let Some(xsave) = &self.xsave {
+            self.kvm_vcpu
+                .set_xsave(xsave)
+                .map_err(|_| VmError::HvError("Could not commit xsave area"))?;
+        }
+
+        self.extended_state_dirty = false;
+
+        Ok(())
+    }
+
+    /// Returns a handle interrupting the runs of this `Vm` from other threads
+    pub fn kicker(&self) -> VcpuKicker {
+        VcpuKicker {
+            state: Arc::clone(&self.kick_state),
+        }
+    }
+
+    /// Sets the callback invoked right before each vcpu entry, e.g. to
+    /// write the input or randomize the environment of the guest
+    pub fn set_pre_run_callback(&mut self, callback: Option<PreRunCallback>) {
+        self.pre_run_callback = callback;
+    }
+
+    /// Sets the callback invoked right after each exit. Exits for which it
+    /// returns `ExitAction::Resume` are handled by the callback and the guest
+    /// runs again instead of returning them.
+    pub fn set_exit_callback(&mut self, callback: Option<ExitCallback>) {
+        self.exit_callback = callback;
+    }
+
+    /// Run the `Vm` instance until the first `Vm` that cannot be
+    /// handled directly
+    pub fn run(&mut self) -> Result<VmExit> {
+        loop {
+            // The callbacks are taken out of the vm while they run, a
+            // callback installed meanwhile replaces them
+            if let Some(mut callback) = self.pre_run_callback.take() {
+                callback(self);
+                self.pre_run_callback.get_or_insert(callback);
+            }
+
+            let exit = self.run_vcpu()?;
+            self.resume_lbr(&exit)?;
+
+            // Reads of the watched range are recorded and not reported
+            if self.fault_watched_page(&exit)? || self.hide_watched_page(&exit)? {
+                continue;
+            }
+
+            // Nor are steps over persistent coverage points and writes to
+            // their pages
+            if self.rearm_coverage_point(&exit)? || self.unprotect_written_page(&exit)? {
+                continue;
+            }
+
+            // Nor are the first accesses to the snapshot pages loaded on demand
+            if self.fault_demand_page(&exit)? {
+                continue;
+            }
+
+            // Nor are the CPUID instructions emulated by the handler
+            if self.emulate_cpuid(&exit)? {
+                continue;
+            }
+
+            // The branches are only read on the exits reaching the caller,
+            // the stack holding the last branches taken before them
+            self.collect_branches()?;
+
+            if let Some(mut callback) = self.exit_callback.take() {
+                let action = callback(self, &exit);
+                self.exit_callback.get_or_insert(callback);
+
+                if action == ExitAction::Resume {
+                    continue;
+                }
+            }
+
+            return Ok(exit);
+        }
+    }
+
+    /// Runs the vcpu until the first exit that cannot be handled directly
+    fn run_vcpu(&mut self) -> Result<VmExit> {
+        // A kick received since the last run interrupts this one
+        if self.kick_state.take_pending() {
+            return Ok(VmExit::Interrupted);
+        }
+
+        // Register the thread as running the vcpu, so that it can be kicked
+        let kick_state = Arc::clone(&self.kick_state);
+        let _guard = kick_state.enter(self.kvm_vcpu_run.as_mut_ref());
+
+        let result = loop {
+            // Commit potential modification done on registers
+            self.commit_registers()?;
+
+            // Set the valid synchronised registers
+            self.kvm_vcpu_run.as_mut_ref().kvm_valid_regs |=
+                KVM_SYNC_X86_REGS as u64 | KVM_SYNC_X86_SREGS as u64;
+
+            // Ask kvm to run the vm's vcpu
+            let start = Instant::now();
+            let exit = self.kvm_vcpu.run();
+            self.stats.run_time += start.elapsed();
+            self.stats.runs += 1;
+
+            // Pull registers and special registers
+            unsafe {
+                self.registers = self.kvm_vcpu_run.as_mut_ref().s.regs.regs;
+                self.special_registers = self.kvm_vcpu_run.as_mut_ref().s.regs.sregs;
+            }
+
+            // Pull fs_base, gs_base and kernel_gs_base, which swapgs exchanges
+            let mut msrs = Msrs::from_entries(&[
+                kvm_msr_entry {
+                    index: IA32_FS_BASE,
+                    ..Default::default()
+                },
+                kvm_msr_entry {
+                    index: IA32_GS_BASE,
+                    ..Default::default()
+                },
+                kvm_msr_entry {
+                    index: IA32_KERNEL_GS_BASE,
+                    ..Default::default()
+                },
+            ])
+            .unwrap();
+
+            let count = self
+                .kvm_vcpu
+                .get_msrs(&mut msrs)
+                .map_err(|_| VmError::HvError("Could not read fs_base and gs_bases"))?;
+            if count != 3 {
+                return Err(VmError::HvError("Invalid number of msrs returned"));
+            }
+
+            let msrs_res = msrs.as_slice();
+            self.fs_base = msrs_res[0].data;
+            self.gs_base = msrs_res[1].data;
+            self.kernel_gs_base = msrs_res[2].data;
+
+            // Handle possible interrupts (timeout)
+            if let Err(err) = exit {
+                match Errno::from_i32(err.errno()) {
+                    Errno::EINTR | Errno::EAGAIN => {
+                        // The kick, if any, was consumed by this run
+                        self.kick_state.take_pending();
+                        break VmExit::Interrupted;
+                    }
+                    _ => return Err(VmError::HvError("Unexpected errno in KVM_RUN")),
+                }
+            }
+
+            match exit.unwrap() {
+                VcpuExit::Debug(debug) => {
+                    self.debug_exception = debug.exception;
+                    break VmExit::Breakpoint;
+                }
+                // Only the exception handlers are hypercalls, halting or
+                // writing to the hypercall port with an irqchip
+                VcpuExit::Hlt | VcpuExit::IoOut(HYPERCALL_PORT, _)
+                    if (self.registers.rip >= self.hypercall_page)
+                        && (self.registers.rip < self.hypercall_page + PAGE_SIZE as u64) => {}
+                VcpuExit::Hlt => {
+                    break VmExit::Hlt;
+                }
+                // Syscalls from user mode go through the syscall entry stub,
+                // which saved rip in rcx and rflags in r11
+                VcpuExit::IoOut(SYSCALL_PORT, _)
+                    if (self.registers.rip >= self.hypercall_page)
+                        && (self.registers.rip < self.hypercall_page + PAGE_SIZE as u64) =>
+                {
+                    let (code, stack) = self
+                        .user_segments
+                        .ok_or(VmError::HvError("Syscall entry outside of user mode"))?;
+                    self.registers.rip = self.registers.rcx;
+                    self.return_to_user(self.registers.r11, code, stack)?;
+                    break VmExit::Syscall;
+                }
+                VcpuExit::FailEntry => {
+                    let fail_entry =
+                        unsafe { self.kvm_vcpu_run.as_mut_ref().__bindgen_anon_1.fail_entry };
+                    return Err(self.guest_failure(FailureReason::FailEntry {
+                        hardware_reason: fail_entry.hardware_entry_failure_reason,
+                        cpu: fail_entry.cpu,
+                    }));
+                }
+                VcpuExit::InternalError => {
+                    let internal =
+                        unsafe { self.kvm_vcpu_run.as_mut_ref().__bindgen_anon_1.internal };
+                    let ndata = (internal.ndata as usize).min(internal.data.len());
+                    return Err(self.guest_failure(FailureReason::InternalError {
+                        suberror: internal.suberror,
+                        data: internal.data[..ndata].to_vec(),
+                    }));
+                }
+                VcpuExit::Shutdown => {
+                    return Err(self.guest_failure(FailureReason::Shutdown));
+                }
+                _ => break VmExit::Unhandled,
+            }
+
+            // If we are within the hypercall region, handle the
+            // exception forwarding.
+            let exception_code: u64 = self.memory.read_val(self.registers.rsp)?;
+
+            let error_code: Option<u64> = match ExceptionType::from(exception_code) {
+                ExceptionType::DoubleFault
+                | ExceptionType::InvalidTSS
+                | ExceptionType::SegmentNotPresent
+                | ExceptionType::StackFault
+                | ExceptionType::GeneralProtection
+                | ExceptionType::PageFault
+                | ExceptionType::AlignmentCheck
+                | ExceptionType::ControlProtection => {
+                    Some(self.memory.read_val(self.registers.rsp + 8)?)
+                }
+                _ => None,
+            };
+
+            let exception_frame: ExceptionFrame = if error_code.is_some() {
+                self.memory.read_val(self.registers.rsp + 16)?
+            } else {
+                self.memory.read_val(self.registers.rsp + 8)?
+            };
+
+            // Reset register context to before exception
+            self.registers.rsp = exception_frame.rsp;
+            self.registers.rip = exception_frame.rip;
+
+            // Exceptions raised in user mode switched to the kernel segments
+            let (cs, ss, rflags) = (
+                exception_frame.cs,
+                exception_frame.ss,
+                exception_frame.rflags,
+            );
+            if cs & 0b11 == PrivilegeLevel::Ring3 as u64 {
+                self.return_to_user(rflags, cs as u16, ss as u16)?;
+            }
+
+            // Timer interrupts only wake the guest up: they are acknowledged,
+            // and the guest resumes where it was interrupted
+            if let Some(timer) = self.timer.filter(|t| t.vector as u64 == exception_code) {
+                self.registers.rflags = rflags;
+                self.end_of_interrupt(timer.vector)?;
+                continue;
+            }
+
+            match ExceptionType::from(exception_code) {
+                ExceptionType::PageFault => {
+                    break VmExit::PageFault(PageFaultDetail {
+                        status: error_code.unwrap() as u32,
+                        address: self.special_registers.cr2,
+                    });
+                }
+                ExceptionType::InvalidOpcode => {
+                    // As IA32_EFER.SCE is not enabled, a syscall instruction will trigger
+                    // a #UD exception. We cannot enable the SCE bit in EFER as it would
+                    // require us to setup the whole syscall machinery as well as the LSTAR
+                    // register.
+                    // To give the opportunity to the Vm user to emulate the syscall, we try
+                    // to detect the instruction bytes, set the rip to after the syscall
+                    // and return with a special `Syscall` VmExit.
+                    let mut code_bytes: [u8; 2] = [0; 2];
+
+                    if self
+                        .memory
+                        .read(self.registers.rip, &mut code_bytes)
+                        .is_ok()
+                    {
+                        //  0f 05 -> syscall
+                        if code_bytes == [0x0f, 0x05] {
+                            // We advance rip by two bytes to move over the syscall
+                            // instruction.
+                            self.registers.rip += 2;
+                            break VmExit::Syscall;
+                        }
+                    }
+
+                    if let Some(instruction) = self.virtualization_instruction() {
+                        break VmExit::Virtualization(instruction);
+                    }
+
+                    break VmExit::InvalidInstruction;
+                }
+                ExceptionType::GeneralProtection => match self.virtualization_instruction() {
+                    Some(instruction) => break VmExit::Virtualization(instruction),
+                    None => break VmExit::Exception(exception_code),
+                },
+                ExceptionType::DoubleFault => break VmExit::DoubleFault,
+                _ => break VmExit::Exception(exception_code),
+            }
+        };
+
+        Ok(result)
+    }
+
+    /// Restores the user mode segments and rflags after the guest went
+    /// through the kernel
+    fn return_to_user(&mut self, rflags: u64, code: u16, stack: u16) -> Result<()> {
+        self.registers.rflags = rflags;
+        self.set_segment(SegmentRegister::Cs, code)?;
+        self.set_segment(SegmentRegister::Ss, stack)
+    }
+
+    /// Runs the `Vm` one instruction at a time, until an exit or `limit`
+    /// instructions, recording the executed instructions. Register changes
+    /// are recorded if `deltas` is set.
+    pub fn run_traced(&mut self, limit: usize, deltas: bool) -> Result<Trace> {
+        let mut steps = Vec::new();
+
+        self.set_guest_debug(true)?;
+        let exit = loop {
+            if steps.len() >= limit {
+                break None;
+            }
+
+            let rip = self.registers.rip;
+            let before = Register::ALL.map(|reg| self.get_reg(reg));
+
+            match self.run() {
+                Ok(VmExit::Breakpoint) if self.debug_exception == 1 => {}
+                Ok(vmexit) => break Some(vmexit),
+                Err(err) => {
+                    self.set_guest_debug(false)?;
+                    return Err(err);
+                }
+            }
+
+            // The exception handlers are not part of the guest execution
+            if (rip >= self.hypercall_page) && (rip < self.hypercall_page + PAGE_SIZE as u64) {
+                continue;
+            }
+
+            let changes = match deltas {
+                true => Register::ALL
+                    .iter()
+                    .zip(before.iter())
+                    .filter(|(&reg, &value)| reg != Register::Rip && self.get_reg(reg) != value)
+                    .map(|(&reg, _)| (reg, self.get_reg(reg)))
+                    .collect(),
+                false => Vec::new(),
+            };
+            steps.push(TraceStep { rip, changes });
+        };
+        self.set_guest_debug(false)?;
+
+        Ok(Trace { steps, exit })
+    }
+
+    /// Gathers the last guest state after kvm failed to run it
+    fn guest_failure(&self, reason: FailureReason) -> VmError {
+        let sregs = &self.special_registers;
+
+        VmError::GuestFailure(Box::new(GuestFailure {
+            reason,
+            rip: self.registers.rip,
+            rsp: self.registers.rsp,
+            rflags: self.registers.rflags,
+            cr: [sregs.cr0, sregs.cr2, sregs.cr3, sregs.cr4],
+            efer: sregs.efer,
+            cs: sregs.cs.selector,
+            ss: sregs.ss.selector,
+            idt: (sregs.idt.base, sregs.idt.limit),
+            gdt: (sregs.gdt.base, sregs.gdt.limit),
+            pending_interrupt: sregs.interrupt_bitmap.iter().any(|&word| word != 0),
+        }))
+    }
+
+    // Set `Vm` registers from a `SnapshotRegisters` instance
+    #[inline]
+    pub fn set_regs_snapshot(&mut self, regs: &SnapshotRegisters) -> Result<()> {
+        self.set_reg(Register::Rax, regs.rax);
+        self.set_reg(Register::Rbx, regs.rbx);
+        self.set_reg(Register::Rcx, regs.rcx);
+        self.set_reg(Register::Rdx, regs.rdx);
+        self.set_reg(Register::Rsi, regs.rsi);
+        self.set_reg(Register::Rdi, regs.rdi);
+        self.set_reg(Register::Rsp, regs.rsp);
+        self.set_reg(Register::Rbp, regs.rbp);
+        self.set_reg(Register::R8, regs.r8);
+        self.set_reg(Register::R9, regs.r9);
+        self.set_reg(Register::R10, regs.r10);
+        self.set_reg(Register::R11, regs.r11);
+        self.set_reg(Register::R12, regs.r12);
+        self.set_reg(Register::R13, regs.r13);
+        self.set_reg(Register::R14, regs.r14);
+        self.set_reg(Register::R15, regs.r15);
+        self.set_reg(Register::Rip, regs.rip);
+        self.set_reg(Register::Rflags, regs.rflags);
+        self.set_reg(Register::FsBase, regs.fs_base);
+        self.set_reg(Register::GsBase, regs.gs_base);
+        if let Some(kernel_gs_base) = regs.kernel_gs_base {
+            self.set_reg(Register::KernelGsBase, kernel_gs_base);
+        }
+
+        // Only the NXE bit can be honored, long mode bits are required and
+        // SCE must stay disabled for syscalls to be trapped.
+        if let Some(efer) = regs.efer {
+            self.special_registers.efer &= !IA32_EFER_NXE;
+            self.special_registers.efer |= efer & IA32_EFER_NXE;
+        }
+
+        // Load debug registers
+        let debug_regs = [regs.dr0, regs.dr1, regs.dr2, regs.dr3];
+        for (i, dr) in debug_regs.iter().enumerate() {
+            if let Some(value) = dr {
+                self.debug_registers.db[i] = *value;
+                self.extended_state = true;
+            }
+        }
+        if let Some(dr6) = regs.dr6 {
+            self.debug_registers.dr6 = dr6;
+            self.extended_state = true;
+        }
+        if let Some(dr7) = regs.dr7 {
+            self.debug_registers.dr7 = dr7;
+            self.extended_state = true;
+        }
+
+        // Load the xsave area
+        if let Some(data) = &regs.xsave {
+            let mut xsave = Box::new(kvm_xsave { region: [0; 1024] });
+            let region_size = core::mem::size_of_val(&xsave.region);
+
+            if data.len() > region_size {
+                return Err(VmError::SnapshotError(SnapshotError::ParsingError(
+                    format!(
+                        "xsave area too big (0x{:x} > 0x{:x})",
+                        data.len(),
+                        region_size
+                    ),
+                )));
+            }
+
+            // Copy the raw area into the kvm structure
+            for (i, chunk) in data.chunks(4).enumerate() {
+                let mut bytes = [0u8; 4];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                xsave.region[i] = u32::from_le_bytes(bytes);
+            }
+
+            self.xsave = Some(xsave);
+            self.extended_state = true;
+        }
+
+        self.extended_state_dirty = self.extended_state;
+
+        // Enable the recorded extended state components
+        if let Some(xcr0) = regs.xcr0 {
+            self.set_xcr0(xcr0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the CPUID kvm supports on the host, without the features
+    /// hidden from the guest and with the overridden results
+    fn supported_cpuid(&self) -> Result<CpuId> {
+        const CPUID_RDRAND_BIT: usize = 30;
+        const CPUID_RDSEED_BIT: usize = 18;
+        const CPUID_VMX_BIT: usize = 5;
+        const CPUID_SVM_BIT: usize = 2;
+
+        let mut cpuid = self
+            ._kvm
+            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map_err(|_| VmError::HvError("Could not get supported cpuid"))?;
+
+        if self.rdrand_hidden {
+            for entry in cpuid.as_mut_slice() {
+                match (entry.function, entry.index) {
+                    (1, _) => entry.ecx.set_bit(CPUID_RDRAND_BIT, false),
+                    (7, 0) => entry.ebx.set_bit(CPUID_RDSEED_BIT, false),
+                    _ => {}
+                }
+            }
+        }
+
+        let virtualization = match self.virtualization {
+            Some(VirtualizationCpuid::Hidden) => Some(false),
+            Some(VirtualizationCpuid::Fake) => Some(true),
+            _ => None,
+        };
+        if let Some(virtualization) = virtualization {
+            for entry in cpuid.as_mut_slice() {
+                match entry.function {
+                    1 => entry.ecx.set_bit(CPUID_VMX_BIT, virtualization),
+                    0x8000_0001 => entry.ecx.set_bit(CPUID_SVM_BIT, virtualization),
+                    _ => {}
+                }
+            }
+        }
+
+        // Overrides of leaves kvm does not report are added to the table
+        for (&(leaf, subleaf), result) in &self.cpuid_overrides {
+            let position = cpuid
+                .as_slice()
+                .iter()
+                .position(|entry| cpuid_entry_matches(entry, leaf, subleaf));
+            let entry = match position {
+                Some(position) => &mut cpuid.as_mut_slice()[position],
+                None => {
+                    cpuid
+                        .push(kvm_cpuid_entry2 {
+                            function: leaf,
+                            index: subleaf,
+                            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+                            ..Default::default()
+                        })
+                        .map_err(|_| VmError::HvError("Too many cpuid entries"))?;
+                    cpuid.as_mut_slice().last_mut().unwrap()
+                }
+            };
+            entry.eax = result.eax;
+            entry.ebx = result.ebx;
+            entry.ecx = result.ecx;
+            entry.edx = result.edx;
+        }
+
+        Ok(cpuid)
+    }
+
+    /// Gives the CPUID table of the vm to kvm
+    fn commit_cpuid(&mut self) -> Result<()> {
+        let cpuid = self.supported_cpuid()?;
+        self.kvm_vcpu
+            .set_cpuid2(&cpuid)
+            .map_err(|_| VmError::HvError("Could not set cpuid"))?;
+        self.cpuid = Some(cpuid);
+
+        Ok(())
+    }
+
+    /// Returns the result of CPUID for a leaf and subleaf from the CPUID
+    /// table of the vm, or the one kvm supports until the vm has one. Leaves
+    /// missing from the table are zeroes.
+    pub fn cpuid(&self, leaf: u32, subleaf: u32) -> CpuidResult {
+        let supported;
+        let table = match &self.cpuid {
+            Some(cpuid) => cpuid,
+            None => match self.supported_cpuid() {
+                Ok(cpuid) => {
+                    supported = cpuid;
+                    &supported
+                }
+                Err(_) => return CpuidResult::default(),
+            },
+        };
+
+        table
+            .as_slice()
+            .iter()
+            .find(|entry| cpuid_entry_matches(entry, leaf, subleaf))
+            .map_or_else(CpuidResult::default, |entry| CpuidResult {
+                eax: entry.eax,
+                ebx: entry.ebx,
+                ecx: entry.ecx,
+                edx: entry.edx,
+            })
+    }
+
+    /// Replaces the result of the guest CPUID for a leaf and subleaf, e.g.
+    /// to clear the hypervisor bit or report another cache topology. Since
+    /// kvm only takes a new CPUID table before the first run, the overrides
+    /// are set up front, see `set_cpuid_handler` for results decided at run
+    /// time.
+    pub fn set_cpuid_override(
+        &mut self,
+        leaf: u32,
+        subleaf: u32,
+        result: CpuidResult,
+    ) -> Result<()> {
+        self.cpuid_overrides.insert((leaf, subleaf), result);
+        self.commit_cpuid()
+    }
+
+    /// Sets the handler of the CPUID instructions run in user mode, which
+    /// decides their result each time they run. The instruction raises a
+    /// #GP through CPUID faulting and is emulated, the guest resuming after
+    /// it. CPUID run in ring 0 cannot fault and still reads the CPUID table.
+    pub fn set_cpuid_handler(&mut self, handler: Option<CpuidHandler>) -> Result<()> {
+        let msrs = Msrs::from_entries(&[kvm_msr_entry {
+            index: MSR_MISC_FEATURES_ENABLES,
+            data: match handler {
+                Some(_) => CPUID_FAULT,
+                None => 0,
+            },
+            ..Default::default()
+        }])
+        .unwrap();
+
+        let count = self
+            .kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|_| VmError::HvError("Could not set cpuid faulting"))?;
+        if count != 1 {
+            return Err(VmError::HvError("Could not set cpuid faulting"));
+        }
+        self.cpuid_handler = handler;
+
+        Ok(())
+    }
+
+    /// Emulates a faulting CPUID instruction with the CPUID handler.
+    /// Returns whether the exit was handled.
+    fn emulate_cpuid(&mut self, exit: &VmExit) -> Result<bool> {
+        // CPUID faulting raises a #GP
+        if *exit != VmExit::Exception(13) {
+            return Ok(false);
+        }
+        let mut handler = match self.cpuid_handler.take() {
+            Some(handler) => handler,
+            None => return Ok(false),
+        };
+
+        //  0f a2 -> cpuid
+        let mut code_bytes = [0u8; 2];
+        let rip = self.registers.rip;
+        let handled = self.memory.read(rip, &mut code_bytes).is_ok() && code_bytes == [0x0f, 0xa2];
+        if handled {
+            let leaf = self.registers.rax as u32;
+            let subleaf = self.registers.rcx as u32;
+            let result = handler(leaf, subleaf, self.cpuid(leaf, subleaf));
+            self.registers.rax = result.eax as u64;
+            self.registers.rbx = result.ebx as u64;
+            self.registers.rcx = result.ecx as u64;
+            self.registers.rdx = result.edx as u64;
+            self.registers.rip = rip + 2;
+        }
+        self.cpuid_handler.get_or_insert(handler);
+
+        Ok(handled)
+    }
+
+    /// Enables the recording of the branches taken by the guest, read back
+    /// with `take_branches`. The LBR stack only holds the last branches
+    /// taken before each exit returned by `run`, giving partial edge
+    /// coverage for the cost of a read and a write of MSRs per exit, without
+    /// any breakpoint.
+    pub fn enable_branch_recording(&mut self, recording: BranchRecording) -> Result<()> {
+        match recording {
+            BranchRecording::Lbr => self.enable_lbr()?,
+        }
+        self.branch_recording = Some(recording);
+        self.branches.clear();
+
+        Ok(())
+    }
+
+    /// Returns the mechanism recording the branches of the guest, if any
+    #[inline]
+    pub fn branch_recording(&self) -> Option<BranchRecording> {
+        self.branch_recording
+    }
+
+    /// Returns the branches taken by the guest since the last call, oldest
+    /// first
+    #[inline]
+    pub fn take_branches(&mut self) -> Vec<Branch> {
+        std::mem::take(&mut self.branches)
+    }
+
+    /// Enables the LBR stack of the vcpu and probes its depth
+    fn enable_lbr(&mut self) -> Result<()> {
+        // Kvm only exposes the LBR MSRs once the guest has a CPUID table
+        if self.cpuid.is_none() {
+            self.commit_cpuid()?;
+        }
+
+        let mut probe = Msrs::from_entries(
+            &(0..MAX_LBR_DEPTH as u32)
+                .map(|index| kvm_msr_entry {
+                    index: MSR_LBR_FROM + index,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        // The read stops on the first missing entry
+        let depth = self
+            .kvm_vcpu
+            .get_msrs(&mut probe)
+            .map_err(|_| VmError::HvError("LBR is not supported"))?;
+        if depth == 0 {
+            return Err(VmError::HvError("LBR is not supported"));
+        }
+        self.lbr_depth = depth;
+
+        let msrs = Msrs::from_entries(&[kvm_msr_entry {
+            index: MSR_LBR_SELECT,
+            data: 0,
+            ..Default::default()
+        }])
+        .unwrap();
+        let count = self
+            .kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|_| VmError::HvError("LBR is not supported"))?;
+        if count != 1 {
+            return Err(VmError::HvError("LBR is not supported"));
+        }
+
+        self.rearm_lbr()
+    }
+
+    /// Clears the LBR stack and enables it, noting the current top of stack
+    fn rearm_lbr(&mut self) -> Result<()> {
+        let mut entries = vec![kvm_msr_entry {
+            index: IA32_DEBUGCTL,
+            data: DEBUGCTL_LBR,
+            ..Default::default()
+        }];
+        for index in 0..self.lbr_depth as u32 {
+            entries.push(kvm_msr_entry {
+                index: MSR_LBR_FROM + index,
+                ..Default::default()
+            });
+            entries.push(kvm_msr_entry {
+                index: MSR_LBR_TO + index,
+                ..Default::default()
+            });
+        }
+
+        let msrs = Msrs::from_entries(&entries).unwrap();
+        let count = self
+            .kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|_| VmError::HvError("Could not enable LBR"))?;
+        if count != entries.len() {
+            return Err(VmError::HvError("Could not enable LBR"));
+        }
+
+        let mut tos = Msrs::from_entries(&[kvm_msr_entry {
+            index: MSR_LBR_TOS,
+            ..Default::default()
+        }])
+        .unwrap();
+        let count = self
+            .kvm_vcpu
+            .get_msrs(&mut tos)
+            .map_err(|_| VmError::HvError("Could not enable LBR"))?;
+        if count != 1 {
+            return Err(VmError::HvError("Could not enable LBR"));
+        }
+        self.lbr_tos = tos.as_slice()[0].data;
+
+        Ok(())
+    }
+
+    /// Enables the LBR stack again after a debug exception, which disables
+    /// it. The other exits leave it running.
+    fn resume_lbr(&mut self, exit: &VmExit) -> Result<()> {
+        if self.branch_recording != Some(BranchRecording::Lbr)
+            || !matches!(exit, VmExit::Breakpoint | VmExit::Exception(1))
+        {
+            return Ok(());
+        }
+
+        let msrs = Msrs::from_entries(&[kvm_msr_entry {
+            index: IA32_DEBUGCTL,
+            data: DEBUGCTL_LBR,
+            ..Default::default()
+        }])
+        .unwrap();
+        let count = self
+            .kvm_vcpu
+            .set_msrs(&msrs)
+            .map_err(|_| VmError::HvError("Could not enable LBR"))?;
+        if count != 1 {
+            return Err(VmError::HvError("Could not enable LBR"));
+        }
+
+        Ok(())
+    }
+
+    /// Appends the branches recorded since the last collection to the
+    /// recorded ones
+    fn collect_branches(&mut self) -> Result<()> {
+        let branches = match self.branch_recording {
+            Some(BranchRecording::Lbr) => self.read_lbr()?,
+            None => return Ok(()),
+        };
+
+        // The branches into and out of the hypercall region are not the
+        // guest's
+        let reserved = RESERVED_ADDRESS..RESERVED_ADDRESS + RESERVED_SIZE;
+        self.branches.extend(
+            branches.into_iter().filter(|branch| {
+                !reserved.contains(&branch.from) && !reserved.contains(&branch.to)
+            }),
+        );
+
+        Ok(())
+    }
+
+    /// Returns the branches of the LBR stack written since the last read.
+    /// Instead of clearing the whole stack, only the most recent entry is
+    /// cleared, marking where the next read starts.
+    fn read_lbr(&mut self) -> Result<Vec<Branch>> {
+        let depth = self.lbr_depth;
+        let mut entries = vec![kvm_msr_entry {
+            index: MSR_LBR_TOS,
+            ..Default::default()
+        }];
+        entries.extend((0..depth as u32).map(|index| kvm_msr_entry {
+            index: MSR_LBR_FROM + index,
+            ..Default::default()
+        }));
+        entries.extend((0..depth as u32).map(|index| kvm_msr_entry {
+            index: MSR_LBR_TO + index,
+            ..Default::default()
+        }));
+
+        let mut msrs = Msrs::from_entries(&entries).unwrap();
+        let count = self
+            .kvm_vcpu
+            .get_msrs(&mut msrs)
+            .map_err(|_| VmError::HvError("Could not read the LBR stack"))?;
+        if count != entries.len() {
+            return Err(VmError::HvError("Could not read the LBR stack"));
+        }
+
+        let data = msrs
+            .as_slice()
+            .iter()
+            .map(|entry| entry.data)
+            .collect::<Vec<_>>();
+        let tos = data[0];
+        let (from, to) = data[1..].split_at(depth);
+        let branches = lbr_branches_since(tos, self.lbr_tos, from, to);
+
+        // Nothing to mark if no branch was taken since the last read
+        if !branches.is_empty() {
+            let index = (tos as usize % depth) as u32;
+            let msrs = Msrs::from_entries(&[
+                kvm_msr_entry {
+                    index: MSR_LBR_FROM + index,
+                    ..Default::default()
+                },
+                kvm_msr_entry {
+                    index: MSR_LBR_TO + index,
+                    ..Default::default()
+                },
+            ])
+            .unwrap();
+            let count = self
+                .kvm_vcpu
+                .set_msrs(&msrs)
+                .map_err(|_| VmError::HvError("Could not clear the LBR stack"))?;
+            if count != 2 {
+                return Err(VmError::HvError("Could not clear the LBR stack"));
+            }
+            self.lbr_tos = tos;
+        }
+
+        Ok(branches)
+    }
+
+    /// Hides RDRAND and RDSEED from the guest CPUID, so that it takes the
+    /// fallback paths of its random sources (which can then be stubbed, see
+    /// `DeterminismStubs`). The instructions themselves stay executable.
+    pub fn hide_rdrand(&mut self) -> Result<()> {
+        self.rdrand_hidden = true;
+        self.commit_cpuid()
+    }
+
+    /// Sets the virtualization capabilities reported by the guest CPUID, for
+    /// fuzzing hypervisor code inside the snapshot. Without nested
+    /// virtualization, the virtualization instructions stop the vm with
+    /// `VmExit::Virtualization`.
+    pub fn set_virtualization_cpuid(&mut self, virtualization: VirtualizationCpuid) -> Result<()> {
+        self.virtualization = Some(virtualization);
+        self.commit_cpuid()
+    }
+
+    /// Decodes the instruction at rip, which raised an invalid opcode or a
+    /// general protection fault, as a virtualization instruction
+    fn virtualization_instruction(&self) -> Option<VirtualizationInstruction> {
+        use VirtualizationInstruction::*;
+
+        const CR4_VMXE_BIT: usize = 13;
+        const EFER_SVME_BIT: usize = 12;
+        const MSR_EFER: u32 = 0xc000_0080;
+        const MSR_FEATURE_CONTROL: u32 = 0x3a;
+        const MSR_VMX_FIRST: u32 = 0x480;
+        const MSR_VMX_LAST: u32 = 0x491;
+
+        // Instructions may end on an unmapped page
+        let mut code = Vec::with_capacity(8);
+        for address in self.registers.rip..self.registers.rip + 8 {
+            match self.memory.read_val::<u8>(address) {
+                Ok(byte) => code.push(byte),
+                Err(_) => break,
+            }
+        }
+
+        // Legacy and REX prefixes
+        let (mut operand_size, mut rep, mut repne, mut rex) = (false, false, false, 0u8);
+        let mut index = 0;
+        while let Some(&byte) = code.get(index) {
+            match byte {
+                0x66 => operand_size = true,
+                0xf3 => rep = true,
+                0xf2 => repne = true,
+                0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x67 | 0xf0 => {}
+                0x40..=0x4f => rex = byte,
+                _ => break,
+            }
+            index += 1;
+        }
+        let opcode = code.get(index..)?;
+        let legacy = !operand_size && !rep && !repne;
+
+        // Value of the register operand of a modrm byte
+        let operand = |modrm: u8| {
+            const REGISTERS: [Register; 16] = [
+                Register::Rax,
+                Register::Rcx,
+                Register::Rdx,
+                Register::Rbx,
+                Register::Rsp,
+                Register::Rbp,
+                Register::Rsi,
+                Register::Rdi,
+                Register::R8,
+                Register::R9,
+                Register::R10,
+                Register::R11,
+                Register::R12,
+                Register::R13,
+                Register::R14,
+                Register::R15,
+            ];
+            self.get_reg(REGISTERS[((modrm & 7) | (rex & 1) << 3) as usize])
+        };
+        let msr = self.registers.rcx as u32;
+        let vmx_msr = msr == MSR_FEATURE_CONTROL || (MSR_VMX_FIRST..=MSR_VMX_LAST).contains(&msr);
+
+        let instruction = match *opcode {
+            [0x0f, 0x01, modrm, ..] => match modrm {
+                0xc1 => Vmcall,
+                0xc2 => Vmlaunch,
+                0xc3 => Vmresume,
+                0xc4 => Vmxoff,
+                0xd4 => Vmfunc,
+                0xd8 => Vmrun,
+                0xd9 => Vmmcall,
+                0xda => Vmload,
+                0xdb => Vmsave,
+                0xdc => Stgi,
+                0xdd => Clgi,
+                0xde => Skinit,
+                0xdf => Invlpga,
+                _ => return None,
+            },
+            // Register forms are RDRAND and RDSEED
+            [0x0f, 0xc7, modrm, ..] if modrm >> 6 != 3 => match (modrm >> 3 & 7, operand_size, rep)
+            {
+                (6, false, false) => Vmptrld,
+                (6, true, false) => Vmclear,
+                (6, false, true) => Vmxon,
+                (7, false, false) => Vmptrst,
+                _ => return None,
+            },
+            [0x0f, 0x78, ..] if legacy => Vmread,
+            [0x0f, 0x79, ..] if legacy => Vmwrite,
+            [0x0f, 0x38, 0x80, ..] if operand_size => Invept,
+            [0x0f, 0x38, 0x81, ..] if operand_size => Invvpid,
+            // mov cr4, reg
+            [0x0f, 0x22, modrm, ..]
+                if modrm >> 3 & 7 == 4
+                    && rex & 4 == 0
+                    && operand(modrm).is_bit_set(CR4_VMXE_BIT) =>
+            {
+                EnableVmx
+            }
+            [0x0f, 0x30, ..] if msr == MSR_EFER && self.registers.rax.is_bit_set(EFER_SVME_BIT) => {
+                EnableSvm
+            }
+            [0x0f, 0x30, ..] | [0x0f, 0x32, ..] if vmx_msr => VmxMsr(msr),
+            _ => return None,
+        };
+
+        Some(instruction)
+    }
+
+    /// Enables extended state components (e.g. `XCR0_AVX512`) by programming
+    /// the guest XCR0. The guest CPUID is set to what kvm supports on the
+    /// host, which must support all the requested components.
+    pub fn set_xcr0(&mut self, xcr0: u64) -> Result<()> {
+        if xcr0 & 1 == 0 {
+            return Err(VmError::HvError("XCR0 must enable the x87 state"));
+        }
+
+        // Components supported by the host are reported in CPUID leaf 0xd
+        let cpuid = self.supported_cpuid()?;
+        let supported = cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 0xd && entry.index == 0)
+            .map_or(1, |entry| (entry.edx as u64) << 32 | entry.eax as u64);
+        if xcr0 & !supported != 0 {
+            return Err(VmError::HvError(
+                "Extended state components not supported by the host",
+            ));
+        }
+
+        self.kvm_vcpu
+            .set_cpuid2(&cpuid)
+            .map_err(|_| VmError::HvError("Could not set cpuid"))?;
+        self.xcr0 = xcr0;
+        self.commit_xcr0()
+    }
+
+    /// Configures the `Vm` for kernel mode snapshots. The guest CPUID is set
+    /// to what kvm supports on the host, which must support the requested
+    /// protections.
+    pub fn set_supervisor_profile(&mut self, profile: SupervisorProfile) -> Result<()> {
+        const CR4_SMEP_BIT: usize = 20;
+        const CR4_SMAP_BIT: usize = 21;
+        const CPUID_SMEP_BIT: usize = 7;
+        const CPUID_SMAP_BIT: usize = 20;
+
+        // Protections supported by the host are reported in CPUID leaf 7
+        let cpuid = self.supported_cpuid()?;
+        let features = cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 7 && entry.index == 0)
+            .map_or(0, |entry| entry.ebx);
+        if (profile.smep && !features.is_bit_set(CPUID_SMEP_BIT))
+            || (profile.smap && !features.is_bit_set(CPUID_SMAP_BIT))
+        {
+            return Err(VmError::HvError("Protections not supported by the host"));
+        }
+
+        self.kvm_vcpu
+            .set_cpuid2(&cpuid)
+            .map_err(|_| VmError::HvError("Could not set cpuid"))?;
+
+        self.special_registers
+            .cr4
+            .set_bit(CR4_SMEP_BIT, profile.smep);
+        self.special_registers
+            .cr4
+            .set_bit(CR4_SMAP_BIT, profile.smap);
+        self.supervisor = profile;
+
+        Ok(())
+    }
+
+    /// Programs the local APIC timer to periodically deliver an interrupt to
+    /// the guest, or disables it. The interrupt wakes up a halted guest,
+    /// which resumes without running a handler of its own. Requires a `Vm`
+    /// created with `with_irqchip`, and a vector past the exceptions.
+    pub fn set_timer(&mut self, timer: Option<Timer>) -> Result<()> {
+        if !self.irqchip {
+            return Err(VmError::HvError("Timer requires an irqchip"));
+        }
+
+        // The vector is handled by a stub exiting to the hypervisor
+        if let Some(timer) = timer {
+            if timer.vector < 32 {
+                return Err(VmError::HvError("Timer vector is an exception vector"));
+            }
+
+            let handler = self.hypercall_page + TIMER_ENTRY_OFFSET;
+            self.memory
+                .write(handler, &self.hypercall_stub(timer.vector))?;
+
+            // Interrupt gate, so that the guest is not interrupted again
+            // before the stub exits
+            let entry = IdtEntryBuilder::new()
+                .base(handler)
+                .dpl(PrivilegeLevel::Ring0)
+                .segment_selector(1, PrivilegeLevel::Ring0)
+                .gate_type(IdtEntryType::Interrupt)
+                .ist(1)
+                .collect();
+            let address = self.special_registers.idt.base
+                + (timer.vector as usize * std::mem::size_of::<IdtEntry>()) as u64;
+            self.memory.write_val(address, entry)?;
+        }
+
+        self.program_lapic(timer)?;
+        self.timer = timer;
+
+        Ok(())
+    }
+
+    /// Programs the local APIC timer, discarding the pending and in-service
+    /// interrupts
+    fn program_lapic(&mut self, timer: Option<Timer>) -> Result<()> {
+        // The timer is clocked by a 1GHz bus, divided by 1
+        let count = match timer {
+            Some(timer) => match u32::try_from(timer.period.as_nanos()) {
+                Ok(count) if count > 0 => count,
+                _ => return Err(VmError::HvError("Timer period out of range")),
+            },
+            None => 0,
+        };
+
+        let mut lapic = self
+            .kvm_vcpu
+            .get_lapic()
+            .map_err(|_| VmError::HvError("Could not get local apic"))?;
+
+        // Software enable the local apic, with a spurious vector of 0xff
+        set_lapic_reg(&mut lapic, APIC_SVR, 0x1ff);
+        set_lapic_reg(&mut lapic, APIC_TIMER_DIVIDE, 0b1011);
+        match timer {
+            // Periodic mode
+            Some(timer) => set_lapic_reg(&mut lapic, APIC_LVT_TIMER, 1 << 17 | timer.vector as u32),
+            // Masked
+            None => set_lapic_reg(&mut lapic, APIC_LVT_TIMER, 1 << 16),
+        }
+        set_lapic_reg(&mut lapic, APIC_TIMER_INITIAL_COUNT, count);
+        set_lapic_reg(&mut lapic, APIC_TIMER_CURRENT_COUNT, count);
+        for register in 0..8 {
+            set_lapic_reg(&mut lapic, APIC_ISR + register * 0x10, 0);
+            set_lapic_reg(&mut lapic, APIC_IRR + register * 0x10, 0);
+        }
+
+        self.kvm_vcpu
+            .set_lapic(&lapic)
+            .map_err(|_| VmError::HvError("Could not set local apic"))
+    }
+
+    /// Acknowledges an interrupt of the local APIC, clearing it from the
+    /// in-service register as an end of interrupt written by the guest
+    /// would. The timer period restarts from the acknowledgment, as the
+    /// current count read back can be 0, which would fire it at once.
+    fn end_of_interrupt(&mut self, vector: u8) -> Result<()> {
+        let mut lapic = self
+            .kvm_vcpu
+            .get_lapic()
+            .map_err(|_| VmError::HvError("Could not get local apic"))?;
+
+        let offset = APIC_ISR + (vector as usize / 32) * 0x10;
+        let isr = get_lapic_reg(&lapic, offset) & !(1 << (vector % 32));
+        set_lapic_reg(&mut lapic, offset, isr);
+        let count = get_lapic_reg(&lapic, APIC_TIMER_INITIAL_COUNT);
+        set_lapic_reg(&mut lapic, APIC_TIMER_CURRENT_COUNT, count);
+
+        self.kvm_vcpu
+            .set_lapic(&lapic)
+            .map_err(|_| VmError::HvError("Could not set local apic"))
+    }
+
+    /// Returns the code of a handler pushing `vector` and exiting to the
+    /// hypervisor. `hlt` does not exit with an in-kernel irqchip, those
+    /// handlers write to the hypercall port instead.
+    fn hypercall_stub(&self, vector: u8) -> Vec<u8> {
+        match self.irqchip {
+            true => vec![
+                0x6a,                 // push
+                vector,               // <exception index>
+                0xe6,                 // out
+                HYPERCALL_PORT as u8, // -> our hypercall
+            ],
+            false => vec![
+                0x6a, vector, // push <exception index>
+                0xf4,   // hlt -> our hypercall
+            ],
+        }
+    }
+
+    /// Commits the XCR0 value to kvm
+    fn commit_xcr0(&self) -> Result<()> {
+        let mut xcrs = kvm_xcrs {
+            nr_xcrs: 1,
+            ..Default::default()
+        };
+        xcrs.xcrs[0].xcr = 0;
+        xcrs.xcrs[0].value = self.xcr0;
+
+        self.kvm_vcpu
+            .set_xcrs(&xcrs)
+            .map_err(|_| VmError::HvError("Could not set XCR0"))
+    }
+
+    /// Loads a vm state from snapshot files
+    pub fn from_snapshot<T: AsRef<Path>>(
+        snapshot_info: T,
+        memory_dump: T,
+        memory_size: usize,
+    ) -> Result<Vm> {
+        Vm::from_snapshot_filtered(
+            snapshot_info,
+            memory_dump,
+            memory_size,
+            &MappingFilter::default(),
+        )
+    }
+
+    /// Loads a vm state from snapshot files, only mapping the memory selected
+    /// by `filter`
+    pub fn from_snapshot_filtered<T: AsRef<Path>>(
+        snapshot_info: T,
+        memory_dump: T,
+        memory_size: usize,
+        filter: &MappingFilter,
+    ) -> Result<Vm> {
+        // Create a new VN instance
+        let mut vm = Vm::new(memory_size)?;
+        vm.load_snapshot_filtered(snapshot_info, memory_dump, filter)?;
+
+        Ok(vm)
+    }
+
+    /// Loads the mappings and registers of snapshot files in this `Vm`
+    /// (e.g. one created with `with_irqchip`)
+    pub fn load_snapshot<T: AsRef<Path>>(
+        &mut self,
+        snapshot_info: T,
+        memory_dump: T,
+    ) -> Result<()> {
+        self.load_snapshot_filtered(snapshot_info, memory_dump, &MappingFilter::default())
+    }
+
+    /// Loads the registers of snapshot files and the mappings selected by
+    /// `filter` in this `Vm`
+    pub fn load_snapshot_filtered<T: AsRef<Path>>(
+        &mut self,
+        snapshot_info: T,
+        memory_dump: T,
+        filter: &MappingFilter,
+    ) -> Result<()> {
+        let vm = self;
+
+        // Get the snapshot information
+        let info = SnapshotInfo::from_file(snapshot_info)?;
+
+        // Loading the mappings
+        let mut dump = File::open(memory_dump)?;
+        let mut buf: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+        // Loop through the selected mappings
+        for mapping in info.mappings.iter().filter(|m| filter.matches(m)) {
+            assert!(mapping.start < mapping.end, "mapping.start > mapping.end");
+
+            // Create the mapping
+            let mapping_size = (mapping.end - mapping.start) as usize;
+            vm.mmap(mapping.start, mapping_size, mapping.permissions)?;
+
+            // TODO: Implement more efficient copy to memory
+            // Loop through each page of the mapping and copy it
+            for off in (0..mapping_size).step_by(PAGE_SIZE) {
+                dump.seek(SeekFrom::Start(mapping.physical_offset + off as u64))?;
+                dump.read(&mut buf)?;
+                vm.write(mapping.start + off as u64, &buf)?;
+            }
+        }
+
+        // Load all the registers
+        vm.set_regs_snapshot(&info.registers)?;
+        vm.flush_registers()?;
+
+        Ok(())
+    }
+
+    /// Loads a vm state from snapshot files, reading the memory selected by
+    /// `filter` on demand (see `load_snapshot_on_demand`)
+    pub fn from_snapshot_on_demand<T: AsRef<Path>>(
+        snapshot_info: T,
+        memory_dump: T,
+        memory_size: usize,
+        filter: &MappingFilter,
+    ) -> Result<Vm> {
+        let mut vm = Vm::new(memory_size)?;
+        vm.load_snapshot_on_demand(snapshot_info, memory_dump, filter)?;
+
+        Ok(vm)
+    }
+
+    /// Loads the registers of snapshot files and the mappings selected by
+    /// `filter` in this `Vm`, without reading the memory dump: the pages are
+    /// read on their first access, from the guest or from the host. Large
+    /// snapshots are then ready at once, only the memory in use being read.
+    ///
+    /// The frames of all the pages are allocated here, so that the vms
+    /// cloned or frozen from this one keep the same layout. Each vm loads
+    /// the pages on its own, so pages changed from the host (e.g.
+    /// breakpoints) must be changed before cloning the vm.
+    pub fn load_snapshot_on_demand<T: AsRef<Path>>(
+        &mut self,
+        snapshot_info: T,
+        memory_dump: T,
+        filter: &MappingFilter,
+    ) -> Result<()> {
+        let info = SnapshotInfo::from_file(snapshot_info)?;
+        let mut pages = SnapshotPages::new(File::open(memory_dump)?);
+
+        for mapping in info.mappings.iter().filter(|m| filter.matches(m)) {
+            assert!(mapping.start < mapping.end, "mapping.start > mapping.end");
+
+            let mapping_size = (mapping.end - mapping.start) as usize;
+            self.mmap(mapping.start, mapping_size, mapping.permissions)?;
+
+            for off in (0..mapping_size).step_by(PAGE_SIZE) {
+                let address = mapping.start + off as u64;
+                self.memory.set_present(address, false)?;
+                pages.insert(address, mapping.physical_offset + off as u64);
+            }
+        }
+        self.demand_pages = Some(Arc::new(pages));
+
+        // Load all the registers
+        self.set_regs_snapshot(&info.registers)?;
+        self.flush_registers()
+    }
+
+    /// Loads a vm state from a `Snapshot` in memory (e.g. one assembled with
+    /// `Snapshot::from_maps_files`)
+    pub fn from_snapshot_data(snapshot: &Snapshot, memory_size: usize) -> Result<Vm> {
+        let mut vm = Vm::new(memory_size)?;
+        vm.load_snapshot_data(snapshot, &MappingFilter::default())?;
+
+        Ok(vm)
+    }
+
+    /// Loads the registers of a `Snapshot` in memory and the mappings
+    /// selected by `filter` in this `Vm`
+    pub fn load_snapshot_data(
+        &mut self,
+        snapshot: &Snapshot,
+        filter: &MappingFilter,
+    ) -> Result<()> {
+        for mapping in snapshot.info.mappings.iter().filter(|m| filter.matches(m)) {
+            let mapping_size = (mapping.end - mapping.start) as usize;
+            let offset = mapping.physical_offset as usize;
+            let data = snapshot
+                .data
+                .get(offset..offset + mapping_size)
+                .ok_or_else(|| {
+                    SnapshotError::ParsingError(format!(
+                        "Mapping {:#x} out of the memory dump",
+                        mapping.start
+                    ))
+                })?;
+
+            self.mmap(mapping.start, mapping_size, mapping.permissions)?;
+            self.write(mapping.start, data)?;
+        }
+
+        // Load all the registers
+        self.set_regs_snapshot(&snapshot.info.registers)?;
+        self.flush_registers()
+    }
+
+    /// Loads a static (or static-pie) ELF executable, without any snapshot.
+    /// A System V initial stack is built with the given arguments.
+    pub fn from_elf<T: AsRef<Path>>(path: T, args: &[&str], memory_size: usize) -> Result<Vm> {
+        // Load address of position independent executables
+        const PIE_BASE: u64 = 0x5555_5555_4000;
+        // Initial stack region
+        const STACK_TOP: u64 = 0x7fff_ffff_f000;
+        const STACK_SIZE: usize = 0x10_0000;
+
+        // Auxiliary vector entry types
+        const AT_NULL: u64 = 0;
+        const AT_PHDR: u64 = 3;
+        const AT_PHENT: u64 = 4;
+        const AT_PHNUM: u64 = 5;
+        const AT_PAGESZ: u64 = 6;
+        const AT_BASE: u64 = 7;
+        const AT_ENTRY: u64 = 9;
+        const AT_RANDOM: u64 = 25;
+
+        // Create a new VM instance
+        let mut vm = Vm::new(memory_size)?;
+
+        // Parse the executable
+        let data = std::fs::read(path)?;
+        let elf = Elf::parse(&data)?;
+        let base = if elf.pie { PIE_BASE } else { 0 };
+
+        // Compute page permissions, merging the ones of pages shared by
+        // several segments
+        let mut pages: BTreeMap<u64, PagePermissions> = BTreeMap::new();
+        for segment in elf.segments.iter() {
+            let start = (base + segment.vaddr).align_power2(PAGE_SIZE as u64);
+            let end = (base + segment.vaddr + segment.memsz).align_up_power2(PAGE_SIZE as u64);
+
+            for page in (start..end).step_by(PAGE_SIZE) {
+                *pages.entry(page).or_insert_with(|| PagePermissions::new(0)) |=
+                    segment.permissions;
+            }
+        }
+
+        // Create the mappings
+        for (page, perms) in pages {
+            vm.mmap(page, PAGE_SIZE, perms)?;
+        }
+
+        // Copy segments data, the remaining (bss) is already zeroed
+        for segment in elf.segments.iter() {
+            let start = segment.offset as usize;
+            let end = start + segment.filesz as usize;
+            vm.write(base + segment.vaddr, &data[start..end])?;
+        }
+
+        // Apply relocations
+        for (offset, addend) in elf.relocations.iter() {
+            vm.write_value::<u64>(base + offset, base + addend)?;
+        }
+
+        // Setting up the stack
+        vm.mmap(
+            STACK_TOP - STACK_SIZE as u64,
+            STACK_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        let mut sp = STACK_TOP;
+
+        // Copy the argument strings
+        let mut argv: Vec<u64> = Vec::new();
+        for arg in args {
+            sp -= arg.len() as u64 + 1;
+            vm.write(sp, arg.as_bytes())?;
+            vm.write_value::<u8>(sp + arg.len() as u64, 0)?;
+            argv.push(sp);
+        }
+
+        // AT_RANDOM bytes, kept constant for reproducible executions
+        sp -= 16;
+        vm.write(sp, &[0x42; 16])?;
+        let random = sp;
+
+        // argc, argv, empty envp and auxv
+        let mut words: Vec<u64> = vec![args.len() as u64];
+        words.extend(argv);
+        words.push(0);
+        words.push(0);
+        for (key, value) in [
+            (AT_PHDR, base + elf.phdr),
+            (AT_PHENT, 56),
+            (AT_PHNUM, elf.phnum as u64),
+            (AT_PAGESZ, PAGE_SIZE as u64),
+            (AT_BASE, 0),
+            (AT_ENTRY, base + elf.entry),
+            (AT_RANDOM, random),
+            (AT_NULL, 0),
+        ] {
+            words.push(key);
+            words.push(value);
+        }
+
+        // The stack pointer must be 16 bytes aligned on argc
+        sp = (sp - (words.len() * 8) as u64).align_power2(16);
+        for (i, word) in words.iter().enumerate() {
+            vm.write_value::<u64>(sp + (i * 8) as u64, *word)?;
+        }
+
+        // Setting up registers, rdx holds the atexit function pointer
+        vm.set_reg(Register::Rsp, sp);
+        vm.set_reg(Register::Rip, base + elf.entry);
+        vm.set_reg(Register::Rdx, 0);
+        vm.flush_registers()?;
+
+        Ok(vm)
+    }
+
+    /// Reset the `Vm` state from an other one
+    pub fn reset(&mut self, other: &Vm) {
+        let start = Instant::now();
+
+        // Here we prefer aborting as if you are resetting a vm with a completely different one you
+        // are doing something extremely wrong.
+        assert_eq!(
+            self.memory.host_memory_size(),
+            other.memory.host_memory_size(),
+            "Vm memory mismatch"
+        );
+
+        // Reset registers
+        self.reset_registers(other);
+
+        // Reset memory state
+        let origin = other
+            .memory
+            .pmem
+            .raw_slice(0, other.memory.host_memory_size())
+            .expect("Could not get physical memory from source vm");
+        let pages = self.reset_memory(origin);
+
+        // Reset the timer, along with its pending interrupts
+        if self.irqchip {
+            self.program_lapic(other.timer)
+                .expect("Could not reset the local apic");
+            self.timer = other.timer;
+        }
+
+        self.record_reset(pages, start);
+    }
+
+    /// Reset the registers of the `Vm` from another one, leaving the memory
+    /// as is. Harnesses keeping state in memory between cases can use it
+    /// instead of `reset`.
+    pub fn reset_registers_only(&mut self, other: &Vm) {
+        let start = Instant::now();
+
+        self.reset_registers(other);
+        self.record_reset(0, start);
+    }
+
+    /// Restore the `len` bytes at the virtual address `addr` from another
+    /// `Vm`, without walking the dirty log. Registers are left as is.
+    ///
+    /// Both vms must share the same page tables, as when one is a clone of
+    /// the other. The dirtied pages are still restored by the next `reset`.
+    pub fn reset_range(&mut self, other: &Vm, addr: u64, len: usize) -> Result<()> {
+        assert_eq!(
+            self.memory.host_memory_size(),
+            other.memory.host_memory_size(),
+            "Vm memory mismatch"
+        );
+
+        let origin = other
+            .memory
+            .pmem
+            .raw_slice(0, other.memory.host_memory_size())?;
+        let end = addr
+            .checked_add(len as u64)
+            .ok_or(MemoryError::IntegerOverflow)?;
+
+        // Restore page by page, the physical pages not being contiguous
+        let mut current = addr;
+        while current < end {
+            let page_end = (current & !(PAGE_SIZE as u64 - 1)) + PAGE_SIZE as u64;
+            let size = (end.min(page_end) - current) as usize;
+
+            let (pa, _) = self
+                .memory
+                .virt_to_phys(current)
+                .ok_or(MemoryError::AddressUnmapped(current))?;
+            let pa = pa as usize;
+            self.memory.pmem.write(pa, &origin[pa..pa + size])?;
+
+            current += size as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the registers of another `Vm`
+    fn reset_registers(&mut self, other: &Vm) {
+        self.stepped_coverage_point = None;
+        self.watch_step = None;
+        self.abort_written_pages();
+        self.registers = other.registers;
+        self.special_registers = other.special_registers;
+        self.fs_base = other.fs_base;
+        self.gs_base = other.gs_base;
+        self.kernel_gs_base = other.kernel_gs_base;
+
+        // Reset debug registers and xsave area only if they were loaded
+        if other.extended_state {
+            self.debug_registers = other.debug_registers;
+            self.xsave = other.xsave.clone();
+            self.extended_state = true;
+            self.extended_state_dirty = true;
+        }
+    }
+
+    /// Reset the `Vm` state from a `PristineVm`
+    pub fn reset_pristine(&mut self, pristine: &PristineVm) {
+        let start = Instant::now();
+
+        assert_eq!(
+            self.memory.host_memory_size(),
+            pristine.memory.size(),
+            "Vm memory mismatch"
+        );
+
+        // Reset registers
+        self.stepped_coverage_point = None;
+        self.watch_step = None;
+        self.abort_written_pages();
+        self.registers = pristine.registers;
+        self.special_registers = pristine.special_registers;
+        self.fs_base = pristine.fs_base;
+        self.gs_base = pristine.gs_base;
+        self.kernel_gs_base = pristine.kernel_gs_base;
+
+        // Reset debug registers and xsave area only if they were loaded
+        if pristine.extended_state {
+            self.debug_registers = pristine.debug_registers;
+            self.xsave = pristine.xsave.clone();
+            self.extended_state = true;
+            self.extended_state_dirty = true;
+        }
+
+        // Reset memory state
+        let pages = self.reset_memory(pristine.memory.as_slice());
+
+        // Reset the timer, along with its pending interrupts
+        if self.irqchip {
+            self.program_lapic(pristine.timer)
+                .expect("Could not reset the local apic");
+            self.timer = pristine.timer;
+        }
+
+        self.record_reset(pages, start);
+    }
+
+    /// Accounts for a reset which restored `pages` pages since `start`
+    fn record_reset(&mut self, pages: u64, start: Instant) {
+        self.stats.resets += 1;
+        self.stats.last_reset_pages = pages;
+        self.stats.reset_pages += pages;
+        self.stats.reset_time += start.elapsed();
+    }
+
+    /// Returns the execution statistics
+    #[inline]
+    pub fn stats(&self) -> VmStats {
+        self.stats
+    }
+
+    /// Clears the execution statistics
+    #[inline]
+    pub fn clear_stats(&mut self) {
+        self.stats = VmStats::default();
+    }
+
+    /// Returns an iterator over the pages the guest dirtied since the last
+    /// reset, with their guest physical address and current contents. The
+    /// dirty log is left as is, pages written from the host are not part of
+    /// it.
+    pub fn dirty_iter(&self) -> Result<DirtyPages<'_>> {
+        let mut bitmap = Vec::new();
+        self.read_dirty_log(&mut bitmap)?;
+        let bits = bitmap.first().copied().unwrap_or(0);
+
+        Ok(DirtyPages {
+            memory: &self.memory,
+            bitmap,
+            index: 0,
+            bits,
+        })
+    }
+
+    /// Writes the dirty log (one bit per physical page dirtied by the guest
+    /// since the last reset) to `bitmap`, resized as needed. Retaining the
+    /// bitmap between calls avoids allocating it each time. The dirty log is
+    /// left as is.
+    pub fn read_dirty_log(&self, bitmap: &mut Vec<u64>) -> Result<()> {
+        let pages = self.memory.host_memory_size() / PAGE_SIZE;
+        bitmap.resize(pages.div_ceil(64), 0);
+
+        let dirty_log = kvm_dirty_log {
+            slot: 0,
+            padding1: 0,
+            __bindgen_anon_1: kvm_bindings::kvm_dirty_log__bindgen_ty_1 {
+                dirty_bitmap: bitmap.as_mut_ptr() as *mut core::ffi::c_void,
+            },
+        };
+
+        // The bitmap holds one bit per page of the memory slot
+        let ret = unsafe { ioctl::ioctl_with_ref(&self.kvm_vm, KVM_GET_DIRTY_LOG(), &dirty_log) };
+        if ret != 0 {
+            return Err(VmError::HvError("Could not get dirty log"));
+        }
+
+        Ok(())
+    }
+
+    /// Restore the pages dirtied since the last reset from a copy of the
+    /// whole physical memory, returning the number of restored pages
+    fn reset_memory(&mut self, origin: &[u8]) -> u64 {
+        // Get the dirty log from kvm
+        let mut dirty_log = std::mem::take(&mut self.dirty_log);
+        self.read_dirty_log(&mut dirty_log)
+            .expect("Could not get dirty log for current vm");
+
+        // Gather the runs of consecutive dirty frames, restored with a
+        // single copy each
+        let mut runs = std::mem::take(&mut self.dirty_runs);
+        runs.clear();
+
+        let mut pages = 0;
+        let mut tables_dirty = false;
+        for (bm_index, bm_entry) in dirty_log.iter().enumerate() {
+            let mut bm = *bm_entry;
+
+            while bm != 0 {
+                // Get next frame dirtied
+                let i = bm.trailing_zeros() as usize;
+                let pa = (bm_index * 64 + i) * PAGE_SIZE;
+                pages += 1;
+                tables_dirty |= self.demand_tables.contains_key(&(pa as u64));
+
+                // Go to the next bit
+                bm &= bm - 1;
+
+                // Pages loaded on demand come from the snapshot if the
+                // origin lacks them
+                if let (Some(&(address, table)), Some(demand_pages)) =
+                    (self.demand_frames.get(&(pa as u64)), &self.demand_pages)
+                {
+                    if !origin_present(origin, table, address) {
+                        let contents = demand_pages
+                            .read(address)
+                            .expect("Could not read snapshot page");
+                        self.memory
+                            .pmem
+                            .write(pa, &contents)
+                            .expect("Could not restore page in dirty vm");
+                        continue;
+                    }
+                }
+
+                match runs.last_mut() {
+                    Some(run) if run.end == pa => run.end += PAGE_SIZE,
+                    _ => runs.push(pa..pa + PAGE_SIZE),
+                }
+            }
+        }
+
+        // Write original data to the pmem to restore
+        for run in runs.iter() {
+            self.memory
+                .pmem
+                .write(run.start, &origin[run.clone()])
+                .expect("Could not restore pages in dirty vm");
+        }
+        self.dirty_runs = runs;
+
+        // The pages loaded on demand stay present
+        if tables_dirty {
+            for (&table, addresses) in self.demand_tables.iter() {
+                let frame = table as usize / PAGE_SIZE;
+                if dirty_log[frame / 64] & (1 << (frame % 64)) == 0 {
+                    continue;
+                }
+
+                for &address in addresses {
+                    self.memory
+                        .set_present(address, true)
+                        .expect("Could not restore page presence");
+                }
+            }
+        }
+
+        // The watched pages stay hidden, whatever the origin holds
+        for &page in self.watched_pages.iter() {
+            self.memory
+                .set_present(page, false)
+                .expect("Could not hide watched page");
+        }
+
+        // Define the dirty log clear structure
+        let clear_log = kvm_bindings::kvm_clear_dirty_log {
+            slot: 0,
+            num_pages: (self.memory.host_memory_size() / PAGE_SIZE) as u32,
+            first_page: 0,
+            __bindgen_anon_1: kvm_bindings::kvm_clear_dirty_log__bindgen_ty_1 {
+                dirty_bitmap: dirty_log.as_ptr() as *mut core::ffi::c_void,
+            },
+        };
+
+        // Clear dirty log
+        let ret = unsafe { ioctl::ioctl_with_ref(&self.kvm_vm, KVM_CLEAR_DIRTY_LOG(), &clear_log) };
+        if ret != 0 {
+            panic!("Failed to clean dirty log");
+        }
+        self.dirty_log = dirty_log;
+
+        pages
+    }
+}
+
+/// Checks whether the page at `address` is present in the physical memory
+/// `origin`, given the physical address of its page table
+fn origin_present(origin: &[u8], table: u64, address: u64) -> bool {
+    let entry = table as usize + ((address >> 12) & 0x1ff) as usize * 8;
+    origin[entry] & 1 != 0
+}
+
+/// Checks whether a CPUID table entry holds a leaf and subleaf, the subleaf
+/// only mattering for the leaves flagged as indexed
+fn cpuid_entry_matches(entry: &kvm_cpuid_entry2, leaf: u32, subleaf: u32) -> bool {
+    entry.function == leaf
+        && (entry.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX == 0 || entry.index == subleaf)
+}
+
+/// Reads a 32 bits register of a local apic state
+fn get_lapic_reg(lapic: &kvm_lapic_state, offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = lapic.regs[offset + i] as u8;
+    }
+    u32::from_le_bytes(bytes)
+}
+
+/// Writes a 32 bits register of a local apic state
+fn set_lapic_reg(lapic: &mut kvm_lapic_state, offset: usize, value: u32) {
+    for (i, byte) in value.to_le_bytes().iter().enumerate() {
+        lapic.regs[offset + i] = *byte as _;
+    }
+}
+
+impl Vm {
+    /// Creates a read-only copy of the current state, to be used as a shared
+    /// reset source. Every change made to memory afterwards (breakpoints
+    /// included) is not visible from the `PristineVm`.
+    pub fn freeze(&self) -> Result<PristineVm> {
+        Ok(PristineVm {
+            registers: self.registers,
+            special_registers: self.special_registers,
+            fs_base: self.fs_base,
+            gs_base: self.gs_base,
+            kernel_gs_base: self.kernel_gs_base,
+            debug_registers: self.debug_registers,
+            xsave: self.xsave.clone(),
+            extended_state: self.extended_state,
+            xcr0: self.xcr0,
+            irqchip: self.irqchip,
+            timer: self.timer,
+            user_segments: self.user_segments,
+            supervisor: self.supervisor,
+            rdrand_hidden: self.rdrand_hidden,
+            virtualization: self.virtualization,
+            cpuid_overrides: self.cpuid_overrides.clone(),
+            coverage_points: self.coverage_points.clone(),
+            persistent_coverage: self.persistent_coverage,
+            coverage_write_protection: self.coverage_write_protection,
+            protected_pages: self.protected_pages.clone(),
+            demand_pages: self.demand_pages.clone(),
+            demand_frames: self.demand_frames.clone(),
+            demand_tables: self.demand_tables.clone(),
+            wx_enforced: self.memory.wx_enforced(),
+            page_directory: self.memory.page_directory(),
+            hypercall_page: self.hypercall_page,
+            memory: FrozenMemory::new(&self.memory.pmem)?,
+        })
+    }
+
+    /// Runs the guest until its snapshot point (`SNAPSHOT_HYPERCALL`), then
+    /// freezes it. The hypercall returns 0 when resuming from the
+    /// `PristineVm`. The other syscalls are handed to `syscall`, which
+    /// returns whether the guest keeps running.
+    pub fn run_until_snapshot_point<F>(&mut self, mut syscall: F) -> Result<PristineVm>
+    where
+        F: FnMut(&mut Vm) -> Result<bool>,
+    {
+        loop {
+            match self.run()? {
+                VmExit::Syscall if self.get_reg(Register::Rax) == SNAPSHOT_HYPERCALL => {
+                    self.set_reg(Register::Rax, 0);
+                    return self.freeze();
+                }
+                VmExit::Syscall => {
+                    if !syscall(self)? {
+                        return Err(VmError::HvError("Guest exited before its snapshot point"));
+                    }
+                }
+                _ => return Err(VmError::HvError("Guest stopped before its snapshot point")),
+            }
+        }
+    }
+
+    /// Creates a new `Vm` instance from a `PristineVm` state
+    pub fn from_pristine(pristine: &PristineVm) -> Result<Vm> {
+        let mut vm = Vm::setup_barebones(pristine.memory.size(), pristine.irqchip)?;
+
+        // Both memories start with the page directory allocation
+        assert_eq!(
+            vm.memory.page_directory(),
+            pristine.page_directory,
+            "Page directory mismatch"
+        );
+
+        // Copy memory and the frame allocator state
+        vm.memory.pmem.write(0, pristine.memory.as_slice())?;
+        vm.memory.pmem.set_top(pristine.memory.top());
+        vm.memory.set_wx_enforced(pristine.wx_enforced);
+        vm.hypercall_page = pristine.hypercall_page;
+
+        // Enable breakpoints and the tss
+        vm.setup_registers()?;
+
+        // Copy registers
+        vm.registers = pristine.registers;
+        vm.special_registers = pristine.special_registers;
+        vm.fs_base = pristine.fs_base;
+        vm.gs_base = pristine.gs_base;
+        vm.kernel_gs_base = pristine.kernel_gs_base;
+        vm.debug_registers = pristine.debug_registers;
+        vm.xsave = pristine.xsave.clone();
+        vm.extended_state = pristine.extended_state;
+        if pristine.xcr0 != XCR0_SSE {
+            vm.set_xcr0(pristine.xcr0)?;
+        }
+        if pristine.timer.is_some() {
+            vm.set_timer(pristine.timer)?;
+        }
+        vm.user_segments = pristine.user_segments;
+        if vm.user_segments.is_some() {
+            vm.commit_syscall_msrs()?;
+        }
+        if pristine.supervisor != SupervisorProfile::default() {
+            vm.set_supervisor_profile(pristine.supervisor)?;
+        }
+        if pristine.rdrand_hidden {
+            vm.hide_rdrand()?;
+        }
+        if let Some(virtualization) = pristine.virtualization {
+            vm.set_virtualization_cpuid(virtualization)?;
+        }
+        if !pristine.cpuid_overrides.is_empty() {
+            vm.cpuid_overrides = pristine.cpuid_overrides.clone();
+            vm.commit_cpuid()?;
+        }
+        vm.coverage_points = pristine.coverage_points.clone();
+        vm.persistent_coverage = pristine.persistent_coverage;
+        vm.coverage_write_protection = pristine.coverage_write_protection;
+        vm.protected_pages = pristine.protected_pages.clone();
+        vm.demand_pages = pristine.demand_pages.clone();
+        vm.demand_frames = pristine.demand_frames.clone();
+        vm.demand_tables = pristine.demand_tables.clone();
+        vm.flush_registers()?;
+
+        Ok(vm)
+    }
+}
+
+impl Clone for Vm {
+    fn clone(&self) -> Self {
+        let mut vm = Vm::create(self.memory.host_memory_size(), self.irqchip)
+            .expect("Could not create vm for clone");
+
+        // Copy registers
+        vm.registers = self.registers;
+        vm.special_registers = self.special_registers;
+        vm.fs_base = self.fs_base;
+        vm.gs_base = self.gs_base;
+        vm.kernel_gs_base = self.kernel_gs_base;
+        vm.debug_registers = self.debug_registers;
+        vm.xsave = self.xsave.clone();
+        vm.extended_state = self.extended_state;
+        vm.extended_state_dirty = self.extended_state;
+        if self.xcr0 != XCR0_SSE {
+            vm.set_xcr0(self.xcr0)
+                .expect("Could not set XCR0 for clone");
+        }
+        if self.timer.is_some() {
+            vm.set_timer(self.timer)
+                .expect("Could not set timer for clone");
+        }
+        vm.user_segments = self.user_segments;
+        if vm.user_segments.is_some() {
+            vm.commit_syscall_msrs()
+                .expect("Could not set syscall msrs for clone");
+        }
+        if self.supervisor != SupervisorProfile::default() {
+            vm.set_supervisor_profile(self.supervisor)
+                .expect("Could not set supervisor profile for clone");
+        }
+        if self.rdrand_hidden {
+            vm.hide_rdrand().expect("Could not hide rdrand for clone");
+        }
+        if let Some(virtualization) = self.virtualization {
+            vm.set_virtualization_cpuid(virtualization)
+                .expect("Could not set virtualization cpuid for clone");
+        }
+        if !self.cpuid_overrides.is_empty() {
+            vm.cpuid_overrides = self.cpuid_overrides.clone();
+            vm.commit_cpuid()
+                .expect("Could not set cpuid overrides for clone");
+        }
+        vm.coverage_points = self.coverage_points.clone();
+        vm.persistent_coverage = self.persistent_coverage;
+        vm.coverage_write_protection = self.coverage_write_protection;
+        vm.protected_pages = self.protected_pages.clone();
+        vm.demand_pages = self.demand_pages.clone();
+        vm.demand_frames = self.demand_frames.clone();
+        vm.demand_tables = self.demand_tables.clone();
+
+        // Copy memory
+        let orig_mem = self
+            .memory
+            .pmem
+            .raw_slice(0, self.memory.host_memory_size())
+            .expect("Could not get original physical memory");
+        vm.memory
+            .pmem
+            .write(0, &orig_mem)
+            .expect("Could not set actual memory to original");
+        vm.memory.set_wx_enforced(self.memory.wx_enforced());
+
+        vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CpuidResult, DirtyPages, ExitAction, FailureReason, PageFaultDetail, Register, Result,
+        SegmentRegister, SupervisorProfile, Timer, TraceStep, VirtualizationInstruction, Vm,
+        VmError, VmExit, VmStats, SNAPSHOT_HYPERCALL, XCR0_AVX, XCR0_SSE,
+    };
+    use crate::access::{AccessKind, MemoryAccess};
+    use crate::branches::{Branch, BranchRecording};
+    use crate::decode::CmpOperands;
+    use crate::elf::tests::build_elf;
+    use crate::memory::{PagePermissions, PAGE_SIZE};
+    use crate::snapshot::{MappingFilter, Snapshot, SnapshotInfo};
+    use crate::x64::PrivilegeLevel;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    /// Runs a simple piece of code until completion
+    fn test_simple_exec() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Simple shellcode
+        let shellcode: &[u8] = &[
+            0x48, 0x01, 0xc2, // add rdx, rax
+            0xcc, // breakpoint
+        ];
+
+        // Mapping the code
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        // Set registers to known values
+        vm.set_reg(Register::Rax, 0x1000);
+        vm.set_reg(Register::Rdx, 0x337);
+
+        // Execute from beginning of shellcode
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let vmexit = vm.run()?;
+
+        assert_eq!(vmexit, VmExit::Breakpoint);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337003);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Tests the collection and clearing of dirty pages
+    fn test_dirty_status() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Simple shellcode
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xcc, // int3
+        ];
+
+        // Mapping the code
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        // Mapping the target page of the write
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        // Set registers to known values
+        vm.set_reg(Register::Rax, 0xdeadbeef);
+        vm.set_reg(Register::Rdx, 0x42424242);
+
+        // Execute from beginning of shellcode
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let vmexit = vm.run()?;
+
+        // Sanity check
+        assert_eq!(vmexit, VmExit::Breakpoint);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337003);
+
+        // Check that the target page was dirtied
+        assert!(vm.dirty_mappings().any(|m| m.address == 0xdeadb000));
+
+        // Reset the pages dirty status
+        vm.clear_dirty_mappings();
+
+        // Check again the dirty pages
+        assert!(vm.dirty_mappings().count() == 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Loads and runs a minimal static executable
+    fn test_from_elf() -> Result<()> {
+        let path = std::env::temp_dir().join("tartiflette_test_from_elf");
+        std::fs::write(&path, build_elf(&[0xf4], 0x400000))?;
+
+        let mut vm = Vm::from_elf(&path, &["test", "arg"], 512 * PAGE_SIZE)?;
+        std::fs::remove_file(&path)?;
+
+        // Check the entry point and the initial stack
+        let rsp = vm.get_reg(Register::Rsp);
+        assert_eq!(vm.get_reg(Register::Rip), 0x400078);
+        assert_eq!(rsp & 0xf, 0);
+        assert_eq!(vm.memory.read_val::<u64>(rsp)?, 2);
+
+        let mut arg = [0u8; 4];
+        vm.read(vm.memory.read_val::<u64>(rsp + 16)?, &mut arg)?;
+        assert_eq!(&arg, b"arg\0");
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Resets several vms from a shared pristine state
+    fn test_pristine_reset() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Simple shellcode
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xf4, // hlt
+        ];
+
+        // Mapping the code and the target page of the write
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        // Set registers to known values
+        vm.set_reg(Register::Rax, 0xdeadbeef);
+        vm.set_reg(Register::Rdx, 0x42424242);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let pristine = std::sync::Arc::new(vm.freeze()?);
+        drop(vm);
+
+        for _ in 0..2 {
+            let mut worker = Vm::from_pristine(&pristine)?;
+
+            for _ in 0..2 {
+                assert_eq!(worker.run()?, VmExit::Hlt);
+                assert_eq!(worker.memory.read_val::<u32>(0xdeadbeef)?, 0x42424242);
+
+                worker.reset_pristine(&pristine);
+                assert_eq!(worker.get_reg(Register::Rip), 0x1337000);
+                assert_eq!(worker.memory.read_val::<u32>(0xdeadbeef)?, 0);
+            }
+
+            // Mappings created after the fork must not overlap the frozen ones
+            worker.mmap(0x4000, PAGE_SIZE, PagePermissions::READ)?;
+            assert_eq!(worker.memory.read_val::<u64>(0x1337000)?, 0xf4108948);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Runs a simple piece of code until completion
+    fn test_simple_syscall() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // The syscall in the shellcode will add rax and rdx together
+        let shellcode: &[u8] = &[
+            0x0f, 0x05, // syscall
+            0xcc, // breakpoint
+        ];
+
+        // Mapping the code
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+
+        // Set registers to known values
+        vm.set_reg(Register::Rax, 0x1000);
+        vm.set_reg(Register::Rdx, 0x337);
+
+        // Execute from beginning of shellcode
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let vmexit = vm.run()?;
+
+        assert_eq!(vmexit, VmExit::Syscall);
+
+        // Emulated syscall doing rax = rax + rdx
+        vm.set_reg(
+            Register::Rax,
+            vm.get_reg(Register::Rax) + vm.get_reg(Register::Rdx),
+        );
+
+        let vmexit_end = vm.run()?;
+
+        assert_eq!(vmexit_end, VmExit::Breakpoint);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
+        assert_eq!(vm.get_reg(Register::Rax), 0x1337);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Validates the extended state components enabled in XCR0
+    fn test_set_xcr0() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // The x87 state can not be disabled
+        assert!(vm.set_xcr0(XCR0_SSE & !1).is_err());
+        vm.set_xcr0(XCR0_SSE)?;
+
+        // Clones keep the enabled components
+        assert_eq!(vm.clone().xcr0, XCR0_SSE);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Wakes up a halted vm with the timer interrupt
+    fn test_timer() -> Result<()> {
+        let timer = Timer {
+            vector: 32,
+            period: Duration::from_millis(1),
+        };
+
+        // The timer needs the in-kernel irqchip, and a vector which is not
+        // an exception
+        assert!(Vm::new(512 * PAGE_SIZE)?.set_timer(Some(timer)).is_err());
+
+        let mut vm = Vm::with_irqchip(512 * PAGE_SIZE)?;
+        let exception = Timer {
+            vector: 13,
+            ..timer
+        };
+        assert!(vm.set_timer(Some(exception)).is_err());
+        vm.set_timer(Some(timer))?;
+
+        let shellcode: &[u8] = &[
+            0xfb, // sti
+            0xf4, // hlt
+            0xf4, // hlt
+            0xb8, 0x37, 0x13, 0x00, 0x00, // mov eax, 0x1337
+            0x0f, 0x0b, // ud2
+        ];
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        // The guest goes through both halts, waking up on each tick
+        let pristine = vm.clone();
+        assert_eq!(vm.run()?, VmExit::InvalidInstruction);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337008);
+        assert_eq!(vm.get_reg(Register::Rax), 0x1337);
+
+        // The timer keeps ticking after a reset
+        vm.reset(&pristine);
+        assert_eq!(vm.run()?, VmExit::InvalidInstruction);
+        assert_eq!(vm.get_reg(Register::Rax), 0x1337);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Exits on the exception handlers of a vm without irqchip
+    fn test_exception_hlt() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0xf4, 0x0f, 0x0b])?; // hlt; ud2
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.run()?, VmExit::InvalidInstruction);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337001);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Reports the guest state on a triple fault
+    fn test_guest_failure() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Without IDT the #UD escalates to a triple fault
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0x0f, 0x0b])?; // ud2
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.special_registers.idt.limit = 0;
+
+        match vm.run() {
+            Err(VmError::GuestFailure(failure)) => {
+                assert_eq!(failure.reason, FailureReason::Shutdown);
+                assert_eq!(failure.idt.1, 0);
+            }
+            other => panic!("Unexpected run result: {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Traces the instructions of a simple piece of code
+    fn test_run_traced() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            0x48, 0x89, 0xc3, // mov rbx, rax
+            0xf4, // hlt
+        ];
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rax, 0);
+        vm.set_reg(Register::Rbx, 0);
+
+        // Stops at the limit
+        let trace = vm.run_traced(1, false)?;
+        assert_eq!(trace.exit, None);
+        assert_eq!(trace.steps.len(), 1);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337005);
+
+        // Then runs until the hlt
+        let trace = vm.run_traced(10, true)?;
+        assert_eq!(trace.exit, Some(VmExit::Hlt));
+        assert_eq!(
+            trace.steps[0],
+            TraceStep {
+                rip: 0x1337005,
+                changes: vec![(Register::Rbx, 1)],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    /// Interrupts a looping vm from another thread
+    fn test_kicker() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0xeb, 0xfe])?; // jmp $
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        // A kick received while not running interrupts the next run
+        let kicker = vm.kicker();
+        kicker.kick();
+        assert_eq!(vm.run()?, VmExit::Interrupted);
+
+        let kicking = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            kicker.kick();
+        });
+        assert_eq!(vm.run()?, VmExit::Interrupted);
+        kicking.join().unwrap();
+
+        Ok(())
+    }
+
+    #[test]
+    /// Freezes a vm at its snapshot point and resumes from it
+    fn test_snapshot_point() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let mut shellcode = vec![
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+            0x0f, 0x05, // syscall
+            0xb8, // mov eax, SNAPSHOT_HYPERCALL
+        ];
+        shellcode.extend_from_slice(&(SNAPSHOT_HYPERCALL as u32).to_le_bytes());
+        shellcode.extend_from_slice(&[
+            0x0f, 0x05, // syscall
+            0xf4, // hlt
+        ]);
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        // Other syscalls go through the handler
+        let mut syscalls = Vec::new();
+        let pristine = vm.run_until_snapshot_point(|vm| {
+            syscalls.push(vm.get_reg(Register::Rax));
+            Ok(true)
+        })?;
+        assert_eq!(syscalls, vec![1]);
+
+        let mut worker = Vm::from_pristine(&pristine)?;
+        assert_eq!(worker.run()?, VmExit::Hlt);
+        assert_eq!(worker.get_reg(Register::Rax), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Loads a segment added to the GDT
+    fn test_custom_segment() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let mut gdt = vm.default_gdt();
+        let data = gdt.data_segment(PrivilegeLevel::Ring0);
+        vm.set_gdt(&gdt)?;
+        vm.set_segment(SegmentRegister::Ds, data)?;
+        assert!(vm.set_segment(SegmentRegister::Es, data + 8).is_err());
+
+        let shellcode: &[u8] = &[
+            0x8c, 0xd8, // mov eax, ds
+            0xf4, // hlt
+        ];
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rax) & 0xffff, data as u64);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Runs code in user mode, trapping its syscalls and privileged
+    /// instructions
+    fn test_user_mode() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x0f, 0x05, // syscall
+            0xfa, // cli
+        ];
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.enter_user_mode()?;
+
+        // Mapped after entering user mode
+        let perms = PagePermissions::READ | PagePermissions::WRITE | PagePermissions::USER;
+        vm.mmap(0x4000, PAGE_SIZE, perms)?;
+        vm.set_reg(Register::Rsp, 0x5000);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        assert_eq!(vm.run()?, VmExit::Syscall);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
+        assert_eq!(vm.get_reg(Register::Rsp), 0x5000);
+        assert_eq!(vm.special_registers.cs.dpl, 3);
+
+        // cli is a privileged instruction: #GP
+        assert_eq!(vm.run()?, VmExit::Exception(13));
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
+        assert_eq!(vm.special_registers.cs.dpl, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Swaps the gs bases of a kernel snapshot with swapgs
+    fn test_swapgs() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x0f, 0x01, 0xf8, // swapgs
+            0xf4, // hlt
+        ];
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::GsBase, 0x7f00_0000_1000);
+        vm.set_reg(Register::KernelGsBase, 0xffff_8880_0000_1000);
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::GsBase), 0xffff_8880_0000_1000);
+        assert_eq!(vm.get_reg(Register::KernelGsBase), 0x7f00_0000_1000);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Faults on kernel code executing a user page with SMEP
+    fn test_supervisor_profile() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+        vm.set_supervisor_profile(SupervisorProfile {
+            smep: true,
+            smap: false,
+        })?;
+
+        let perms = PagePermissions::EXECUTE | PagePermissions::USER;
+        vm.mmap(0x1337000, PAGE_SIZE, perms)?;
+        vm.write(0x1337000, &[0xf4])?; // hlt
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        match vm.run()? {
+            VmExit::PageFault(detail) => assert_eq!(detail.address, 0x1337000),
+            vmexit => panic!("Unexpected vmexit {:?}", vmexit),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    /// Reports a page fault which cannot be delivered as a double fault
+    fn test_double_fault() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x10, // mov eax, [0x10000000]
+        ];
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        // The page fault vector is past the IDT limit: #PF then #GP
+        vm.special_registers.idt.limit = 14 * 16 - 1;
+
+        assert_eq!(vm.run()?, VmExit::DoubleFault);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Accounts for the runs and the pages restored by the resets
+    fn test_stats() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Simple shellcode
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        vm.set_reg(Register::Rax, 0xdeadbeef);
+        vm.set_reg(Register::Rdx, 0x42424242);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let pristine = vm.freeze()?;
+        let mut worker = Vm::from_pristine(&pristine)?;
+        assert_eq!(worker.stats(), VmStats::default());
+
+        assert_eq!(worker.run()?, VmExit::Hlt);
+        worker.reset_pristine(&pristine);
+
+        let stats = worker.stats();
+        assert_eq!(stats.runs, 1);
+        assert_eq!(stats.resets, 1);
+        assert!(stats.last_reset_pages > 0);
+        assert_eq!(stats.reset_pages, stats.last_reset_pages);
+
+        // Nothing was dirtied since the last reset
+        worker.reset_pristine(&pristine);
+        assert_eq!(worker.stats().last_reset_pages, 0);
+        assert_eq!(worker.stats().reset_pages, stats.reset_pages);
+
+        worker.clear_stats();
+        assert_eq!(worker.stats(), VmStats::default());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Resets only the registers, then only a range of the memory
+    fn test_partial_reset() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Writes to two pages
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0x48, 0x89, 0x13, // mov [rbx], rdx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdead0000,
+            2 * PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        vm.set_reg(Register::Rax, 0xdead0010);
+        vm.set_reg(Register::Rbx, 0xdead1010);
+        vm.set_reg(Register::Rdx, 0x42424242);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let origin = vm.clone();
+        assert_eq!(vm.run()?, VmExit::Hlt);
+
+        // Memory is kept
+        vm.reset_registers_only(&origin);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337000);
+        assert_eq!(vm.memory.read_val::<u32>(0xdead0010)?, 0x42424242);
+
+        // Only the range is restored
+        vm.reset_range(&origin, 0xdead1000, PAGE_SIZE)?;
+        assert_eq!(vm.memory.read_val::<u32>(0xdead0010)?, 0x42424242);
+        assert_eq!(vm.memory.read_val::<u32>(0xdead1010)?, 0);
+
+        assert!(vm.reset_range(&origin, 0x4000, 1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    /// Invokes the callbacks around each entry and resumes the handled exits
+    fn test_run_callbacks() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Count the entries in rax, halting twice
+        let shellcode: &[u8] = &[
+            0x48, 0x01, 0xd0, // add rax, rdx
+            0xf4, // hlt
+            0x48, 0x01, 0xd0, // add rax, rdx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rax, 0);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let exits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&exits);
+
+        vm.set_pre_run_callback(Some(Box::new(|vm: &mut Vm| {
+            vm.set_reg(Register::Rdx, 0x10);
+        })));
+        vm.set_exit_callback(Some(Box::new(move |_: &mut Vm, exit: &VmExit| {
+            // Resume the first halt only
+            match (exit, counter.fetch_add(1, Ordering::SeqCst)) {
+                (VmExit::Hlt, 0) => ExitAction::Resume,
+                _ => ExitAction::Return,
+            }
+        })));
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(exits.load(Ordering::SeqCst), 2);
+        assert_eq!(vm.get_reg(Register::Rax), 0x20);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337008);
+
+        // Without callbacks, rdx is left as is
+        vm.set_pre_run_callback(None);
+        vm.set_exit_callback(None);
+        vm.set_reg(Register::Rdx, 1);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rax), 0x21);
+        Ok(())
+    }
+
+    #[test]
+    /// Yields the pages written by the guest until the next reset
+    fn test_dirty_iter() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Simple shellcode
+        let shellcode: &[u8] = &[
+            0x48, 0x89, 0x10, // mov [rax], rdx
+            0xf4, // hlt
+        ];
+
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0xdeadb000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+
+        vm.set_reg(Register::Rax, 0xdeadbeef);
+        vm.set_reg(Register::Rdx, 0x42424242);
+        vm.set_reg(Register::Rip, 0x1337000);
 
-        // Get the snapshot information
-        let info = SnapshotInfo::from_file(snapshot_info)?;
+        let pristine = vm.freeze()?;
+        let mut worker = Vm::from_pristine(&pristine)?;
+        let (target, _) = worker.memory.virt_to_phys(0xdeadb000).unwrap();
+
+        assert_eq!(worker.run()?, VmExit::Hlt);
+
+        let mut dirty: DirtyPages = worker.dirty_iter()?;
+        let (_, page) = dirty.find(|(pa, _)| *pa == target).unwrap();
+        assert_eq!(&page[0xeef..0xef3], &0x42424242u32.to_le_bytes());
+
+        // The dirty log is left as is, and restored by the reset
+        let pages = worker.dirty_iter()?.count();
+        let mut bitmap = Vec::new();
+        worker.read_dirty_log(&mut bitmap)?;
+        let bits: u32 = bitmap.iter().map(|entry| entry.count_ones()).sum();
+        assert_eq!(bits as usize, pages);
+        worker.reset_pristine(&pristine);
+        assert_eq!(worker.stats().last_reset_pages, pages as u64);
+        assert_eq!(worker.dirty_iter()?.count(), 0);
+        Ok(())
+    }
 
-        // Loading the mappings
-        let mut dump = File::open(memory_dump)?;
-        let mut buf: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+    #[test]
+    /// Restores runs of consecutive dirty frames along with isolated ones
+    fn test_reset_runs() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
 
-        // Loop through mapping
-        for mapping in info.mappings {
-            assert!(mapping.start < mapping.end, "mapping.start > mapping.end");
+        // Fill three pages, then write a byte to a fourth one
+        let shellcode: &[u8] = &[
+            0xf3, 0xaa, // rep stosb
+            0x88, 0x02, // mov [rdx], al
+            0xf4, // hlt
+        ];
 
-            // Create the mapping
-            let mapping_size = (mapping.end - mapping.start) as usize;
-            vm.mmap(mapping.start, mapping_size, mapping.permissions)?;
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x2000000,
+            3 * PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.mmap(
+            0x3000000,
+            PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.write_bytes(0x2000000, 0x41, 3 * PAGE_SIZE)?;
 
-            // TODO: Implement more efficient copy to memory
-            // Loop through each page of the mapping and copy it
-            for off in (0..mapping_size).step_by(PAGE_SIZE) {
-                dump.seek(SeekFrom::Start(mapping.physical_offset + off as u64))?;
-                dump.read(&mut buf)?;
-                vm.write(mapping.start + off as u64, &buf)?;
-            }
+        vm.set_reg(Register::Rax, 0x42);
+        vm.set_reg(Register::Rcx, 3 * PAGE_SIZE as u64);
+        vm.set_reg(Register::Rdi, 0x2000000);
+        vm.set_reg(Register::Rdx, 0x3000800);
+        vm.set_reg(Register::Rip, 0x1337000);
+
+        let mut worker = vm.clone();
+        for _ in 0..2 {
+            assert_eq!(worker.run()?, VmExit::Hlt);
+            assert_eq!(worker.memory.read_val::<u8>(0x2002fff)?, 0x42);
+
+            worker.reset(&vm);
+            assert!(worker.stats().last_reset_pages >= 4);
+            let mut data = vec![0u8; 3 * PAGE_SIZE];
+            worker.read(0x2000000, &mut data)?;
+            assert!(data.iter().all(|&b| b == 0x41));
+            assert_eq!(worker.memory.read_val::<u8>(0x3000800)?, 0);
         }
+        Ok(())
+    }
 
-        // Load all the registers
-        vm.set_regs_snapshot(&info.registers);
-        vm.flush_registers()?;
+    #[test]
+    /// Runs a snapshot assembled from maps and register text
+    fn test_snapshot_data() -> Result<()> {
+        let maps = "1337000-1338000 r-xp 00000000 00:00 0\n\
+                    7ffd1c000000-7ffd1c001000 rw-p 00000000 00:00 0 [stack]\n";
+        let registers = "rip 0x1337000\nrsp 0x7ffd1c001000\nrax 0x41\nrflags 0x202\n\
+                         fs_base 0\ngs_base 0\nrbx 0\nrcx 0\nrdx 0\nrsi 0\nrdi 0\n\
+                         rbp 0\nr8 0\nr9 0\nr10 0\nr11 0\nr12 0\nr13 0\nr14 0\nr15 0";
+
+        // push rax; hlt
+        let mut data = vec![0u8; 2 * PAGE_SIZE];
+        data[..2].copy_from_slice(&[0x50, 0xf4]);
+
+        let info = SnapshotInfo::from_maps(maps, registers)?;
+        let mut vm = Vm::from_snapshot_data(&Snapshot::new(info, data), 512 * PAGE_SIZE)?;
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
+        assert_eq!(vm.memory.read_val::<u64>(0x7ffd1c000ff8)?, 0x41);
 
-        Ok(vm)
+        // The dump must hold the mappings
+        let info = SnapshotInfo::from_maps(maps, registers)?;
+        let truncated = Snapshot::new(info, vec![0u8; PAGE_SIZE]);
+        assert!(Vm::from_snapshot_data(&truncated, 512 * PAGE_SIZE).is_err());
+        Ok(())
     }
 
-    /// Reset the `Vm` state from an other one
-    pub fn reset(&mut self, other: &Vm) {
-        // Reset registers
-        self.registers = other.registers;
-        self.special_registers = other.special_registers;
-        self.fs_base = other.fs_base;
-        self.gs_base = other.gs_base;
+    #[test]
+    /// Reads the snapshot pages on their first access, and keeps them
+    /// loaded across resets
+    fn test_snapshot_on_demand() -> Result<()> {
+        const INFO: &str = "../fuzzers/giflib/data/snapshot_info.json";
+        const DUMP: &str = "../fuzzers/giflib/data/snapshot_data.bin";
+        const MEMORY_SIZE: usize = 32 * 1024 * 1024;
+
+        let eager = Vm::from_snapshot(INFO, DUMP, MEMORY_SIZE)?;
+        let mut vm =
+            Vm::from_snapshot_on_demand(INFO, DUMP, MEMORY_SIZE, &MappingFilter::default())?;
+        let rip = vm.get_reg(Register::Rip);
+        let rsp = vm.get_reg(Register::Rsp);
+
+        // Reads from the host do not load the pages
+        let mut expected = [0u8; 16];
+        let mut code = [0u8; 16];
+        eager.read(rip, &mut expected)?;
+        vm.read(rip, &mut code)?;
+        assert_eq!(code, expected);
+        assert_eq!(vm.stats().demand_loads, 0);
+        let top = eager.memory.read_val::<u64>(rsp)?;
+
+        // mov rax, [rsp]; push rax; hlt
+        vm.write(rip, &[0x48, 0x8b, 0x04, 0x24, 0x50, 0xf4])?;
+        assert_eq!(vm.stats().demand_loads, 1);
+        let origin = vm.clone();
+
+        // The stack page is loaded by the guest access
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rax), top);
+        let loads = vm.stats().demand_loads;
+        assert!(loads > 1);
+
+        // The origin lacks the stack page, it comes back from the snapshot
+        vm.reset(&origin);
+        assert_eq!(vm.memory.read_val::<u64>(rsp)?, top);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rax), top);
+        assert_eq!(vm.stats().demand_loads, loads);
+        Ok(())
+    }
+
+    #[test]
+    /// Adds, hits and removes coverage points
+    fn test_coverage_points() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0x90, 0x91, 0x92, 0x93])?;
 
-        // Reset memory state
-        // Here we prefer aborting as if you are resetting a vm with a completely different one you
-        // are doing something extremely wrong.
         assert_eq!(
-            self.memory.host_memory_size(),
-            other.memory.host_memory_size(),
-            "Vm memory mismatch"
+            vm.add_coverage_points(&[0x1337000, 0x1337001, 0x1337002])?,
+            3
         );
+        assert!(!vm.add_coverage_point(0x1337001)?);
+        assert!(vm.add_coverage_point(0x1337003)?);
+        assert!(vm.has_coverage_point(0x1337002));
+        assert_eq!(vm.coverage_points_remaining(), 4);
+        assert_eq!(vm.memory.read_val::<u32>(0x1337000)?, 0xcccccccc);
+        assert!(vm.add_coverage_point(0xdead000).is_err());
+
+        // Hits restore the original byte
+        vm.set_reg(Register::Rip, 0x1337001);
+        assert_eq!(vm.hit_coverage_point()?, Some(0x1337001));
+        assert_eq!(vm.hit_coverage_point()?, None);
+        assert_eq!(vm.coverage_points_remaining(), 3);
+
+        // Clones and frozen states keep the remaining points
+        assert!(vm.clone().has_coverage_point(0x1337000));
+        let pristine = vm.freeze()?;
+        assert_eq!(Vm::from_pristine(&pristine)?.coverage_points_remaining(), 3);
+
+        assert!(vm.remove_coverage_point(0x1337002)?);
+        assert!(!vm.remove_coverage_point(0x1337002)?);
+        assert_eq!(vm.memory.read_val::<u32>(0x1337000)?, 0xcc9291cc);
+
+        vm.clear_coverage_points()?;
+        assert_eq!(vm.coverage_points_remaining(), 0);
+        assert_eq!(vm.memory.read_val::<u32>(0x1337000)?, 0x93929190);
+        Ok(())
+    }
 
-        // Get the dirty log from kvm
-        let dirty_log = self
-            .kvm_vm
-            .get_dirty_log(0, self.memory.host_memory_size())
-            .expect("Could not get dirty log for current vm");
-
-        // Loop through each dirty page and reset it
-        for (bm_index, bm_entry) in dirty_log.iter().enumerate() {
-            let mut bm = *bm_entry;
+    #[test]
+    /// Counts the hits of persistent coverage points, which stay in place
+    fn test_persistent_coverage() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, &[0x90, 0x91])?;
+        vm.set_persistent_coverage(true);
+        vm.add_coverage_points(&[0x1337000, 0x1337001])?;
+
+        // The original byte is restored to step over the point
+        vm.set_reg(Register::Rip, 0x1337001);
+        assert_eq!(vm.hit_coverage_point()?, Some(0x1337001));
+        assert_eq!(vm.memory.read_val::<u16>(0x1337000)?, 0x91cc);
+        assert_ne!(vm.get_reg(Register::Rflags) & (1 << 8), 0);
+        assert!(vm.has_coverage_point(0x1337001));
+        assert_eq!(vm.coverage_points_remaining(), 2);
+
+        // Re-armed on the singlestep exit
+        assert!(vm.rearm_coverage_point(&VmExit::Exception(1))?);
+        assert!(!vm.rearm_coverage_point(&VmExit::Exception(1))?);
+        assert_eq!(vm.memory.read_val::<u16>(0x1337000)?, 0xcccc);
+        assert_eq!(vm.get_reg(Register::Rflags) & (1 << 8), 0);
+
+        vm.hit_coverage_point()?;
+        assert_eq!(vm.coverage_hits()[&0x1337001], 2);
+        vm.clear_coverage_hits();
+        assert!(vm.coverage_hits().is_empty());
+        Ok(())
+    }
 
-            while bm != 0 {
-                // Get next frame dirtied
-                let i = bm.trailing_zeros() as usize;
-                let pa = (bm_index * 64 + i) * PAGE_SIZE;
+    #[test]
+    /// Decodes the virtualization instructions faulting in the guest
+    fn test_virtualization_instruction() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.set_reg(Register::Rip, 0x1337000);
 
-                // Get raw mutable slice to the pmem to restore
-                let mut page_data = self
-                    .memory
-                    .pmem
-                    .raw_slice_mut(pa, PAGE_SIZE)
-                    .expect("Could not restore page in dirty vm");
-
-                // Read original data to the slice
-                other
-                    .memory
-                    .pmem
-                    .read(pa, &mut page_data)
-                    .expect("Could not read physical memory from source vm");
-
-                // Go tp the next bit
-                bm &= bm - 1;
-            }
+        let cases: &[(&[u8], Option<VirtualizationInstruction>)] = &[
+            (
+                &[0xf3, 0x0f, 0xc7, 0x30],
+                Some(VirtualizationInstruction::Vmxon),
+            ),
+            (
+                &[0x66, 0x0f, 0xc7, 0x30],
+                Some(VirtualizationInstruction::Vmclear),
+            ),
+            (
+                &[0x0f, 0x01, 0xc2],
+                Some(VirtualizationInstruction::Vmlaunch),
+            ),
+            (
+                &[0x48, 0x0f, 0x79, 0xc1],
+                Some(VirtualizationInstruction::Vmwrite),
+            ),
+            (
+                &[0x66, 0x0f, 0x38, 0x80, 0x01],
+                Some(VirtualizationInstruction::Invept),
+            ),
+            (&[0x0f, 0x01, 0xd8], Some(VirtualizationInstruction::Vmrun)),
+            // rdrand rax
+            (&[0x48, 0x0f, 0xc7, 0xf0], None),
+            (&[0x0f, 0x0b], None),
+        ];
+        for (code, instruction) in cases {
+            vm.write(0x1337000, code)?;
+            assert_eq!(vm.virtualization_instruction(), *instruction);
         }
 
-        // Define the dirty log clear structure
-        let dirty_log = kvm_bindings::kvm_clear_dirty_log {
-            slot: 0,
-            num_pages: (self.memory.host_memory_size() / PAGE_SIZE) as u32,
-            first_page: 0,
-            __bindgen_anon_1: kvm_bindings::kvm_clear_dirty_log__bindgen_ty_1 {
-                dirty_bitmap: dirty_log.as_ptr() as *mut core::ffi::c_void,
-            },
-        };
+        // mov cr4, rax only matters when enabling VMX
+        vm.write(0x1337000, &[0x0f, 0x22, 0xe0])?;
+        vm.set_reg(Register::Rax, 0x20);
+        assert_eq!(vm.virtualization_instruction(), None);
+        vm.set_reg(Register::Rax, 0x2020);
+        assert_eq!(
+            vm.virtualization_instruction(),
+            Some(VirtualizationInstruction::EnableVmx)
+        );
 
-        // Clear dirty log
-        let ret = unsafe { ioctl::ioctl_with_ref(&self.kvm_vm, KVM_CLEAR_DIRTY_LOG(), &dirty_log) };
-        if ret != 0 {
-            panic!("Failed to clean dirty log");
-        }
+        // rdmsr of IA32_VMX_BASIC
+        vm.write(0x1337000, &[0x0f, 0x32])?;
+        vm.set_reg(Register::Rcx, 0x480);
+        assert_eq!(
+            vm.virtualization_instruction(),
+            Some(VirtualizationInstruction::VmxMsr(0x480))
+        );
+        Ok(())
     }
-}
-
-impl Clone for Vm {
-    fn clone(&self) -> Self {
-        let mut vm =
-            Vm::new(self.memory.host_memory_size()).expect("Could not create vm for clone");
-
-        // Copy registers
-        vm.registers = self.registers;
-        vm.special_registers = self.special_registers;
-        vm.fs_base = self.fs_base;
-        vm.gs_base = self.gs_base;
-
-        // Copy memory
-        let orig_mem = self
-            .memory
-            .pmem
-            .raw_slice(0, self.memory.host_memory_size())
-            .expect("Could not get original physical memory");
-        vm.memory
-            .pmem
-            .write(0, &orig_mem)
-            .expect("Could not set actual memory to original");
 
-        vm
+    #[test]
+    /// Re-installs or retires the coverage points the guest writes over
+    fn test_coverage_write_protection() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+        let rwx = PagePermissions::READ | PagePermissions::WRITE | PagePermissions::EXECUTE;
+        vm.mmap(0x1337000, PAGE_SIZE, rwx)?;
+        vm.mmap(0x1338000, PAGE_SIZE, rwx)?;
+        vm.write(0x1337000, &[0x90, 0x91])?;
+        vm.add_coverage_point(0x1337000)?;
+        vm.set_coverage_write_protection(true)?;
+        vm.add_coverage_points(&[0x1337001, 0x1338000])?;
+        assert!(!vm.memory.virt_to_phys(0x1337000).unwrap().1.writable());
+
+        // Unrelated page faults are reported
+        let mut detail = PageFaultDetail {
+            status: 0,
+            address: 0x1339000,
+        };
+        assert!(!vm.unprotect_written_page(&VmExit::PageFault(detail))?);
+
+        // The write is replayed over the original bytes
+        detail.address = 0x1337001;
+        assert!(vm.unprotect_written_page(&VmExit::PageFault(detail))?);
+        assert_eq!(vm.memory.read_val::<u16>(0x1337000)?, 0x9190);
+        assert!(vm.memory.virt_to_phys(0x1337000).unwrap().1.writable());
+        assert_ne!(vm.get_reg(Register::Rflags) & (1 << 8), 0);
+
+        // The overwritten point is retired, the other one re-installed
+        vm.write_value::<u8>(0x1337001, 0x90)?;
+        assert!(vm.rearm_coverage_point(&VmExit::Exception(1))?);
+        assert_eq!(vm.memory.read_val::<u16>(0x1337000)?, 0x90cc);
+        assert!(!vm.has_coverage_point(0x1337001));
+        assert_eq!(vm.retired_coverage_points(), 1);
+        assert!(!vm.memory.virt_to_phys(0x1337000).unwrap().1.writable());
+
+        // Pages without points left are writable again
+        vm.remove_coverage_point(0x1338000)?;
+        assert!(vm.memory.virt_to_phys(0x1338000).unwrap().1.writable());
+        vm.set_coverage_write_protection(false)?;
+        assert!(vm.memory.virt_to_phys(0x1337000).unwrap().1.writable());
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::{Register, Result, Vm, VmExit};
-    use crate::memory::{PagePermissions, PAGE_SIZE};
 
     #[test]
-    /// Runs a simple piece of code until completion
-    fn test_simple_exec() -> Result<()> {
+    /// Records the reads of a watched buffer, across resets
+    fn test_watch_reads() -> Result<()> {
         let mut vm = Vm::new(512 * PAGE_SIZE)?;
 
-        // Simple shellcode
         let shellcode: &[u8] = &[
-            0x48, 0x01, 0xc2, // add rdx, rax
-            0xcc, // breakpoint
+            0x8a, 0x47, 0x05, // mov al, [rdi+5]
+            0x8a, 0x5f, 0x02, // mov bl, [rdi+2]
+            0x8b, 0x8f, 0xfe, 0x0f, 0x00, 0x00, // mov ecx, [rdi+0xffe]
+            0x88, 0x47, 0x07, // mov [rdi+7], al
+            0xf4, // hlt
         ];
 
-        // Mapping the code
         vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
         vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x2000000,
+            2 * PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.write(0x2000000, b"abcdefgh")?;
+        vm.set_reg(Register::Rdi, 0x2000000);
+        vm.set_reg(Register::Rip, 0x1337000);
 
-        // Set registers to known values
-        vm.set_reg(Register::Rax, 0x1000);
-        vm.set_reg(Register::Rdx, 0x337);
+        let mut worker = vm.clone();
+        worker.watch_reads(0x2000000, 2 * PAGE_SIZE)?;
+        for _ in 0..2 {
+            assert_eq!(worker.run()?, VmExit::Hlt);
+            assert_eq!(worker.get_reg(Register::Rax) & 0xff, b'f' as u64);
+            assert_eq!(worker.get_reg(Register::Rbx) & 0xff, b'c' as u64);
+            assert_eq!(worker.memory.read_val::<u8>(0x2000007)?, b'f');
+
+            // The read spanning both pages faults on each of them
+            assert_eq!(
+                worker.take_watched_reads(),
+                vec![0x2000005, 0x2000002, 0x2000ffe, 0x2001000]
+            );
+            worker.reset(&vm);
+        }
 
-        // Execute from beginning of shellcode
+        worker.unwatch_reads()?;
+        assert_eq!(worker.run()?, VmExit::Hlt);
+        assert!(worker.take_watched_reads().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    /// Reports the overridden CPUID results to ring 0 code
+    fn test_cpuid_override() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        let shellcode: &[u8] = &[
+            0xb8, 0x00, 0x00, 0x00, 0x40, // mov eax, 0x40000000
+            0x31, 0xc9, // xor ecx, ecx
+            0x0f, 0xa2, // cpuid
+            0xf4, // hlt
+        ];
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
         vm.set_reg(Register::Rip, 0x1337000);
 
-        let vmexit = vm.run()?;
+        let result = CpuidResult {
+            eax: 0x4000_0001,
+            ebx: 0x7472_6174,
+            ecx: 0,
+            edx: 0,
+        };
+        vm.set_cpuid_override(0x4000_0000, 0, result)?;
+        assert_eq!(vm.cpuid(0x4000_0000, 0), result);
 
-        assert_eq!(vmexit, VmExit::Breakpoint);
-        assert_eq!(vm.get_reg(Register::Rip), 0x1337003);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert_eq!(vm.get_reg(Register::Rax), 0x4000_0001);
+        assert_eq!(vm.get_reg(Register::Rbx), 0x7472_6174);
 
+        // The overrides survive a clone
+        let clone = vm.clone();
+        assert_eq!(clone.cpuid(0x4000_0000, 0), result);
         Ok(())
     }
 
     #[test]
-    /// Tests the collection and clearing of dirty pages
-    fn test_dirty_status() -> Result<()> {
+    /// Decides the CPUID results of user mode code with the handler
+    fn test_cpuid_handler() -> Result<()> {
         let mut vm = Vm::new(512 * PAGE_SIZE)?;
 
-        // Simple shellcode
         let shellcode: &[u8] = &[
-            0x48, 0x89, 0x10, // mov [rax], rdx
-            0xcc, // int3
+            0x0f, 0xa2, // cpuid
+            0x0f, 0x05, // syscall
         ];
-
-        // Mapping the code
         vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
         vm.write(0x1337000, shellcode)?;
+        vm.enter_user_mode()?;
 
-        // Mapping the target page of the write
-        vm.mmap(
-            0xdeadb000,
-            PAGE_SIZE,
-            PagePermissions::READ | PagePermissions::WRITE,
-        )?;
-
-        // Set registers to known values
-        vm.set_reg(Register::Rax, 0xdeadbeef);
-        vm.set_reg(Register::Rdx, 0x42424242);
-
-        // Execute from beginning of shellcode
+        let perms = PagePermissions::READ | PagePermissions::WRITE | PagePermissions::USER;
+        vm.mmap(0x4000, PAGE_SIZE, perms)?;
+        vm.set_reg(Register::Rsp, 0x5000);
         vm.set_reg(Register::Rip, 0x1337000);
+        vm.set_reg(Register::Rax, 1);
 
-        let vmexit = vm.run()?;
+        // The hypervisor bit is cleared from the table result
+        vm.set_cpuid_handler(Some(Box::new(|leaf, _, mut result: CpuidResult| {
+            if leaf == 1 {
+                result.ecx &= !(1 << 31);
+                result.edx = 0x1337;
+            }
+            result
+        })))?;
 
-        // Sanity check
-        assert_eq!(vmexit, VmExit::Breakpoint);
-        assert_eq!(vm.get_reg(Register::Rip), 0x1337003);
+        assert_eq!(vm.run()?, VmExit::Syscall);
+        assert_eq!(vm.get_reg(Register::Rip), 0x1337004);
+        assert_eq!(vm.get_reg(Register::Rcx) >> 31 & 1, 0);
+        assert_eq!(vm.get_reg(Register::Rdx), 0x1337);
+        Ok(())
+    }
 
-        // Check that the target page was dirtied
-        assert!(vm.dirty_mappings().any(|m| m.address == 0xdeadb000));
+    #[test]
+    #[ignore = "needs a host exposing LBR to its guests"]
+    /// Records the branches taken by the guest in the LBR stack
+    fn test_lbr_branches() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
 
-        // Reset the pages dirty status
-        vm.clear_dirty_mappings();
+        let shellcode: &[u8] = &[
+            0xeb, 0x01, // jmp +1
+            0x90, // nop
+            0xf4, // hlt
+        ];
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.set_reg(Register::Rip, 0x1337000);
 
-        // Check again the dirty pages
-        assert!(vm.dirty_mappings().count() == 0);
+        vm.enable_branch_recording(BranchRecording::Lbr)?;
+        assert_eq!(vm.branch_recording(), Some(BranchRecording::Lbr));
+
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        assert!(vm.take_branches().contains(&Branch {
+            from: 0x1337000,
+            to: 0x1337003
+        }));
+        assert!(vm.take_branches().is_empty());
 
+        // The branches read before are not reported again
+        vm.set_reg(Register::Rip, 0x1337000);
+        assert_eq!(vm.run()?, VmExit::Hlt);
+        let jumps = vm
+            .take_branches()
+            .into_iter()
+            .filter(|branch| branch.from == 0x1337000)
+            .count();
+        assert_eq!(jumps, 1);
         Ok(())
     }
 
     #[test]
-    /// Runs a simple piece of code until completion
-    fn test_simple_syscall() -> Result<()> {
+    /// Logs the reads and writes of two ranges, across resets
+    fn test_log_accesses() -> Result<()> {
         let mut vm = Vm::new(512 * PAGE_SIZE)?;
 
-        // The syscall in the shellcode will add rax and rdx together
         let shellcode: &[u8] = &[
-            0x0f, 0x05, // syscall
-            0xcc, // breakpoint
+            0x48, 0x8b, 0x47, 0x08, // mov rax, [rdi+8]
+            0x66, 0x89, 0x46, 0x02, // mov [rsi+2], ax
+            0x8a, 0x5f, 0x40, // mov bl, [rdi+0x40]
+            0xf4, // hlt
         ];
 
-        // Mapping the code
         vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
         vm.write(0x1337000, shellcode)?;
+        vm.mmap(
+            0x2000000,
+            2 * PAGE_SIZE,
+            PagePermissions::READ | PagePermissions::WRITE,
+        )?;
+        vm.write(0x2000008, b"abcdefgh")?;
+        vm.set_reg(Register::Rdi, 0x2000000);
+        vm.set_reg(Register::Rsi, 0x2001000);
+        vm.set_reg(Register::Rip, 0x1337000);
 
-        // Set registers to known values
-        vm.set_reg(Register::Rax, 0x1000);
-        vm.set_reg(Register::Rdx, 0x337);
+        let mut worker = vm.clone();
+        worker.log_accesses(&[0x2000000..0x2000020, 0x2001000..0x2001010])?;
+        for _ in 0..2 {
+            assert_eq!(worker.run()?, VmExit::Hlt);
+            assert_eq!(worker.memory.read_val::<u16>(0x2001002)?, 0x6261);
+
+            // The read outside of the ranges, in a logged page, is not logged
+            assert_eq!(
+                worker.take_accesses(),
+                vec![
+                    MemoryAccess {
+                        pc: 0x1337000,
+                        address: 0x2000008,
+                        size: 8,
+                        kind: AccessKind::Read,
+                    },
+                    MemoryAccess {
+                        pc: 0x1337004,
+                        address: 0x2001002,
+                        size: 2,
+                        kind: AccessKind::Write,
+                    },
+                ]
+            );
+            worker.reset(&vm);
+        }
 
-        // Execute from beginning of shellcode
-        vm.set_reg(Register::Rip, 0x1337000);
+        worker.unlog_accesses()?;
+        assert_eq!(worker.run()?, VmExit::Hlt);
+        assert!(worker.take_accesses().is_empty());
+        Ok(())
+    }
 
-        let vmexit = vm.run()?;
+    #[test]
+    /// Reads the operands of comparisons with registers, memory and
+    /// immediates, under a breakpoint
+    fn test_comparison_operands() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
 
-        assert_eq!(vmexit, VmExit::Syscall);
+        let shellcode: &[u8] = &[
+            0x48, 0x39, 0xd8, // cmp rax, rbx
+            0x3b, 0x4f, 0x08, // cmp ecx, [rdi+8]
+            0x80, 0xfc, 0x41, // cmp ah, 0x41
+            0x48, 0x8d, 0x07, // lea rax, [rdi]
+        ];
 
-        // Emulated syscall doing rax = rax + rdx
-        vm.set_reg(
-            Register::Rax,
-            vm.get_reg(Register::Rax) + vm.get_reg(Register::Rdx),
+        vm.mmap(0x1337000, PAGE_SIZE, PagePermissions::EXECUTE)?;
+        vm.write(0x1337000, shellcode)?;
+        vm.write_value::<u8>(0x1337003, 0xcc)?;
+        vm.mmap(0x2000000, PAGE_SIZE, PagePermissions::READ)?;
+        vm.write(0x2000008, b"MAGIC!!!")?;
+        vm.set_reg(Register::Rax, 0x4142);
+        vm.set_reg(Register::Rbx, 0x1337);
+        vm.set_reg(Register::Rcx, 0x1_4947_414d);
+        vm.set_reg(Register::Rdi, 0x2000000);
+
+        assert_eq!(
+            vm.comparison_operands(0x1337000, None),
+            Some(CmpOperands {
+                size: 8,
+                left: 0x4142,
+                right: 0x1337,
+            })
+        );
+        assert_eq!(
+            vm.comparison_operands(0x1337003, Some(0x3b)),
+            Some(CmpOperands {
+                size: 4,
+                left: 0x4947_414d,
+                right: u32::from_le_bytes(*b"MAGI") as u64,
+            })
+        );
+        assert_eq!(
+            vm.comparison_operands(0x1337006, None),
+            Some(CmpOperands {
+                size: 1,
+                left: 0x41,
+                right: 0x41,
+            })
         );
+        assert_eq!(vm.comparison_operands(0x1337009, None), None);
 
-        let vmexit_end = vm.run()?;
+        // Unmapped memory operands are not read
+        vm.set_reg(Register::Rdi, 0x3000000);
+        assert_eq!(vm.comparison_operands(0x1337003, Some(0x3b)), None);
+        Ok(())
+    }
 
-        assert_eq!(vmexit_end, VmExit::Breakpoint);
-        assert_eq!(vm.get_reg(Register::Rip), 0x1337002);
-        assert_eq!(vm.get_reg(Register::Rax), 0x1337);
+    #[test]
+    /// Enables the AVX state in the guest XCR0, skipped on hosts without AVX
+    fn test_xcr0_avx() -> Result<()> {
+        let mut vm = Vm::new(512 * PAGE_SIZE)?;
+
+        // Not every host supports AVX
+        if vm.set_xcr0(XCR0_AVX).is_err() {
+            return Ok(());
+        }
+
+        let xcrs = vm
+            .kvm_vcpu
+            .get_xcrs()
+            .map_err(|_| VmError::HvError("Could not get xcrs"))?;
+        assert_eq!(xcrs.xcrs[0].value, XCR0_AVX);
 
+        // Clones program the same components
+        let clone = vm.clone();
+        let xcrs = clone
+            .kvm_vcpu
+            .get_xcrs()
+            .map_err(|_| VmError::HvError("Could not get xcrs"))?;
+        assert_eq!(xcrs.xcrs[0].value, XCR0_AVX);
         Ok(())
     }
 }
@@ -28,10 +28,14 @@ impl IdtEntry {
         }
     }
 }
-#[derive(Debug, Copy, Clone)]
+/// Privilege level of a descriptor or selector
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum PrivilegeLevel {
+    /// Kernel mode
     Ring0 = 0,
+    /// User mode
+    Ring3 = 3,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -200,6 +204,69 @@ impl TssEntry {
     }
 }
 
+/// Builder of a GDT made of flat 64 bits segments and TSS descriptors
+#[derive(Debug, Clone)]
+pub struct GdtBuilder {
+    /// Descriptors, starting with the null descriptor
+    entries: Vec<u64>,
+}
+
+impl GdtBuilder {
+    /// Creates a GDT holding only the null descriptor
+    pub fn new() -> Self {
+        GdtBuilder { entries: vec![0] }
+    }
+
+    /// Adds a segment descriptor, returning its selector
+    fn add_segment(&mut self, type_: u64, long_mode: bool, dpl: PrivilegeLevel) -> u16 {
+        let mut descriptor: u64 = 1 << 47; // Present
+        descriptor |= (dpl as u64) << 45; // Dpl
+        descriptor |= 1 << 44; // Code or data
+        descriptor |= type_ << 40; // Type
+        if long_mode {
+            descriptor |= 1 << 53; // 64 bits code
+        }
+
+        self.entries.push(descriptor);
+        ((self.entries.len() - 1) as u16) << 3 | dpl as u16
+    }
+
+    /// Adds an execute/read 64 bits code segment, returning its selector
+    #[inline]
+    pub fn code_segment(&mut self, dpl: PrivilegeLevel) -> u16 {
+        self.add_segment(0b1010, true, dpl)
+    }
+
+    /// Adds a read/write data segment, returning its selector
+    #[inline]
+    pub fn data_segment(&mut self, dpl: PrivilegeLevel) -> u16 {
+        self.add_segment(0b0010, false, dpl)
+    }
+
+    /// Adds the descriptor of a TSS (see `Tss`) located at `base`,
+    /// returning its selector
+    pub fn tss(&mut self, base: u64, dpl: PrivilegeLevel) -> u16 {
+        let selector = (self.entries.len() as u16) << 3;
+
+        // A TSS descriptor spans two entries
+        let entry: [u64; 2] = unsafe { core::mem::transmute(TssEntry::new(base, dpl)) };
+        self.entries.extend_from_slice(&entry);
+        selector
+    }
+
+    /// Returns the descriptors of the table
+    #[inline]
+    pub fn collect(&self) -> Vec<u64> {
+        self.entries.clone()
+    }
+}
+
+impl Default for GdtBuilder {
+    fn default() -> Self {
+        GdtBuilder::new()
+    }
+}
+
 /// IA-32e exception frame
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]